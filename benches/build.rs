@@ -0,0 +1,50 @@
+use std::time::Instant;
+
+use rwr::cpu::bvh::{BuildMode, BuildPreference, Bvh};
+use rwr::cpu::lbvh::LbvhOptions;
+use rwr::cpu::primary_rays;
+use rwr::cpu::ray::Hit;
+use rwr::cpu::triangle::terrain;
+
+fn main() {
+    let tris = terrain(256);
+    let rays = primary_rays(640, 480);
+
+    println!("{} triangles, {} rays", tris.len(), rays.len());
+
+    let modes = [
+        ("sah", BuildMode::Sah),
+        ("fast trace", BuildPreference::PreferFastTrace.build_mode()),
+        ("lbvh", BuildMode::Lbvh(LbvhOptions::default())),
+        ("lbvh+ploc", BuildMode::Lbvh(LbvhOptions { ploc_radius: Some(16), ..Default::default() })),
+        ("default", BuildPreference::None.build_mode()),
+        ("fast build", BuildPreference::PreferFastBuild.build_mode()),
+    ];
+
+    let mut reference: Option<Vec<Option<Hit>>> = None;
+
+    for (name, mode) in modes {
+        let start = Instant::now();
+        let bvh = Bvh::build_with(&tris, mode);
+        let build_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let start = Instant::now();
+        let hits: Vec<Option<Hit>> = rays.iter().map(|r| bvh.intersect(&tris, r)).collect();
+        let rays_per_sec = rays.len() as f64 / start.elapsed().as_secs_f64();
+
+        println!("{:<10} build {:>8.2} ms, {:>6.2} Mrays/s, {}", name, build_ms, rays_per_sec / 1.0e6, bvh.stats());
+
+        match &reference {
+            Some(reference) => {
+                for (a, b) in reference.iter().zip(&hits) {
+                    match (a, b) {
+                        (Some(a), Some(b)) => assert!((a.t - b.t).abs() < 1.0e-5),
+                        (None, None) => {}
+                        _ => panic!("{} disagrees with sah", name),
+                    }
+                }
+            }
+            None => reference = Some(hits),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::time::Instant;
+
+use rwr::cpu::bvh::Bvh;
+use rwr::cpu::primary_rays;
+use rwr::cpu::ray::Hit;
+use rwr::cpu::triangle::terrain;
+
+const ITERATIONS: u32 = 5;
+
+//ほかの処理に邪魔された回を除くため一番速かった回の時間
+fn best_secs(mut f: impl FnMut() -> Vec<Option<Hit>>) -> f64 {
+    std::hint::black_box(f());
+    (0..ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            std::hint::black_box(f());
+            start.elapsed().as_secs_f64()
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn main() {
+    let tris = terrain(256);
+    let rays = primary_rays(640, 480);
+
+    let bvh = Bvh::build(&tris);
+    let compressed = bvh.compress(&tris);
+
+    let uncompressed_secs = best_secs(|| rays.iter().map(|r| bvh.intersect(&tris, r)).collect());
+    let compressed_secs = best_secs(|| rays.iter().map(|r| compressed.intersect(r)).collect());
+
+    println!("bvh        {}", bvh.stats());
+    println!("compressed {}", compressed.stats());
+    println!(
+        "traversal  {:.2} Mrays/s -> {:.2} Mrays/s ({:.1}% slower)",
+        rays.len() as f64 / uncompressed_secs / 1.0e6,
+        rays.len() as f64 / compressed_secs / 1.0e6,
+        (compressed_secs / uncompressed_secs - 1.0) * 100.0,
+    );
+}
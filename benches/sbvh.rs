@@ -0,0 +1,86 @@
+use std::time::Instant;
+
+use rwr::cpu::bvh::{BuildMode, Bvh};
+use rwr::cpu::primary_rays;
+use rwr::cpu::ray::{Hit, Ray};
+use rwr::cpu::sbvh::SbvhOptions;
+use rwr::cpu::triangle::Triangle;
+use rwr::math::Vec3;
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 480;
+
+//斜めに走る細長い梁と床のグリッドの建築っぽいシーン
+//梁のAABBが細長い三角形に比べて大きすぎるので重心分割のBVHが苦手とする
+fn beams(count: u32) -> Vec<Triangle> {
+    let along = Vec3::new(1.0, 1.0, 0.0).normalize();
+    let across = Vec3::new(-1.0, 1.0, 0.0).normalize();
+    let width = 0.004;
+    let length = 0.8;
+
+    //決定的な疑似乱数
+    let hash = |i: u32, salt: u32| {
+        let h = (i.wrapping_mul(0x9E37_79B9) ^ salt.wrapping_mul(0x85EB_CA6B)).wrapping_mul(0xC2B2_AE35);
+        (h >> 8) as f32 / (1 << 24) as f32
+    };
+
+    let mut tris = vec![];
+    for i in 0..count {
+        let center = Vec3::new(hash(i, 1) * 2.4 - 1.2, hash(i, 2) * 2.4 - 1.2, hash(i, 3) * 0.4);
+        let start = center - along * (length * 0.5);
+        let end = center + along * (length * 0.5);
+        let w = across * width;
+
+        tris.push(Triangle::new(start - w, end - w, end + w));
+        tris.push(Triangle::new(start - w, end + w, start + w));
+    }
+
+    //床
+    let resolution = 64;
+    let floor = |i: u32, j: u32| Vec3::new(i as f32 / resolution as f32 * 4.0 - 2.0, j as f32 / resolution as f32 * 4.0 - 2.0, -0.5);
+    for j in 0..resolution {
+        for i in 0..resolution {
+            tris.push(Triangle::new(floor(i, j), floor(i + 1, j), floor(i + 1, j + 1)));
+            tris.push(Triangle::new(floor(i, j), floor(i + 1, j + 1), floor(i, j + 1)));
+        }
+    }
+
+    tris
+}
+
+fn trace(name: &str, bvh: &Bvh, tris: &[Triangle], rays: &[Ray]) -> Vec<Option<Hit>> {
+    let start = Instant::now();
+    let hits: Vec<Option<Hit>> = rays.iter().map(|r| bvh.intersect(tris, r)).collect();
+    let secs = start.elapsed().as_secs_f64();
+
+    println!("{:<6} {}", name, bvh.stats());
+    println!("{:<6} {:.2} Mrays/s", "", rays.len() as f64 / secs / 1.0e6);
+
+    hits
+}
+
+fn main() {
+    let tris = beams(2000);
+    let rays = primary_rays(WIDTH, HEIGHT);
+
+    println!("{} triangles, {} rays", tris.len(), rays.len());
+
+    let start = Instant::now();
+    let sah = Bvh::build_with(&tris, BuildMode::Sah);
+    println!("sah build: {:.1} ms", start.elapsed().as_secs_f64() * 1000.0);
+
+    let start = Instant::now();
+    let sbvh = Bvh::build_with(&tris, BuildMode::Sbvh(SbvhOptions::default()));
+    println!("sbvh build: {:.1} ms", start.elapsed().as_secs_f64() * 1000.0);
+
+    let a = trace("sah", &sah, &tris, &rays);
+    let b = trace("sbvh", &sbvh, &tris, &rays);
+
+    for (a, b) in a.iter().zip(&b) {
+        match (a, b) {
+            (Some(a), Some(b)) => assert!((a.t - b.t).abs() < 1.0e-5),
+            (None, None) => {}
+            _ => panic!("sah and sbvh disagree"),
+        }
+    }
+}
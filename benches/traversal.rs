@@ -0,0 +1,62 @@
+use std::time::Instant;
+
+use rwr::cpu::bvh::Bvh;
+use rwr::cpu::packet::STREAM_PACKET_SIZE;
+use rwr::cpu::primary_rays;
+use rwr::cpu::ray::{Hit, Ray};
+use rwr::cpu::triangle::{terrain, Triangle};
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 480;
+const ITERATIONS: u32 = 5;
+
+fn bench(name: &str, rays: &[Ray], mut f: impl FnMut() -> Vec<Option<Hit>>) -> Vec<Option<Hit>> {
+    let mut hits = f();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        hits = f();
+    }
+    let secs = start.elapsed().as_secs_f64();
+
+    let rays_per_sec = (rays.len() as u32 * ITERATIONS) as f64 / secs;
+    println!("{:<12} {:>8.2} Mrays/s", name, rays_per_sec / 1.0e6);
+
+    hits
+}
+
+fn packets<const N: usize>(bvh: &Bvh, tris: &[Triangle], rays: &[Ray]) -> Vec<Option<Hit>> {
+    let mut hits = Vec::with_capacity(rays.len());
+    for chunk in rays.chunks(N) {
+        match <&[Ray; N]>::try_from(chunk) {
+            Ok(packet) => hits.extend(bvh.intersect_packet(tris, packet)),
+            Err(_) => hits.extend(chunk.iter().map(|r| bvh.intersect(tris, r))),
+        }
+    }
+    hits
+}
+
+fn main() {
+    let tris = terrain(256);
+    let bvh = Bvh::build(&tris);
+    let rays = primary_rays(WIDTH, HEIGHT);
+
+    println!("{} triangles, {} nodes, {} rays", tris.len(), bvh.nodes.len(), rays.len());
+
+    let single = bench("single", &rays, || rays.iter().map(|r| bvh.intersect(&tris, r)).collect());
+    let packet8 = bench("packet x8", &rays, || packets::<8>(&bvh, &tris, &rays));
+    let packet16 = bench("packet x16", &rays, || packets::<16>(&bvh, &tris, &rays));
+    let stream = bench(&format!("stream x{}", STREAM_PACKET_SIZE), &rays, || bvh.intersect_stream(&tris, &rays));
+
+    //共有辺の上のレイは同じtで複数の三角形に当たるのでtで比べる
+    let same = |a: &[Option<Hit>], b: &[Option<Hit>]| {
+        a.iter().zip(b).all(|(a, b)| match (a, b) {
+            (Some(a), Some(b)) => (a.t - b.t).abs() < 1.0e-5,
+            (None, None) => true,
+            _ => false,
+        })
+    };
+    assert!(same(&single, &packet8));
+    assert!(same(&single, &packet16));
+    assert!(same(&single, &stream));
+}
@@ -1,5 +1,36 @@
 RaytracingAccelerationStructure gRtScene : register(t0);
 RWTexture2D<float4> gOutput : register(u0);
+//t[܂
+RWTexture2D<float4> gAccumulation : register(u1);
+StructuredBuffer<float> gAlphaMask : register(t1);
+
+cbuffer AlphaTest : register(b0) {
+    uint gAlphaMaskWidth;
+    uint gAlphaMaskHeight;
+    float gAlphaCutoff;
+};
+
+//src/camera.rsHlslCamera
+cbuffer Camera : register(b1) {
+    float3 gCameraPosition;
+    float gLensRadius;
+    float3 gCameraRight;
+    float gFocusDistance;
+    float3 gCameraUp;
+    uint gProjection;
+    float3 gCameraForward;
+    uint gBladeCount;
+    float2 gImagePlane;
+    float gBladeRotation;
+    float gFisheyeFov;
+};
+
+//src/cpu/shaders.rsHlslFrame
+cbuffer Frame : register(b2) {
+    //gAccumulationɑt[ 0Ȃ痚̂Ă
+    uint gSampleCount;
+    float gExposure;
+};
 
 struct Payload {
     float3 color;
@@ -9,6 +40,191 @@ struct MyAttribute {
     float2 barys;
 };
 
+struct ShadowPayload {
+    bool occluded;
+};
+
+//}eAcallableVF[_[ɓn
+struct MaterialParams {
+    float2 barys;
+    float3 color;
+};
+
+//C̎ RayContributionToHitGroupIndexMissShaderIndexɎg
+static const uint RAY_TYPE_RADIANCE = 0;
+static const uint RAY_TYPE_SHADOW = 1;
+static const uint RAY_TYPE_COUNT = 2;
+
+static const uint PROJECTION_PERSPECTIVE = 0;
+static const uint PROJECTION_ORTHOGRAPHIC = 1;
+static const uint PROJECTION_THIN_LENS = 2;
+static const uint PROJECTION_EQUIRECTANGULAR = 3;
+static const uint PROJECTION_FISHEYE = 4;
+static const uint PROJECTION_CUBE_CROSS = 5;
+static const uint PROJECTION_CUBE_STRIP = 6;
+
+static const float PI = 3.14159265;
+
+static const float3 LIGHT_DIRECTION = normalize(float3(0.5, 1.0, 1.0));
+static const float SHADOW_FACTOR = 0.3;
+static const float SHADOW_RAY_T_MIN = 0.001;
+
+//ՂĂtrue
+//ŏɌqbgőł؂Aclosest-hitĂ΂Ȃ̂ň
+bool TraceOcclusion(float3 origin, float3 direction) {
+    RayDesc rayDesc;
+    rayDesc.Origin = origin;
+    rayDesc.Direction = direction;
+    rayDesc.TMin = SHADOW_RAY_T_MIN;
+    rayDesc.TMax = 100000;
+
+    ShadowPayload payload;
+    payload.occluded = true;
+
+    TraceRay(
+        gRtScene,
+        RAY_FLAG_ACCEPT_FIRST_HIT_AND_END_SEARCH | RAY_FLAG_SKIP_CLOSEST_HIT_SHADER,
+        0xFF,
+        RAY_TYPE_SHADOW,
+        RAY_TYPE_COUNT,
+        RAY_TYPE_SHADOW,
+        rayDesc,
+        payload
+    );
+
+    return payload.occluded;
+}
+
+bool PassesAlphaTest(float2 barys) {
+    //_obt@UVȂ̂ŏdSŴ܂UVɂ
+    float2 uv = frac(barys);
+    uint2 texel = min(uint2(uv * float2(gAlphaMaskWidth, gAlphaMaskHeight)), uint2(gAlphaMaskWidth - 1, gAlphaMaskHeight - 1));
+
+    return gAlphaMask[texel.y * gAlphaMaskWidth + texel.x] >= gAlphaCutoff;
+}
+
+//sNZƂɌ܂闐 src/camera.rspcg_hashƓ
+uint PcgHash(uint v) {
+    uint state = v * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+//24rbg[0, 1)ɂ
+float UnitFloat(uint v) {
+    return (v >> 8) * (1.0 / 16777216.0);
+}
+
+//[0, 1)^2𔼌a1̍i̒̓_ɂ H3Ȃ~
+float2 SampleLens(float2 u) {
+    if (gBladeCount < 3) {
+        float r = sqrt(u.x);
+        float theta = 2.0 * PI * u.y;
+        return float2(cos(theta), sin(theta)) * r;
+    }
+
+    //H1̎Op`(S, p0, p1)Ił̒ňlɎ
+    float blades = float(gBladeCount);
+    float blade = floor(u.x * blades);
+    float s = sqrt(u.x * blades - blade);
+    float angle = 2.0 * PI / blades;
+    float a0 = gBladeRotation + blade * angle;
+    float a1 = a0 + angle;
+    return (float2(cos(a0), sin(a0)) * (1.0 - u.y) + float2(cos(a1), sin(a1)) * u.y) * s;
+}
+
+//L[u}bv̖ (O, E, )J̑OAEȀd݂ŕ\
+//Ԃ͉EAAAAOA src/camera.rsCUBE_FACESƓ
+static const float3x3 CUBE_FACES[6] = {
+    float3x3(0, 1, 0, -1, 0, 0, 0, 0, 1),
+    float3x3(0, -1, 0, 1, 0, 0, 0, 0, 1),
+    float3x3(0, 0, 1, 0, 1, 0, -1, 0, 0),
+    float3x3(0, 0, -1, 0, 1, 0, 1, 0, 0),
+    float3x3(1, 0, 0, 0, 1, 0, 0, 0, 1),
+    float3x3(-1, 0, 0, 0, -1, 0, 0, 0, 1),
+};
+
+//\4x3̃}Xɒu 󂢂Ă}X-1
+static const int CUBE_CROSS[12] = {
+    -1, 2, -1, -1,
+    1, 4, 0, 5,
+    -1, 3, -1, -1,
+};
+
+//(O, E, )̏d݂烏[h̕ɂ
+float3 CameraBasis(float3 w) {
+    return gCameraForward * w.x + gCameraRight * w.y + gCameraUp * w.z;
+}
+
+//ndc͉ʂ̍(-1, -1)
+//̉~̊OL[u}bv̋󂢂Ă}X̂悤ɃCȂƂfalse
+bool CameraRay(float2 ndc, float2 lensSample, out RayDesc rayDesc) {
+    float2 p = ndc * gImagePlane;
+    float3 offset = gCameraRight * p.x + gCameraUp * p.y;
+
+    rayDesc.TMin = 0;
+    rayDesc.TMax = 100000;
+    rayDesc.Origin = gCameraPosition;
+
+    if (gProjection == PROJECTION_ORTHOGRAPHIC) {
+        rayDesc.Origin = gCameraPosition + offset;
+        rayDesc.Direction = gCameraForward;
+        return true;
+    }
+
+    if (gProjection == PROJECTION_EQUIRECTANGULAR) {
+        float longitude = p.x * PI;
+        float latitude = p.y * PI * 0.5;
+        rayDesc.Direction = CameraBasis(float3(cos(latitude) * cos(longitude), cos(latitude) * sin(longitude), sin(latitude)));
+        return true;
+    }
+
+    if (gProjection == PROJECTION_FISHEYE) {
+        float r = length(p);
+        if (r > 1.0) {
+            return false;
+        }
+        //S̋ɔႵđOX
+        float theta = r * gFisheyeFov * 0.5;
+        float s = r > 0.0 ? sin(theta) / r : 0.0;
+        rayDesc.Direction = CameraBasis(float3(cos(theta), p.x * s, p.y * s));
+        return true;
+    }
+
+    if (gProjection == PROJECTION_CUBE_CROSS || gProjection == PROJECTION_CUBE_STRIP) {
+        //ʂ̍(0, 0)AE(1, 1)ɂă}XI
+        float2 uv = float2(p.x + 1.0, 1.0 - p.y) * 0.5;
+        bool cross = gProjection == PROJECTION_CUBE_CROSS;
+        float2 cells = cross ? float2(4, 3) : float2(6, 1);
+        float2 cell = min(floor(uv * cells), cells - 1.0);
+        int face = cross ? CUBE_CROSS[uint(cell.y) * 4 + uint(cell.x)] : int(cell.x);
+        if (face < 0) {
+            return false;
+        }
+
+        //}X̒ʂ[-1, 1]ɂ
+        float2 local = uv * cells - cell;
+        float a = local.x * 2.0 - 1.0;
+        float b = 1.0 - local.y * 2.0;
+        float3x3 f = CUBE_FACES[face];
+        rayDesc.Direction = normalize(CameraBasis(f[0]) + CameraBasis(f[1]) * a + CameraBasis(f[2]) * b);
+        return true;
+    }
+
+    float3 direction = normalize(gCameraForward + offset);
+    rayDesc.Direction = direction;
+
+    if (gProjection == PROJECTION_THIN_LENS) {
+        //ŶǂʂĂsgʂ̓_ɏW܂
+        float3 focus = gCameraPosition + direction * (gFocusDistance / dot(direction, gCameraForward));
+        float2 lens = SampleLens(lensSample);
+        rayDesc.Origin = gCameraPosition + gCameraRight * (lens.x * gLensRadius) + gCameraUp * (lens.y * gLensRadius);
+        rayDesc.Direction = normalize(focus - rayDesc.Origin);
+    }
+
+    return true;
+}
+
 //Ray Generation VF[_[
 //C𔭎˂VF[_[
 [shader("raygeneration")]
@@ -18,11 +234,14 @@ void MainRayGen() {
 
     float2 d = (launchIndex.xy + 0.5) / dims.xy * 2.0 - 1.0;
 
+    uint h1 = PcgHash(launchIndex.y * DispatchRaysDimensions().x + launchIndex.x);
+    uint h2 = PcgHash(h1);
     RayDesc rayDesc;
-    rayDesc.Origin = float3(d.x, -d.y, 1);
-    rayDesc.Direction = float3(0, 0, -1);
-    rayDesc.TMin = 0;
-    rayDesc.TMax = 100000;
+    if (!CameraRay(float2(d.x, -d.y), float2(UnitFloat(h1), UnitFloat(h2)), rayDesc)) {
+        //CȂsNZ͍
+        gOutput[launchIndex.xy] = float4(0, 0, 0, 1);
+        return;
+    }
 
     Payload payload;
     payload.color = float3(0, 0, 0);
@@ -34,9 +253,9 @@ void MainRayGen() {
         gRtScene,
         flags,
         rayMask,
-        0,
-        1,
-        0,
+        RAY_TYPE_RADIANCE,
+        RAY_TYPE_COUNT,
+        RAY_TYPE_RADIANCE,
         rayDesc,
         payload
     );
@@ -46,6 +265,25 @@ void MainRayGen() {
     gOutput[launchIndex.xy] = float4(col, 1);
 }
 
+//t[OtaccumulatepX
+//MainRayGengOutputgAccumulation̕ςɑ
+[shader("raygeneration")]
+void AccumulateRayGen() {
+    uint2 launchIndex = DispatchRaysIndex().xy;
+
+    float3 history = gSampleCount == 0 ? float3(0, 0, 0) : gAccumulation[launchIndex].rgb;
+    float3 average = lerp(history, gOutput[launchIndex].rgb, 1.0 / (gSampleCount + 1));
+    gAccumulation[launchIndex] = float4(average, 1);
+}
+
+//t[Otpost_processpX
+//post_outputtrace_outputƓXbgȂ̂gOutputɏ
+[shader("raygeneration")]
+void PostProcessRayGen() {
+    uint2 launchIndex = DispatchRaysIndex().xy;
+    gOutput[launchIndex] = float4(saturate(gAccumulation[launchIndex].rgb * gExposure), 1);
+}
+
 //Miss VF[_[
 //Cǂ̃IuWFNgɂՓ˂ȂƂɌĂ΂VF[_[
 [shader("miss")]
@@ -57,8 +295,56 @@ void MainMiss(inout Payload payload) {
 //CIuWFNgɏՓ˂ƂɌĂ΂VF[_[
 [shader("closesthit")]
 void MainClosestHit(inout Payload payload, MyAttribute attrib) {
-    float3 col = 0;
-    col.xy = attrib.barys;
-    col.z = 1.0 - col.x - col.y;
+    //}eAInstanceID()ԖڂcallableVF[_[ŕ]
+    MaterialParams params;
+    params.barys = attrib.barys;
+    params.color = 0;
+    CallShader(InstanceID(), params);
+
+    float3 col = params.color;
+
+    float3 position = WorldRayOrigin() + WorldRayDirection() * RayTCurrent();
+    if (TraceOcclusion(position, LIGHT_DIRECTION)) {
+        col *= SHADOW_FACTOR;
+    }
+
     payload.color = col;
 }
+
+//AnyHit VF[_[
+//słȂWIgɓ邽тɌĂ΂AAt@臒lȂ瓖ȂƂɂ
+[shader("anyhit")]
+void MainAnyHit(inout Payload payload, MyAttribute attrib) {
+    if (!PassesAlphaTest(attrib.barys)) {
+        IgnoreHit();
+    }
+}
+
+//ẽCpMiss VF[_[
+[shader("miss")]
+void ShadowMiss(inout ShadowPayload payload) {
+    payload.occluded = false;
+}
+
+//ẽCpAnyHit VF[_[
+//ĂȂ΂ŒTI
+[shader("anyhit")]
+void ShadowAnyHit(inout ShadowPayload payload, MyAttribute attrib) {
+    if (!PassesAlphaTest(attrib.barys)) {
+        IgnoreHit();
+    }
+    AcceptHitAndEndSearch();
+}
+
+//Callable VF[_[
+//}eAƂ1 яshaders.rsdefault_materials()Ɠ
+[shader("callable")]
+void BarycentricMaterial(inout MaterialParams params) {
+    params.color = float3(params.barys, 1.0 - params.barys.x - params.barys.y);
+}
+
+[shader("callable")]
+void CheckerMaterial(inout MaterialParams params) {
+    uint checker = (uint(params.barys.x * 8.0) + uint(params.barys.y * 8.0)) % 2;
+    params.color = checker == 0 ? 0.9 : 0.2;
+}
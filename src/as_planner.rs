@@ -0,0 +1,311 @@
+//GetRaytracingAccelerationStructurePrebuildInfoの結果からバッファの配置を決める
+//D3D12の型には依存しないのでWindows以外でも動く
+
+//D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BYTE_ALIGNMENT
+//結果バッファもスクラッチバッファもこのアライメントが必要
+pub const AS_BYTE_ALIGNMENT: u64 = 256;
+pub const DEFAULT_POOL_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AsType {
+    BottomLevel,
+    TopLevel,
+}
+
+//D3D12_RAYTRACING_ACCELERATION_STRUCTURE_PREBUILD_INFOと同じ中身
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrebuildInfo {
+    pub result_data_max_size: u64,
+    pub scratch_data_size: u64,
+    pub update_scratch_data_size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum BuildInputs {
+    //ジオメトリごとの三角形数
+    BottomLevel { triangle_counts: Vec<u32> },
+    TopLevel { instance_count: u32 },
+}
+
+impl BuildInputs {
+    pub fn as_type(&self) -> AsType {
+        match self {
+            BuildInputs::BottomLevel { .. } => AsType::BottomLevel,
+            BuildInputs::TopLevel { .. } => AsType::TopLevel,
+        }
+    }
+
+    //デバイスなしで使う見積もり
+    //ドライバの実際の値とは一致しないが、ノード数が要素数の約2倍になる前提で大きめに取る
+    pub fn estimate_prebuild_info(&self) -> PrebuildInfo {
+        const HEADER_SIZE: u64 = 256;
+        const NODE_SIZE: u64 = 64;
+        const TRIANGLE_SIZE: u64 = 48;
+        const INSTANCE_SIZE: u64 = 128;
+
+        match self {
+            BuildInputs::BottomLevel { triangle_counts } => {
+                let prims: u64 = triangle_counts.iter().map(|&c| c as u64).sum();
+                let geometries = triangle_counts.len() as u64;
+
+                PrebuildInfo {
+                    result_data_max_size: HEADER_SIZE + geometries * 16 + prims * (2 * NODE_SIZE + TRIANGLE_SIZE),
+                    scratch_data_size: HEADER_SIZE + prims * 2 * NODE_SIZE,
+                    update_scratch_data_size: HEADER_SIZE + prims * 8,
+                }
+            }
+            BuildInputs::TopLevel { instance_count } => {
+                let instances = *instance_count as u64;
+
+                PrebuildInfo {
+                    result_data_max_size: HEADER_SIZE + instances * (2 * NODE_SIZE + INSTANCE_SIZE),
+                    scratch_data_size: HEADER_SIZE + instances * 2 * NODE_SIZE,
+                    update_scratch_data_size: HEADER_SIZE + instances * 8,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferRange {
+    pub pool: usize,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AsAllocation {
+    pub as_type: AsType,
+    pub result: BufferRange,
+    //スクラッチは1本のバッファを共有する
+    //同じbatchのビルドは同時に走るので重ならないように置き、batchが変わったら(UAVバリアの後)使い回す
+    pub scratch_offset: u64,
+    pub scratch_size: u64,
+    pub batch: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResultPool {
+    pub size: u64,
+    //pool_sizeより大きい1つだけを入れたプール ほかのものは詰めない
+    pub dedicated: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AsPlan {
+    //add()した順番
+    pub allocations: Vec<AsAllocation>,
+    pub result_pools: Vec<ResultPool>,
+    pub batch_count: usize,
+    pub peak_scratch_size: u64,
+}
+
+impl AsPlan {
+    pub fn total_result_size(&self) -> u64 {
+        self.result_pools.iter().map(|p| p.size).sum()
+    }
+
+    //pool_sizeに収まらず専用のプールになったもの
+    pub fn oversized(&self) -> impl Iterator<Item = usize> + '_ {
+        self.allocations.iter().enumerate().filter(|(_, a)| self.result_pools[a.result.pool].dedicated).map(|(i, _)| i)
+    }
+}
+
+pub struct AsPlanner {
+    entries: Vec<(AsType, PrebuildInfo)>,
+    pool_size: u64,
+    scratch_budget: Option<u64>,
+}
+
+impl Default for AsPlanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsPlanner {
+    pub fn new() -> Self {
+        AsPlanner { entries: vec![], pool_size: DEFAULT_POOL_SIZE, scratch_budget: None }
+    }
+
+    //結果バッファを詰め込むプール1つあたりの上限
+    pub fn with_pool_size(mut self, pool_size: u64) -> Self {
+        self.pool_size = align(pool_size, AS_BYTE_ALIGNMENT);
+        self
+    }
+
+    //1回のbatchで同時に使うスクラッチの上限
+    //超える場合はbatchを分ける(1つで超えるものはそれ単体のbatchになる)
+    pub fn with_scratch_budget(mut self, scratch_budget: u64) -> Self {
+        self.scratch_budget = Some(scratch_budget);
+        self
+    }
+
+    pub fn add(&mut self, as_type: AsType, info: PrebuildInfo) -> usize {
+        self.entries.push((as_type, info));
+        self.entries.len() - 1
+    }
+
+    pub fn add_inputs(&mut self, inputs: &BuildInputs) -> usize {
+        self.add(inputs.as_type(), inputs.estimate_prebuild_info())
+    }
+
+    pub fn plan(&self) -> AsPlan {
+        let mut plan = AsPlan::default();
+
+        //結果バッファ: 先頭から順に共有のプールへ詰める
+        //pool_sizeより大きいものは専用のプールにして、詰めている途中のプールはそのまま続ける
+        let mut shared: Option<usize> = None;
+        for &(as_type, info) in &self.entries {
+            let size = align(info.result_data_max_size, AS_BYTE_ALIGNMENT);
+
+            let (pool, offset) = if size > self.pool_size {
+                plan.result_pools.push(ResultPool { size, dedicated: true });
+                (plan.result_pools.len() - 1, 0)
+            } else {
+                match shared {
+                    Some(pool) if plan.result_pools[pool].size + size <= self.pool_size => {
+                        let offset = plan.result_pools[pool].size;
+                        plan.result_pools[pool].size += size;
+                        (pool, offset)
+                    }
+                    _ => {
+                        plan.result_pools.push(ResultPool { size, dedicated: false });
+                        shared = Some(plan.result_pools.len() - 1);
+                        (plan.result_pools.len() - 1, 0)
+                    }
+                }
+            };
+
+            plan.allocations.push(AsAllocation {
+                as_type,
+                result: BufferRange { pool, offset, size },
+                scratch_offset: 0,
+                scratch_size: align(info.scratch_data_size, AS_BYTE_ALIGNMENT),
+                batch: 0,
+            });
+        }
+
+        //スクラッチ: TLASはBLASを参照するのでBLASのbatchが全部終わってから
+        let mut batch: Option<usize> = None;
+        for as_type in [AsType::BottomLevel, AsType::TopLevel] {
+            let mut batch_used: Option<u64> = None;
+
+            for alloc in plan.allocations.iter_mut().filter(|a| a.as_type == as_type) {
+                let fits = |used: u64| self.scratch_budget.is_none_or(|budget| used + alloc.scratch_size <= budget);
+
+                let offset = match batch_used {
+                    Some(used) if fits(used) => used,
+                    _ => {
+                        batch = Some(batch.map_or(0, |b| b + 1));
+                        0
+                    }
+                };
+
+                alloc.batch = batch.unwrap();
+                alloc.scratch_offset = offset;
+                batch_used = Some(offset + alloc.scratch_size);
+                plan.peak_scratch_size = plan.peak_scratch_size.max(offset + alloc.scratch_size);
+            }
+        }
+
+        plan.batch_count = batch.map_or(0, |b| b + 1);
+
+        plan
+    }
+}
+
+pub fn align(size: u64, alignment: u64) -> u64 {
+    (size + alignment - 1) & !(alignment - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(result: u64, scratch: u64) -> PrebuildInfo {
+        PrebuildInfo { result_data_max_size: result, scratch_data_size: scratch, update_scratch_data_size: 0 }
+    }
+
+    #[test]
+    fn packs_results_into_shared_pools_with_alignment() {
+        let mut planner = AsPlanner::new().with_pool_size(1024);
+        planner.add(AsType::BottomLevel, info(100, 10));
+        planner.add(AsType::BottomLevel, info(300, 10));
+        planner.add(AsType::BottomLevel, info(600, 10));
+        planner.add(AsType::TopLevel, info(200, 10));
+        let plan = planner.plan();
+
+        let ranges: Vec<_> = plan.allocations.iter().map(|a| a.result).collect();
+        assert_eq!(ranges[0], BufferRange { pool: 0, offset: 0, size: 256 });
+        assert_eq!(ranges[1], BufferRange { pool: 0, offset: 256, size: 512 });
+        //残り256に入らないので次のプール
+        assert_eq!(ranges[2], BufferRange { pool: 1, offset: 0, size: 768 });
+        assert_eq!(ranges[3], BufferRange { pool: 1, offset: 768, size: 256 });
+        assert!(ranges.iter().all(|r| r.offset % AS_BYTE_ALIGNMENT == 0));
+
+        assert_eq!(plan.result_pools, vec![ResultPool { size: 768, dedicated: false }, ResultPool { size: 1024, dedicated: false }]);
+        assert_eq!(plan.total_result_size(), 1792);
+        assert_eq!(plan.oversized().count(), 0);
+    }
+
+    #[test]
+    fn oversized_entries_get_a_dedicated_pool() {
+        let mut planner = AsPlanner::new().with_pool_size(1024);
+        planner.add(AsType::BottomLevel, info(256, 0));
+        planner.add(AsType::BottomLevel, info(5000, 0));
+        planner.add(AsType::BottomLevel, info(256, 0));
+        let plan = planner.plan();
+
+        assert_eq!(plan.result_pools[1], ResultPool { size: 5120, dedicated: true });
+        //専用のプールを挟んでも前の共有プールに詰め続ける
+        assert_eq!(plan.allocations[2].result, BufferRange { pool: 0, offset: 256, size: 256 });
+        assert_eq!(plan.oversized().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn scratch_is_shared_within_a_batch_and_reused_across_batches() {
+        let mut planner = AsPlanner::new().with_scratch_budget(1024);
+        planner.add(AsType::BottomLevel, info(256, 300));
+        planner.add(AsType::TopLevel, info(256, 100));
+        planner.add(AsType::BottomLevel, info(256, 500));
+        planner.add(AsType::BottomLevel, info(256, 2000));
+        let plan = planner.plan();
+
+        let scratch: Vec<_> = plan.allocations.iter().map(|a| (a.batch, a.scratch_offset, a.scratch_size)).collect();
+        assert_eq!(scratch[0], (0, 0, 512));
+        assert_eq!(scratch[2], (0, 512, 512));
+        //予算を超えるものは単体のbatch
+        assert_eq!(scratch[3], (1, 0, 2048));
+        //TLASはBLASのbatchが全部終わってから
+        assert_eq!(scratch[1], (2, 0, 256));
+        assert_eq!(plan.batch_count, 3);
+        assert_eq!(plan.peak_scratch_size, 2048);
+    }
+
+    #[test]
+    fn without_a_budget_all_blas_build_together() {
+        let mut planner = AsPlanner::new();
+        for count in [10, 2000, 300] {
+            planner.add_inputs(&BuildInputs::BottomLevel { triangle_counts: vec![count] });
+        }
+        planner.add_inputs(&BuildInputs::TopLevel { instance_count: 3 });
+        let plan = planner.plan();
+
+        assert_eq!(plan.batch_count, 2);
+        assert!(plan.allocations[..3].iter().all(|a| a.batch == 0));
+        assert_eq!(plan.result_pools.len(), 1);
+
+        let blas_scratch: u64 = plan.allocations[..3].iter().map(|a| a.scratch_size).sum();
+        assert_eq!(plan.peak_scratch_size, blas_scratch);
+    }
+
+    #[test]
+    fn estimates_grow_with_the_inputs() {
+        let small = BuildInputs::BottomLevel { triangle_counts: vec![10] }.estimate_prebuild_info();
+        let large = BuildInputs::BottomLevel { triangle_counts: vec![10, 1000] }.estimate_prebuild_info();
+        assert!(large.result_data_max_size > small.result_data_max_size);
+        assert!(large.scratch_data_size > small.scratch_data_size);
+    }
+}
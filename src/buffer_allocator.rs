@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::resource_state::ResourceStates;
+
+//大きなバッファ(ブロック)から範囲を切り出して使う
+//長く使うバッファはバディアロケーター、フレームごとのアップロードはリニアアロケーターで取る
+//ブロックを作るのはバックエンドに任せるので、GPUなしでも割り当てだけを確かめられる
+
+//バディアロケーターの最小単位 ASの256バイトのアライメントもこれで揃う
+pub const MIN_ALLOCATION_SIZE: u64 = 256;
+
+//CreateCommittedResourceで作るバッファの先頭は64KBに揃う
+pub const BLOCK_ALIGNMENT: u64 = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapKind {
+    //CPUから書いてGPUで読む
+    Upload,
+    //GPUだけが読み書きする
+    Default,
+    //GPUが書いてCPUで読む
+    Readback,
+}
+
+impl HeapKind {
+    //D3D12ではアップロードとリードバックのヒープの状態は決まっている
+    pub fn required_state(self) -> Option<ResourceStates> {
+        match self {
+            HeapKind::Upload => Some(ResourceStates::GENERIC_READ),
+            HeapKind::Default => None,
+            HeapKind::Readback => Some(ResourceStates::COPY_DEST),
+        }
+    }
+}
+
+pub trait BufferHeapBackend {
+    type Block;
+
+    fn create_block(&mut self, size: u64) -> Result<Self::Block, BufferError>;
+    //D3D12_GPU_VIRTUAL_ADDRESS
+    fn gpu_address(&self, block: &Self::Block) -> u64;
+}
+
+//GPUのバッファを作らずに、ブロックの大きさとアドレスだけを決める
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VirtualBufferHeap {
+    //作ったブロックの大きさの合計がこれを超えたら失敗する
+    pub limit: Option<u64>,
+    pub created: Vec<u64>,
+    next_address: u64,
+}
+
+impl VirtualBufferHeap {
+    pub fn new() -> Self {
+        VirtualBufferHeap { limit: None, created: vec![], next_address: BLOCK_ALIGNMENT }
+    }
+
+    pub fn with_limit(limit: u64) -> Self {
+        VirtualBufferHeap { limit: Some(limit), ..Self::new() }
+    }
+}
+
+//(GPUアドレス, 大きさ)
+impl BufferHeapBackend for VirtualBufferHeap {
+    type Block = (u64, u64);
+
+    fn create_block(&mut self, size: u64) -> Result<(u64, u64), BufferError> {
+        let total: u64 = self.created.iter().sum();
+        if self.limit.is_some_and(|limit| total + size > limit) {
+            return Err(BufferError::BlockCreation { size, message: "the virtual heap is full".to_string() });
+        }
+
+        let address = self.next_address;
+        self.next_address += align(size, BLOCK_ALIGNMENT);
+        self.created.push(size);
+        Ok((address, size))
+    }
+
+    fn gpu_address(&self, block: &(u64, u64)) -> u64 {
+        block.0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BufferError {
+    //アライメントが2の累乗でない
+    InvalidAlignment(u64),
+    //確保していない、もしくは二重に解放した
+    InvalidFree { block: usize, offset: u64 },
+    BlockCreation { size: u64, message: String },
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BufferError::InvalidAlignment(alignment) => write!(f, "alignment {} is not a power of two", alignment),
+            BufferError::InvalidFree { block, offset } => write!(f, "offset {} of block {} is not allocated", offset, block),
+            BufferError::BlockCreation { size, message } => write!(f, "cannot create a buffer block of {} bytes: {}", size, message),
+        }
+    }
+}
+
+impl Error for BufferError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferAllocation {
+    pub block: usize,
+    pub offset: u64,
+    //頼まれた大きさ
+    pub size: u64,
+    pub gpu_address: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BufferStats {
+    pub block_count: usize,
+    //ブロックの大きさの合計
+    pub reserved: u64,
+    //切り出した範囲の合計 バディの切り上げやアライメントで空けた分も含む
+    pub used: u64,
+    pub peak_used: u64,
+    //1回で取れる一番大きな範囲
+    pub largest_free: u64,
+}
+
+impl BufferStats {
+    //空きのうち一番大きな範囲に入らない割合 0なら空きが1つにまとまっている
+    pub fn fragmentation(&self) -> f64 {
+        let free = self.reserved - self.used;
+        if free == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free as f64 / free as f64
+        }
+    }
+}
+
+impl fmt::Display for BufferStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks, {} of {} bytes used (peak {}), largest free {}, fragmentation {:.1}%",
+            self.block_count,
+            self.used,
+            self.reserved,
+            self.peak_used,
+            self.largest_free,
+            self.fragmentation() * 100.0
+        )
+    }
+}
+
+struct BuddyBlock<B> {
+    block: B,
+    gpu_address: u64,
+    //MIN_ALLOCATION_SIZE << max_order
+    max_order: u32,
+    //次数ごとの空きのオフセット
+    free: Vec<Vec<u64>>,
+}
+
+//ブロックを2の累乗の大きさに割って使う 解放したら隣(バディ)と合わせて戻す
+pub struct BuddyAllocator<H: BufferHeapBackend> {
+    heap: H,
+    block_size: u64,
+    blocks: Vec<BuddyBlock<H::Block>>,
+    //(ブロック, オフセット)から次数
+    allocated: HashMap<(usize, u64), u32>,
+    used: u64,
+    peak_used: u64,
+}
+
+impl<H: BufferHeapBackend> BuddyAllocator<H> {
+    //block_sizeは2の累乗に切り上げる これより大きい確保は専用のブロックになる
+    pub fn new(heap: H, block_size: u64) -> Self {
+        BuddyAllocator {
+            heap,
+            block_size: block_size.max(MIN_ALLOCATION_SIZE).next_power_of_two(),
+            blocks: vec![],
+            allocated: HashMap::new(),
+            used: 0,
+            peak_used: 0,
+        }
+    }
+
+    pub fn heap(&self) -> &H {
+        &self.heap
+    }
+
+    pub fn block(&self, i: usize) -> &H::Block {
+        &self.blocks[i].block
+    }
+
+    pub fn allocate(&mut self, size: u64, alignment: u64) -> Result<BufferAllocation, BufferError> {
+        if !alignment.is_power_of_two() {
+            return Err(BufferError::InvalidAlignment(alignment));
+        }
+
+        //バディのオフセットは自分の大きさに揃うので、大きさをアライメントまで広げれば揃う
+        let rounded = size.max(alignment).max(MIN_ALLOCATION_SIZE).next_power_of_two();
+        let order = (rounded / MIN_ALLOCATION_SIZE).trailing_zeros();
+
+        let found = self.blocks.iter().enumerate().find_map(|(i, b)| {
+            (order..=b.max_order).find(|&k| !b.free.get(k as usize).is_none_or(Vec::is_empty)).map(|k| (i, k))
+        });
+
+        let (i, mut k) = match found {
+            Some(found) => found,
+            None => {
+                let i = self.add_block(rounded.max(self.block_size))?;
+                (i, self.blocks[i].max_order)
+            }
+        };
+
+        //大きい空きを半分ずつに割って後ろ半分を空きに戻す
+        //kはfoundなら空きのある次数、add_blockならfree[max_order]に0だけを入れたばかりなので必ず取り出せる
+        let block = &mut self.blocks[i];
+        let offset = block.free[k as usize].pop().expect("the free list is not empty");
+        while k > order {
+            k -= 1;
+            block.free[k as usize].push(offset + (MIN_ALLOCATION_SIZE << k));
+        }
+
+        self.allocated.insert((i, offset), order);
+        self.used += rounded;
+        self.peak_used = self.peak_used.max(self.used);
+
+        Ok(BufferAllocation { block: i, offset, size, gpu_address: block.gpu_address + offset })
+    }
+
+    pub fn free(&mut self, allocation: BufferAllocation) -> Result<(), BufferError> {
+        let (i, mut offset) = (allocation.block, allocation.offset);
+        let Some(mut order) = self.allocated.remove(&(i, offset)) else {
+            return Err(BufferError::InvalidFree { block: i, offset });
+        };
+        self.used -= MIN_ALLOCATION_SIZE << order;
+
+        let block = &mut self.blocks[i];
+        while order < block.max_order {
+            let buddy = offset ^ (MIN_ALLOCATION_SIZE << order);
+            let Some(j) = block.free[order as usize].iter().position(|&o| o == buddy) else {
+                break;
+            };
+            block.free[order as usize].swap_remove(j);
+            offset = offset.min(buddy);
+            order += 1;
+        }
+        block.free[order as usize].push(offset);
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let largest_free = self
+            .blocks
+            .iter()
+            .flat_map(|b| b.free.iter().enumerate().filter(|(_, f)| !f.is_empty()).map(|(k, _)| MIN_ALLOCATION_SIZE << k))
+            .max()
+            .unwrap_or(0);
+
+        BufferStats {
+            block_count: self.blocks.len(),
+            reserved: self.blocks.iter().map(|b| MIN_ALLOCATION_SIZE << b.max_order).sum(),
+            used: self.used,
+            peak_used: self.peak_used,
+            largest_free,
+        }
+    }
+
+    fn add_block(&mut self, size: u64) -> Result<usize, BufferError> {
+        let block = self.heap.create_block(size)?;
+        let max_order = (size / MIN_ALLOCATION_SIZE).trailing_zeros();
+
+        let mut free = vec![vec![]; max_order as usize + 1];
+        free[max_order as usize].push(0);
+
+        self.blocks.push(BuddyBlock { gpu_address: self.heap.gpu_address(&block), block, max_order, free });
+        Ok(self.blocks.len() - 1)
+    }
+}
+
+struct LinearBlock<B> {
+    block: B,
+    gpu_address: u64,
+    size: u64,
+}
+
+//先頭から詰めていくだけのアロケーター 解放はbegin_frameでフレームごとにまとめて
+pub struct LinearAllocator<H: BufferHeapBackend> {
+    heap: H,
+    block_size: u64,
+    blocks: Vec<LinearBlock<H::Block>>,
+    //フレームごとに使っているブロック 最後のものに詰めている
+    frames: Vec<Vec<usize>>,
+    //どのフレームも使っていないブロック
+    spare: Vec<usize>,
+    frame_index: usize,
+    //今のブロックで次に使うオフセット
+    cursor: u64,
+    //フレームごとに切り出した量
+    frame_used: Vec<u64>,
+    peak_used: u64,
+}
+
+impl<H: BufferHeapBackend> LinearAllocator<H> {
+    pub fn new(heap: H, block_size: u64, frame_count: u32) -> Self {
+        let frame_count = frame_count.max(1) as usize;
+        LinearAllocator {
+            heap,
+            block_size,
+            blocks: vec![],
+            frames: vec![vec![]; frame_count],
+            spare: vec![],
+            frame_index: 0,
+            cursor: 0,
+            frame_used: vec![0; frame_count],
+            peak_used: 0,
+        }
+    }
+
+    pub fn heap(&self) -> &H {
+        &self.heap
+    }
+
+    pub fn block(&self, i: usize) -> &H::Block {
+        &self.blocks[i].block
+    }
+
+    //フレームの最初に呼ぶ frame_indexで前に確保したものはGPUが使い終わっていること
+    pub fn begin_frame(&mut self, frame_index: u32) {
+        self.frame_index = frame_index as usize % self.frames.len();
+        self.spare.append(&mut self.frames[self.frame_index]);
+        self.frame_used[self.frame_index] = 0;
+        self.cursor = 0;
+    }
+
+    pub fn allocate(&mut self, size: u64, alignment: u64) -> Result<BufferAllocation, BufferError> {
+        if !alignment.is_power_of_two() {
+            return Err(BufferError::InvalidAlignment(alignment));
+        }
+
+        let current = self.frames[self.frame_index].last().copied();
+        let fits = |i: usize| align(self.cursor, alignment) + size <= self.blocks[i].size;
+
+        //アライメントで空けた分も使った量に数える
+        let (i, offset, padding) = match current.filter(|&i| fits(i)) {
+            Some(i) => {
+                let offset = align(self.cursor, alignment);
+                (i, offset, offset - self.cursor)
+            }
+            None => (self.next_block(size)?, 0, 0),
+        };
+
+        self.cursor = offset + size;
+        let used = &mut self.frame_used[self.frame_index];
+        *used += padding + size;
+        self.peak_used = self.peak_used.max(self.frame_used.iter().sum());
+
+        Ok(BufferAllocation { block: i, offset, size, gpu_address: self.blocks[i].gpu_address + offset })
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let current = self.frames[self.frame_index].last().map_or(0, |&i| self.blocks[i].size - self.cursor);
+        let spare = self.spare.iter().map(|&i| self.blocks[i].size).max().unwrap_or(0);
+
+        BufferStats {
+            block_count: self.blocks.len(),
+            reserved: self.blocks.iter().map(|b| b.size).sum(),
+            used: self.frame_used.iter().sum(),
+            peak_used: self.peak_used,
+            largest_free: current.max(spare),
+        }
+    }
+
+    //空いているブロックか新しいブロックをこのフレームに足す
+    fn next_block(&mut self, size: u64) -> Result<usize, BufferError> {
+        let i = match self.spare.iter().position(|&i| self.blocks[i].size >= size) {
+            Some(j) => self.spare.swap_remove(j),
+            None => {
+                let size = size.max(self.block_size);
+                let block = self.heap.create_block(size)?;
+                self.blocks.push(LinearBlock { gpu_address: self.heap.gpu_address(&block), block, size });
+                self.blocks.len() - 1
+            }
+        };
+
+        self.frames[self.frame_index].push(i);
+        Ok(i)
+    }
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buddy() -> BuddyAllocator<VirtualBufferHeap> {
+        BuddyAllocator::new(VirtualBufferHeap::new(), 4096)
+    }
+
+    #[test]
+    fn buddy_splits_and_merges() {
+        let mut buffers = buddy();
+
+        let a = buffers.allocate(100, 16).unwrap();
+        let b = buffers.allocate(256, 1).unwrap();
+        let c = buffers.allocate(600, 1).unwrap();
+        assert_eq!((a.block, a.offset, a.size), (0, 0, 100));
+        assert_eq!(b.offset, 256);
+        //512の空きには入らないので1024を使う
+        assert_eq!(c.offset, 1024);
+
+        buffers.free(a).unwrap();
+        buffers.free(c).unwrap();
+        //bがいる間は256と512はまとまらない
+        assert_eq!(buffers.stats().largest_free, 2048);
+
+        buffers.free(b).unwrap();
+        assert_eq!(buffers.stats().largest_free, 4096);
+        assert_eq!(buffers.allocate(4096, 1).unwrap().offset, 0);
+        assert_eq!(buffers.stats().block_count, 1);
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let mut buffers = buddy();
+        let a = buffers.allocate(256, 1).unwrap();
+
+        buffers.free(a).unwrap();
+        assert_eq!(buffers.free(a), Err(BufferError::InvalidFree { block: 0, offset: 0 }));
+        assert_eq!(buffers.free(BufferAllocation { offset: 512, ..a }), Err(BufferError::InvalidFree { block: 0, offset: 512 }));
+    }
+
+    #[test]
+    fn allocations_are_aligned() {
+        let mut buffers = buddy();
+        buffers.allocate(256, 1).unwrap();
+
+        let a = buffers.allocate(300, 1024).unwrap();
+        assert_eq!(a.offset % 1024, 0);
+        assert_eq!(a.gpu_address % 1024, 0);
+        assert_eq!(a.gpu_address, BLOCK_ALIGNMENT + a.offset);
+
+        assert_eq!(buffers.allocate(256, 3), Err(BufferError::InvalidAlignment(3)));
+    }
+
+    #[test]
+    fn oversized_allocations_get_their_own_block() {
+        let mut buffers = buddy();
+        buffers.allocate(256, 1).unwrap();
+
+        let big = buffers.allocate(10000, 256).unwrap();
+        assert_eq!((big.block, big.offset), (1, 0));
+        assert_eq!(buffers.heap().created, vec![4096, 16384]);
+        //ブロックは64KBごとに並ぶ
+        assert_eq!(big.gpu_address, 2 * BLOCK_ALIGNMENT);
+
+        let mut limited = BuddyAllocator::new(VirtualBufferHeap::with_limit(4096), 4096);
+        limited.allocate(4096, 1).unwrap();
+        assert!(matches!(limited.allocate(256, 1), Err(BufferError::BlockCreation { size: 4096, .. })));
+    }
+
+    #[test]
+    fn buddy_stats_count_rounding_and_fragmentation() {
+        let mut buffers = buddy();
+        let a = buffers.allocate(100, 16).unwrap();
+        buffers.allocate(256, 1).unwrap();
+        buffers.allocate(600, 1).unwrap();
+
+        let stats = buffers.stats();
+        assert_eq!((stats.block_count, stats.reserved, stats.used, stats.largest_free), (1, 4096, 256 + 256 + 1024, 2048));
+        //空き2560のうち2048だけが1つにまとまっている
+        assert!((stats.fragmentation() - 0.2).abs() < 1e-9);
+
+        buffers.free(a).unwrap();
+        let stats = buffers.stats();
+        assert_eq!((stats.used, stats.peak_used), (1280, 1536));
+    }
+
+    #[test]
+    fn linear_blocks_are_recycled_per_frame() {
+        let mut upload = LinearAllocator::new(VirtualBufferHeap::new(), 1024, 2);
+
+        upload.begin_frame(0);
+        let a = upload.allocate(100, 1).unwrap();
+        let b = upload.allocate(100, 256).unwrap();
+        let c = upload.allocate(1000, 1).unwrap();
+        assert_eq!((a.block, a.offset), (0, 0));
+        assert_eq!((b.block, b.offset), (0, 256));
+        //入らなければ次のブロックの先頭から
+        assert_eq!((c.block, c.offset), (1, 0));
+
+        //フレーム0のブロックはまだGPUが使っている
+        upload.begin_frame(1);
+        assert_eq!(upload.allocate(10, 1).unwrap().block, 2);
+
+        upload.begin_frame(2);
+        assert_eq!(upload.allocate(10, 1).unwrap().block, 0);
+        assert_eq!(upload.heap().created, vec![1024, 1024, 1024]);
+
+        //ブロックより大きいものはその大きさで作る
+        let big = upload.allocate(5000, 1).unwrap();
+        assert_eq!((big.block, big.offset), (3, 0));
+        assert_eq!(upload.heap().created[3], 5000);
+    }
+
+    #[test]
+    fn linear_stats_include_alignment_padding() {
+        let mut upload = LinearAllocator::new(VirtualBufferHeap::new(), 1024, 2);
+
+        upload.begin_frame(0);
+        upload.allocate(100, 1).unwrap();
+        upload.allocate(100, 256).unwrap();
+        let stats = upload.stats();
+        assert_eq!((stats.used, stats.reserved, stats.largest_free), (356, 1024, 1024 - 356));
+        assert_eq!(stats.fragmentation(), 0.0);
+
+        //前のブロックの残りは次のフレームまで使えない
+        upload.allocate(1000, 1).unwrap();
+        let stats = upload.stats();
+        assert_eq!((stats.used, stats.reserved, stats.largest_free), (1356, 2048, 24));
+        assert!((stats.fragmentation() - (1.0 - 24.0 / 692.0)).abs() < 1e-9);
+
+        upload.begin_frame(1);
+        upload.allocate(10, 1).unwrap();
+        upload.begin_frame(0);
+        let stats = upload.stats();
+        assert_eq!((stats.used, stats.peak_used), (10, 1366));
+    }
+}
@@ -0,0 +1,1030 @@
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+
+use crate::cpu::ray::Ray;
+use crate::hlsl_struct;
+use crate::input::{InputEvent, InputState, Key, MouseButton};
+use crate::math::Vec3;
+
+const WORLD_UP: Vec3 = Vec3::new(0.0, 1.0, 0.0);
+//真上と真下ではforwardとupが平行になるので少し手前で止める
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraPose {
+    pub position: Vec3,
+    //正規化済み
+    pub forward: Vec3,
+    pub up: Vec3,
+}
+
+impl CameraPose {
+    pub fn look_at(position: Vec3, target: Vec3, up: Vec3) -> Self {
+        let forward = (target - position).normalize();
+        let right = forward.cross(up).normalize();
+        CameraPose { position, forward, up: right.cross(forward) }
+    }
+
+    pub fn right(&self) -> Vec3 {
+        self.forward.cross(self.up).normalize()
+    }
+}
+
+//元のMainRayGenと同じ、z=1から-Z方向を見る
+impl Default for CameraPose {
+    fn default() -> Self {
+        CameraPose { position: Vec3::new(0.0, 0.0, 1.0), forward: Vec3::new(0.0, 0.0, -1.0), up: WORLD_UP }
+    }
+}
+
+//yawはY軸回り、pitchは上向きが正 (0, 0)で-Zを向く
+pub fn direction(yaw: f32, pitch: f32) -> Vec3 {
+    Vec3::new(pitch.cos() * yaw.sin(), pitch.sin(), -pitch.cos() * yaw.cos())
+}
+
+//directionの逆 dは正規化済み
+pub fn yaw_pitch(d: Vec3) -> (f32, f32) {
+    (d.x.atan2(-d.z), d.y.clamp(-1.0, 1.0).asin())
+}
+
+//イベントで状態を溜め、updateで経過時間dt(秒)だけ進める
+//時間は外から渡すので同じイベントとdtの並びなら同じ姿勢になる
+pub trait CameraController {
+    fn handle(&mut self, event: &InputEvent);
+    fn update(&mut self, dt: f32);
+    fn pose(&self) -> CameraPose;
+}
+
+//左ドラッグでtargetの周りを回り、中ドラッグで平行移動、ホイールで近づく
+#[derive(Clone, Debug)]
+pub struct OrbitController {
+    pub target: Vec3,
+    pub distance: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    //1ピクセルあたりのラジアン
+    pub rotate_speed: f32,
+    //1ピクセルあたりのdistanceに対する割合
+    pub pan_speed: f32,
+    //1ノッチで縮める割合
+    pub zoom_speed: f32,
+    pub min_distance: f32,
+    input: InputState,
+}
+
+impl OrbitController {
+    pub fn new(target: Vec3, distance: f32) -> Self {
+        OrbitController {
+            target,
+            distance,
+            yaw: 0.0,
+            pitch: 0.0,
+            rotate_speed: 0.005,
+            pan_speed: 0.002,
+            zoom_speed: 0.1,
+            min_distance: 0.01,
+            input: InputState::new(),
+        }
+    }
+
+    //positionからtargetを見ている状態から始める
+    pub fn looking_at(position: Vec3, target: Vec3) -> Self {
+        let offset = target - position;
+        let distance = offset.length();
+        let (yaw, pitch) = yaw_pitch(offset / distance);
+        let mut orbit = Self::new(target, distance);
+        orbit.rotate(yaw, pitch);
+        orbit
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.input.is_button_down(MouseButton::Left) || self.input.is_button_down(MouseButton::Middle)
+    }
+
+    fn rotate(&mut self, yaw: f32, pitch: f32) {
+        self.yaw = (self.yaw + yaw).rem_euclid(TAU);
+        self.pitch = (self.pitch + pitch).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+}
+
+impl CameraController for OrbitController {
+    fn handle(&mut self, event: &InputEvent) {
+        self.input.handle(event);
+    }
+
+    fn update(&mut self, _dt: f32) {
+        let (dx, dy) = self.input.take_mouse_delta();
+        if self.input.is_button_down(MouseButton::Left) {
+            //上にドラッグすると上から覗き込む
+            self.rotate(dx * self.rotate_speed, dy * self.rotate_speed);
+        } else if self.input.is_button_down(MouseButton::Middle) {
+            let pose = self.pose();
+            let scale = self.distance * self.pan_speed;
+            self.target = self.target - pose.right() * (dx * scale) + pose.up * (dy * scale);
+        }
+
+        let wheel = self.input.take_wheel();
+        self.distance = (self.distance * (1.0 - self.zoom_speed).powf(wheel)).max(self.min_distance);
+    }
+
+    fn pose(&self) -> CameraPose {
+        let position = self.target - direction(self.yaw, self.pitch) * self.distance;
+        CameraPose::look_at(position, self.target, WORLD_UP)
+    }
+}
+
+//WASDで前後左右、E/Qで上下、右ドラッグで向きを変える Shiftで速くなる
+#[derive(Clone, Debug)]
+pub struct FlyController {
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    //1秒あたりの移動量
+    pub speed: f32,
+    pub boost: f32,
+    //1ピクセルあたりのラジアン
+    pub look_speed: f32,
+    input: InputState,
+}
+
+impl FlyController {
+    pub fn new(position: Vec3, yaw: f32, pitch: f32) -> Self {
+        FlyController { position, yaw, pitch, speed: 1.0, boost: 4.0, look_speed: 0.003, input: InputState::new() }
+    }
+}
+
+impl CameraController for FlyController {
+    fn handle(&mut self, event: &InputEvent) {
+        self.input.handle(event);
+    }
+
+    fn update(&mut self, dt: f32) {
+        let (dx, dy) = self.input.take_mouse_delta();
+        if self.input.is_button_down(MouseButton::Right) {
+            self.yaw = (self.yaw + dx * self.look_speed).rem_euclid(TAU);
+            self.pitch = (self.pitch - dy * self.look_speed).clamp(-MAX_PITCH, MAX_PITCH);
+        }
+
+        //ホイールで速さを変える
+        self.speed *= 1.25f32.powf(self.input.take_wheel());
+
+        let pose = self.pose();
+        let axes = [
+            (Key::W, pose.forward),
+            (Key::S, -pose.forward),
+            (Key::D, pose.right()),
+            (Key::A, -pose.right()),
+            (Key::E, WORLD_UP),
+            (Key::Q, -WORLD_UP),
+        ];
+        let mut velocity = Vec3::ZERO;
+        for (key, axis) in axes {
+            if self.input.is_key_down(key) {
+                velocity += axis;
+            }
+        }
+
+        //斜めに進んでも速さは変えない
+        if velocity.length() > 0.0 {
+            let boost = if self.input.is_key_down(Key::Shift) { self.boost } else { 1.0 };
+            self.position += velocity.normalize() * (self.speed * boost * dt);
+        }
+    }
+
+    fn pose(&self) -> CameraPose {
+        let forward = direction(self.yaw, self.pitch);
+        CameraPose::look_at(self.position, self.position + forward, WORLD_UP)
+    }
+}
+
+//targetの周りを一定の速さで回り続ける Spaceで止める
+//ドラッグとホイールはOrbitControllerと同じで、ドラッグ中は回らない
+#[derive(Clone, Debug)]
+pub struct TurntableController {
+    pub orbit: OrbitController,
+    //1秒あたりのラジアン
+    pub angular_speed: f32,
+    pub paused: bool,
+}
+
+impl TurntableController {
+    pub fn new(target: Vec3, distance: f32, pitch: f32) -> Self {
+        let mut orbit = OrbitController::new(target, distance);
+        orbit.rotate(0.0, pitch);
+        Self::with_orbit(orbit)
+    }
+
+    pub fn looking_at(position: Vec3, target: Vec3) -> Self {
+        Self::with_orbit(OrbitController::looking_at(position, target))
+    }
+
+    fn with_orbit(orbit: OrbitController) -> Self {
+        TurntableController { orbit, angular_speed: 0.5, paused: false }
+    }
+}
+
+impl CameraController for TurntableController {
+    fn handle(&mut self, event: &InputEvent) {
+        //押しっぱなしのリピートでは切り替えない
+        if *event == InputEvent::KeyDown(Key::Space) && !self.orbit.input.is_key_down(Key::Space) {
+            self.paused = !self.paused;
+        }
+        self.orbit.handle(event);
+    }
+
+    fn update(&mut self, dt: f32) {
+        if !self.paused && !self.orbit.is_dragging() {
+            self.orbit.rotate(self.angular_speed * dt, 0.0);
+        }
+        self.orbit.update(dt);
+    }
+
+    fn pose(&self) -> CameraPose {
+        self.orbit.pose()
+    }
+}
+
+//コマンドラインとシーンファイルで選ぶコントローラー
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ControllerKind {
+    #[default]
+    Orbit,
+    Fly,
+    Turntable,
+}
+
+impl ControllerKind {
+    pub const ALL: [ControllerKind; 3] = [ControllerKind::Orbit, ControllerKind::Fly, ControllerKind::Turntable];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ControllerKind::Orbit => "orbit",
+            ControllerKind::Fly => "fly",
+            ControllerKind::Turntable => "turntable",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ControllerKind> {
+        Self::ALL.into_iter().find(|k| k.name() == name)
+    }
+
+    //cameraの位置からtargetを見ている状態から動かし始める
+    pub fn create(self, camera: &Camera) -> Box<dyn CameraController> {
+        match self {
+            ControllerKind::Orbit => Box::new(OrbitController::looking_at(camera.position, camera.target)),
+            ControllerKind::Fly => {
+                let (yaw, pitch) = yaw_pitch(camera.pose().forward);
+                Box::new(FlyController::new(camera.position, yaw, pitch))
+            }
+            ControllerKind::Turntable => Box::new(TurntableController::looking_at(camera.position, camera.target)),
+        }
+    }
+}
+
+//ray_shader.hlslのgProjection
+pub const PROJECTION_PERSPECTIVE: u32 = 0;
+pub const PROJECTION_ORTHOGRAPHIC: u32 = 1;
+pub const PROJECTION_THIN_LENS: u32 = 2;
+pub const PROJECTION_EQUIRECTANGULAR: u32 = 3;
+pub const PROJECTION_FISHEYE: u32 = 4;
+pub const PROJECTION_CUBE_CROSS: u32 = 5;
+pub const PROJECTION_CUBE_STRIP: u32 = 6;
+
+//MainRayGenのレイのTMax
+const RAY_T_MAX: f32 = 100000.0;
+
+//キューブマップの面の並べ方
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CubeLayout {
+    //横4x縦3の十字 上の段が上、中の段が左、前、右、後ろ、下の段が下
+    Cross,
+    //横6x縦1 D3D12のキューブの面と同じ右、左、上、下、前、後ろの順
+    Strip,
+}
+
+impl CubeLayout {
+    //面の数で決まる幅/高さ
+    pub fn aspect(self) -> f32 {
+        match self {
+            CubeLayout::Cross => 4.0 / 3.0,
+            CubeLayout::Strip => 6.0,
+        }
+    }
+}
+
+//角度はラジアン aspectは幅/高さ
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    //fov_yは縦の画角
+    Perspective { fov_y: f32, aspect: f32 },
+    //extentは画面の端までの距離(横, 縦)
+    Orthographic { extent: [f32; 2] },
+    //apertureはレンズの半径、focus_distanceは前方向にピントが合う距離
+    //bladesが3以上なら絞りをその数の多角形にする 0なら円
+    ThinLens { fov_y: f32, aspect: f32, aperture: f32, focus_distance: f32, blades: u32, blade_rotation: f32 },
+    //全天球を経度(横)と緯度(縦)で並べる 画面の中心が前
+    Equirectangular,
+    //等距離射影 画面の高さに収まる円の端までがfov(直径の画角)
+    Fisheye { fov: f32, aspect: f32 },
+    //カメラの向きに合わせた6面を並べる 各面は画角90度
+    CubeMap { layout: CubeLayout },
+}
+
+impl Projection {
+    //前方向に距離1の面での画面の端(横, 縦) 平行投影ではそのままの大きさ
+    //魚眼では円の半径を1としたときの画面の端
+    pub fn image_plane(&self) -> [f32; 2] {
+        match *self {
+            Projection::Perspective { fov_y, aspect } | Projection::ThinLens { fov_y, aspect, .. } => {
+                let half = (fov_y * 0.5).tan();
+                [half * aspect, half]
+            }
+            Projection::Orthographic { extent } => extent,
+            Projection::Fisheye { aspect, .. } => [aspect, 1.0],
+            Projection::Equirectangular | Projection::CubeMap { .. } => [1.0, 1.0],
+        }
+    }
+
+    //画像の縦横比が決まっているもの パノラマは引き伸ばすと球面の対応がずれる
+    pub fn fixed_aspect(&self) -> Option<f32> {
+        match *self {
+            Projection::Equirectangular => Some(2.0),
+            Projection::CubeMap { layout } => Some(layout.aspect()),
+            _ => None,
+        }
+    }
+
+    //出力の縦横比に合わせる 平行投影とパノラマはそのまま
+    pub fn with_aspect(self, aspect: f32) -> Projection {
+        match self {
+            Projection::Perspective { fov_y, .. } => Projection::Perspective { fov_y, aspect },
+            Projection::ThinLens { fov_y, aperture, focus_distance, blades, blade_rotation, .. } => {
+                Projection::ThinLens { fov_y, aspect, aperture, focus_distance, blades, blade_rotation }
+            }
+            Projection::Fisheye { fov, .. } => Projection::Fisheye { fov, aspect },
+            p => p,
+        }
+    }
+
+    //画角を変えられるものだけ変える 範囲はSceneFile::projectionで確かめる
+    pub fn with_fov(self, fov: f32) -> Projection {
+        match self {
+            Projection::Perspective { aspect, .. } => Projection::Perspective { fov_y: fov, aspect },
+            Projection::ThinLens { aspect, aperture, focus_distance, blades, blade_rotation, .. } => {
+                Projection::ThinLens { fov_y: fov, aspect, aperture, focus_distance, blades, blade_rotation }
+            }
+            Projection::Fisheye { aspect, .. } => Projection::Fisheye { fov, aspect },
+            p => p,
+        }
+    }
+}
+
+//コマンドラインとシーンファイルで選ぶカメラ パラメーターは既定値
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CameraModel {
+    Perspective,
+    //元のMainRayGenと同じ平行なレイ
+    #[default]
+    Orthographic,
+    ThinLens,
+    Equirectangular,
+    Fisheye,
+    CubeCross,
+    CubeStrip,
+}
+
+impl CameraModel {
+    pub const ALL: [CameraModel; 7] = [
+        CameraModel::Perspective,
+        CameraModel::Orthographic,
+        CameraModel::ThinLens,
+        CameraModel::Equirectangular,
+        CameraModel::Fisheye,
+        CameraModel::CubeCross,
+        CameraModel::CubeStrip,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CameraModel::Perspective => "perspective",
+            CameraModel::Orthographic => "orthographic",
+            CameraModel::ThinLens => "thin-lens",
+            CameraModel::Equirectangular => "equirectangular",
+            CameraModel::Fisheye => "fisheye",
+            CameraModel::CubeCross => "cube-cross",
+            CameraModel::CubeStrip => "cube-strip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CameraModel> {
+        Self::ALL.into_iter().find(|m| m.name() == name)
+    }
+
+    //z=1から原点を見て、z=0の[-1, 1]の範囲が縦に収まるようにする
+    pub fn projection(self, aspect: f32) -> Projection {
+        let fov_y = FRAC_PI_2;
+        match self {
+            CameraModel::Perspective => Projection::Perspective { fov_y, aspect },
+            CameraModel::Orthographic => Projection::Orthographic { extent: [1.0, 1.0] },
+            //三角形にピントを合わせる
+            CameraModel::ThinLens => Projection::ThinLens { fov_y, aspect, aperture: 0.05, focus_distance: 1.0, blades: 6, blade_rotation: 0.0 },
+            CameraModel::Equirectangular => Projection::Equirectangular,
+            //半球
+            CameraModel::Fisheye => Projection::Fisheye { fov: PI, aspect },
+            CameraModel::CubeCross => Projection::CubeMap { layout: CubeLayout::Cross },
+            CameraModel::CubeStrip => Projection::CubeMap { layout: CubeLayout::Strip },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    pub position: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+    pub projection: Projection,
+}
+
+impl Camera {
+    pub fn new(position: Vec3, target: Vec3, up: Vec3, projection: Projection) -> Self {
+        Camera { position, target, up, projection }
+    }
+
+    //コントローラーの姿勢から作る
+    pub fn from_pose(pose: &CameraPose, projection: Projection) -> Self {
+        Camera { position: pose.position, target: pose.position + pose.forward, up: pose.up, projection }
+    }
+
+    pub fn with_model(model: CameraModel, aspect: f32) -> Self {
+        Self::from_pose(&CameraPose::default(), model.projection(aspect))
+    }
+
+    pub fn pose(&self) -> CameraPose {
+        CameraPose::look_at(self.position, self.target, self.up)
+    }
+
+    //ray_shader.hlslのb1に渡す
+    pub fn constants(&self) -> HlslCamera {
+        let pose = self.pose();
+        let (lens_radius, focus_distance, blade_count, blade_rotation) = match self.projection {
+            Projection::ThinLens { aperture, focus_distance, blades, blade_rotation, .. } => (aperture, focus_distance, blades, blade_rotation),
+            _ => (0.0, 1.0, 0, 0.0),
+        };
+
+        HlslCamera {
+            position: pose.position.into(),
+            lens_radius,
+            right: pose.right().into(),
+            focus_distance,
+            up: pose.up.into(),
+            projection: match self.projection {
+                Projection::Perspective { .. } => PROJECTION_PERSPECTIVE,
+                Projection::Orthographic { .. } => PROJECTION_ORTHOGRAPHIC,
+                Projection::ThinLens { .. } => PROJECTION_THIN_LENS,
+                Projection::Equirectangular => PROJECTION_EQUIRECTANGULAR,
+                Projection::Fisheye { .. } => PROJECTION_FISHEYE,
+                Projection::CubeMap { layout: CubeLayout::Cross } => PROJECTION_CUBE_CROSS,
+                Projection::CubeMap { layout: CubeLayout::Strip } => PROJECTION_CUBE_STRIP,
+            },
+            forward: pose.forward.into(),
+            blade_count,
+            image_plane: self.projection.image_plane(),
+            blade_rotation,
+            fisheye_fov: match self.projection {
+                Projection::Fisheye { fov, .. } => fov,
+                _ => 0.0,
+            },
+        }
+    }
+}
+
+//元のMainRayGenと同じ平行なレイ
+impl Default for Camera {
+    fn default() -> Self {
+        Self::with_model(CameraModel::Orthographic, 1.0)
+    }
+}
+
+hlsl_struct! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub cbuffer HlslCamera as "Camera" {
+        pub position: [f32; 3] as "gCameraPosition",
+        pub lens_radius: f32 as "gLensRadius",
+        pub right: [f32; 3] as "gCameraRight",
+        pub focus_distance: f32 as "gFocusDistance",
+        pub up: [f32; 3] as "gCameraUp",
+        pub projection: u32 as "gProjection",
+        pub forward: [f32; 3] as "gCameraForward",
+        pub blade_count: u32 as "gBladeCount",
+        pub image_plane: [f32; 2] as "gImagePlane",
+        pub blade_rotation: f32 as "gBladeRotation",
+        pub fisheye_fov: f32 as "gFisheyeFov",
+    }
+}
+
+//キューブマップの面 (前, 右, 上)をカメラの前、右、上の重みで表す
+//順番はCubeLayout::Stripと同じ
+const CUBE_FACES: [[[f32; 3]; 3]; 6] = [
+    //右
+    [[0.0, 1.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+    //左
+    [[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+    //上
+    [[0.0, 0.0, 1.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 0.0]],
+    //下
+    [[0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]],
+    //前
+    [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    //後ろ
+    [[-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]],
+];
+
+//十字の4x3のマスに置く面 空いているマスは-1
+const CUBE_CROSS: [[i32; 4]; 3] = [
+    [-1, 2, -1, -1],
+    [1, 4, 0, 5],
+    [-1, 3, -1, -1],
+];
+
+//ray_shader.hlslのCameraRayとMainRayGenを移したもの GPUと同じレイになる
+impl HlslCamera {
+    //ndcは画面の左下が(-1, -1)、lens_sampleは[0, 1)の乱数
+    //魚眼の円の外やキューブマップの空いているマスのようにレイがないところはNone
+    pub fn ray(&self, ndc: [f32; 2], lens_sample: [f32; 2]) -> Option<Ray> {
+        let position = Vec3::from(self.position);
+        let forward = Vec3::from(self.forward);
+        let right = Vec3::from(self.right);
+        let up = Vec3::from(self.up);
+        let [x, y] = [ndc[0] * self.image_plane[0], ndc[1] * self.image_plane[1]];
+        let offset = right * x + up * y;
+        //(前, 右, 上)の重みからワールドの方向にする
+        let basis = |[f, r, u]: [f32; 3]| forward * f + right * r + up * u;
+
+        let direction = match self.projection {
+            PROJECTION_ORTHOGRAPHIC => return Some(Ray::new(position + offset, forward, 0.0, RAY_T_MAX)),
+            PROJECTION_EQUIRECTANGULAR => {
+                let longitude = x * PI;
+                let latitude = y * FRAC_PI_2;
+                basis([latitude.cos() * longitude.cos(), latitude.cos() * longitude.sin(), latitude.sin()])
+            }
+            PROJECTION_FISHEYE => {
+                let r = (x * x + y * y).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                //中心からの距離に比例して前から傾ける
+                let theta = r * self.fisheye_fov * 0.5;
+                let (s, c) = if r > 0.0 { (theta.sin() / r, theta.cos()) } else { (0.0, 1.0) };
+                basis([c, x * s, y * s])
+            }
+            PROJECTION_CUBE_CROSS | PROJECTION_CUBE_STRIP => {
+                //画面の左上を(0, 0)、右下を(1, 1)にしてマスを選ぶ
+                let u = (x + 1.0) * 0.5;
+                let v = (1.0 - y) * 0.5;
+                let (columns, rows) = if self.projection == PROJECTION_CUBE_CROSS { (4.0, 3.0) } else { (6.0, 1.0) };
+                let column = (u * columns).floor().min(columns - 1.0);
+                let row = (v * rows).floor().min(rows - 1.0);
+                let face = if self.projection == PROJECTION_CUBE_CROSS { CUBE_CROSS[row as usize][column as usize] } else { column as i32 };
+                if face < 0 {
+                    return None;
+                }
+
+                //マスの中を面の[-1, 1]にする
+                let a = (u * columns - column) * 2.0 - 1.0;
+                let b = 1.0 - (v * rows - row) * 2.0;
+                let [center, face_right, face_up] = CUBE_FACES[face as usize];
+                (basis(center) + basis(face_right) * a + basis(face_up) * b).normalize()
+            }
+            _ => (forward + offset).normalize(),
+        };
+
+        if self.projection != PROJECTION_THIN_LENS {
+            return Some(Ray::new(position, direction, 0.0, RAY_T_MAX));
+        }
+
+        //レンズのどこを通ってもピント面の同じ点に集まる
+        let focus = position + direction * (self.focus_distance / direction.dot(forward));
+        let [x, y] = self.sample_lens(lens_sample);
+        let origin = position + right * (x * self.lens_radius) + up * (y * self.lens_radius);
+        Some(Ray::new(origin, (focus - origin).normalize(), 0.0, RAY_T_MAX))
+    }
+
+    //DispatchRaysIndex()のピクセルの中心を通るレイ レンズの位置はピクセルごとのハッシュで決める
+    pub fn pixel_ray(&self, launch_index: [u32; 2], launch_dimensions: [u32; 2]) -> Option<Ray> {
+        let [x, y] = launch_index;
+        let [w, h] = launch_dimensions;
+
+        let d_x = (x as f32 + 0.5) / w as f32 * 2.0 - 1.0;
+        let d_y = (y as f32 + 0.5) / h as f32 * 2.0 - 1.0;
+
+        let h1 = pcg_hash(y * w + x);
+        let h2 = pcg_hash(h1);
+        self.ray([d_x, -d_y], [unit_float(h1), unit_float(h2)])
+    }
+    //[0, 1)^2を半径1の絞りの中の点にする
+    fn sample_lens(&self, [u, v]: [f32; 2]) -> [f32; 2] {
+        if self.blade_count < 3 {
+            let r = u.sqrt();
+            let theta = 2.0 * PI * v;
+            return [theta.cos() * r, theta.sin() * r];
+        }
+
+        //羽根1枚分の三角形(中心, p0, p1)を選んでその中で一様に取る
+        let blades = self.blade_count as f32;
+        let blade = (u * blades).floor();
+        let s = (u * blades - blade).sqrt();
+        let angle = 2.0 * PI / blades;
+        let a0 = self.blade_rotation + blade * angle;
+        let a1 = a0 + angle;
+        [(a0.cos() * (1.0 - v) + a1.cos() * v) * s, (a0.sin() * (1.0 - v) + a1.sin() * v) * s]
+    }
+}
+
+//ray_shader.hlslのPcgHashと同じ
+pub fn pcg_hash(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+//上位24ビットを[0, 1)にする
+pub fn unit_float(v: u32) -> f32 {
+    (v >> 8) as f32 * (1.0 / 16777216.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-5
+    }
+
+    //fromで押してtoまで動かす
+    fn drag(controller: &mut dyn CameraController, button: MouseButton, from: (f32, f32), to: (f32, f32)) {
+        controller.handle(&InputEvent::MouseMove { x: from.0, y: from.1 });
+        controller.handle(&InputEvent::MouseDown(button));
+        controller.handle(&InputEvent::MouseMove { x: to.0, y: to.1 });
+    }
+
+    #[test]
+    fn orbit_rotates_zooms_and_pans() {
+        let mut orbit = OrbitController::new(Vec3::ZERO, 2.0);
+        assert!(close(orbit.pose().position, Vec3::new(0.0, 0.0, 2.0)));
+
+        drag(&mut orbit, MouseButton::Left, (0.0, 0.0), (100.0, 0.0));
+        orbit.update(1.0 / 60.0);
+        assert!((orbit.yaw - 0.5).abs() < 1e-6);
+        assert!(close(orbit.pose().position, Vec3::new(-2.0 * 0.5f32.sin(), 0.0, 2.0 * 0.5f32.cos())));
+        orbit.handle(&InputEvent::MouseUp(MouseButton::Left));
+
+        orbit.handle(&InputEvent::Wheel(1.0));
+        orbit.update(1.0 / 60.0);
+        assert!((orbit.distance - 1.8).abs() < 1e-6);
+
+        //yaw 0に戻すと右は+X
+        orbit.yaw = 0.0;
+        drag(&mut orbit, MouseButton::Middle, (100.0, 0.0), (200.0, 0.0));
+        orbit.update(1.0 / 60.0);
+        assert!(close(orbit.target, Vec3::new(-1.8 * 0.002 * 100.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn orbit_pitch_is_clamped() {
+        let mut orbit = OrbitController::new(Vec3::ZERO, 1.0);
+        drag(&mut orbit, MouseButton::Left, (0.0, 0.0), (0.0, 10000.0));
+        orbit.update(1.0 / 60.0);
+        assert_eq!(orbit.pitch, MAX_PITCH);
+
+        drag(&mut orbit, MouseButton::Left, (0.0, 10000.0), (0.0, -10000.0));
+        orbit.update(1.0 / 60.0);
+        assert_eq!(orbit.pitch, -MAX_PITCH);
+        let pose = orbit.pose();
+        assert!(pose.up.length() > 0.99 && pose.forward.length() > 0.99);
+    }
+
+    #[test]
+    fn fly_moves_by_speed_and_dt() {
+        let mut fly = FlyController::new(Vec3::ZERO, 0.0, 0.0);
+        fly.handle(&InputEvent::KeyDown(Key::W));
+        fly.update(0.5);
+        assert!(close(fly.position, Vec3::new(0.0, 0.0, -0.5)));
+
+        fly.handle(&InputEvent::KeyDown(Key::Shift));
+        fly.update(0.25);
+        assert!(close(fly.position, Vec3::new(0.0, 0.0, -1.5)));
+
+        //斜めでも1秒で1
+        let mut fly = FlyController::new(Vec3::ZERO, 0.0, 0.0);
+        fly.handle(&InputEvent::KeyDown(Key::W));
+        fly.handle(&InputEvent::KeyDown(Key::D));
+        fly.update(1.0);
+        assert!((fly.position.length() - 1.0).abs() < 1e-5);
+        assert!(fly.position.x > 0.0 && fly.position.z < 0.0);
+
+        fly.handle(&InputEvent::FocusLost);
+        fly.handle(&InputEvent::Wheel(1.0));
+        let before = fly.position;
+        fly.update(1.0);
+        assert!((fly.speed - 1.25).abs() < 1e-6);
+        assert!(close(fly.position, before));
+    }
+
+    #[test]
+    fn fly_looks_with_right_drag_and_clamps_pitch() {
+        let mut fly = FlyController::new(Vec3::ZERO, 0.0, 0.0);
+        drag(&mut fly, MouseButton::Left, (0.0, 0.0), (100.0, 100.0));
+        fly.update(1.0 / 60.0);
+        assert_eq!((fly.yaw, fly.pitch), (0.0, 0.0));
+        fly.handle(&InputEvent::MouseUp(MouseButton::Left));
+
+        drag(&mut fly, MouseButton::Right, (100.0, 100.0), (200.0, -10000.0));
+        fly.update(1.0 / 60.0);
+        assert!((fly.yaw - 0.3).abs() < 1e-6);
+        assert_eq!(fly.pitch, MAX_PITCH);
+        assert!(close(fly.pose().forward, direction(0.3, MAX_PITCH)));
+    }
+
+    #[test]
+    fn turntable_spins_until_paused() {
+        let mut turntable = TurntableController::new(Vec3::ZERO, 2.0, 0.0);
+        turntable.update(1.0);
+        assert!((turntable.orbit.yaw - 0.5).abs() < 1e-6);
+
+        turntable.handle(&InputEvent::KeyDown(Key::Space));
+        assert!(turntable.paused);
+        turntable.update(1.0);
+        assert!((turntable.orbit.yaw - 0.5).abs() < 1e-6);
+
+        //押しっぱなしのリピートでは戻らない
+        turntable.handle(&InputEvent::KeyDown(Key::Space));
+        turntable.handle(&InputEvent::KeyDown(Key::Space));
+        assert!(turntable.paused);
+
+        turntable.handle(&InputEvent::KeyUp(Key::Space));
+        turntable.handle(&InputEvent::KeyDown(Key::Space));
+        assert!(!turntable.paused);
+        turntable.update(0.5);
+        assert!((turntable.orbit.yaw - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn turntable_stops_while_dragging() {
+        let mut turntable = TurntableController::new(Vec3::ZERO, 2.0, 0.2);
+        drag(&mut turntable, MouseButton::Left, (0.0, 0.0), (0.0, 0.0));
+        turntable.update(1.0);
+        assert_eq!(turntable.orbit.yaw, 0.0);
+        assert!((turntable.orbit.pitch - 0.2).abs() < 1e-6);
+
+        turntable.handle(&InputEvent::MouseUp(MouseButton::Left));
+        turntable.update(1.0);
+        assert!((turntable.orbit.yaw - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn controllers_start_at_the_camera_pose() {
+        let camera = Camera { position: Vec3::new(1.0, 2.0, 3.0), target: Vec3::new(0.0, 0.5, 0.0), ..Camera::default() };
+        let expected = camera.pose();
+
+        for kind in ControllerKind::ALL {
+            assert_eq!(ControllerKind::from_name(kind.name()), Some(kind));
+            let pose = kind.create(&camera).pose();
+            assert!(close(pose.position, expected.position), "{:?}", kind);
+            assert!(close(pose.forward, expected.forward), "{:?}", kind);
+        }
+        assert_eq!(ControllerKind::from_name("trackball"), None);
+    }
+
+    fn camera(projection: Projection) -> HlslCamera {
+        Camera::from_pose(&CameraPose::default(), projection).constants()
+    }
+
+    fn angle(a: Vec3, b: Vec3) -> f32 {
+        (a.dot(b) / (a.length() * b.length())).clamp(-1.0, 1.0).acos()
+    }
+
+    #[test]
+    fn perspective_rays_follow_the_fov() {
+        let fov_y = 1.0f32;
+        let aspect = 2.0;
+        let camera = camera(Projection::Perspective { fov_y, aspect });
+        let forward = Vec3::new(0.0, 0.0, -1.0);
+
+        let center = camera.ray([0.0, 0.0], [0.0, 0.0]).unwrap();
+        assert!(close(center.origin, Vec3::new(0.0, 0.0, 1.0)));
+        assert!(close(center.direction, forward));
+
+        //上端の中央は縦の画角の半分
+        let top = camera.ray([0.0, 1.0], [0.0, 0.0]).unwrap();
+        assert!((angle(top.direction, forward) - fov_y * 0.5).abs() < 1e-5);
+        assert!(top.direction.y > 0.0);
+
+        //右上の角は(tan * aspect, tan, -1)の方向
+        let half = (fov_y * 0.5).tan();
+        let corner = camera.ray([1.0, 1.0], [0.0, 0.0]).unwrap();
+        assert!(close(corner.direction, Vec3::new(half * aspect, half, -1.0).normalize()));
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel_across_the_extent() {
+        let camera = camera(Projection::Orthographic { extent: [2.0, 0.5] });
+        let forward = Vec3::new(0.0, 0.0, -1.0);
+
+        for (ndc, origin) in [
+            ([0.0, 0.0], Vec3::new(0.0, 0.0, 1.0)),
+            ([1.0, 1.0], Vec3::new(2.0, 0.5, 1.0)),
+            ([-1.0, 1.0], Vec3::new(-2.0, 0.5, 1.0)),
+            ([-1.0, -1.0], Vec3::new(-2.0, -0.5, 1.0)),
+            ([0.5, -0.5], Vec3::new(1.0, -0.25, 1.0)),
+        ] {
+            let ray = camera.ray(ndc, [0.3, 0.7]).unwrap();
+            assert!(close(ray.origin, origin), "{:?}", ndc);
+            assert!(close(ray.direction, forward), "{:?}", ndc);
+        }
+    }
+
+    #[test]
+    fn thin_lens_rays_meet_at_the_focus_plane() {
+        let focus_distance = 3.0;
+        for blades in [0, 5] {
+            let projection = Projection::ThinLens { fov_y: 1.0, aspect: 1.5, aperture: 0.2, focus_distance, blades, blade_rotation: 0.3 };
+            let camera = camera(projection);
+            let pinhole = Camera::from_pose(&CameraPose::default(), Projection::Perspective { fov_y: 1.0, aspect: 1.5 }).constants();
+
+            //カメラはz=1から-Zを向くのでピント面はz = 1 - focus_distance
+            let plane = 1.0 - focus_distance;
+            for ndc in [[0.0, 0.0], [0.6, -0.4], [-1.0, 1.0]] {
+                let expected = pinhole.ray(ndc, [0.0, 0.0]).unwrap();
+                let expected = expected.at((plane - expected.origin.z) / expected.direction.z);
+                let mut origins = Vec::new();
+                for sample in [[0.1, 0.2], [0.5, 0.9], [0.95, 0.4], [0.3, 0.6]] {
+                    let ray = camera.ray(ndc, sample).unwrap();
+                    let t = (plane - ray.origin.z) / ray.direction.z;
+                    assert!((ray.at(t) - expected).length() < 1e-4, "{} {:?} {:?}", blades, ndc, sample);
+                    origins.push(ray.origin);
+                }
+                //レンズの違う位置から出ている
+                assert!(origins.windows(2).all(|o| (o[0] - o[1]).length() > 1e-3));
+            }
+        }
+    }
+
+    #[test]
+    fn blade_samples_stay_inside_the_polygon() {
+        for blades in [3, 5, 6, 8] {
+            let rotation = 0.4;
+            let camera = camera(Projection::ThinLens { fov_y: 1.0, aspect: 1.0, aperture: 1.0, focus_distance: 1.0, blades, blade_rotation: rotation });
+            let angle = TAU / blades as f32;
+
+            for i in 0..64 {
+                for j in 0..16 {
+                    let [x, y] = camera.sample_lens([i as f32 / 64.0, j as f32 / 16.0]);
+                    //すべての辺の内側にある 辺までの距離は中心からcos(angle/2)
+                    for blade in 0..blades {
+                        let normal = rotation + (blade as f32 + 0.5) * angle;
+                        let distance = x * normal.cos() + y * normal.sin();
+                        assert!(distance <= (angle * 0.5).cos() + 1e-5, "{} {:?}", blades, [x, y]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn primary_rays_match_the_default_camera() {
+        let (width, height) = (8, 4);
+        let rays = crate::cpu::primary_rays(width, height);
+        assert_eq!(rays.len(), (width * height) as usize);
+
+        //元のMainRayGenは(d_x, -d_y, 1)から-Zへ飛ばす
+        for y in 0..height {
+            for x in 0..width {
+                let d_x = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+                let d_y = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+                let ray = rays[(y * width + x) as usize];
+                assert!(close(ray.origin, Vec3::new(d_x, -d_y, 1.0)), "{} {}", x, y);
+                assert!(close(ray.direction, Vec3::new(0.0, 0.0, -1.0)), "{} {}", x, y);
+                assert_eq!(ray.t_max, RAY_T_MAX);
+            }
+        }
+    }
+
+    #[test]
+    fn equirectangular_center_is_forward_and_top_row_is_up() {
+        let camera = camera(Projection::Equirectangular);
+        let (w, h) = (64, 32);
+        let forward = Vec3::new(0.0, 0.0, -1.0);
+
+        //ピクセルの中心は半ピクセルずれる
+        let center = camera.pixel_ray([w / 2, h / 2], [w, h]).unwrap();
+        assert!(angle(center.direction, forward) < PI / w as f32 * 1.5);
+
+        //一番上の行はどの経度でも真上から半ピクセル以内
+        for x in 0..w {
+            let ray = camera.pixel_ray([x, 0], [w, h]).unwrap();
+            assert!(angle(ray.direction, WORLD_UP) <= FRAC_PI_2 / h as f32 + 1e-5, "{}", x);
+        }
+
+        //右端は真後ろ、左右の端の中央は右と左
+        assert!(close(camera.ray([1.0, 0.0], [0.0, 0.0]).unwrap().direction, Vec3::new(0.0, 0.0, 1.0)));
+        assert!(close(camera.ray([0.5, 0.0], [0.0, 0.0]).unwrap().direction, Vec3::new(1.0, 0.0, 0.0)));
+        assert!(close(camera.ray([-0.5, 0.0], [0.0, 0.0]).unwrap().direction, Vec3::new(-1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn fisheye_angle_grows_with_radius() {
+        let fov = 3.0;
+        let camera = camera(Projection::Fisheye { fov, aspect: 2.0 });
+        let forward = Vec3::new(0.0, 0.0, -1.0);
+
+        for i in 0..=10 {
+            let r = i as f32 / 10.0;
+            for (x, y) in [(r, 0.0), (0.0, r), (-r * 0.6, r * 0.8)] {
+                //縦は円の半径がそのままndc、横はaspect倍されるので割っておく
+                let ray = camera.ray([x / 2.0, y], [0.0, 0.0]).unwrap();
+                assert!((angle(ray.direction, forward) - r * fov * 0.5).abs() < 1e-4, "{} {}", x, y);
+            }
+        }
+
+        //円の外
+        assert!(camera.ray([0.6, 0.0], [0.0, 0.0]).is_none());
+        assert!(camera.ray([0.4, 0.8], [0.0, 0.0]).is_none());
+        assert!(camera.ray([1.0, 1.0], [0.0, 0.0]).is_none());
+        assert!(camera.pixel_ray([0, 0], [64, 32]).is_none());
+        assert!(camera.pixel_ray([32, 16], [64, 32]).is_some());
+    }
+
+    //layoutのcolumn, rowのマスの中の(a, b)を画面のndcにする a, bは面の[-1, 1]
+    fn cube_ndc(columns: f32, rows: f32, column: f32, row: f32, a: f32, b: f32) -> [f32; 2] {
+        let u = (column + (a + 1.0) * 0.5) / columns;
+        let v = (row + (1.0 - b) * 0.5) / rows;
+        [u * 2.0 - 1.0, 1.0 - v * 2.0]
+    }
+
+    #[test]
+    fn cube_cells_pick_their_face() {
+        //カメラは-Zを向いて右が+X、上が+Y
+        let faces = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ];
+
+        let strip = camera(Projection::CubeMap { layout: CubeLayout::Strip });
+        for (column, face) in faces.iter().enumerate() {
+            let ray = strip.ray(cube_ndc(6.0, 1.0, column as f32, 0.0, 0.0, 0.0), [0.0, 0.0]).unwrap();
+            assert!(close(ray.direction, *face), "{}", column);
+        }
+
+        let cross = camera(Projection::CubeMap { layout: CubeLayout::Cross });
+        for (row, cells) in CUBE_CROSS.iter().enumerate() {
+            for (column, &face) in cells.iter().enumerate() {
+                let ray = cross.ray(cube_ndc(4.0, 3.0, column as f32, row as f32, 0.3, -0.2), [0.0, 0.0]);
+                if face < 0 {
+                    assert!(ray.is_none(), "{} {}", column, row);
+                    continue;
+                }
+                //マスの中の点も面の中心に一番近い
+                let direction = ray.unwrap().direction;
+                let nearest = (0..6).max_by(|&i, &j| direction.dot(faces[i]).total_cmp(&direction.dot(faces[j]))).unwrap();
+                assert_eq!(nearest as i32, face, "{} {}", column, row);
+            }
+        }
+        assert!(cross.pixel_ray([0, 0], [8, 6]).is_none());
+        assert!(cross.pixel_ray([7, 5], [8, 6]).is_none());
+    }
+
+    #[test]
+    fn cube_cross_is_continuous_across_face_edges() {
+        let cross = camera(Projection::CubeMap { layout: CubeLayout::Cross });
+        let direction = |column: f32, row: f32, a: f32, b: f32| cross.ray(cube_ndc(4.0, 3.0, column, row, a, b), [0.0, 0.0]).unwrap().direction;
+        let e = 1.0 - 1e-4;
+
+        //真ん中の行 左、前、右、後ろ
+        for b in [-0.8, 0.0, 0.5] {
+            for column in 0..3 {
+                let column = column as f32;
+                assert!((direction(column, 1.0, e, b) - direction(column + 1.0, 1.0, -e, b)).length() < 1e-3, "{} {}", column, b);
+            }
+        }
+
+        //縦の列 上、前、下
+        for a in [-0.8, 0.0, 0.5] {
+            assert!((direction(1.0, 0.0, a, -e) - direction(1.0, 1.0, a, e)).length() < 1e-3, "{}", a);
+            assert!((direction(1.0, 1.0, a, -e) - direction(1.0, 2.0, a, e)).length() < 1e-3, "{}", a);
+        }
+    }
+
+    #[test]
+    fn panoramas_fix_the_aspect() {
+        assert_eq!(Projection::Equirectangular.fixed_aspect(), Some(2.0));
+        assert_eq!(Projection::CubeMap { layout: CubeLayout::Strip }.fixed_aspect(), Some(6.0));
+        assert_eq!(Projection::CubeMap { layout: CubeLayout::Cross }.fixed_aspect(), Some(4.0 / 3.0));
+
+        for model in [CameraModel::Perspective, CameraModel::Orthographic, CameraModel::ThinLens, CameraModel::Fisheye] {
+            assert_eq!(model.projection(1.5).fixed_aspect(), None, "{:?}", model);
+        }
+    }
+}
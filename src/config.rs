@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::camera::{CameraModel, ControllerKind};
+use crate::pipeline_config::DEFAULT_MAX_RECURSION_DEPTH;
+use crate::scene_file::{SceneError, SceneFile};
+use crate::shader_manifest::DEFAULT_MANIFEST;
+
+//コマンドラインから決まる設定
+//rwr [--headless] [--alpha-mask] [--scene PATH] [--camera MODEL] [--fov DEG] [--controller NAME] [--max-recursion-depth N] [--manifest PATH] [out.ppm]
+//  MODELはperspective|orthographic|thin-lens|equirectangular|fisheye|cube-cross|cube-strip
+//  NAMEはorbit|fly|turntable
+//rwr info [--manifest PATH] [--update-manifest]
+//rwr compile [--manifest PATH] [--scene PATH] [--dxc PATH]
+//rwr graph [--alpha-mask]
+
+pub const DEFAULT_OUTPUT: &str = "out.ppm";
+//PATHにあるdxcを使う
+pub const DEFAULT_DXC: &str = "dxc";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Command {
+    #[default]
+    Render,
+    //シェーダーマニフェストと.csoの中身を表示する
+    Info,
+    //フレームグラフをコンパイルした実行順とバリアを表示する
+    Graph,
+    //マニフェストのシェーダーをdxcでコンパイルし直してマニフェストを書き直す
+    Compile,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub command: Command,
+    //DXRを使わずCPUで描いてPPMに書き出す
+    pub headless: bool,
+    pub output: String,
+    pub alpha_mask: bool,
+    pub scene: Option<String>,
+    //シーンファイルのcamera、fov、controllerより優先する fovはラジアン
+    pub camera: Option<CameraModel>,
+    pub fov: Option<f32>,
+    pub controller: Option<ControllerKind>,
+    pub max_recursion_depth: u32,
+    pub manifest: String,
+    //infoで今のソースと.csoのハッシュをマニフェストに書き込む
+    pub update_manifest: bool,
+    pub dxc: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            command: Command::Render,
+            headless: false,
+            output: DEFAULT_OUTPUT.to_string(),
+            alpha_mask: false,
+            scene: None,
+            camera: None,
+            fov: None,
+            controller: None,
+            max_recursion_depth: DEFAULT_MAX_RECURSION_DEPTH,
+            manifest: DEFAULT_MANIFEST.to_string(),
+            update_manifest: false,
+            dxc: DEFAULT_DXC.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    UnknownOption(String),
+    MissingValue(&'static str),
+    InvalidValue { option: &'static str, value: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::UnknownOption(option) => write!(f, "unknown option {}", option),
+            ConfigError::MissingValue(option) => write!(f, "{} needs a value", option),
+            ConfigError::InvalidValue { option, value } => write!(f, "invalid value \"{}\" for {}", value, option),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Config {
+    //argsはプログラム名を除いたもの
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut args = args.into_iter().peekable();
+
+        match args.peek().map(String::as_str) {
+            Some("info") => config.command = Command::Info,
+            Some("graph") => config.command = Command::Graph,
+            Some("compile") => config.command = Command::Compile,
+            _ => {}
+        }
+        if config.command != Command::Render {
+            args.next();
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => config.headless = true,
+                "--alpha-mask" => config.alpha_mask = true,
+                "--camera" => {
+                    const OPTION: &str = "--camera";
+                    let value = args.next().ok_or(ConfigError::MissingValue(OPTION))?;
+                    config.camera = Some(CameraModel::from_name(&value).ok_or(ConfigError::InvalidValue { option: OPTION, value })?);
+                }
+                "--fov" => {
+                    const OPTION: &str = "--fov";
+                    let value = args.next().ok_or(ConfigError::MissingValue(OPTION))?;
+                    //範囲はカメラによるのでscene()で確かめる
+                    let fov = value.parse::<f32>().map_err(|_| ConfigError::InvalidValue { option: OPTION, value })?;
+                    config.fov = Some(fov.to_radians());
+                }
+                "--controller" => {
+                    const OPTION: &str = "--controller";
+                    let value = args.next().ok_or(ConfigError::MissingValue(OPTION))?;
+                    config.controller = Some(ControllerKind::from_name(&value).ok_or(ConfigError::InvalidValue { option: OPTION, value })?);
+                }
+                "--scene" => config.scene = Some(args.next().ok_or(ConfigError::MissingValue("--scene"))?),
+                "--max-recursion-depth" => {
+                    const OPTION: &str = "--max-recursion-depth";
+                    let value = args.next().ok_or(ConfigError::MissingValue(OPTION))?;
+                    config.max_recursion_depth = value.parse().map_err(|_| ConfigError::InvalidValue { option: OPTION, value })?;
+                }
+                "--manifest" => config.manifest = args.next().ok_or(ConfigError::MissingValue("--manifest"))?,
+                "--update-manifest" => config.update_manifest = true,
+                "--dxc" => config.dxc = args.next().ok_or(ConfigError::MissingValue("--dxc"))?,
+                option if option.starts_with("--") => return Err(ConfigError::UnknownOption(arg)),
+                _ => config.output = arg,
+            }
+        }
+
+        Ok(config)
+    }
+
+    //シーンファイルにコマンドラインの指定を重ねる シーンファイルがなければ既定のカメラ
+    pub fn scene(&self) -> Result<SceneFile, SceneError> {
+        let scene = match &self.scene {
+            Some(path) => SceneFile::load(path)?,
+            None => SceneFile::default(),
+        };
+        let scene = SceneFile {
+            camera: self.camera.or(scene.camera),
+            fov: self.fov.or(scene.fov),
+            controller: self.controller.or(scene.controller),
+            ..scene
+        };
+        scene.camera(1.0)?;
+        Ok(scene)
+    }
+}
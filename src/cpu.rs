@@ -0,0 +1,132 @@
+pub mod aabb;
+pub mod bvh;
+pub mod compressed;
+pub mod image;
+pub mod lbvh;
+pub mod morton;
+pub mod packet;
+pub mod pipeline;
+pub mod ray;
+pub mod sbvh;
+pub mod scene;
+pub mod shaders;
+pub mod texture;
+pub mod triangle;
+
+use std::sync::Arc;
+
+use crate::camera::Camera;
+use crate::pipeline_config::PipelineConfig;
+use crate::render_graph::{CompiledGraph, CompiledPass, FrameGraph, GraphBackend, PassKind};
+use crate::resource_state::ResourceId;
+use crate::vertex;
+use pipeline::TraceError;
+use shaders::HlslFrame;
+use ray::Ray;
+use scene::{BottomLevel, Geometry, Instance, Scene};
+use texture::AlphaTexture;
+use triangle::Triangle;
+
+//既定のカメラでMainRayGenが飛ばすレイ
+//ピクセルごとに-Z方向へ平行なレイを飛ばす
+pub fn primary_rays(width: u32, height: u32) -> Vec<Ray> {
+    let camera = Camera::default().constants();
+    let mut rays = Vec::with_capacity((width * height) as usize);
+
+    for y in 0..height {
+        for x in 0..width {
+            rays.extend(camera.pixel_ray([x, y], [width, height]));
+        }
+    }
+
+    rays
+}
+
+//init_dxrと同じ三角形1つのシーンをray_shader.hlslの移植で描く
+//alpha_maskがあれば三角形を不透明でないジオメトリにしてアルファテストする
+//cameraはDXRでb1に渡すものと同じ
+pub fn render_headless(width: u32, height: u32, camera: &Camera, alpha_mask: Option<AlphaTexture>, config: &PipelineConfig) -> crate::error::Result<Vec<[f32; 4]>> {
+    let triangles = Triangle::from_vertices(&vertex::sample_triangle());
+    let tex_coords = shaders::barycentric_tex_coords(triangles.len());
+    let opaque = alpha_mask.is_none();
+
+    let blas = BottomLevel::new(vec![Geometry::triangles(triangles, opaque)]);
+    let scene = Scene::new(vec![blas], vec![Instance::new(0)]);
+
+    let alpha_mask_size = alpha_mask.as_ref().map_or(0, |m| std::mem::size_of_val(m.texels.as_slice()) as u64);
+    let pipeline = shaders::ray_shader_pipeline(camera, alpha_mask.map(Arc::new), tex_coords, shaders::default_materials(), config);
+
+    //DXRと同じフレームグラフを実行する 平均は1フレーム分だけ
+    let frame = FrameGraph::new(width, height, alpha_mask_size);
+    let graph = frame.graph.compile()?;
+
+    let trace = || pipeline.dispatch_rays(&scene, width, height);
+    let mut backend = CpuFrame {
+        trace: &trace,
+        slots: vec![vec![]; graph.slots.len()],
+        imported: vec![vec![]; graph.resources.len()],
+        frame: HlslFrame { sample_count: 0, exposure: 1.0 },
+    };
+    graph.execute(&mut backend)?;
+    Ok(std::mem::take(&mut backend.imported[frame.back_buffer.0]))
+}
+
+//CPUのバックエンド バリアは要らないのでパスだけ実行する
+//一時リソースはスロットごと、外から渡すテクスチャはResourceIdごとに持つ
+struct CpuFrame<'a> {
+    trace: &'a dyn Fn() -> Result<Vec<[f32; 4]>, TraceError>,
+    slots: Vec<Vec<[f32; 4]>>,
+    imported: Vec<Vec<[f32; 4]>>,
+    frame: HlslFrame,
+}
+
+impl CpuFrame<'_> {
+    fn image(&mut self, graph: &CompiledGraph, resource: ResourceId) -> &mut Vec<[f32; 4]> {
+        match graph.placement[resource.0] {
+            Some(slot) => &mut self.slots[slot],
+            None => &mut self.imported[resource.0],
+        }
+    }
+}
+
+impl GraphBackend for CpuFrame<'_> {
+    type Error = TraceError;
+
+    fn execute(&mut self, graph: &CompiledGraph, pass: &CompiledPass) -> Result<(), TraceError> {
+        match pass.pass.kind {
+            PassKind::RayTrace => {
+                let pixels = (self.trace)()?;
+                for &(resource, _) in &pass.pass.writes {
+                    *self.image(graph, resource) = pixels.clone();
+                }
+            }
+            PassKind::Accumulate => {
+                let (source, _) = pass.pass.reads[0];
+                let (dest, _) = pass.pass.writes[0];
+                let samples = self.image(graph, source).clone();
+                let sample_count = self.frame.sample_count;
+                let history = self.image(graph, dest);
+                history.resize(samples.len(), [0.0; 4]);
+                for (h, s) in history.iter_mut().zip(samples) {
+                    *h = shaders::accumulate(*h, s, sample_count);
+                }
+            }
+            PassKind::PostProcess => {
+                let (source, _) = pass.pass.reads[0];
+                let (dest, _) = pass.pass.writes[0];
+                let exposure = self.frame.exposure;
+                let pixels = self.image(graph, source).iter().map(|&c| shaders::post_process(c, exposure)).collect();
+                *self.image(graph, dest) = pixels;
+            }
+            PassKind::Copy => {
+                let (source, _) = pass.pass.reads[0];
+                let (dest, _) = pass.pass.writes[0];
+                let pixels = self.image(graph, source).clone();
+                *self.image(graph, dest) = pixels;
+            }
+            //PPMに書き出すのは呼び出し側
+            PassKind::Present => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::math::{Mat3x4, Vec3};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::splat(f32::INFINITY),
+        max: Vec3::splat(f32::NEG_INFINITY),
+    };
+
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Aabb { min, max }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, p: Vec3) {
+        self.min = self.min.min(p);
+        self.max = self.max.max(p);
+    }
+
+    pub fn union(&self, rhs: &Aabb) -> Aabb {
+        Aabb::new(self.min.min(rhs.min), self.max.max(rhs.max))
+    }
+
+    pub fn intersection(&self, rhs: &Aabb) -> Aabb {
+        Aabb::new(self.min.max(rhs.min), self.max.min(rhs.max))
+    }
+
+    //8つの角を変換して囲い直す
+    pub fn transformed(&self, m: &Mat3x4) -> Aabb {
+        if self.is_empty() {
+            return Aabb::EMPTY;
+        }
+
+        let mut b = Aabb::EMPTY;
+        for corner in 0..8 {
+            let p = Vec3::new(
+                if corner & 1 == 0 { self.min.x } else { self.max.x },
+                if corner & 2 == 0 { self.min.y } else { self.max.y },
+                if corner & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            b.grow(m.transform_point(p));
+        }
+        b
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let e = self.extent();
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    //スラブ法 当たった場合は入射側のtを返す
+    pub fn intersect(&self, origin: Vec3, inv_dir: Vec3, t_min: f32, t_max: f32) -> Option<f32> {
+        let mut t_near = t_min;
+        let mut t_far = t_max;
+
+        for axis in 0..3 {
+            let t0 = (self.min[axis] - origin[axis]) * inv_dir[axis];
+            let t1 = (self.max[axis] - origin[axis]) * inv_dir[axis];
+
+            //軸に平行なレイが面の上にあると0 * infでNaNになるので、その軸では範囲を絞らない
+            let (near, far) = if t0 <= t1 {
+                (t0, t1)
+            } else if t1 < t0 {
+                (t1, t0)
+            } else {
+                (f32::NEG_INFINITY, f32::INFINITY)
+            };
+
+            t_near = t_near.max(near);
+            t_far = t_far.min(far);
+        }
+
+        if t_near <= t_far {
+            Some(t_near)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::fmt;
+
+use super::aabb::Aabb;
+use super::lbvh::{self, LbvhOptions, DEFAULT_PLOC_RADIUS};
+use super::ray::{Hit, Ray};
+use super::sbvh::{self, SbvhOptions};
+use super::triangle::Triangle;
+
+pub(super) const BIN_COUNT: usize = 16;
+pub(super) const MAX_LEAF_SIZE: u32 = 4;
+pub(super) const TRAVERSAL_COST: f32 = 1.0;
+pub(super) const INTERSECTION_COST: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum BuildMode {
+    //重心でのbinned SAH
+    #[default]
+    Sah,
+    //空間分割で三角形の参照を複製するSBVH
+    Sbvh(SbvhOptions),
+    //Mortonコードで並べてマルチスレッドで作るLBVH(+PLOC) 毎フレームの再構築向け
+    Lbvh(LbvhOptions),
+}
+
+//D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BUILD_FLAGSのPREFER_FAST_TRACE/PREFER_FAST_BUILDに相当
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BuildPreference {
+    PreferFastTrace,
+    #[default]
+    None,
+    PreferFastBuild,
+}
+
+impl BuildPreference {
+    //SBVHは細長い三角形がないと空間分割が選ばれずSAHと同じ木に時間だけかかるので、ここでは選ばない
+    pub fn build_mode(self) -> BuildMode {
+        match self {
+            BuildPreference::PreferFastTrace => BuildMode::Sah,
+            BuildPreference::None => BuildMode::Lbvh(LbvhOptions { ploc_radius: Some(DEFAULT_PLOC_RADIUS), ..Default::default() }),
+            BuildPreference::PreferFastBuild => BuildMode::Lbvh(LbvhOptions::default()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BvhNode {
+    pub bounds: Aabb,
+    //葉ならprim_indicesの先頭、内部ノードなら左の子のインデックス(右の子は+1)
+    pub first: u32,
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub prim_indices: Vec<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BvhStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    //SBVHでは同じ三角形が複数の葉から参照される
+    pub reference_count: usize,
+    pub max_depth: usize,
+    //ルートの表面積で正規化したSAHコスト
+    pub sah_cost: f32,
+    pub memory_bytes: usize,
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "nodes: {}, leaves: {}, refs: {}, depth: {}, sah cost: {:.2}, memory: {} KiB",
+            self.node_count,
+            self.leaf_count,
+            self.reference_count,
+            self.max_depth,
+            self.sah_cost,
+            self.memory_bytes / 1024,
+        )
+    }
+}
+
+impl Bvh {
+    pub fn build_with(triangles: &[Triangle], mode: BuildMode) -> Self {
+        match mode {
+            BuildMode::Sah => Self::build(triangles),
+            BuildMode::Sbvh(options) => sbvh::build(triangles, &options),
+            BuildMode::Lbvh(options) => lbvh::build(triangles, &options),
+        }
+    }
+
+    //binned SAHで構築する
+    pub fn build(triangles: &[Triangle]) -> Self {
+        let prim_bounds: Vec<Aabb> = triangles.iter().map(|t| t.bounds()).collect();
+        Self::build_from_bounds(&prim_bounds)
+    }
+
+    //プロシージャルなAABBやインスタンスなど、箱だけが分かっている要素から構築する
+    pub fn build_from_bounds(prim_bounds: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(prim_bounds.len() * 2),
+            prim_indices: (0..prim_bounds.len() as u32).collect(),
+        };
+
+        if prim_bounds.is_empty() {
+            return bvh;
+        }
+
+        let bounds = prim_bounds.iter().fold(Aabb::EMPTY, |acc, b| acc.union(b));
+        bvh.nodes.push(BvhNode { bounds, first: 0, count: prim_bounds.len() as u32 });
+        bvh.subdivide(0, prim_bounds);
+
+        bvh
+    }
+
+    fn subdivide(&mut self, node_index: usize, prim_bounds: &[Aabb]) {
+        let node = self.nodes[node_index];
+        if node.count <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let first = node.first as usize;
+        let prims = &mut self.prim_indices[first..first + node.count as usize];
+
+        let centroid_bounds = prims.iter().fold(Aabb::EMPTY, |mut acc, &p| {
+            acc.grow(prim_bounds[p as usize].centroid());
+            acc
+        });
+
+        let Some((axis, split_bin, cost)) = Self::find_split(prims, prim_bounds, &centroid_bounds, &node.bounds) else {
+            return;
+        };
+
+        //分割しない方が安いなら葉のまま
+        if cost >= INTERSECTION_COST * node.count as f32 {
+            return;
+        }
+
+        let bin_of = |p: u32| bin_index(prim_bounds[p as usize].centroid()[axis], &centroid_bounds, axis);
+
+        let mut left_count = 0;
+        for i in 0..prims.len() {
+            if bin_of(prims[i]) < split_bin {
+                prims.swap(i, left_count);
+                left_count += 1;
+            }
+        }
+
+        if left_count == 0 || left_count == prims.len() {
+            return;
+        }
+
+        let left_bounds = prims[..left_count].iter().fold(Aabb::EMPTY, |acc, &p| acc.union(&prim_bounds[p as usize]));
+        let right_bounds = prims[left_count..].iter().fold(Aabb::EMPTY, |acc, &p| acc.union(&prim_bounds[p as usize]));
+
+        let left_index = self.nodes.len();
+        self.nodes.push(BvhNode { bounds: left_bounds, first: node.first, count: left_count as u32 });
+        self.nodes.push(BvhNode { bounds: right_bounds, first: node.first + left_count as u32, count: node.count - left_count as u32 });
+
+        self.nodes[node_index].first = left_index as u32;
+        self.nodes[node_index].count = 0;
+
+        self.subdivide(left_index, prim_bounds);
+        self.subdivide(left_index + 1, prim_bounds);
+    }
+
+    //戻り値は(軸, 右側の先頭のbin, SAHコスト)
+    fn find_split(prims: &[u32], prim_bounds: &[Aabb], centroid_bounds: &Aabb, node_bounds: &Aabb) -> Option<(usize, usize, f32)> {
+        let mut best: Option<(usize, usize, f32)> = None;
+        let node_area = node_bounds.surface_area();
+
+        for axis in 0..3 {
+            if centroid_bounds.max[axis] <= centroid_bounds.min[axis] {
+                continue;
+            }
+
+            let mut bins = [(Aabb::EMPTY, 0u32); BIN_COUNT];
+            for &p in prims {
+                let b = &prim_bounds[p as usize];
+                let bin = &mut bins[bin_index(b.centroid()[axis], centroid_bounds, axis)];
+                bin.0 = bin.0.union(b);
+                bin.1 += 1;
+            }
+
+            //右から累積した面積と個数
+            let mut right_area = [0.0f32; BIN_COUNT];
+            let mut right_count = [0u32; BIN_COUNT];
+            let mut acc = (Aabb::EMPTY, 0u32);
+            for i in (1..BIN_COUNT).rev() {
+                acc.0 = acc.0.union(&bins[i].0);
+                acc.1 += bins[i].1;
+                right_area[i] = acc.0.surface_area();
+                right_count[i] = acc.1;
+            }
+
+            let mut acc = (Aabb::EMPTY, 0u32);
+            for i in 1..BIN_COUNT {
+                acc.0 = acc.0.union(&bins[i - 1].0);
+                acc.1 += bins[i - 1].1;
+
+                if acc.1 == 0 || right_count[i] == 0 {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + INTERSECTION_COST * (acc.0.surface_area() * acc.1 as f32 + right_area[i] * right_count[i] as f32) / node_area;
+
+                if best.is_none_or(|(_, _, c)| cost < c) {
+                    best = Some((axis, i, cost));
+                }
+            }
+        }
+
+        best
+    }
+
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats {
+            node_count: self.nodes.len(),
+            reference_count: self.prim_indices.len(),
+            memory_bytes: self.nodes.len() * std::mem::size_of::<BvhNode>() + self.prim_indices.len() * std::mem::size_of::<u32>(),
+            ..Default::default()
+        };
+
+        if self.nodes.is_empty() {
+            return stats;
+        }
+
+        let root_area = self.nodes[0].bounds.surface_area();
+        let mut stack = vec![(0u32, 1usize)];
+
+        while let Some((node_index, depth)) = stack.pop() {
+            let node = &self.nodes[node_index as usize];
+            let area = if root_area > 0.0 { node.bounds.surface_area() / root_area } else { 1.0 };
+
+            stats.max_depth = stats.max_depth.max(depth);
+
+            if node.is_leaf() {
+                stats.leaf_count += 1;
+                stats.sah_cost += INTERSECTION_COST * node.count as f32 * area;
+            } else {
+                stats.sah_cost += TRAVERSAL_COST * area;
+                stack.push((node.first, depth + 1));
+                stack.push((node.first + 1, depth + 1));
+            }
+        }
+
+        stats
+    }
+
+    //箱に当たった葉の要素ごとにvisitを呼ぶ
+    //visitはray.t_maxを縮めてよく、falseを返すとそこで探索を打ち切る
+    //打ち切った場合はfalseを返す
+    pub fn traverse(&self, ray: &mut Ray, mut visit: impl FnMut(u32, &mut Ray) -> bool) -> bool {
+        if self.nodes.is_empty() {
+            return true;
+        }
+
+        let inv_dir = ray.direction.recip();
+        let mut stack: Vec<u32> = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index as usize];
+            if node.bounds.intersect(ray.origin, inv_dir, ray.t_min, ray.t_max).is_none() {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let first = node.first as usize;
+                for &p in &self.prim_indices[first..first + node.count as usize] {
+                    if !visit(p, ray) {
+                        return false;
+                    }
+                }
+            } else {
+                stack.push(node.first + 1);
+                stack.push(node.first);
+            }
+        }
+
+        true
+    }
+
+    pub fn intersect(&self, triangles: &[Triangle], ray: &Ray) -> Option<Hit> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_dir = ray.direction.recip();
+        let mut ray = *ray;
+        let mut hit = None;
+
+        self.nodes[0].bounds.intersect(ray.origin, inv_dir, ray.t_min, ray.t_max)?;
+
+        let mut stack: Vec<u32> = Vec::with_capacity(64);
+        let mut node_index = 0;
+
+        loop {
+            let node = &self.nodes[node_index as usize];
+
+            if node.is_leaf() {
+                let first = node.first as usize;
+                for &p in &self.prim_indices[first..first + node.count as usize] {
+                    if let Some((t, barys)) = triangles[p as usize].intersect(&ray) {
+                        ray.t_max = t;
+                        hit = Some(Hit { t, prim_index: p, barys });
+                    }
+                }
+            } else {
+                let (l, r) = (node.first, node.first + 1);
+                let dl = self.nodes[l as usize].bounds.intersect(ray.origin, inv_dir, ray.t_min, ray.t_max);
+                let dr = self.nodes[r as usize].bounds.intersect(ray.origin, inv_dir, ray.t_min, ray.t_max);
+
+                //近い方から辿る
+                match (dl, dr) {
+                    (Some(a), Some(b)) => {
+                        let (near, far) = if a <= b { (l, r) } else { (r, l) };
+                        stack.push(far);
+                        node_index = near;
+                        continue;
+                    }
+                    (Some(_), None) => {
+                        node_index = l;
+                        continue;
+                    }
+                    (None, Some(_)) => {
+                        node_index = r;
+                        continue;
+                    }
+                    (None, None) => {}
+                }
+            }
+
+            match stack.pop() {
+                Some(next) => node_index = next,
+                None => break,
+            }
+        }
+
+        hit
+    }
+}
+
+//重心の範囲をBIN_COUNT等分したときのbin SAHとSBVHのオブジェクト分割で使う
+pub(super) fn bin_index(c: f32, centroid_bounds: &Aabb, axis: usize) -> usize {
+    let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+    let scale = BIN_COUNT as f32 / extent;
+    (((c - centroid_bounds.min[axis]) * scale) as usize).min(BIN_COUNT - 1)
+}
@@ -0,0 +1,413 @@
+use std::fmt;
+
+use super::aabb::Aabb;
+use super::bvh::Bvh;
+use super::ray::{Hit, Ray};
+use super::triangle::Triangle;
+use crate::math::Vec3;
+
+//子の参照の最上位ビットが立っていれば葉(TriangleBlockのインデックス)
+const LEAF_FLAG: u32 = 0x8000_0000;
+//頂点インデックスをu8で持つので1ブロックの三角形数を抑える(3 * 64 < 256)
+const MAX_BLOCK_TRIANGLES: usize = 64;
+
+//子の箱を親の(復元後の)箱に対する8bitの座標で持つ
+//自分の箱は持たず、辿るときに親から復元した箱をスタックに積む
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct QuantizedNode {
+    pub child_min: [[u8; 3]; 2],
+    pub child_max: [[u8; 3]; 2],
+    pub children: [u32; 2],
+}
+
+//葉の三角形 ブロック内で重複する頂点は1つにまとめてu8のインデックスで参照する
+#[derive(Clone, Copy, Debug)]
+pub struct TriangleBlock {
+    pub vertex_offset: u32,
+    pub triangle_offset: u32,
+    pub triangle_count: u8,
+    pub vertex_count: u8,
+}
+
+pub struct CompressedBvh {
+    pub bounds: Aabb,
+    pub root: u32,
+    pub nodes: Vec<QuantizedNode>,
+    pub blocks: Vec<TriangleBlock>,
+    pub vertices: Vec<Vec3>,
+    pub indices: Vec<[u8; 3]>,
+    pub prim_indices: Vec<u32>,
+    //比較用に元のBVH + 三角形配列のサイズとノード配列だけのサイズを覚えておく
+    uncompressed_bytes: usize,
+    uncompressed_node_bytes: usize,
+}
+
+//メモリが減る代わりに走査は遅くなる 子の箱を辿るたびに復元し、三角形も頂点のインデックスから組み立てるため
+//benches/compressed.rs(256x256の地形に640x480の平行なレイ)ではメモリが40%減り、走査は3〜5割遅い
+//(2.0〜2.6 Mrays/sが1.3〜2.0 Mrays/s 測るたびにばらつくので5回のうち最速で比べている)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompressedBvhStats {
+    pub node_count: usize,
+    pub block_count: usize,
+    pub vertex_count: usize,
+    pub memory_bytes: usize,
+    pub uncompressed_bytes: usize,
+    //ノードの量子化だけの効果 残りは頂点をまとめた分
+    pub node_bytes: usize,
+    pub uncompressed_node_bytes: usize,
+}
+
+impl fmt::Display for CompressedBvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let saved = |bytes: usize, uncompressed: usize| if uncompressed > 0 { (1.0 - bytes as f32 / uncompressed as f32) * 100.0 } else { 0.0 };
+
+        write!(
+            f,
+            "nodes: {}, blocks: {}, vertices: {}, memory: {} KiB (uncompressed {} KiB, {:.1}% saved), node memory: {} KiB (uncompressed {} KiB, {:.1}% saved)",
+            self.node_count,
+            self.block_count,
+            self.vertex_count,
+            self.memory_bytes / 1024,
+            self.uncompressed_bytes / 1024,
+            saved(self.memory_bytes, self.uncompressed_bytes),
+            self.node_bytes / 1024,
+            self.uncompressed_node_bytes / 1024,
+            saved(self.node_bytes, self.uncompressed_node_bytes),
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Node(u32),
+    Prims(&'a [u32]),
+}
+
+impl Bvh {
+    pub fn compress(&self, triangles: &[Triangle]) -> CompressedBvh {
+        let mut compressed = CompressedBvh {
+            bounds: Aabb::EMPTY,
+            root: 0,
+            nodes: vec![],
+            blocks: vec![],
+            vertices: vec![],
+            indices: vec![],
+            prim_indices: vec![],
+            uncompressed_bytes: self.stats().memory_bytes + std::mem::size_of_val(triangles),
+            uncompressed_node_bytes: std::mem::size_of_val(self.nodes.as_slice()),
+        };
+
+        if self.nodes.is_empty() {
+            return compressed;
+        }
+
+        let mut compressor = Compressor { bvh: self, triangles, out: &mut compressed };
+        let bounds = compressor.bounds(Source::Node(0));
+        let root = compressor.emit(Source::Node(0), bounds);
+
+        compressed.bounds = bounds;
+        compressed.root = root;
+        compressed
+    }
+}
+
+struct Compressor<'a> {
+    bvh: &'a Bvh,
+    triangles: &'a [Triangle],
+    out: &'a mut CompressedBvh,
+}
+
+impl<'a> Compressor<'a> {
+    fn bounds(&self, source: Source) -> Aabb {
+        match source {
+            Source::Node(i) => self.bvh.nodes[i as usize].bounds,
+            Source::Prims(prims) => prims.iter().fold(Aabb::EMPTY, |acc, &p| acc.union(&self.triangles[p as usize].bounds())),
+        }
+    }
+
+    fn children(&self, source: Source<'a>) -> Option<[Source<'a>; 2]> {
+        let prims = match source {
+            Source::Node(i) => {
+                let node = &self.bvh.nodes[i as usize];
+                if !node.is_leaf() {
+                    return Some([Source::Node(node.first), Source::Node(node.first + 1)]);
+                }
+                &self.bvh.prim_indices[node.first as usize..(node.first + node.count) as usize]
+            }
+            Source::Prims(prims) => prims,
+        };
+
+        //1ブロックに収まらない葉は半分ずつに分ける
+        if prims.len() > MAX_BLOCK_TRIANGLES {
+            let (l, r) = prims.split_at(prims.len() / 2);
+            Some([Source::Prims(l), Source::Prims(r)])
+        } else {
+            None
+        }
+    }
+
+    //decodedはこのノードを辿るときに使われる箱
+    fn emit(&mut self, source: Source<'a>, decoded: Aabb) -> u32 {
+        let Some(children) = self.children(source) else {
+            return self.emit_block(source) | LEAF_FLAG;
+        };
+
+        let node_index = self.out.nodes.len();
+        self.out.nodes.push(QuantizedNode { child_min: [[0; 3]; 2], child_max: [[0; 3]; 2], children: [0; 2] });
+
+        let mut node = QuantizedNode { child_min: [[0; 3]; 2], child_max: [[0; 3]; 2], children: [0; 2] };
+        for (c, child) in children.into_iter().enumerate() {
+            let (q_min, q_max) = quantize(&self.bounds(child), &decoded);
+            node.child_min[c] = q_min;
+            node.child_max[c] = q_max;
+            node.children[c] = self.emit(child, dequantize(q_min, q_max, &decoded));
+        }
+
+        self.out.nodes[node_index] = node;
+        node_index as u32
+    }
+
+    fn emit_block(&mut self, source: Source) -> u32 {
+        let prims = match source {
+            Source::Node(i) => {
+                let node = &self.bvh.nodes[i as usize];
+                &self.bvh.prim_indices[node.first as usize..(node.first + node.count) as usize]
+            }
+            Source::Prims(prims) => prims,
+        };
+
+        let vertex_offset = self.out.vertices.len();
+        let block = TriangleBlock {
+            vertex_offset: vertex_offset as u32,
+            triangle_offset: self.out.indices.len() as u32,
+            triangle_count: prims.len() as u8,
+            vertex_count: 0,
+        };
+
+        for &p in prims {
+            let tri = &self.triangles[p as usize];
+            let mut index = [0u8; 3];
+
+            for (k, v) in [tri.v0, tri.v1, tri.v2].into_iter().enumerate() {
+                //ビット単位で同じ頂点だけまとめる(交差判定の結果を変えないため)
+                let same = |u: &Vec3| u.x.to_bits() == v.x.to_bits() && u.y.to_bits() == v.y.to_bits() && u.z.to_bits() == v.z.to_bits();
+
+                index[k] = match self.out.vertices[vertex_offset..].iter().position(same) {
+                    Some(i) => i as u8,
+                    None => {
+                        self.out.vertices.push(v);
+                        (self.out.vertices.len() - vertex_offset - 1) as u8
+                    }
+                };
+            }
+
+            self.out.indices.push(index);
+            self.out.prim_indices.push(p);
+        }
+
+        self.out.blocks.push(TriangleBlock { vertex_count: (self.out.vertices.len() - vertex_offset) as u8, ..block });
+        self.out.blocks.len() as u32 - 1
+    }
+}
+
+//端(0と255)は親の箱の端とぴったり同じ値にする
+fn dequantize_axis(q: u8, lo: f32, hi: f32) -> f32 {
+    match q {
+        0 => lo,
+        255 => hi,
+        //辿るたびに子1つで6回呼ぶので割り算をしない
+        _ => lo + (hi - lo) * (q as f32 * (1.0 / 255.0)),
+    }
+}
+
+fn dequantize(q_min: [u8; 3], q_max: [u8; 3], parent: &Aabb) -> Aabb {
+    Aabb::new(
+        Vec3::new(
+            dequantize_axis(q_min[0], parent.min.x, parent.max.x),
+            dequantize_axis(q_min[1], parent.min.y, parent.max.y),
+            dequantize_axis(q_min[2], parent.min.z, parent.max.z),
+        ),
+        Vec3::new(
+            dequantize_axis(q_max[0], parent.min.x, parent.max.x),
+            dequantize_axis(q_max[1], parent.min.y, parent.max.y),
+            dequantize_axis(q_max[2], parent.min.z, parent.max.z),
+        ),
+    )
+}
+
+//復元した箱が必ず元の箱を含むように切り下げ/切り上げる
+fn quantize(child: &Aabb, parent: &Aabb) -> ([u8; 3], [u8; 3]) {
+    let mut q_min = [0u8; 3];
+    let mut q_max = [255u8; 3];
+
+    for axis in 0..3 {
+        let (lo, hi) = (parent.min[axis], parent.max[axis]);
+        let extent = hi - lo;
+        if extent <= 0.0 {
+            continue;
+        }
+
+        let mut qa = ((child.min[axis] - lo) / extent * 255.0).floor().clamp(0.0, 255.0) as u8;
+        while qa > 0 && dequantize_axis(qa, lo, hi) > child.min[axis] {
+            qa -= 1;
+        }
+
+        let mut qb = ((child.max[axis] - lo) / extent * 255.0).ceil().clamp(0.0, 255.0) as u8;
+        while qb < 255 && dequantize_axis(qb, lo, hi) < child.max[axis] {
+            qb += 1;
+        }
+
+        q_min[axis] = qa;
+        q_max[axis] = qb;
+    }
+
+    (q_min, q_max)
+}
+
+impl CompressedBvh {
+    pub fn stats(&self) -> CompressedBvhStats {
+        let node_bytes = std::mem::size_of::<Aabb>() + std::mem::size_of_val(self.nodes.as_slice());
+
+        CompressedBvhStats {
+            node_count: self.nodes.len(),
+            block_count: self.blocks.len(),
+            vertex_count: self.vertices.len(),
+            memory_bytes: node_bytes
+                + std::mem::size_of_val(self.blocks.as_slice())
+                + std::mem::size_of_val(self.vertices.as_slice())
+                + std::mem::size_of_val(self.indices.as_slice())
+                + std::mem::size_of_val(self.prim_indices.as_slice()),
+            uncompressed_bytes: self.uncompressed_bytes,
+            node_bytes,
+            uncompressed_node_bytes: self.uncompressed_node_bytes,
+        }
+    }
+
+    //三角形はブロックから復元するので元の配列は要らない
+    pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+
+        let inv_dir = ray.direction.recip();
+        let mut ray = *ray;
+        let mut hit = None;
+
+        self.bounds.intersect(ray.origin, inv_dir, ray.t_min, ray.t_max)?;
+
+        let mut stack: Vec<(u32, Aabb)> = Vec::with_capacity(64);
+        let mut current = (self.root, self.bounds);
+
+        loop {
+            let (child, bounds) = current;
+
+            if child & LEAF_FLAG != 0 {
+                let block = &self.blocks[(child & !LEAF_FLAG) as usize];
+                let vertices = &self.vertices[block.vertex_offset as usize..];
+                let first = block.triangle_offset as usize;
+
+                for k in first..first + block.triangle_count as usize {
+                    let [a, b, c] = self.indices[k];
+                    let tri = Triangle::new(vertices[a as usize], vertices[b as usize], vertices[c as usize]);
+
+                    if let Some((t, barys)) = tri.intersect(&ray) {
+                        ray.t_max = t;
+                        hit = Some(Hit { t, prim_index: self.prim_indices[k], barys });
+                    }
+                }
+            } else {
+                let node = &self.nodes[child as usize];
+                let l = dequantize(node.child_min[0], node.child_max[0], &bounds);
+                let r = dequantize(node.child_min[1], node.child_max[1], &bounds);
+
+                let dl = l.intersect(ray.origin, inv_dir, ray.t_min, ray.t_max);
+                let dr = r.intersect(ray.origin, inv_dir, ray.t_min, ray.t_max);
+
+                let next = match (dl, dr) {
+                    (Some(a), Some(b)) => {
+                        let (near, far) = if a <= b { ((node.children[0], l), (node.children[1], r)) } else { ((node.children[1], r), (node.children[0], l)) };
+                        stack.push(far);
+                        Some(near)
+                    }
+                    (Some(_), None) => Some((node.children[0], l)),
+                    (None, Some(_)) => Some((node.children[1], r)),
+                    (None, None) => None,
+                };
+
+                if let Some(next) = next {
+                    current = next;
+                    continue;
+                }
+            }
+
+            match stack.pop() {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::triangle::terrain;
+
+    fn assert_same_hits(tris: &[Triangle]) {
+        let bvh = Bvh::build(tris);
+        let compressed = bvh.compress(tris);
+
+        for i in 0..400 {
+            let x = (i % 20) as f32 * 0.1 - 0.97;
+            let y = (i / 20) as f32 * 0.1 - 0.97;
+            let ray = Ray::new(Vec3::new(x, y, 2.0), Vec3::new(0.01, -0.02, -1.0), 0.0, 100.0);
+
+            //箱が広がる分だけ辿る順番は変わるが、最も近い交点は変わらない
+            match (bvh.intersect(tris, &ray), compressed.intersect(&ray)) {
+                (Some(a), Some(b)) => assert_eq!(a.t, b.t),
+                (None, None) => {}
+                (a, b) => panic!("compressed bvh disagrees with the uncompressed one: {:?} {:?}", a, b),
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_hits_match_the_uncompressed_bvh() {
+        assert_same_hits(&terrain(32));
+    }
+
+    #[test]
+    fn oversized_leaves_are_split_into_blocks() {
+        //重心が同じ三角形は分けられないので1つの葉にまとまる
+        let tris: Vec<Triangle> = (0..150)
+            .map(|i| {
+                let s = 0.5 + i as f32 * 0.003;
+                let d = i as f32 * 0.001;
+                Triangle::new(Vec3::new(-s, -s, -d), Vec3::new(s, -s, d), Vec3::new(0.0, s, 0.0))
+            })
+            .collect();
+
+        let bvh = Bvh::build(&tris);
+        assert_eq!(bvh.nodes.len(), 1);
+
+        let compressed = bvh.compress(&tris);
+        assert!(compressed.blocks.len() >= 3);
+        assert!(compressed.blocks.iter().all(|b| b.triangle_count as usize <= MAX_BLOCK_TRIANGLES));
+        assert_same_hits(&tris);
+    }
+
+    #[test]
+    fn stats_separate_the_node_saving() {
+        let tris = terrain(32);
+        let bvh = Bvh::build(&tris);
+        let stats = bvh.compress(&tris).stats();
+
+        assert_eq!(stats.uncompressed_node_bytes, bvh.nodes.len() * std::mem::size_of::<crate::cpu::bvh::BvhNode>());
+        assert_eq!(stats.node_bytes, std::mem::size_of::<Aabb>() + stats.node_count * std::mem::size_of::<QuantizedNode>());
+        assert!(stats.node_bytes < stats.uncompressed_node_bytes);
+        assert!(stats.memory_bytes < stats.uncompressed_bytes);
+    }
+}
@@ -0,0 +1,14 @@
+use std::io::{self, Write};
+
+//dispatch_raysの結果をバイナリPPM(P6)で書き出す アルファは捨てる
+pub fn write_ppm(out: &mut impl Write, width: u32, height: u32, pixels: &[[f32; 4]]) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+
+    let bytes: Vec<u8> = pixels
+        .iter()
+        .flat_map(|p| [p[0], p[1], p[2]])
+        .map(|c| (c.clamp(0.0, 1.0) * 255.0 + 0.5) as u8)
+        .collect();
+
+    out.write_all(&bytes)
+}
@@ -0,0 +1,451 @@
+use std::thread;
+
+use super::aabb::Aabb;
+use super::bvh::{Bvh, BvhNode, MAX_LEAF_SIZE};
+use super::morton::morton_code;
+use super::triangle::Triangle;
+
+//Karras "Maximizing Parallelism in the Construction of BVHs, Octrees, and k-d Trees"
+//Meister and Bittner "Parallel Locally-Ordered Clustering for Bounding Volume Hierarchy Construction"
+
+//これより少ない要素しか担当しないならスレッドを増やさない
+const MIN_ITEMS_PER_THREAD: usize = 1024;
+//16に広げても木の質はほとんど変わらず、時間だけ増える
+pub const DEFAULT_PLOC_RADIUS: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LbvhOptions {
+    //SomeならMorton順で並べた後にPLOCでクラスタリングして階層を作り直す 値は近傍の探索半径
+    pub ploc_radius: Option<usize>,
+    //0ならavailable_parallelism()
+    pub threads: usize,
+}
+
+//構築途中の二分木 0..nは葉(Morton順)でleftに三角形のインデックスを持つ
+#[derive(Clone, Copy)]
+struct TempNode {
+    left: u32,
+    right: u32,
+    count: u32,
+}
+
+pub fn build(triangles: &[Triangle], options: &LbvhOptions) -> Bvh {
+    let n = triangles.len();
+    let mut bvh = Bvh { nodes: Vec::with_capacity(n * 2), prim_indices: Vec::with_capacity(n) };
+
+    if n == 0 {
+        return bvh;
+    }
+
+    let threads = match options.threads {
+        0 => thread::available_parallelism().map_or(1, |t| t.get()),
+        t => t,
+    };
+
+    let prim_bounds: Vec<Aabb> = parallel_map(triangles, threads, |t| t.bounds());
+
+    let centroid_bounds = prim_bounds.iter().fold(Aabb::EMPTY, |mut acc, b| {
+        acc.grow(b.centroid());
+        acc
+    });
+
+    let mut keys: Vec<(u32, u32)> = parallel_map(&prim_bounds, threads, |b| morton_code(b.centroid(), centroid_bounds.min, centroid_bounds.max))
+        .into_iter()
+        .zip(0..n as u32)
+        .collect();
+
+    radix_sort(&mut keys, threads);
+
+    let leaves = keys.iter().map(|&(_, prim)| TempNode { left: prim, right: 0, count: 1 });
+
+    let mut temp: Vec<TempNode> = leaves.collect();
+    temp.extend(emit_hierarchy(&keys, threads));
+    let mut root = if n == 1 { 0 } else { n as u32 };
+
+    //葉にまとめられる大きさの部分木はLBVHのまま使い、その上だけをクラスタリングし直す
+    if let Some(radius) = options.ploc_radius {
+        let mut clusters = vec![];
+        collect_clusters(&temp, root, &mut clusters);
+        root = ploc(&mut temp, clusters, &prim_bounds, n as u32, radius.max(1), threads);
+    }
+
+    let mut emitter = Emitter { temp: &temp, leaf_count: n as u32, prim_bounds: &prim_bounds, bvh: &mut bvh };
+    emitter.bvh.nodes.push(BvhNode { bounds: Aabb::EMPTY, first: 0, count: 0 });
+    emitter.emit(root, 0);
+
+    bvh
+}
+
+//Karrasの方法で内部ノードを並列に決める
+//内部ノードiはtempのn + iに置かれる
+fn emit_hierarchy(keys: &[(u32, u32)], threads: usize) -> Vec<TempNode> {
+    let n = keys.len();
+    if n == 1 {
+        return vec![];
+    }
+
+    let leaf_count = n as u32;
+
+    //共通プレフィックス長 同じコードはインデックスで区別する
+    let delta = |i: i64, j: i64| -> i64 {
+        if j < 0 || j >= n as i64 {
+            return -1;
+        }
+        let (a, b) = (keys[i as usize].0, keys[j as usize].0);
+        if a == b {
+            32 + (i as u32 ^ j as u32).leading_zeros() as i64
+        } else {
+            (a ^ b).leading_zeros() as i64
+        }
+    };
+
+    let child = |index: i64, is_leaf: bool| if is_leaf { index as u32 } else { leaf_count + index as u32 };
+
+    let mut internal = vec![TempNode { left: 0, right: 0, count: 0 }; n - 1];
+
+    parallel_for_mut(&mut internal, threads, |offset, chunk| {
+        for (k, node) in chunk.iter_mut().enumerate() {
+            let i = (offset + k) as i64;
+
+            let d = if delta(i, i + 1) - delta(i, i - 1) >= 0 { 1 } else { -1 };
+            let delta_min = delta(i, i - d);
+
+            let mut l_max = 2;
+            while delta(i, i + l_max * d) > delta_min {
+                l_max *= 2;
+            }
+
+            let mut l = 0;
+            let mut t = l_max / 2;
+            while t >= 1 {
+                if delta(i, i + (l + t) * d) > delta_min {
+                    l += t;
+                }
+                t /= 2;
+            }
+
+            let j = i + l * d;
+            let delta_node = delta(i, j);
+
+            let mut s = 0;
+            let mut div = 2;
+            loop {
+                let t = (l + div - 1) / div;
+                if delta(i, i + (s + t) * d) > delta_node {
+                    s += t;
+                }
+                if t <= 1 {
+                    break;
+                }
+                div *= 2;
+            }
+
+            let gamma = i + s * d + d.min(0);
+
+            *node = TempNode {
+                left: child(gamma, i.min(j) == gamma),
+                right: child(gamma + 1, i.max(j) == gamma + 1),
+                count: (i - j).unsigned_abs() as u32 + 1,
+            };
+        }
+    });
+
+    internal
+}
+
+//countがMAX_LEAF_SIZE以下になる一番上の部分木をMorton順に集める
+fn collect_clusters(temp: &[TempNode], root: u32, clusters: &mut Vec<u32>) {
+    let mut stack = vec![root];
+    while let Some(t) = stack.pop() {
+        let node = &temp[t as usize];
+        if node.count <= MAX_LEAF_SIZE {
+            clusters.push(t);
+        } else {
+            stack.push(node.right);
+            stack.push(node.left);
+        }
+    }
+}
+
+//近傍半径内で互いに最も近いクラスタ同士を繰り返しまとめる
+fn ploc(temp: &mut Vec<TempNode>, mut clusters: Vec<u32>, prim_bounds: &[Aabb], leaf_count: u32, radius: usize, threads: usize) -> u32 {
+    let mut bounds = vec![Aabb::EMPTY; temp.len()];
+    for &c in &clusters {
+        bounds[c as usize] = subtree_bounds(temp, c, prim_bounds, leaf_count);
+    }
+
+    let mut distances = vec![];
+    let mut nearest = vec![];
+
+    while clusters.len() > 1 {
+        let n = clusters.len();
+
+        //iからi + 1 + kへの距離(まとめた箱の表面積の半分) 前後の両方向から使うので1回だけ求める
+        distances.clear();
+        distances.resize(n * radius, f32::INFINITY);
+        {
+            let clusters = &clusters;
+            let bounds = &bounds;
+
+            parallel_for_mut(&mut distances, threads, |offset, chunk| {
+                for (k, d) in chunk.iter_mut().enumerate() {
+                    let (i, step) = ((offset + k) / radius, (offset + k) % radius);
+                    let j = i + 1 + step;
+                    if j < n {
+                        let (a, b) = (&bounds[clusters[i] as usize], &bounds[clusters[j] as usize]);
+                        let e = a.max.max(b.max) - a.min.min(b.min);
+                        *d = e.x * e.y + e.y * e.z + e.z * e.x;
+                    }
+                }
+            });
+        }
+
+        nearest.clear();
+        nearest.resize(n, 0usize);
+        {
+            let distances = &distances;
+
+            parallel_for_mut(&mut nearest, threads, |offset, chunk| {
+                for (k, nn) in chunk.iter_mut().enumerate() {
+                    let i = offset + k;
+
+                    //同じ距離のときは(小さい方, 大きい方)のインデックス順で決めて、必ず相互最近傍のペアができるようにする
+                    //前にあるものは組の小さい方がjになるので、近いものから見て距離が同じなら前を優先すればよい
+                    let mut best = (f32::INFINITY, usize::MAX);
+                    for j in i.saturating_sub(radius)..i {
+                        let d = distances[j * radius + (i - j - 1)];
+                        if d < best.0 || (d == best.0 && j < best.1) {
+                            best = (d, j);
+                        }
+                    }
+                    for j in i + 1..(i + 1 + radius).min(n) {
+                        let d = distances[i * radius + (j - i - 1)];
+                        if d < best.0 {
+                            best = (d, j);
+                        }
+                    }
+                    *nn = best.1;
+                }
+            });
+        }
+
+        let mut merged = Vec::with_capacity(n);
+        for (i, &c) in clusters.iter().enumerate() {
+            let j = nearest[i];
+
+            if nearest[j] != i {
+                merged.push(c);
+            } else if i < j {
+                let other = clusters[j];
+                temp.push(TempNode { left: c, right: other, count: temp[c as usize].count + temp[other as usize].count });
+                bounds.push(bounds[c as usize].union(&bounds[other as usize]));
+                merged.push(temp.len() as u32 - 1);
+            }
+        }
+
+        clusters = merged;
+    }
+
+    clusters[0]
+}
+
+fn subtree_bounds(temp: &[TempNode], t: u32, prim_bounds: &[Aabb], leaf_count: u32) -> Aabb {
+    let node = &temp[t as usize];
+    if t < leaf_count {
+        prim_bounds[node.left as usize]
+    } else {
+        subtree_bounds(temp, node.left, prim_bounds, leaf_count).union(&subtree_bounds(temp, node.right, prim_bounds, leaf_count))
+    }
+}
+
+//二分木をBvhのノード配列(子は隣り合わせ)に並べ直す
+struct Emitter<'a> {
+    temp: &'a [TempNode],
+    leaf_count: u32,
+    prim_bounds: &'a [Aabb],
+    bvh: &'a mut Bvh,
+}
+
+impl<'a> Emitter<'a> {
+    fn emit(&mut self, t: u32, out_index: usize) -> Aabb {
+        let node = self.temp[t as usize];
+
+        //要素数が少ない部分木は1つの葉にまとめる
+        if node.count <= MAX_LEAF_SIZE {
+            let first = self.bvh.prim_indices.len() as u32;
+            let mut bounds = Aabb::EMPTY;
+            let mut stack = vec![t];
+
+            while let Some(t) = stack.pop() {
+                let node = &self.temp[t as usize];
+                if t < self.leaf_count {
+                    self.bvh.prim_indices.push(node.left);
+                    bounds = bounds.union(&self.prim_bounds[node.left as usize]);
+                } else {
+                    stack.push(node.right);
+                    stack.push(node.left);
+                }
+            }
+
+            self.bvh.nodes[out_index] = BvhNode { bounds, first, count: node.count };
+            return bounds;
+        }
+
+        let left_index = self.bvh.nodes.len();
+        self.bvh.nodes.push(BvhNode { bounds: Aabb::EMPTY, first: 0, count: 0 });
+        self.bvh.nodes.push(BvhNode { bounds: Aabb::EMPTY, first: 0, count: 0 });
+
+        let bounds = self.emit(node.left, left_index).union(&self.emit(node.right, left_index + 1));
+
+        self.bvh.nodes[out_index] = BvhNode { bounds, first: left_index as u32, count: 0 };
+        bounds
+    }
+}
+
+//8bitずつ4パス(Mortonコードは30bit)のLSD基数ソート
+//各スレッドが担当範囲を桁ごとに振り分け、桁ごとの出力範囲への書き込みも並列に行う
+fn radix_sort(keys: &mut Vec<(u32, u32)>, threads: usize) {
+    let n = keys.len();
+    let threads = threads.min(n.div_ceil(MIN_ITEMS_PER_THREAD)).max(1);
+    let chunk_size = n.div_ceil(threads);
+
+    let mut out = vec![(0u32, 0u32); n];
+
+    for pass in 0..4 {
+        let shift = pass * 8;
+        let digit = |k: &(u32, u32)| ((k.0 >> shift) & 0xFF) as usize;
+
+        let buckets: Vec<Vec<Vec<(u32, u32)>>> = thread::scope(|s| {
+            let handles: Vec<_> = keys
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    s.spawn(move || {
+                        let mut buckets = vec![vec![]; 256];
+                        for k in chunk {
+                            buckets[digit(k)].push(*k);
+                        }
+                        buckets
+                    })
+                })
+                .collect();
+
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        //桁ごとの出力範囲に分ける
+        let mut digit_slices: Vec<(usize, &mut [(u32, u32)])> = Vec::with_capacity(256);
+        let mut rest: &mut [(u32, u32)] = &mut out;
+        for d in 0..256 {
+            let len: usize = buckets.iter().map(|b| b[d].len()).sum();
+            let (head, tail) = rest.split_at_mut(len);
+            digit_slices.push((d, head));
+            rest = tail;
+        }
+
+        let buckets = &buckets;
+        thread::scope(|s| {
+            let per_thread = digit_slices.len().div_ceil(threads);
+            let mut digit_slices = digit_slices;
+
+            while !digit_slices.is_empty() {
+                let take = per_thread.min(digit_slices.len());
+                let group: Vec<_> = digit_slices.drain(..take).collect();
+
+                s.spawn(move || {
+                    for (d, slice) in group {
+                        let mut pos = 0;
+                        for b in buckets {
+                            slice[pos..pos + b[d].len()].copy_from_slice(&b[d]);
+                            pos += b[d].len();
+                        }
+                    }
+                });
+            }
+        });
+
+        std::mem::swap(keys, &mut out);
+    }
+}
+
+fn parallel_map<T: Sync, R: Send>(items: &[T], threads: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = threads.min(items.len().div_ceil(MIN_ITEMS_PER_THREAD)).max(1);
+    let chunk_size = items.len().div_ceil(threads).max(1);
+    let f = &f;
+
+    thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move || chunk.iter().map(f).collect::<Vec<R>>()))
+            .collect();
+
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    })
+}
+
+fn parallel_for_mut<T: Send>(items: &mut [T], threads: usize, f: impl Fn(usize, &mut [T]) + Sync) {
+    let threads = threads.min(items.len().div_ceil(MIN_ITEMS_PER_THREAD)).max(1);
+    let chunk_size = items.len().div_ceil(threads).max(1);
+    let f = &f;
+
+    thread::scope(|s| {
+        for (i, chunk) in items.chunks_mut(chunk_size).enumerate() {
+            s.spawn(move || f(i * chunk_size, chunk));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::ray::Ray;
+    use crate::math::Vec3;
+
+    //ばらばらに散らばった小さい三角形
+    fn scattered(count: u32) -> Vec<Triangle> {
+        (0..count)
+            .map(|i| {
+                let h = |k: u32| (crate::camera::pcg_hash(i * 3 + k) % 1000) as f32 / 500.0 - 1.0;
+                let p = Vec3::new(h(0), h(1), h(2));
+                Triangle::new(p, p + Vec3::new(0.05, 0.0, 0.0), p + Vec3::new(0.0, 0.05, 0.01))
+            })
+            .collect()
+    }
+
+    fn assert_valid(bvh: &Bvh, count: usize) {
+        let mut prims = bvh.prim_indices.clone();
+        prims.sort_unstable();
+        assert_eq!(prims, (0..count as u32).collect::<Vec<_>>());
+        assert!(bvh.nodes.iter().filter(|n| n.is_leaf()).all(|n| n.count <= MAX_LEAF_SIZE));
+    }
+
+    #[test]
+    fn lbvh_and_ploc_find_the_same_hits_as_brute_force() {
+        let tris = scattered(3000);
+
+        for options in [
+            LbvhOptions::default(),
+            LbvhOptions { ploc_radius: Some(DEFAULT_PLOC_RADIUS), threads: 1 },
+            LbvhOptions { ploc_radius: Some(1), threads: 3 },
+        ] {
+            let bvh = build(&tris, &options);
+            assert_valid(&bvh, tris.len());
+
+            for i in 0..200 {
+                let x = (i % 20) as f32 * 0.1 - 1.0;
+                let y = (i / 20) as f32 * 0.2 - 1.0;
+                let ray = Ray::new(Vec3::new(x, y, 2.0), Vec3::new(0.01, 0.02, -1.0), 0.0, 100.0);
+
+                let brute = tris.iter().filter_map(|t| t.intersect(&ray)).map(|(t, _)| t).fold(f32::INFINITY, f32::min);
+                let hit = bvh.intersect(&tris, &ray).map_or(f32::INFINITY, |h| h.t);
+                assert_eq!(hit, brute, "{:?}", options);
+            }
+        }
+    }
+
+    #[test]
+    fn single_triangle_is_a_leaf() {
+        let bvh = build(&scattered(1), &LbvhOptions { ploc_radius: Some(4), threads: 1 });
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.prim_indices, vec![0]);
+    }
+}
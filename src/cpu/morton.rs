@@ -0,0 +1,25 @@
+use crate::math::Vec3;
+
+//各軸10bitに量子化した30bitのMortonコード
+pub fn morton_code(p: Vec3, min: Vec3, max: Vec3) -> u32 {
+    let quantize = |v: f32, lo: f32, hi: f32| {
+        let extent = hi - lo;
+        if extent > 0.0 {
+            (((v - lo) / extent) * 1023.0).clamp(0.0, 1023.0) as u32
+        } else {
+            0
+        }
+    };
+
+    expand_bits(quantize(p.x, min.x, max.x)) << 2
+        | expand_bits(quantize(p.y, min.y, max.y)) << 1
+        | expand_bits(quantize(p.z, min.z, max.z))
+}
+
+//上位の桁は溢れて捨てられる前提
+fn expand_bits(v: u32) -> u32 {
+    let v = v.wrapping_mul(0x0001_0001) & 0xFF00_00FF;
+    let v = v.wrapping_mul(0x0000_0101) & 0x0F00_F00F;
+    let v = v.wrapping_mul(0x0000_0011) & 0xC30C_30C3;
+    v.wrapping_mul(0x0000_0005) & 0x4924_9249
+}
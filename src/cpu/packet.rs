@@ -0,0 +1,314 @@
+use super::aabb::Aabb;
+use super::bvh::Bvh;
+use super::morton::morton_code;
+use super::ray::{Hit, Ray};
+use super::triangle::Triangle;
+use crate::math::Vec3;
+
+//アクティブなレイはu32のビットマスクで持つのでこれが上限
+pub const MAX_PACKET_SIZE: usize = 32;
+pub const STREAM_PACKET_SIZE: usize = 16;
+
+impl Bvh {
+    //コヒーレントなレイの束をまとめて辿る
+    //どれか1本でもノードに当たれば降りるので、ばらばらな向きのレイには向かない
+    pub fn intersect_packet<const N: usize>(&self, triangles: &[Triangle], rays: &[Ray; N]) -> [Option<Hit>; N] {
+        assert!(N > 0 && N <= MAX_PACKET_SIZE, "packet size must be in 1..={}", MAX_PACKET_SIZE);
+
+        let mut hits = [None; N];
+        self.traverse_packet(triangles, rays, u32::MAX >> (MAX_PACKET_SIZE - N), &mut hits);
+        hits
+    }
+
+    //象限と原点でソートしてからSTREAM_PACKET_SIZE本ずつパケットとして辿る
+    //結果は入力と同じ順番で返す
+    pub fn intersect_stream(&self, triangles: &[Triangle], rays: &[Ray]) -> Vec<Option<Hit>> {
+        let mut hits = vec![None; rays.len()];
+        let order = coherent_order(rays);
+
+        let mut packet = [Ray::new(Vec3::ZERO, Vec3::ZERO, 0.0, 0.0); STREAM_PACKET_SIZE];
+        let mut packet_hits = [None; STREAM_PACKET_SIZE];
+
+        for chunk in order.chunks(STREAM_PACKET_SIZE) {
+            for (dst, &src) in packet.iter_mut().zip(chunk) {
+                *dst = rays[src as usize];
+            }
+
+            let active = u32::MAX >> (MAX_PACKET_SIZE - chunk.len());
+            packet_hits.iter_mut().for_each(|h| *h = None);
+            self.traverse_packet(triangles, &packet[..chunk.len()], active, &mut packet_hits[..chunk.len()]);
+
+            for (&src, hit) in chunk.iter().zip(&packet_hits) {
+                hits[src as usize] = *hit;
+            }
+        }
+
+        hits
+    }
+
+    fn traverse_packet(&self, triangles: &[Triangle], rays: &[Ray], active: u32, hits: &mut [Option<Hit>]) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut inv_dirs = [Vec3::ZERO; MAX_PACKET_SIZE];
+        let mut t_max = [0.0f32; MAX_PACKET_SIZE];
+        let mut dir_sum = Vec3::ZERO;
+        for (i, ray) in rays.iter().enumerate() {
+            inv_dirs[i] = ray.direction.recip();
+            t_max[i] = ray.t_max;
+            dir_sum += ray.direction;
+        }
+
+        let mut interval = PacketInterval::new(rays, &inv_dirs, active);
+
+        let mut stack: Vec<(u32, u32)> = Vec::with_capacity(64);
+        stack.push((0, active));
+
+        while let Some((node_index, mask)) = stack.pop() {
+            let node = &self.nodes[node_index as usize];
+
+            //束全体がノードを外れていれば1回の判定で捨てる
+            if let Some(interval) = &interval {
+                if interval.misses(&node.bounds) {
+                    continue;
+                }
+            }
+
+            //最初に当たったレイより前のレイは外れているので落とす 後ろのレイは子で判定する
+            let Some(first) = first_hit(mask, |i| node.bounds.intersect(rays[i].origin, inv_dirs[i], rays[i].t_min, t_max[i]).is_some()) else {
+                continue;
+            };
+            let mask = mask & !((1u32 << first) - 1);
+
+            if node.is_leaf() {
+                let mut leaf_mask = 0;
+                for_each_bit(mask, |i| {
+                    if i == first || node.bounds.intersect(rays[i].origin, inv_dirs[i], rays[i].t_min, t_max[i]).is_some() {
+                        leaf_mask |= 1 << i;
+                    }
+                });
+
+                let start = node.first as usize;
+                let mut hit_any = false;
+                for &p in &self.prim_indices[start..start + node.count as usize] {
+                    let tri = &triangles[p as usize];
+                    for_each_bit(leaf_mask, |i| {
+                        let ray = Ray { t_max: t_max[i], ..rays[i] };
+                        if let Some((t, barys)) = tri.intersect(&ray) {
+                            t_max[i] = t;
+                            hits[i] = Some(Hit { t, prim_index: p, barys });
+                            hit_any = true;
+                        }
+                    });
+                }
+
+                if hit_any {
+                    if let Some(interval) = &mut interval {
+                        interval.t_max = max_t(&t_max, active);
+                    }
+                }
+            } else {
+                let (l, r) = (node.first, node.first + 1);
+                let dl = self.nodes[l as usize].bounds.centroid().dot(dir_sum);
+                let dr = self.nodes[r as usize].bounds.centroid().dot(dir_sum);
+
+                //パケットの平均的な向きに対して手前の子を先にpopさせる
+                let (near, far) = if dl <= dr { (l, r) } else { (r, l) };
+                stack.push((far, mask));
+                stack.push((near, mask));
+            }
+        }
+    }
+}
+
+//向きの符号が揃った束の原点と1/方向の範囲 区間演算で束全体を1回でAABBと判定する
+struct PacketInterval {
+    origin_min: Vec3,
+    origin_max: Vec3,
+    inv_min: Vec3,
+    inv_max: Vec3,
+    t_min: f32,
+    //まだ当たっていない範囲のうち一番遠いt
+    t_max: f32,
+}
+
+impl PacketInterval {
+    //象限がばらばらだと範囲が0をまたいで何も絞れないのでNone
+    fn new(rays: &[Ray], inv_dirs: &[Vec3], active: u32) -> Option<PacketInterval> {
+        let octant = rays[active.trailing_zeros() as usize].octant();
+        let mut interval = PacketInterval {
+            origin_min: Vec3::splat(f32::INFINITY),
+            origin_max: Vec3::splat(f32::NEG_INFINITY),
+            inv_min: Vec3::splat(f32::INFINITY),
+            inv_max: Vec3::splat(f32::NEG_INFINITY),
+            t_min: f32::INFINITY,
+            t_max: f32::NEG_INFINITY,
+        };
+
+        let mut same_octant = true;
+        for_each_bit(active, |i| {
+            let ray = &rays[i];
+            same_octant &= ray.octant() == octant;
+            interval.origin_min = interval.origin_min.min(ray.origin);
+            interval.origin_max = interval.origin_max.max(ray.origin);
+            interval.inv_min = interval.inv_min.min(inv_dirs[i]);
+            interval.inv_max = interval.inv_max.max(inv_dirs[i]);
+            interval.t_min = interval.t_min.min(ray.t_min);
+            interval.t_max = interval.t_max.max(ray.t_max);
+        });
+
+        same_octant.then_some(interval)
+    }
+
+    //trueなら束のどのレイもboundsに当たらない falseでも当たるとは限らない
+    fn misses(&self, bounds: &Aabb) -> bool {
+        let mut t_near = self.t_min;
+        let mut t_far = self.t_max;
+
+        for axis in 0..3 {
+            let (inv_min, inv_max) = (self.inv_min[axis], self.inv_max[axis]);
+            let (o_min, o_max) = (self.origin_min[axis], self.origin_max[axis]);
+
+            if inv_min.is_infinite() || inv_max.is_infinite() {
+                //全部のレイがこの軸に平行なら原点の範囲がスラブと重なるかだけ見る
+                if inv_min == inv_max && (o_max < bounds.min[axis] || o_min > bounds.max[axis]) {
+                    return true;
+                }
+                continue;
+            }
+
+            //1/方向の符号が揃っているので入る面と出る面は束で共通
+            let (enter, exit) = if inv_min > 0.0 { (bounds.min[axis], bounds.max[axis]) } else { (bounds.max[axis], bounds.min[axis]) };
+            let near = interval_mul(enter - o_max, enter - o_min, inv_min, inv_max).0;
+            let far = interval_mul(exit - o_max, exit - o_min, inv_min, inv_max).1;
+
+            t_near = t_near.max(near);
+            t_far = t_far.min(far);
+        }
+
+        t_near > t_far
+    }
+}
+
+//[a0, a1] * [b0, b1]の範囲
+fn interval_mul(a0: f32, a1: f32, b0: f32, b1: f32) -> (f32, f32) {
+    let (p0, p1, p2, p3) = (a0 * b0, a0 * b1, a1 * b0, a1 * b1);
+    (p0.min(p1).min(p2.min(p3)), p0.max(p1).max(p2.max(p3)))
+}
+
+fn max_t(t_max: &[f32], active: u32) -> f32 {
+    let mut max = f32::NEG_INFINITY;
+    for_each_bit(active, |i| max = max.max(t_max[i]));
+    max
+}
+
+//maskの下位ビットから順に試して最初にhitがtrueになったレイ
+fn first_hit(mut mask: u32, mut hit: impl FnMut(usize) -> bool) -> Option<usize> {
+    while mask != 0 {
+        let i = mask.trailing_zeros() as usize;
+        if hit(i) {
+            return Some(i);
+        }
+        mask &= mask - 1;
+    }
+    None
+}
+
+fn for_each_bit(mut mask: u32, mut f: impl FnMut(usize)) {
+    while mask != 0 {
+        f(mask.trailing_zeros() as usize);
+        mask &= mask - 1;
+    }
+}
+
+//象限を上位ビット、原点のMortonコードを下位ビットにしたキーでソートしたインデックス
+pub fn coherent_order(rays: &[Ray]) -> Vec<u32> {
+    let origin_bounds = rays.iter().fold((Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)), |(min, max), r| {
+        (min.min(r.origin), max.max(r.origin))
+    });
+
+    let mut keys: Vec<(u64, u32)> = rays
+        .iter()
+        .enumerate()
+        .map(|(i, r)| ((r.octant() as u64) << 30 | morton_code(r.origin, origin_bounds.0, origin_bounds.1) as u64, i as u32))
+        .collect();
+
+    keys.sort_unstable();
+    keys.into_iter().map(|(_, i)| i).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> Vec<Triangle> {
+        let mut tris = vec![];
+        for j in 0..8 {
+            for i in 0..8 {
+                let (x, y) = (i as f32 * 0.25 - 1.0, j as f32 * 0.25 - 1.0);
+                let z = 0.1 * (i + j) as f32;
+                tris.push(Triangle::new(Vec3::new(x, y, z), Vec3::new(x + 0.25, y, z), Vec3::new(x + 0.25, y + 0.25, z)));
+                tris.push(Triangle::new(Vec3::new(x, y, z), Vec3::new(x + 0.25, y + 0.25, z), Vec3::new(x, y + 0.25, z)));
+            }
+        }
+        tris
+    }
+
+    //平行なレイと、原点から四方に広がるレイ(象限がばらばら)
+    fn rays() -> Vec<Ray> {
+        let mut rays = vec![];
+        for j in 0..8 {
+            for i in 0..8 {
+                let (x, y) = (i as f32 * 0.3 - 1.1, j as f32 * 0.3 - 1.1);
+                rays.push(Ray::new(Vec3::new(x, y, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0, 100.0));
+                rays.push(Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(x, y, -3.0), 0.0, 100.0));
+            }
+        }
+        rays
+    }
+
+    fn assert_same(a: &[Option<Hit>], b: &[Option<Hit>]) {
+        for (a, b) in a.iter().zip(b) {
+            match (a, b) {
+                (Some(a), Some(b)) => assert!((a.t - b.t).abs() < 1.0e-5, "{:?} != {:?}", a, b),
+                (a, b) => assert_eq!(a.is_some(), b.is_some()),
+            }
+        }
+    }
+
+    #[test]
+    fn packets_match_single_rays() {
+        let tris = grid();
+        let bvh = Bvh::build(&tris);
+        let rays = rays();
+        let single: Vec<_> = rays.iter().map(|r| bvh.intersect(&tris, r)).collect();
+        assert!(single.iter().any(Option::is_some) && single.iter().any(Option::is_none));
+
+        let mut packets = vec![];
+        for chunk in rays.chunks(8) {
+            packets.extend(bvh.intersect_packet(&tris, <&[Ray; 8]>::try_from(chunk).unwrap()));
+        }
+        assert_same(&single, &packets);
+        assert_same(&single, &bvh.intersect_stream(&tris, &rays));
+    }
+
+    #[test]
+    fn interval_rejects_only_when_every_ray_misses() {
+        let rays = [
+            Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.1, 0.0, -1.0), 0.0, 100.0),
+            Ray::new(Vec3::new(0.5, 0.0, 5.0), Vec3::new(0.2, 0.1, -1.0), 0.0, 100.0),
+        ];
+        let inv_dirs = [rays[0].direction.recip(), rays[1].direction.recip()];
+        let interval = PacketInterval::new(&rays, &inv_dirs, 0b11).unwrap();
+
+        assert!(!interval.misses(&Aabb::new(Vec3::new(0.9, -0.1, -0.1), Vec3::new(1.1, 0.6, 0.1))));
+        assert!(interval.misses(&Aabb::new(Vec3::new(3.0, 3.0, -0.1), Vec3::new(4.0, 4.0, 0.1))));
+        //束より後ろ
+        assert!(interval.misses(&Aabb::new(Vec3::new(-1.0, -1.0, 6.0), Vec3::new(1.0, 1.0, 7.0))));
+
+        let mixed = [rays[0], Ray::new(Vec3::ZERO, Vec3::new(-1.0, 0.0, 1.0), 0.0, 100.0)];
+        let inv_dirs = [mixed[0].direction.recip(), mixed[1].direction.recip()];
+        assert!(PacketInterval::new(&mixed, &inv_dirs, 0b11).is_none());
+    }
+}
@@ -0,0 +1,620 @@
+use std::error::Error;
+use std::fmt;
+use std::thread;
+
+use super::ray::Ray;
+use super::scene::{GeometryKind, InstanceFlags, Scene};
+use crate::math::Mat3x4;
+use crate::shader_table::hit_group_index;
+
+//DispatchRays/TraceRayのソフトウェア実装
+//シェーダーはクロージャで、ペイロードPとアトリビュートAは型で持つ
+
+//HLSLのRAY_FLAG
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RayFlags(pub u32);
+
+impl RayFlags {
+    pub const NONE: RayFlags = RayFlags(0x00);
+    pub const FORCE_OPAQUE: RayFlags = RayFlags(0x01);
+    pub const FORCE_NON_OPAQUE: RayFlags = RayFlags(0x02);
+    pub const ACCEPT_FIRST_HIT_AND_END_SEARCH: RayFlags = RayFlags(0x04);
+    pub const SKIP_CLOSEST_HIT_SHADER: RayFlags = RayFlags(0x08);
+    pub const CULL_BACK_FACING_TRIANGLES: RayFlags = RayFlags(0x10);
+    pub const CULL_FRONT_FACING_TRIANGLES: RayFlags = RayFlags(0x20);
+    pub const CULL_OPAQUE: RayFlags = RayFlags(0x40);
+    pub const CULL_NON_OPAQUE: RayFlags = RayFlags(0x80);
+    pub const SKIP_TRIANGLES: RayFlags = RayFlags(0x100);
+    pub const SKIP_PROCEDURAL_PRIMITIVES: RayFlags = RayFlags(0x200);
+
+    pub fn contains(self, flags: RayFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl std::ops::BitOr for RayFlags {
+    type Output = RayFlags;
+
+    fn bitor(self, rhs: RayFlags) -> RayFlags {
+        RayFlags(self.0 | rhs.0)
+    }
+}
+
+pub const HIT_KIND_TRIANGLE_FRONT_FACE: u32 = 0xFE;
+pub const HIT_KIND_TRIANGLE_BACK_FACE: u32 = 0xFF;
+
+//三角形のときにシェーダーへ渡されるアトリビュート
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BuiltInTriangleIntersectionAttributes {
+    pub barycentrics: [f32; 2],
+}
+
+//ヒット系のシェーダーから見えるシステム値
+//world_ray.t_maxがRayTCurrent()
+#[derive(Clone, Copy, Debug)]
+pub struct HitInfo {
+    pub world_ray: Ray,
+    pub object_ray: Ray,
+    pub ray_flags: RayFlags,
+    pub instance_index: u32,
+    pub instance_id: u32,
+    pub geometry_index: u32,
+    pub primitive_index: u32,
+    pub hit_kind: u32,
+    pub object_to_world: Mat3x4,
+    pub world_to_object: Mat3x4,
+}
+
+impl HitInfo {
+    pub fn ray_t_current(&self) -> f32 {
+        self.world_ray.t_max
+    }
+
+    pub fn world_hit_position(&self) -> crate::math::Vec3 {
+        self.world_ray.at(self.world_ray.t_max)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnyHitResult {
+    Accept,
+    //IgnoreHit()
+    Ignore,
+    //AcceptHitAndEndSearch()
+    AcceptAndEndSearch,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceError {
+    //シェーダーテーブルの範囲外を参照した
+    MissingHitGroup(usize),
+    MissingMissShader(usize),
+    MissingCallableShader(usize),
+    //TraceRayの深さがmax_recursion_depthを超えた
+    RecursionDepthExceeded(u32),
+    //シェーダーがpanicした 中身はpanicのメッセージ
+    ShaderPanicked(String),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::MissingHitGroup(i) => write!(f, "hit group record {} is out of the shader table", i),
+            TraceError::MissingMissShader(i) => write!(f, "miss shader record {} is out of the shader table", i),
+            TraceError::MissingCallableShader(i) => write!(f, "callable shader record {} is out of the shader table", i),
+            TraceError::RecursionDepthExceeded(depth) => write!(f, "trace recursion depth {} exceeds the pipeline maximum", depth),
+            TraceError::ShaderPanicked(message) => write!(f, "a shader panicked: {}", message),
+        }
+    }
+}
+
+impl Error for TraceError {}
+
+//CはCallShader()の引数の型 callableを使わないなら()
+pub type RayGenShader<P, A, C = ()> = Box<dyn Fn(&TraceContext<P, A, C>) -> Result<[f32; 4], TraceError> + Send + Sync>;
+pub type MissShader<P, A, C = ()> = Box<dyn Fn(&TraceContext<P, A, C>, &Ray, &mut P) -> Result<(), TraceError> + Send + Sync>;
+pub type ClosestHitShader<P, A, C = ()> = Box<dyn Fn(&TraceContext<P, A, C>, &HitInfo, &A, &mut P) -> Result<(), TraceError> + Send + Sync>;
+pub type CallableShader<P, A, C> = Box<dyn Fn(&TraceContext<P, A, C>, &mut C) -> Result<(), TraceError> + Send + Sync>;
+pub type AnyHitShader<P, A> = Box<dyn Fn(&HitInfo, &A, &mut P) -> AnyHitResult + Send + Sync>;
+//ReportHit(t, hit_kind, attr)は2番目の引数で呼ぶ 受け入れられたらtrueが返る
+pub type IntersectionShader<A> = Box<dyn Fn(&HitInfo, &mut dyn FnMut(f32, u32, A) -> bool) + Send + Sync>;
+
+pub struct HitGroup<P, A, C = ()> {
+    pub closest_hit: Option<ClosestHitShader<P, A, C>>,
+    pub any_hit: Option<AnyHitShader<P, A>>,
+    pub intersection: Option<IntersectionShader<A>>,
+}
+
+impl<P, A, C> HitGroup<P, A, C> {
+    pub fn triangles(closest_hit: ClosestHitShader<P, A, C>) -> Self {
+        HitGroup { closest_hit: Some(closest_hit), any_hit: None, intersection: None }
+    }
+}
+
+pub struct Pipeline<P, A, C = ()> {
+    pub ray_gen: RayGenShader<P, A, C>,
+    pub miss_shaders: Vec<MissShader<P, A, C>>,
+    pub hit_groups: Vec<HitGroup<P, A, C>>,
+    //並び順がCallShader()のインデックス
+    pub callables: Vec<CallableShader<P, A, C>>,
+    //D3D12_RAYTRACING_PIPELINE_CONFIGのMaxTraceRecursionDepth
+    pub max_recursion_depth: u32,
+}
+
+//シェーダーからTraceRay()やDispatchRaysIndex()を呼ぶための入口
+pub struct TraceContext<'a, P, A, C = ()> {
+    pipeline: &'a Pipeline<P, A, C>,
+    scene: &'a Scene,
+    pub launch_index: [u32; 2],
+    pub launch_dimensions: [u32; 2],
+    pub depth: u32,
+}
+
+struct Committed<A> {
+    info: HitInfo,
+    attr: A,
+    hit_group_index: usize,
+}
+
+struct TraversalState<'p, P, A> {
+    payload: &'p mut P,
+    flags: RayFlags,
+    committed: Option<Committed<A>>,
+    end_search: bool,
+    error: Option<TraceError>,
+}
+
+impl<P, A: From<BuiltInTriangleIntersectionAttributes>, C> Pipeline<P, A, C> {
+    //ピクセルごとにray_genを呼び、返した色を並べて返す
+    pub fn dispatch_rays(&self, scene: &Scene, width: u32, height: u32) -> Result<Vec<[f32; 4]>, TraceError> {
+        let mut output = vec![[0.0f32; 4]; (width * height) as usize];
+        if output.is_empty() {
+            return Ok(output);
+        }
+
+        let threads = thread::available_parallelism().map_or(1, |t| t.get());
+        let rows_per_thread = (height as usize).div_ceil(threads);
+        let chunk_size = rows_per_thread * width as usize;
+
+        thread::scope(|s| {
+            let handles: Vec<_> = output
+                .chunks_mut(chunk_size)
+                .enumerate()
+                .map(|(c, chunk)| {
+                    s.spawn(move || {
+                        for (k, pixel) in chunk.iter_mut().enumerate() {
+                            let index = c * chunk_size + k;
+                            let ctx = TraceContext {
+                                pipeline: self,
+                                scene,
+                                launch_index: [index as u32 % width, index as u32 / width],
+                                launch_dimensions: [width, height],
+                                depth: 0,
+                            };
+
+                            *pixel = (self.ray_gen)(&ctx)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+
+            //panicは呼び出し側まで伝えずにエラーにする
+            handles.into_iter().try_for_each(|h| {
+                h.join().unwrap_or_else(|e| {
+                    let message = e.downcast_ref::<&str>().map(|m| m.to_string()).or_else(|| e.downcast_ref::<String>().cloned());
+                    Err(TraceError::ShaderPanicked(message.unwrap_or_default()))
+                })
+            })
+        })?;
+
+        Ok(output)
+    }
+}
+
+impl<'a, P, A: From<BuiltInTriangleIntersectionAttributes>, C> TraceContext<'a, P, A, C> {
+    //CallShader() TraceRayと違って再帰の深さは変わらない
+    pub fn call_shader(&self, shader_index: u32, parameter: &mut C) -> Result<(), TraceError> {
+        let callable = self
+            .pipeline
+            .callables
+            .get(shader_index as usize)
+            .ok_or(TraceError::MissingCallableShader(shader_index as usize))?;
+
+        callable(self, parameter)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn trace_ray(
+        &self,
+        flags: RayFlags,
+        instance_inclusion_mask: u32,
+        ray_contribution_to_hit_group_index: u32,
+        multiplier_for_geometry_contribution_to_hit_group_index: u32,
+        miss_shader_index: u32,
+        ray: &Ray,
+        payload: &mut P,
+    ) -> Result<(), TraceError> {
+        //GPUでは未定義動作になるところ スタックを食いつぶす前にエラーにする
+        if self.depth >= self.pipeline.max_recursion_depth {
+            return Err(TraceError::RecursionDepthExceeded(self.depth + 1));
+        }
+
+        let mut state = TraversalState { payload, flags, committed: None, end_search: false, error: None };
+        let mut world_ray = *ray;
+
+        self.scene.tlas.traverse(&mut world_ray, |instance_index, world_ray| {
+            let instance = &self.scene.instances[instance_index as usize];
+            if instance.instance_mask as u32 & instance_inclusion_mask == 0 {
+                return true;
+            }
+
+            let world_to_object = self.scene.world_to_object[instance_index as usize];
+            let mut object_ray = Ray {
+                origin: world_to_object.transform_point(world_ray.origin),
+                direction: world_to_object.transform_vector(world_ray.direction),
+                ..*world_ray
+            };
+
+            let blas = &self.scene.blases[instance.blas];
+            for (geometry_index, geometry) in blas.geometries.iter().enumerate() {
+                let is_triangles = matches!(geometry.kind, GeometryKind::Triangles(_));
+                if (is_triangles && flags.contains(RayFlags::SKIP_TRIANGLES))
+                    || (!is_triangles && flags.contains(RayFlags::SKIP_PROCEDURAL_PRIMITIVES))
+                {
+                    continue;
+                }
+
+                //不透明かどうかはジオメトリ < インスタンス < レイの順に上書きされる
+                let mut opaque = geometry.opaque;
+                if instance.flags.contains(InstanceFlags::FORCE_OPAQUE) {
+                    opaque = true;
+                } else if instance.flags.contains(InstanceFlags::FORCE_NON_OPAQUE) {
+                    opaque = false;
+                }
+                if flags.contains(RayFlags::FORCE_OPAQUE) {
+                    opaque = true;
+                } else if flags.contains(RayFlags::FORCE_NON_OPAQUE) {
+                    opaque = false;
+                }
+
+                if (opaque && flags.contains(RayFlags::CULL_OPAQUE)) || (!opaque && flags.contains(RayFlags::CULL_NON_OPAQUE)) {
+                    continue;
+                }
+
+                //シェーダーテーブルのヒットグループのインデックス
+                let hit_group_index = hit_group_index(
+                    ray_contribution_to_hit_group_index,
+                    multiplier_for_geometry_contribution_to_hit_group_index,
+                    geometry_index as u32,
+                    instance.instance_contribution_to_hit_group_index,
+                ) as usize;
+
+                let base_info = HitInfo {
+                    world_ray: *world_ray,
+                    object_ray,
+                    ray_flags: flags,
+                    instance_index,
+                    instance_id: instance.instance_id & 0x00FF_FFFF,
+                    geometry_index: geometry_index as u32,
+                    primitive_index: 0,
+                    hit_kind: 0,
+                    object_to_world: instance.transform,
+                    world_to_object,
+                };
+
+                let keep_going = match &geometry.kind {
+                    GeometryKind::Triangles(triangles) => geometry.bvh.traverse(&mut object_ray, |prim, object_ray| {
+                        let tri = &triangles[prim as usize];
+                        let Some((t, barys)) = tri.intersect(object_ray) else {
+                            return true;
+                        };
+
+                        let mut front = tri.is_front_facing(object_ray.direction);
+                        if instance.flags.contains(InstanceFlags::TRIANGLE_FRONT_COUNTERCLOCKWISE) {
+                            front = !front;
+                        }
+
+                        if !instance.flags.contains(InstanceFlags::TRIANGLE_CULL_DISABLE)
+                            && ((front && flags.contains(RayFlags::CULL_FRONT_FACING_TRIANGLES))
+                                || (!front && flags.contains(RayFlags::CULL_BACK_FACING_TRIANGLES)))
+                        {
+                            return true;
+                        }
+
+                        let info = HitInfo {
+                            world_ray: Ray { t_max: t, ..base_info.world_ray },
+                            object_ray: Ray { t_max: t, ..*object_ray },
+                            primitive_index: prim,
+                            hit_kind: if front { HIT_KIND_TRIANGLE_FRONT_FACE } else { HIT_KIND_TRIANGLE_BACK_FACE },
+                            ..base_info
+                        };
+
+                        let attr = A::from(BuiltInTriangleIntersectionAttributes { barycentrics: barys });
+                        if self.consider_hit(&mut state, hit_group_index, opaque, info, attr) {
+                            object_ray.t_max = t;
+                        }
+
+                        !state.end_search && state.error.is_none()
+                    }),
+                    GeometryKind::Procedural(aabbs) => geometry.bvh.traverse(&mut object_ray, |prim, object_ray| {
+                        let inv_dir = object_ray.direction.recip();
+                        if aabbs[prim as usize].intersect(object_ray.origin, inv_dir, object_ray.t_min, object_ray.t_max).is_none() {
+                            return true;
+                        }
+
+                        let Some(hit_group) = self.pipeline.hit_groups.get(hit_group_index) else {
+                            state.error = Some(TraceError::MissingHitGroup(hit_group_index));
+                            return false;
+                        };
+
+                        //intersectionシェーダーがないプロシージャルは何にも当たらない
+                        let Some(intersection) = &hit_group.intersection else {
+                            return true;
+                        };
+
+                        let info = HitInfo {
+                            world_ray: Ray { t_max: object_ray.t_max, ..base_info.world_ray },
+                            object_ray: *object_ray,
+                            primitive_index: prim,
+                            ..base_info
+                        };
+
+                        intersection(&info, &mut |t, hit_kind, attr| {
+                            if t < object_ray.t_min || t > object_ray.t_max || state.end_search || state.error.is_some() {
+                                return false;
+                            }
+
+                            let info = HitInfo {
+                                world_ray: Ray { t_max: t, ..info.world_ray },
+                                object_ray: Ray { t_max: t, ..info.object_ray },
+                                hit_kind,
+                                ..info
+                            };
+
+                            let accepted = self.consider_hit(&mut state, hit_group_index, opaque, info, attr);
+                            if accepted {
+                                object_ray.t_max = t;
+                            }
+                            accepted
+                        });
+
+                        !state.end_search && state.error.is_none()
+                    }),
+                };
+
+                if !keep_going {
+                    break;
+                }
+            }
+
+            //オブジェクト空間でも方向を正規化していないのでtはそのまま使える
+            world_ray.t_max = object_ray.t_max;
+
+            !state.end_search && state.error.is_none()
+        });
+
+        if let Some(e) = state.error {
+            return Err(e);
+        }
+
+        let child = TraceContext { depth: self.depth + 1, ..*self };
+
+        match state.committed {
+            Some(committed) => {
+                if flags.contains(RayFlags::SKIP_CLOSEST_HIT_SHADER) {
+                    return Ok(());
+                }
+
+                let hit_group = self
+                    .pipeline
+                    .hit_groups
+                    .get(committed.hit_group_index)
+                    .ok_or(TraceError::MissingHitGroup(committed.hit_group_index))?;
+
+                match &hit_group.closest_hit {
+                    Some(closest_hit) => closest_hit(&child, &committed.info, &committed.attr, state.payload),
+                    None => Ok(()),
+                }
+            }
+            None => {
+                let miss = self
+                    .pipeline
+                    .miss_shaders
+                    .get(miss_shader_index as usize)
+                    .ok_or(TraceError::MissingMissShader(miss_shader_index as usize))?;
+
+                miss(&child, ray, state.payload)
+            }
+        }
+    }
+
+    //候補のヒットを確定させるか決める 確定したらtrue
+    fn consider_hit(&self, state: &mut TraversalState<P, A>, hit_group_index: usize, opaque: bool, info: HitInfo, attr: A) -> bool {
+        if !opaque {
+            let Some(hit_group) = self.pipeline.hit_groups.get(hit_group_index) else {
+                state.error = Some(TraceError::MissingHitGroup(hit_group_index));
+                return false;
+            };
+
+            if let Some(any_hit) = &hit_group.any_hit {
+                match any_hit(&info, &attr, state.payload) {
+                    AnyHitResult::Ignore => return false,
+                    AnyHitResult::Accept => {}
+                    AnyHitResult::AcceptAndEndSearch => state.end_search = true,
+                }
+            }
+        }
+
+        if state.flags.contains(RayFlags::ACCEPT_FIRST_HIT_AND_END_SEARCH) {
+            state.end_search = true;
+        }
+
+        state.committed = Some(Committed { info, attr, hit_group_index });
+        true
+    }
+}
+
+impl<'a, P, A, C> Clone for TraceContext<'a, P, A, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, P, A, C> Copy for TraceContext<'a, P, A, C> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::scene::{BottomLevel, Geometry, Instance};
+    use crate::cpu::triangle::Triangle;
+    use crate::math::Vec3;
+
+    type Attributes = BuiltInTriangleIntersectionAttributes;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Event {
+        Miss(usize),
+        ClosestHit { hit_group: usize, instance_id: u32, t: f32 },
+        AnyHit { instance_id: u32 },
+    }
+
+    //z = -1 (id 1, マスク0x01)とz = -2 (id 2, マスク0x02, ヒットグループ+1)に同じ三角形を置く
+    fn scene() -> Scene {
+        let triangle = Triangle::new(Vec3::new(-1.0, -1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let blas = BottomLevel::new(vec![Geometry::triangles(vec![triangle], true)]);
+
+        let near = Instance { transform: Mat3x4::translation(Vec3::new(0.0, 0.0, -1.0)), instance_id: 1, instance_mask: 0x01, ..Instance::new(0) };
+        let far = Instance {
+            transform: Mat3x4::translation(Vec3::new(0.0, 0.0, -2.0)),
+            instance_id: 2,
+            instance_mask: 0x02,
+            instance_contribution_to_hit_group_index: 1,
+            ..Instance::new(0)
+        };
+
+        Scene::new(vec![blas], vec![near, far])
+    }
+
+    //ヒットグループもmissも呼ばれたインデックスを記録するだけ
+    fn pipeline(hit_groups: usize, ignore_instance: Option<u32>) -> Pipeline<Vec<Event>, Attributes> {
+        Pipeline {
+            ray_gen: Box::new(|_| Ok([0.0; 4])),
+            miss_shaders: (0..2)
+                .map(|i| -> MissShader<Vec<Event>, Attributes> {
+                    Box::new(move |_, _, payload| {
+                        payload.push(Event::Miss(i));
+                        Ok(())
+                    })
+                })
+                .collect(),
+            hit_groups: (0..hit_groups)
+                .map(|i| HitGroup {
+                    closest_hit: Some(Box::new(move |_, info: &HitInfo, _, payload: &mut Vec<Event>| {
+                        payload.push(Event::ClosestHit { hit_group: i, instance_id: info.instance_id, t: info.ray_t_current() });
+                        Ok(())
+                    })),
+                    any_hit: Some(Box::new(move |info, _, payload| {
+                        payload.push(Event::AnyHit { instance_id: info.instance_id });
+                        if Some(info.instance_id) == ignore_instance {
+                            AnyHitResult::Ignore
+                        } else {
+                            AnyHitResult::Accept
+                        }
+                    })),
+                    intersection: None,
+                })
+                .collect(),
+            callables: vec![],
+            max_recursion_depth: 1,
+        }
+    }
+
+    fn trace(pipeline: &Pipeline<Vec<Event>, Attributes>, flags: RayFlags, mask: u32, ray_contribution: u32, miss: u32) -> Result<Vec<Event>, TraceError> {
+        let scene = scene();
+        let ctx = TraceContext { pipeline, scene: &scene, launch_index: [0, 0], launch_dimensions: [1, 1], depth: 0 };
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0, 100.0);
+
+        let mut payload = vec![];
+        ctx.trace_ray(flags, mask, ray_contribution, 1, miss, &ray, &mut payload)?;
+        Ok(payload)
+    }
+
+    #[test]
+    fn closest_hit_uses_the_nearest_instance_and_its_hit_group() {
+        let pipeline = pipeline(4, None);
+
+        let events = trace(&pipeline, RayFlags::NONE, 0xFF, 0, 0).unwrap();
+        assert_eq!(events, vec![Event::ClosestHit { hit_group: 0, instance_id: 1, t: 2.0 }]);
+
+        //マスクで手前を外すと奥のインスタンスのcontribution + RayContributionToHitGroupIndexになる
+        let events = trace(&pipeline, RayFlags::NONE, 0x02, 2, 0).unwrap();
+        assert_eq!(events, vec![Event::ClosestHit { hit_group: 3, instance_id: 2, t: 3.0 }]);
+    }
+
+    #[test]
+    fn miss_shader_index_selects_the_miss_shader() {
+        let pipeline = pipeline(4, None);
+        assert_eq!(trace(&pipeline, RayFlags::NONE, 0x04, 0, 1).unwrap(), vec![Event::Miss(1)]);
+        assert_eq!(trace(&pipeline, RayFlags::NONE, 0x00, 0, 0).unwrap(), vec![Event::Miss(0)]);
+    }
+
+    #[test]
+    fn any_hit_runs_only_for_non_opaque_hits_and_can_ignore_them() {
+        let pipeline = pipeline(4, Some(1));
+
+        let events = trace(&pipeline, RayFlags::NONE, 0xFF, 0, 0).unwrap();
+        assert_eq!(events, vec![Event::ClosestHit { hit_group: 0, instance_id: 1, t: 2.0 }]);
+
+        //手前を無視すると奥が確定する
+        let mut events = trace(&pipeline, RayFlags::FORCE_NON_OPAQUE, 0xFF, 0, 0).unwrap();
+        let closest = events.pop();
+        assert_eq!(closest, Some(Event::ClosestHit { hit_group: 1, instance_id: 2, t: 3.0 }));
+        assert!(events.contains(&Event::AnyHit { instance_id: 1 }));
+
+        //全部無視すればmissになる
+        let events = trace(&pipeline, RayFlags::FORCE_NON_OPAQUE, 0x01, 0, 0).unwrap();
+        assert_eq!(events, vec![Event::AnyHit { instance_id: 1 }, Event::Miss(0)]);
+    }
+
+    #[test]
+    fn skip_closest_hit_commits_without_running_the_shader() {
+        let pipeline = pipeline(4, None);
+        let events = trace(&pipeline, RayFlags::FORCE_NON_OPAQUE | RayFlags::SKIP_CLOSEST_HIT_SHADER, 0x01, 0, 0).unwrap();
+        assert_eq!(events, vec![Event::AnyHit { instance_id: 1 }]);
+    }
+
+    #[test]
+    fn cull_opaque_skips_every_opaque_geometry() {
+        let pipeline = pipeline(4, None);
+        assert_eq!(trace(&pipeline, RayFlags::CULL_OPAQUE, 0xFF, 0, 1).unwrap(), vec![Event::Miss(1)]);
+    }
+
+    #[test]
+    fn missing_hit_group_is_an_error() {
+        let pipeline = pipeline(1, None);
+        assert_eq!(trace(&pipeline, RayFlags::NONE, 0x02, 0, 0), Err(TraceError::MissingHitGroup(1)));
+    }
+
+    #[test]
+    fn recursion_past_the_pipeline_maximum_is_an_error() {
+        let mut pipeline = pipeline(1, None);
+        pipeline.hit_groups[0].closest_hit = Some(Box::new(|ctx, info, _, payload| {
+            ctx.trace_ray(RayFlags::NONE, 0xFF, 0, 1, 0, &info.world_ray, payload)
+        }));
+
+        assert_eq!(trace(&pipeline, RayFlags::NONE, 0xFF, 0, 0), Err(TraceError::RecursionDepthExceeded(2)));
+    }
+
+    #[test]
+    fn dispatch_rays_turns_a_shader_panic_into_an_error() {
+        let mut pipeline = pipeline(1, None);
+        pipeline.ray_gen = Box::new(|ctx| if ctx.launch_index == [1, 1] { panic!("bad pixel") } else { Ok([1.0; 4]) });
+
+        assert_eq!(pipeline.dispatch_rays(&scene(), 2, 2), Err(TraceError::ShaderPanicked("bad pixel".to_string())));
+
+        pipeline.ray_gen = Box::new(|ctx| Ok([ctx.launch_index[0] as f32, ctx.launch_index[1] as f32, 0.0, 1.0]));
+        let image = pipeline.dispatch_rays(&scene(), 2, 2).unwrap();
+        assert_eq!(image, vec![[0.0, 0.0, 0.0, 1.0], [1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0], [1.0, 1.0, 0.0, 1.0]]);
+    }
+}
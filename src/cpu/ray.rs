@@ -0,0 +1,34 @@
+use crate::math::Vec3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+    pub t_min: f32,
+    pub t_max: f32,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3, t_min: f32, t_max: f32) -> Self {
+        Ray { origin, direction, t_min, t_max }
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.direction * t
+    }
+
+    //方向ベクトルの符号で決まる象限(0..8)
+    pub fn octant(&self) -> u32 {
+        (self.direction.x < 0.0) as u32
+            | ((self.direction.y < 0.0) as u32) << 1
+            | ((self.direction.z < 0.0) as u32) << 2
+    }
+}
+
+//DXRのBuiltInTriangleIntersectionAttributesと同じくbarysはv1, v2の重み
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub t: f32,
+    pub prim_index: u32,
+    pub barys: [f32; 2],
+}
@@ -0,0 +1,408 @@
+use super::aabb::Aabb;
+use super::bvh::{bin_index, Bvh, BvhNode, BIN_COUNT, INTERSECTION_COST, MAX_LEAF_SIZE, TRAVERSAL_COST};
+use super::triangle::Triangle;
+use crate::math::Vec3;
+
+//Stich et al. "Spatial Splits in Bounding Volume Hierarchies"
+
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SbvhOptions {
+    //オブジェクト分割した子同士の重なりの面積 / ルートの面積がこれを超えたときだけ空間分割を試す
+    //0に近いほど空間分割が増える
+    pub alpha: f32,
+    //参照の複製で増やしてよい割合 1.0なら三角形数の2倍まで
+    pub reference_budget: f32,
+}
+
+impl Default for SbvhOptions {
+    fn default() -> Self {
+        SbvhOptions { alpha: 1.0e-5, reference_budget: 1.0 }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Reference {
+    prim: u32,
+    bounds: Aabb,
+}
+
+enum SplitKind {
+    //右側の先頭のbin
+    Object { bin: usize, centroid_bounds: Aabb },
+    Spatial { position: f32 },
+}
+
+struct Split {
+    axis: usize,
+    cost: f32,
+    kind: SplitKind,
+}
+
+struct Builder<'a> {
+    triangles: &'a [Triangle],
+    options: SbvhOptions,
+    root_area: f32,
+    max_references: usize,
+    reference_count: usize,
+    bvh: Bvh,
+}
+
+pub fn build(triangles: &[Triangle], options: &SbvhOptions) -> Bvh {
+    let refs: Vec<Reference> = triangles
+        .iter()
+        .enumerate()
+        .map(|(i, t)| Reference { prim: i as u32, bounds: t.bounds() })
+        .collect();
+
+    let mut builder = Builder {
+        triangles,
+        options: *options,
+        root_area: 0.0,
+        max_references: triangles.len() + (triangles.len() as f32 * options.reference_budget.max(0.0)) as usize,
+        reference_count: refs.len(),
+        bvh: Bvh { nodes: Vec::with_capacity(triangles.len() * 2), prim_indices: Vec::with_capacity(triangles.len()) },
+    };
+
+    if refs.is_empty() {
+        return builder.bvh;
+    }
+
+    let bounds = refs.iter().fold(Aabb::EMPTY, |acc, r| acc.union(&r.bounds));
+    builder.root_area = bounds.surface_area();
+    builder.bvh.nodes.push(BvhNode { bounds, first: 0, count: 0 });
+    builder.subdivide(0, refs, 0);
+
+    builder.bvh
+}
+
+impl<'a> Builder<'a> {
+    fn subdivide(&mut self, node_index: usize, refs: Vec<Reference>, depth: usize) {
+        let node_bounds = self.bvh.nodes[node_index].bounds;
+
+        let split = if refs.len() as u32 <= MAX_LEAF_SIZE || depth >= MAX_DEPTH {
+            None
+        } else {
+            self.find_split(&refs, &node_bounds)
+                .filter(|s| s.cost < INTERSECTION_COST * refs.len() as f32)
+        };
+
+        let Some(split) = split else {
+            return self.make_leaf(node_index, &refs);
+        };
+
+        let (left, right) = match split.kind {
+            SplitKind::Object { bin, centroid_bounds } => {
+                refs.into_iter().partition(|r| bin_index(r.bounds.centroid()[split.axis], &centroid_bounds, split.axis) < bin)
+            }
+            SplitKind::Spatial { position } => {
+                let count = refs.len();
+                let (left, right) = self.split_references(refs, split.axis, position);
+                self.reference_count += (left.len() + right.len()).saturating_sub(count);
+                (left, right)
+            }
+        };
+
+        if left.is_empty() || right.is_empty() {
+            let refs: Vec<Reference> = left.into_iter().chain(right).collect();
+            return self.make_leaf(node_index, &refs);
+        }
+
+        let left_bounds = left.iter().fold(Aabb::EMPTY, |acc, r| acc.union(&r.bounds));
+        let right_bounds = right.iter().fold(Aabb::EMPTY, |acc, r| acc.union(&r.bounds));
+
+        let left_index = self.bvh.nodes.len();
+        self.bvh.nodes.push(BvhNode { bounds: left_bounds, first: 0, count: 0 });
+        self.bvh.nodes.push(BvhNode { bounds: right_bounds, first: 0, count: 0 });
+        self.bvh.nodes[node_index].first = left_index as u32;
+        self.bvh.nodes[node_index].count = 0;
+
+        self.subdivide(left_index, left, depth + 1);
+        self.subdivide(left_index + 1, right, depth + 1);
+    }
+
+    fn make_leaf(&mut self, node_index: usize, refs: &[Reference]) {
+        let node = &mut self.bvh.nodes[node_index];
+        node.first = self.bvh.prim_indices.len() as u32;
+        node.count = refs.len() as u32;
+        self.bvh.prim_indices.extend(refs.iter().map(|r| r.prim));
+    }
+
+    fn find_split(&self, refs: &[Reference], node_bounds: &Aabb) -> Option<Split> {
+        let node_area = node_bounds.surface_area();
+        let (object, overlap) = match find_object_split(refs, node_area) {
+            Some((split, overlap)) => (Some(split), overlap),
+            None => (None, f32::INFINITY),
+        };
+
+        //子の重なりが小さければ空間分割するまでもない
+        if overlap / self.root_area <= self.options.alpha {
+            return object;
+        }
+
+        let spatial = self.find_spatial_split(refs, node_bounds, node_area);
+
+        match (object, spatial) {
+            (Some(o), Some(s)) => Some(if s.cost < o.cost { s } else { o }),
+            (o, s) => o.or(s),
+        }
+    }
+
+    fn find_spatial_split(&self, refs: &[Reference], node_bounds: &Aabb, node_area: f32) -> Option<Split> {
+        let mut best: Option<Split> = None;
+
+        for axis in 0..3 {
+            let origin = node_bounds.min[axis];
+            let extent = node_bounds.max[axis] - origin;
+            if extent <= 0.0 {
+                continue;
+            }
+
+            let bin_width = extent / BIN_COUNT as f32;
+            let bin_of = |v: f32| (((v - origin) / bin_width) as usize).min(BIN_COUNT - 1);
+
+            let mut bins = [Aabb::EMPTY; BIN_COUNT];
+            let mut entries = [0usize; BIN_COUNT];
+            let mut exits = [0usize; BIN_COUNT];
+
+            for r in refs {
+                let first = bin_of(r.bounds.min[axis]);
+                let last = bin_of(r.bounds.max[axis]);
+
+                //参照を各binの範囲で切り取ってbinの箱を広げる
+                for (b, bin) in bins.iter_mut().enumerate().take(last + 1).skip(first) {
+                    let lo = origin + bin_width * b as f32;
+                    let hi = if b == BIN_COUNT - 1 { node_bounds.max[axis] } else { lo + bin_width };
+                    *bin = bin.union(&self.clip_reference(r, axis, lo, hi));
+                }
+
+                entries[first] += 1;
+                exits[last] += 1;
+            }
+
+            let mut right_area = [0.0f32; BIN_COUNT];
+            let mut right_count = [0usize; BIN_COUNT];
+            let mut acc = (Aabb::EMPTY, 0usize);
+            for i in (1..BIN_COUNT).rev() {
+                acc.0 = acc.0.union(&bins[i]);
+                acc.1 += exits[i];
+                right_area[i] = acc.0.surface_area();
+                right_count[i] = acc.1;
+            }
+
+            let mut acc = (Aabb::EMPTY, 0usize);
+            for i in 1..BIN_COUNT {
+                acc.0 = acc.0.union(&bins[i - 1]);
+                acc.1 += entries[i - 1];
+
+                if acc.1 == 0 || right_count[i] == 0 {
+                    continue;
+                }
+
+                //両側に入る参照の数だけ複製が増える
+                let straddling = acc.1 + right_count[i] - refs.len();
+                if self.reference_count + straddling > self.max_references {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + INTERSECTION_COST * (acc.0.surface_area() * acc.1 as f32 + right_area[i] * right_count[i] as f32) / node_area;
+
+                if best.as_ref().is_none_or(|b| cost < b.cost) {
+                    best = Some(Split {
+                        axis,
+                        cost,
+                        kind: SplitKind::Spatial { position: origin + bin_width * i as f32 },
+                    });
+                }
+            }
+        }
+
+        best
+    }
+
+    fn split_references(&self, refs: Vec<Reference>, axis: usize, position: f32) -> (Vec<Reference>, Vec<Reference>) {
+        let mut left = Vec::with_capacity(refs.len());
+        let mut right = Vec::with_capacity(refs.len());
+
+        for r in refs {
+            if r.bounds.max[axis] <= position {
+                left.push(r);
+            } else if r.bounds.min[axis] >= position {
+                right.push(r);
+            } else {
+                let l = self.clip_reference(&r, axis, f32::NEG_INFINITY, position);
+                let rr = self.clip_reference(&r, axis, position, f32::INFINITY);
+
+                if !l.is_empty() {
+                    left.push(Reference { prim: r.prim, bounds: l });
+                }
+                if !rr.is_empty() {
+                    right.push(Reference { prim: r.prim, bounds: rr });
+                }
+            }
+        }
+
+        (left, right)
+    }
+
+    //三角形のうちaxis方向に[lo, hi]に入る部分の箱
+    fn clip_reference(&self, r: &Reference, axis: usize, lo: f32, hi: f32) -> Aabb {
+        let tri = &self.triangles[r.prim as usize];
+        let v = [tri.v0, tri.v1, tri.v2];
+
+        let mut clipped = Aabb::EMPTY;
+        for i in 0..3 {
+            let a = v[i];
+            let c = v[(i + 1) % 3];
+            let (pa, pc) = (a[axis], c[axis]);
+
+            if pa >= lo && pa <= hi {
+                clipped.grow(a);
+            }
+
+            for plane in [lo, hi] {
+                if (pa < plane && pc > plane) || (pa > plane && pc < plane) {
+                    let t = (plane - pa) / (pc - pa);
+                    clipped.grow(a + (c - a) * t);
+                }
+            }
+        }
+
+        let slab = Aabb::new(with_axis(Vec3::splat(f32::NEG_INFINITY), axis, lo), with_axis(Vec3::splat(f32::INFINITY), axis, hi));
+
+        clipped.intersection(&r.bounds).intersection(&slab)
+    }
+}
+
+//戻り値は(分割, 子同士の重なりの面積)
+fn find_object_split(refs: &[Reference], node_area: f32) -> Option<(Split, f32)> {
+    let centroid_bounds = refs.iter().fold(Aabb::EMPTY, |mut acc, r| {
+        acc.grow(r.bounds.centroid());
+        acc
+    });
+
+    let mut best: Option<(Split, f32)> = None;
+
+    for axis in 0..3 {
+        if centroid_bounds.max[axis] <= centroid_bounds.min[axis] {
+            continue;
+        }
+
+        let mut bins = [(Aabb::EMPTY, 0usize); BIN_COUNT];
+        for r in refs {
+            let bin = &mut bins[bin_index(r.bounds.centroid()[axis], &centroid_bounds, axis)];
+            bin.0 = bin.0.union(&r.bounds);
+            bin.1 += 1;
+        }
+
+        let mut right = [(Aabb::EMPTY, 0usize); BIN_COUNT];
+        let mut acc = (Aabb::EMPTY, 0usize);
+        for i in (1..BIN_COUNT).rev() {
+            acc.0 = acc.0.union(&bins[i].0);
+            acc.1 += bins[i].1;
+            right[i] = acc;
+        }
+
+        let mut acc = (Aabb::EMPTY, 0usize);
+        for i in 1..BIN_COUNT {
+            acc.0 = acc.0.union(&bins[i - 1].0);
+            acc.1 += bins[i - 1].1;
+
+            let (right_bounds, right_count) = right[i];
+            if acc.1 == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST
+                + INTERSECTION_COST * (acc.0.surface_area() * acc.1 as f32 + right_bounds.surface_area() * right_count as f32) / node_area;
+
+            if best.as_ref().is_none_or(|(b, _)| cost < b.cost) {
+                let overlap = acc.0.intersection(&right_bounds).surface_area();
+                best = Some((Split { axis, cost, kind: SplitKind::Object { bin: i, centroid_bounds } }, overlap));
+            }
+        }
+    }
+
+    best
+}
+
+fn with_axis(v: Vec3, axis: usize, value: f32) -> Vec3 {
+    match axis {
+        0 => Vec3::new(value, v.y, v.z),
+        1 => Vec3::new(v.x, value, v.z),
+        _ => Vec3::new(v.x, v.y, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::ray::Ray;
+
+    //斜めに長く伸びた細い三角形 箱が大きく重なるので空間分割が効く
+    fn slivers(count: u32) -> Vec<Triangle> {
+        (0..count)
+            .map(|i| {
+                let h = |k: u32| (crate::camera::pcg_hash(i * 4 + k) % 1000) as f32 / 500.0 - 1.0;
+                let a = Vec3::new(h(0), h(1), h(2) * 0.5);
+                let b = Vec3::new(-h(1), h(3), -h(2) * 0.5);
+                Triangle::new(a, b, b + Vec3::new(0.0, 0.01, 0.02))
+            })
+            .collect()
+    }
+
+    fn rays() -> impl Iterator<Item = Ray> {
+        (0..400).map(|i| {
+            let x = (i % 20) as f32 * 0.1 - 1.0;
+            let y = (i / 20) as f32 * 0.1 - 1.0;
+            Ray::new(Vec3::new(x, y, 2.0), Vec3::new(0.03, -0.02, -1.0), 0.0, 100.0)
+        })
+    }
+
+    #[test]
+    fn finds_the_same_hits_as_brute_force_and_the_sah_bvh() {
+        let tris = slivers(500);
+        let sbvh = build(&tris, &SbvhOptions::default());
+        let sah = Bvh::build(&tris);
+
+        //どの三角形もどこかの葉にある
+        let mut prims = sbvh.prim_indices.clone();
+        prims.sort_unstable();
+        prims.dedup();
+        assert_eq!(prims, (0..tris.len() as u32).collect::<Vec<_>>());
+        assert!(sbvh.stats().reference_count > tris.len(), "no spatial splits were made");
+
+        let mut hits = 0;
+        for ray in rays() {
+            let brute = tris.iter().filter_map(|t| t.intersect(&ray)).map(|(t, _)| t).fold(f32::INFINITY, f32::min);
+            let hit = sbvh.intersect(&tris, &ray);
+            assert_eq!(hit.map_or(f32::INFINITY, |h| h.t), brute, "{:?}", ray);
+
+            let expected = sah.intersect(&tris, &ray);
+            assert_eq!(hit.map(|h| (h.t, h.prim_index)), expected.map(|h| (h.t, h.prim_index)), "{:?}", ray);
+            hits += hit.is_some() as usize;
+        }
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn references_stay_within_the_budget() {
+        let tris = slivers(500);
+
+        for reference_budget in [0.0, 0.05, 0.3, 1.0] {
+            let bvh = build(&tris, &SbvhOptions { reference_budget, ..SbvhOptions::default() });
+            let max = tris.len() + (tris.len() as f32 * reference_budget) as usize;
+            assert!(bvh.prim_indices.len() <= max, "{} > {} with budget {}", bvh.prim_indices.len(), max, reference_budget);
+
+            for ray in rays().step_by(7) {
+                let brute = tris.iter().filter_map(|t| t.intersect(&ray)).map(|(t, _)| t).fold(f32::INFINITY, f32::min);
+                assert_eq!(bvh.intersect(&tris, &ray).map_or(f32::INFINITY, |h| h.t), brute);
+            }
+        }
+
+        //予算がなければ複製しない
+        assert_eq!(build(&tris, &SbvhOptions { reference_budget: 0.0, ..SbvhOptions::default() }).prim_indices.len(), tris.len());
+    }
+}
@@ -0,0 +1,115 @@
+use super::aabb::Aabb;
+use super::bvh::Bvh;
+use super::triangle::Triangle;
+use crate::math::Mat3x4;
+
+//ソフトウェア版のBLAS/TLAS
+//ジオメトリごとにBVHを持ち、TLASはインスタンスのワールド空間の箱でBVHを作る
+
+pub enum GeometryKind {
+    Triangles(Vec<Triangle>),
+    //交差判定はヒットグループのintersectionシェーダーに任せる
+    Procedural(Vec<Aabb>),
+}
+
+pub struct Geometry {
+    pub kind: GeometryKind,
+    //D3D12_RAYTRACING_GEOMETRY_FLAG_OPAQUE
+    pub opaque: bool,
+    pub bvh: Bvh,
+}
+
+impl Geometry {
+    pub fn triangles(triangles: Vec<Triangle>, opaque: bool) -> Self {
+        let bvh = Bvh::build(&triangles);
+        Geometry { kind: GeometryKind::Triangles(triangles), opaque, bvh }
+    }
+
+    pub fn procedural(aabbs: Vec<Aabb>, opaque: bool) -> Self {
+        let bvh = Bvh::build_from_bounds(&aabbs);
+        Geometry { kind: GeometryKind::Procedural(aabbs), opaque, bvh }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bvh.nodes.first().map_or(Aabb::EMPTY, |n| n.bounds)
+    }
+}
+
+pub struct BottomLevel {
+    pub geometries: Vec<Geometry>,
+    pub bounds: Aabb,
+}
+
+impl BottomLevel {
+    pub fn new(geometries: Vec<Geometry>) -> Self {
+        let bounds = geometries.iter().fold(Aabb::EMPTY, |acc, g| acc.union(&g.bounds()));
+        BottomLevel { geometries, bounds }
+    }
+}
+
+//D3D12_RAYTRACING_INSTANCE_FLAGS
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InstanceFlags(pub u32);
+
+impl InstanceFlags {
+    pub const NONE: InstanceFlags = InstanceFlags(0);
+    pub const TRIANGLE_CULL_DISABLE: InstanceFlags = InstanceFlags(0x1);
+    pub const TRIANGLE_FRONT_COUNTERCLOCKWISE: InstanceFlags = InstanceFlags(0x2);
+    pub const FORCE_OPAQUE: InstanceFlags = InstanceFlags(0x4);
+    pub const FORCE_NON_OPAQUE: InstanceFlags = InstanceFlags(0x8);
+
+    pub fn contains(self, flags: InstanceFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl std::ops::BitOr for InstanceFlags {
+    type Output = InstanceFlags;
+
+    fn bitor(self, rhs: InstanceFlags) -> InstanceFlags {
+        InstanceFlags(self.0 | rhs.0)
+    }
+}
+
+//D3D12_RAYTRACING_INSTANCE_DESCに相当
+#[derive(Clone, Copy, Debug)]
+pub struct Instance {
+    pub transform: Mat3x4,
+    //InstanceID() 下位24bitだけ使う
+    pub instance_id: u32,
+    pub instance_mask: u8,
+    pub instance_contribution_to_hit_group_index: u32,
+    pub flags: InstanceFlags,
+    pub blas: usize,
+}
+
+impl Instance {
+    //build_tlasと同じく単位行列、マスク0xFF
+    pub fn new(blas: usize) -> Self {
+        Instance {
+            transform: Mat3x4::IDENTITY,
+            instance_id: 0,
+            instance_mask: 0xFF,
+            instance_contribution_to_hit_group_index: 0,
+            flags: InstanceFlags::NONE,
+            blas,
+        }
+    }
+}
+
+pub struct Scene {
+    pub blases: Vec<BottomLevel>,
+    pub instances: Vec<Instance>,
+    pub world_to_object: Vec<Mat3x4>,
+    pub tlas: Bvh,
+}
+
+impl Scene {
+    pub fn new(blases: Vec<BottomLevel>, instances: Vec<Instance>) -> Self {
+        let bounds: Vec<Aabb> = instances.iter().map(|i| blases[i.blas].bounds.transformed(&i.transform)).collect();
+        let world_to_object = instances.iter().map(|i| i.transform.inverse()).collect();
+        let tlas = Bvh::build_from_bounds(&bounds);
+
+        Scene { blases, instances, world_to_object, tlas }
+    }
+}
@@ -0,0 +1,510 @@
+use std::sync::Arc;
+
+use super::pipeline::{
+    AnyHitResult, AnyHitShader, BuiltInTriangleIntersectionAttributes, CallableShader, HitGroup, Pipeline, RayFlags, TraceContext, TraceError,
+};
+use super::ray::Ray;
+use super::texture::{AlphaTexture, TriangleTexCoords};
+use crate::camera::{Camera, HlslCamera};
+use crate::hlsl_layout::{check_declared, Bool, HlslStruct};
+use crate::hlsl_struct;
+use crate::math::Vec3;
+use crate::pipeline_config::{PipelineConfig, PipelineConfigError};
+use crate::root_signature::{DescriptorRange, DescriptorRangeType, RootSignatureDesc};
+use crate::shader_library::{HitGroupDesc, ShaderLibraryDesc};
+
+//shaders/ray_shader.hlslをRustに移したもの
+
+//ray_shader.hlslのgAlphaCutoffの既定値
+pub const ALPHA_CUTOFF: f32 = 0.5;
+//影のレイが自分自身に当たらないようにずらす距離
+pub const SHADOW_RAY_T_MIN: f32 = 1e-3;
+
+//Rustの方はペイロードの型を1つしか持てないので、ShadowPayloadのoccludedもここに入れる
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Payload {
+    pub color: Vec3,
+    pub occluded: bool,
+}
+
+hlsl_struct! {
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct MyAttribute as "MyAttribute" {
+        pub barys: [f32; 2],
+    }
+}
+
+//マテリアルのcallableシェーダーに渡す引数
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MaterialParams {
+    pub barys: [f32; 2],
+    pub color: Vec3,
+}
+
+pub type RayShaderContext<'a> = TraceContext<'a, Payload, MyAttribute, MaterialParams>;
+pub type MaterialShader = CallableShader<Payload, MyAttribute, MaterialParams>;
+
+//登録した順番がCallShader()のインデックスになる
+//nameはray_shader.hlslのcallableシェーダーのエクスポート名
+#[derive(Default)]
+pub struct MaterialRegistry {
+    names: Vec<&'static str>,
+    shaders: Vec<MaterialShader>,
+}
+
+impl MaterialRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, name: &'static str, shader: MaterialShader) -> u32 {
+        self.names.push(name);
+        self.shaders.push(shader);
+        self.shaders.len() as u32 - 1
+    }
+
+    pub fn names(&self) -> &[&'static str] {
+        &self.names
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+//InstanceID()がマテリアルのインデックス
+pub fn default_materials() -> MaterialRegistry {
+    let mut materials = MaterialRegistry::new();
+
+    //BarycentricMaterial
+    materials.register(
+        "BarycentricMaterial",
+        Box::new(|_, params| {
+            let [x, y] = params.barys;
+            params.color = Vec3::new(x, y, 1.0 - x - y);
+            Ok(())
+        }),
+    );
+
+    //CheckerMaterial
+    materials.register(
+        "CheckerMaterial",
+        Box::new(|_, params| {
+            let [x, y] = params.barys;
+            let checker = ((x * 8.0) as u32 + (y * 8.0) as u32) % 2;
+            params.color = if checker == 0 { Vec3::splat(0.9) } else { Vec3::splat(0.2) };
+            Ok(())
+        }),
+    );
+
+    materials
+}
+
+//ray_shader.hlslの構造体と同じレイアウト
+//ペイロードはPipelineConfigのサイズを決めるためだけに使う
+hlsl_struct! {
+    pub struct HlslPayload as "Payload" {
+        pub color: [f32; 3],
+    }
+}
+
+hlsl_struct! {
+    pub struct HlslShadowPayload as "ShadowPayload" {
+        pub occluded: Bool,
+    }
+}
+
+hlsl_struct! {
+    pub struct HlslMaterialParams as "MaterialParams" {
+        pub barys: [f32; 2],
+        pub color: [f32; 3],
+    }
+}
+
+//b0のルート定数
+hlsl_struct! {
+    #[derive(Clone, Copy, Debug, Default)]
+    pub cbuffer HlslAlphaTest as "AlphaTest" {
+        pub width: u32 as "gAlphaMaskWidth",
+        pub height: u32 as "gAlphaMaskHeight",
+        pub cutoff: f32 as "gAlphaCutoff",
+    }
+}
+
+//b2のルート定数 フレームグラフのaccumulateとpost_processで使う
+hlsl_struct! {
+    #[derive(Clone, Copy, Debug, Default)]
+    pub cbuffer HlslFrame as "Frame" {
+        pub sample_count: u32 as "gSampleCount",
+        pub exposure: f32 as "gExposure",
+    }
+}
+
+//シェーダー側の宣言が変わったらここでビルドが止まる
+const RAY_SHADER_SOURCE: &[u8] = include_bytes!("../../shaders/ray_shader.hlsl");
+const _: () = {
+    check_declared::<HlslPayload>(RAY_SHADER_SOURCE);
+    check_declared::<HlslShadowPayload>(RAY_SHADER_SOURCE);
+    check_declared::<MyAttribute>(RAY_SHADER_SOURCE);
+    check_declared::<HlslMaterialParams>(RAY_SHADER_SOURCE);
+    check_declared::<HlslAlphaTest>(RAY_SHADER_SOURCE);
+    check_declared::<HlslCamera>(RAY_SHADER_SOURCE);
+    check_declared::<HlslFrame>(RAY_SHADER_SOURCE);
+};
+
+//ray_shader.hlslで使っているペイロードとアトリビュートの最大サイズ
+pub fn pipeline_config(max_recursion_depth: u32) -> Result<PipelineConfig, PipelineConfigError> {
+    PipelineConfig::new(max_recursion_depth)
+        .payload::<HlslPayload>()
+        .payload::<HlslShadowPayload>()
+        .attribute::<MyAttribute>()
+        //MainClosestHitの影のレイ
+        .trace_depth(2)
+        .validate()
+}
+
+//ray_shader.hlslのグローバルルートシグニチャ
+//パラメーターの並びはDispatchRaysの前にSetComputeRoot*で使うインデックス
+pub fn ray_shader_root_signature() -> RootSignatureDesc {
+    RootSignatureDesc::global()
+        //t0 TLAS
+        .table(&[DescriptorRange::new(DescriptorRangeType::Srv, 0, 1)])
+        //u0 出力
+        .table(&[DescriptorRange::new(DescriptorRangeType::Uav, 0, 1)])
+        //t1 アルファマスク
+        .table(&[DescriptorRange::new(DescriptorRangeType::Srv, 1, 1)])
+        //b0 AlphaTest(幅、高さ、閾値)
+        .constants(0, 0, (HlslAlphaTest::SIZE / 4) as u32)
+        //b1 Camera
+        .constants(1, 0, (HlslCamera::SIZE / 4) as u32)
+        //u1 フレームをまたいだ平均
+        .table(&[DescriptorRange::new(DescriptorRangeType::Uav, 1, 1)])
+        //b2 Frame
+        .constants(2, 0, (HlslFrame::SIZE / 4) as u32)
+}
+
+//ray_shader.hlslのエクスポート名 シーンファイルで変えられる
+//missとヒットグループとany-hitはRayTypeの順、closest-hitを呼ぶのは主レイだけ
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RayShaderExports {
+    pub ray_gen: String,
+    pub miss: [String; RAY_TYPE_COUNT as usize],
+    pub hit_groups: [String; RAY_TYPE_COUNT as usize],
+    pub closest_hit: String,
+    pub any_hit: [String; RAY_TYPE_COUNT as usize],
+}
+
+impl Default for RayShaderExports {
+    fn default() -> Self {
+        RayShaderExports {
+            ray_gen: "MainRayGen".to_string(),
+            miss: RayType::ALL.map(|r| r.miss_name().to_string()),
+            hit_groups: RayType::ALL.map(|r| r.hit_group_name().to_string()),
+            closest_hit: "MainClosestHit".to_string(),
+            any_hit: ["MainAnyHit".to_string(), "ShadowAnyHit".to_string()],
+        }
+    }
+}
+
+//ray_shader.hlslからステートオブジェクトに入れるもの
+//missとヒットグループはRayTypeの順、callableはマテリアルの登録順
+pub fn ray_shader_library(exports: &RayShaderExports, materials: &[&str]) -> ShaderLibraryDesc {
+    let [radiance, shadow] = &exports.hit_groups;
+    let [radiance_any_hit, shadow_any_hit] = &exports.any_hit;
+
+    ShaderLibraryDesc {
+        ray_gen: exports.ray_gen.clone(),
+        miss: exports.miss.to_vec(),
+        hit_groups: vec![
+            //不透明なジオメトリではany-hitは呼ばれないので常にインポートしておく
+            HitGroupDesc::new(radiance).closest_hit(&exports.closest_hit).any_hit(radiance_any_hit),
+            //影のレイはclosest-hitを呼ばないのでany-hitだけ
+            HitGroupDesc::new(shadow).any_hit(shadow_any_hit),
+        ],
+        callables: materials.iter().map(|m| m.to_string()).collect(),
+        pass_ray_gens: vec![ACCUMULATE_RAY_GEN.to_string(), POST_PROCESS_RAY_GEN.to_string()],
+    }
+}
+
+//フレームグラフのaccumulateとpost_processで使うraygen
+pub const ACCUMULATE_RAY_GEN: &str = "AccumulateRayGen";
+pub const POST_PROCESS_RAY_GEN: &str = "PostProcessRayGen";
+
+//AccumulateRayGen sample_countはhistoryに足したフレーム数 0なら履歴を捨てる
+pub fn accumulate(history: [f32; 4], sample: [f32; 4], sample_count: u32) -> [f32; 4] {
+    let t = 1.0 / (sample_count + 1) as f32;
+    let h = if sample_count == 0 { [0.0; 3] } else { [history[0], history[1], history[2]] };
+    [h[0] + (sample[0] - h[0]) * t, h[1] + (sample[1] - h[1]) * t, h[2] + (sample[2] - h[2]) * t, 1.0]
+}
+
+//PostProcessRayGen
+pub fn post_process(color: [f32; 4], exposure: f32) -> [f32; 4] {
+    let c = |v: f32| (v * exposure).clamp(0.0, 1.0);
+    [c(color[0]), c(color[1]), c(color[2]), 1.0]
+}
+
+impl From<BuiltInTriangleIntersectionAttributes> for MyAttribute {
+    fn from(attr: BuiltInTriangleIntersectionAttributes) -> Self {
+        MyAttribute { barys: attr.barycentrics }
+    }
+}
+
+impl From<MyAttribute> for BuiltInTriangleIntersectionAttributes {
+    fn from(attr: MyAttribute) -> Self {
+        BuiltInTriangleIntersectionAttributes { barycentrics: attr.barys }
+    }
+}
+
+//アルファが閾値未満ならIgnoreHit()、それ以外はon_passを返すany-hit
+//影のレイはon_passをAcceptAndEndSearchにすれば最初に見つかった遮蔽物で打ち切れる
+//tex_coordsはPrimitiveIndex()ごとの頂点のUV
+pub fn alpha_test<P, A>(texture: Arc<AlphaTexture>, tex_coords: Vec<TriangleTexCoords>, cutoff: f32, on_pass: AnyHitResult) -> AnyHitShader<P, A>
+where
+    A: Copy + Into<BuiltInTriangleIntersectionAttributes>,
+{
+    Box::new(move |hit, attr, _| {
+        let [b1, b2] = (*attr).into().barycentrics;
+        let [t0, t1, t2] = tex_coords[hit.primitive_index as usize];
+        let b0 = 1.0 - b1 - b2;
+
+        let uv = [t0[0] * b0 + t1[0] * b1 + t2[0] * b2, t0[1] * b0 + t1[1] * b1 + t2[1] * b2];
+
+        if texture.sample(uv) < cutoff {
+            AnyHitResult::Ignore
+        } else {
+            on_pass
+        }
+    })
+}
+
+//頂点バッファにUVがないのでray_shader.hlslと同じく頂点0,1,2に(0,0),(1,0),(0,1)を割り当てる
+//つまりUVは重心座標そのもの
+pub fn barycentric_tex_coords(triangle_count: usize) -> Vec<TriangleTexCoords> {
+    vec![[[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]; triangle_count]
+}
+
+//影のレイがライトに届かなかったときに色に掛ける値
+pub const SHADOW_FACTOR: f32 = 0.3;
+
+pub fn light_direction() -> Vec3 {
+    Vec3::new(0.5, 1.0, 1.0).normalize()
+}
+
+//レイの種類 値がRayContributionToHitGroupIndexとMissShaderIndexになる
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayType {
+    Radiance = 0,
+    Shadow = 1,
+}
+
+//MultiplierForGeometryContributionToHitGroupIndex
+pub const RAY_TYPE_COUNT: u32 = 2;
+
+impl RayType {
+    pub const ALL: [RayType; RAY_TYPE_COUNT as usize] = [RayType::Radiance, RayType::Shadow];
+
+    //シーンファイルで使う名前
+    pub fn name(self) -> &'static str {
+        match self {
+            RayType::Radiance => "radiance",
+            RayType::Shadow => "shadow",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RayType> {
+        Self::ALL.into_iter().find(|r| r.name() == name)
+    }
+
+    pub fn hit_group_name(self) -> &'static str {
+        match self {
+            RayType::Radiance => "DefaultHitGroup",
+            RayType::Shadow => "ShadowHitGroup",
+        }
+    }
+
+    pub fn miss_name(self) -> &'static str {
+        match self {
+            RayType::Radiance => "MainMiss",
+            RayType::Shadow => "ShadowMiss",
+        }
+    }
+}
+
+//遮られていればtrue
+//最初に見つかったヒットで打ち切り、closest-hitも呼ばないので安い
+pub fn trace_occlusion(ctx: &RayShaderContext, origin: Vec3, direction: Vec3) -> Result<bool, TraceError> {
+    let ray = Ray::new(origin, direction, SHADOW_RAY_T_MIN, 100000.0);
+    let mut payload = Payload { occluded: true, ..Default::default() };
+
+    ctx.trace_ray(
+        RayFlags::ACCEPT_FIRST_HIT_AND_END_SEARCH | RayFlags::SKIP_CLOSEST_HIT_SHADER,
+        0xFF,
+        RayType::Shadow as u32,
+        RAY_TYPE_COUNT,
+        RayType::Shadow as u32,
+        &ray,
+        &mut payload,
+    )?;
+
+    Ok(payload.occluded)
+}
+
+//ヒットグループは(ジオメトリ, レイの種類)の順に並べる
+//alpha_maskがあればどちらのヒットグループにもany-hitを付ける 不透明なジオメトリでは呼ばれない
+//マテリアルの評価はclosest-hitからCallShader()で呼ぶので、マテリアルを足してもclosest-hitは変わらない
+pub fn ray_shader_pipeline(
+    camera: &Camera,
+    alpha_mask: Option<Arc<AlphaTexture>>,
+    tex_coords: Vec<TriangleTexCoords>,
+    materials: MaterialRegistry,
+    config: &PipelineConfig,
+) -> Pipeline<Payload, MyAttribute, MaterialParams> {
+    let camera = camera.constants();
+    let any_hit = alpha_mask.as_ref().map(|texture| alpha_test(texture.clone(), tex_coords.clone(), ALPHA_CUTOFF, AnyHitResult::Accept));
+    let shadow_any_hit = alpha_mask.map(|texture| alpha_test(texture, tex_coords, ALPHA_CUTOFF, AnyHitResult::AcceptAndEndSearch));
+
+    Pipeline {
+        //MainRayGen
+        ray_gen: Box::new(move |ctx| {
+            //レイがないピクセルは黒
+            let Some(ray) = camera.pixel_ray(ctx.launch_index, ctx.launch_dimensions) else {
+                return Ok([0.0, 0.0, 0.0, 1.0]);
+            };
+            let mut payload = Payload::default();
+
+            ctx.trace_ray(RayFlags::NONE, 0xFF, RayType::Radiance as u32, RAY_TYPE_COUNT, RayType::Radiance as u32, &ray, &mut payload)?;
+
+            let col = payload.color;
+            Ok([col.x, col.y, col.z, 1.0])
+        }),
+        miss_shaders: vec![
+            //MainMiss
+            Box::new(|_, _, payload| {
+                payload.color = Vec3::new(0.4, 0.8, 0.9);
+                Ok(())
+            }),
+            //ShadowMiss
+            Box::new(|_, _, payload| {
+                payload.occluded = false;
+                Ok(())
+            }),
+        ],
+        hit_groups: vec![
+            //DefaultHitGroup(MainClosestHit, MainAnyHit)
+            HitGroup {
+                closest_hit: Some(Box::new(|ctx, hit, attrib, payload| {
+                    let mut params = MaterialParams { barys: attrib.barys, color: Vec3::ZERO };
+                    ctx.call_shader(hit.instance_id, &mut params)?;
+
+                    let mut col = params.color;
+
+                    if trace_occlusion(ctx, hit.world_hit_position(), light_direction())? {
+                        col = col * SHADOW_FACTOR;
+                    }
+
+                    payload.color = col;
+                    Ok(())
+                })),
+                any_hit,
+                intersection: None,
+            },
+            //ShadowHitGroup(ShadowAnyHit)
+            HitGroup { closest_hit: None, any_hit: shadow_any_hit, intersection: None },
+        ],
+        callables: materials.shaders,
+        max_recursion_depth: config.max_recursion_depth,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::scene::{BottomLevel, Geometry, Instance, Scene};
+    use crate::cpu::triangle::Triangle;
+    use crate::math::Mat3x4;
+
+    //z = 0で画面全体を覆う三角形 instance_idがマテリアル
+    fn ground(material: u32) -> Instance {
+        Instance { instance_id: material, ..Instance::new(0) }
+    }
+
+    //ライトの方向にあってカメラからは見えない遮蔽物
+    fn occluder() -> Instance {
+        Instance { transform: Mat3x4::translation(Vec3::new(0.0, 0.0, 2.0)), ..Instance::new(1) }
+    }
+
+    //既定の平行投影のカメラで4x4ピクセル描く
+    fn render(instances: Vec<Instance>) -> Result<Vec<[f32; 4]>, TraceError> {
+        let big = Triangle::new(Vec3::new(-3.0, -3.0, 0.0), Vec3::new(3.0, -3.0, 0.0), Vec3::new(0.0, 3.0, 0.0));
+        let huge = Triangle::new(Vec3::new(-50.0, -50.0, 0.0), Vec3::new(50.0, -50.0, 0.0), Vec3::new(0.0, 50.0, 0.0));
+        let blases = vec![BottomLevel::new(vec![Geometry::triangles(vec![big], true)]), BottomLevel::new(vec![Geometry::triangles(vec![huge], true)])];
+        let scene = Scene::new(blases, instances);
+
+        let config = pipeline_config(2).unwrap();
+        let pipeline = ray_shader_pipeline(&Camera::default(), None, vec![], default_materials(), &config);
+        pipeline.dispatch_rays(&scene, 4, 4)
+    }
+
+    fn sum([r, g, b, _]: [f32; 4]) -> f32 {
+        r + g + b
+    }
+
+    #[test]
+    fn pipeline_config_needs_the_shadow_ray_depth() {
+        assert_eq!(pipeline_config(1), Err(PipelineConfigError::RecursionTooShallow { depth: 1, required: 2 }));
+        assert_eq!(pipeline_config(2).map(|c| c.max_payload_size), Ok(std::mem::size_of::<HlslPayload>() as u32));
+    }
+
+    #[test]
+    fn barycentric_material_is_lit_without_occluders() {
+        let image = render(vec![ground(0)]).unwrap();
+        for pixel in image {
+            assert!((sum(pixel) - 1.0).abs() < 1e-5, "{:?}", pixel);
+            assert!(pixel[..3].iter().all(|&c| c >= 0.0));
+        }
+    }
+
+    #[test]
+    fn occluded_hits_are_darkened_by_the_shadow_factor() {
+        let image = render(vec![ground(0), occluder()]).unwrap();
+        for pixel in image {
+            assert!((sum(pixel) - SHADOW_FACTOR).abs() < 1e-5, "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn checker_material_alternates_between_two_greys() {
+        let image = render(vec![ground(1)]).unwrap();
+        assert!(image.iter().all(|p| p[..3] == [0.9; 3] || p[..3] == [0.2; 3]));
+        assert!(image.iter().any(|p| p[0] == 0.9));
+        assert!(image.iter().any(|p| p[0] == 0.2));
+    }
+
+    #[test]
+    fn rays_that_miss_get_the_sky_color() {
+        //三角形をカメラの後ろに置く
+        let behind = Instance { transform: Mat3x4::translation(Vec3::new(0.0, 0.0, 5.0)), ..ground(0) };
+        let image = render(vec![behind]).unwrap();
+        assert!(image.iter().all(|p| *p == [0.4, 0.8, 0.9, 1.0]));
+    }
+
+    #[test]
+    fn unknown_material_is_a_missing_callable() {
+        assert_eq!(render(vec![ground(7)]), Err(TraceError::MissingCallableShader(7)));
+    }
+
+    #[test]
+    fn accumulation_averages_frames_and_post_process_clamps() {
+        let first = accumulate([9.0; 4], [0.2, 0.4, 0.6, 1.0], 0);
+        assert_eq!(first, [0.2, 0.4, 0.6, 1.0]);
+        let second = accumulate(first, [0.4, 0.0, 1.0, 1.0], 1);
+        assert!(second.iter().zip([0.3, 0.2, 0.8, 1.0]).all(|(a, b)| (a - b).abs() < 1e-6));
+
+        assert_eq!(post_process([0.5, 2.0, -1.0, 0.3], 1.0), [0.5, 1.0, 0.0, 1.0]);
+        assert_eq!(post_process([0.25, 0.5, 0.75, 1.0], 2.0), [0.5, 1.0, 1.0, 1.0]);
+    }
+}
@@ -0,0 +1,40 @@
+//三角形の頂点ごとのUV
+pub type TriangleTexCoords = [[f32; 2]; 3];
+
+//アルファテスト用の1チャンネルのテクスチャ
+//ray_shader.hlslのgAlphaMaskと同じくポイントサンプリングでラップする
+#[derive(Clone, Debug)]
+pub struct AlphaTexture {
+    pub width: u32,
+    pub height: u32,
+    pub texels: Vec<f32>,
+}
+
+impl AlphaTexture {
+    pub fn new(width: u32, height: u32, texels: Vec<f32>) -> Self {
+        assert_eq!(texels.len(), (width * height) as usize);
+        AlphaTexture { width, height, texels }
+    }
+
+    //葉やフェンスの代わりの市松模様 cells x cellsのマスのうち半分が抜ける
+    pub fn checkerboard(size: u32, cells: u32) -> Self {
+        let cell = (size / cells.max(1)).max(1);
+        let texels = (0..size * size)
+            .map(|i| if (i % size / cell + i / size / cell).is_multiple_of(2) { 1.0 } else { 0.0 })
+            .collect();
+
+        AlphaTexture::new(size, size, texels)
+    }
+
+    pub fn sample(&self, uv: [f32; 2]) -> f32 {
+        let wrap = |c: f32, size: u32| ((c - c.floor()) * size as f32) as u32 % size;
+        let (x, y) = (wrap(uv[0], self.width), wrap(uv[1], self.height));
+
+        self.texels[(y * self.width + x) as usize]
+    }
+}
+
+//init_dxrとヘッドレス描画で使うアルファマスク
+pub fn sample_alpha_mask() -> AlphaTexture {
+    AlphaTexture::checkerboard(64, 8)
+}
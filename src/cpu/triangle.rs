@@ -0,0 +1,101 @@
+use super::aabb::Aabb;
+use super::ray::Ray;
+use crate::math::Vec3;
+use crate::vertex::Vertex;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Triangle {
+    pub v0: Vec3,
+    pub v1: Vec3,
+    pub v2: Vec3,
+}
+
+impl Triangle {
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3) -> Self {
+        Triangle { v0, v1, v2 }
+    }
+
+    //頂点バッファ(D3D12_RAYTRACING_GEOMETRY_TRIANGLES_DESCと同じくインデックスなし)から三角形を作る
+    pub fn from_vertices(vertices: &[Vertex]) -> Vec<Triangle> {
+        vertices
+            .chunks_exact(3)
+            .map(|v| {
+                let p = |v: &Vertex| Vec3::new(v.position[0], v.position[1], v.position[2]);
+                Triangle::new(p(&v[0]), p(&v[1]), p(&v[2]))
+            })
+            .collect()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let mut b = Aabb::EMPTY;
+        b.grow(self.v0);
+        b.grow(self.v1);
+        b.grow(self.v2);
+        b
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.v0 + self.v1 + self.v2) * (1.0 / 3.0)
+    }
+
+    //DXRの既定では、レイの原点から見て時計回りに並んでいる面が表
+    pub fn is_front_facing(&self, direction: Vec3) -> bool {
+        let e1 = self.v1 - self.v0;
+        let e2 = self.v2 - self.v0;
+        e1.dot(direction.cross(e2)) > 0.0
+    }
+
+    //Moller-Trumbore 戻り値は(t, [u, v])
+    pub fn intersect(&self, ray: &Ray) -> Option<(f32, [f32; 2])> {
+        let e1 = self.v1 - self.v0;
+        let e2 = self.v2 - self.v0;
+
+        let p = ray.direction.cross(e2);
+        let det = e1.dot(p);
+
+        //DXRと同じく両面とも当たり判定する
+        if det.abs() < f32::EPSILON {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = ray.origin - self.v0;
+        let u = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = s.cross(e1);
+        let v = ray.direction.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = e2.dot(q) * inv_det;
+        if t < ray.t_min || t > ray.t_max {
+            return None;
+        }
+
+        Some((t, [u, v]))
+    }
+}
+
+//テストとベンチマークで使う起伏のあるグリッドメッシュ
+//[-1, 1]の正方形を1辺resolution個に分け、隣り合う三角形は頂点を共有する
+pub fn terrain(resolution: u32) -> Vec<Triangle> {
+    let height = |x: f32, y: f32| 0.1 * (x * 7.0).sin() * (y * 5.0).cos();
+    let p = |i: u32, j: u32| {
+        let x = i as f32 / resolution as f32 * 2.0 - 1.0;
+        let y = j as f32 / resolution as f32 * 2.0 - 1.0;
+        Vec3::new(x, y, height(x, y))
+    };
+
+    let mut tris = vec![];
+    for j in 0..resolution {
+        for i in 0..resolution {
+            tris.push(Triangle::new(p(i, j), p(i + 1, j), p(i + 1, j + 1)));
+            tris.push(Triangle::new(p(i, j), p(i + 1, j + 1), p(i, j + 1)));
+        }
+    }
+    tris
+}
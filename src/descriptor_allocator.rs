@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::fmt;
+
+//ディスクリプタヒープのスロットの割り当て
+//ヒープの前半を長く使うディスクリプタ(フリーリストで再利用)、後半をフレームごとのリングに分ける
+//ハンドルの計算はヒープの先頭アドレスとインクリメントサイズだけで済むのでバックエンドを差し替えられる
+
+pub trait DescriptorHeapBackend {
+    fn capacity(&self) -> u32;
+    fn increment_size(&self) -> u32;
+    //D3D12_CPU_DESCRIPTOR_HANDLE.ptr
+    fn cpu_start(&self) -> usize;
+    //D3D12_GPU_DESCRIPTOR_HANDLE.ptr シェーダーから見えないヒープなら0
+    fn gpu_start(&self) -> u64;
+}
+
+//GPUのヒープを持たずにスロットの割り当てだけを計算する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VirtualHeap {
+    pub capacity: u32,
+    pub increment_size: u32,
+    pub cpu_start: usize,
+    pub gpu_start: u64,
+}
+
+impl VirtualHeap {
+    pub fn new(capacity: u32, increment_size: u32) -> Self {
+        VirtualHeap { capacity, increment_size, cpu_start: 0, gpu_start: 0 }
+    }
+}
+
+impl DescriptorHeapBackend for VirtualHeap {
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn increment_size(&self) -> u32 {
+        self.increment_size
+    }
+
+    fn cpu_start(&self) -> usize {
+        self.cpu_start
+    }
+
+    fn gpu_start(&self) -> u64 {
+        self.gpu_start
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorError {
+    OutOfDescriptors { requested: u32, largest_free: u32 },
+    //このフレームのリングを使い切った
+    TransientOverflow { requested: u32, available: u32 },
+    //確保していない、もしくは二重に解放した
+    InvalidFree { start: u32, count: u32 },
+    //リングの区画がヒープに収まらない
+    InvalidLayout { capacity: u32, transient: u32 },
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DescriptorError::OutOfDescriptors { requested, largest_free } => {
+                write!(f, "cannot allocate {} descriptors, the largest free range is {}", requested, largest_free)
+            }
+            DescriptorError::TransientOverflow { requested, available } => {
+                write!(f, "cannot allocate {} transient descriptors, {} left this frame", requested, available)
+            }
+            DescriptorError::InvalidFree { start, count } => write!(f, "descriptors {}..{} are not allocated", start, start + count),
+            DescriptorError::InvalidLayout { capacity, transient } => {
+                write!(f, "{} transient descriptors do not fit in a heap of {}", transient, capacity)
+            }
+        }
+    }
+}
+
+impl Error for DescriptorError {}
+
+//ヒープ内で連続したディスクリプタ ディスクリプタテーブルには先頭のgpuを渡す
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DescriptorRange {
+    pub start: u32,
+    pub count: u32,
+    pub cpu: usize,
+    pub gpu: u64,
+    increment_size: u32,
+}
+
+impl DescriptorRange {
+    //範囲の中のi番目 (cpu, gpu)
+    pub fn handle(&self, i: u32) -> (usize, u64) {
+        assert!(i < self.count, "descriptor {} is out of the range of {}", i, self.count);
+        let offset = i as u64 * self.increment_size as u64;
+        (self.cpu + offset as usize, self.gpu + offset)
+    }
+}
+
+pub struct DescriptorAllocator<H: DescriptorHeapBackend> {
+    heap: H,
+    //[0, persistent)が長く使う領域
+    persistent: u32,
+    //(start, count) startの順で並べ、隣り合うものはつなげておく
+    free: Vec<(u32, u32)>,
+    frame_count: u32,
+    //1フレームのリングの大きさ
+    transient_per_frame: u32,
+    frame_index: u32,
+    transient_used: u32,
+}
+
+impl<H: DescriptorHeapBackend> DescriptorAllocator<H> {
+    //ヒープの後ろからframe_count * transient_per_frameをフレームごとのリングに使う
+    pub fn new(heap: H, frame_count: u32, transient_per_frame: u32) -> Result<Self, DescriptorError> {
+        let capacity = heap.capacity();
+        let transient = frame_count.checked_mul(transient_per_frame).filter(|&t| t <= capacity);
+        let Some(transient) = transient else {
+            return Err(DescriptorError::InvalidLayout { capacity, transient: frame_count.saturating_mul(transient_per_frame) });
+        };
+
+        let persistent = capacity - transient;
+        Ok(DescriptorAllocator {
+            heap,
+            persistent,
+            free: if persistent > 0 { vec![(0, persistent)] } else { vec![] },
+            frame_count,
+            transient_per_frame,
+            frame_index: 0,
+            transient_used: 0,
+        })
+    }
+
+    pub fn heap(&self) -> &H {
+        &self.heap
+    }
+
+    pub fn allocate(&mut self) -> Result<DescriptorRange, DescriptorError> {
+        self.allocate_range(1)
+    }
+
+    //ディスクリプタテーブル用の連続した領域 先に見つかった空きから取る
+    pub fn allocate_range(&mut self, count: u32) -> Result<DescriptorRange, DescriptorError> {
+        let Some(i) = self.free.iter().position(|&(_, n)| n >= count) else {
+            let largest_free = self.free.iter().map(|&(_, n)| n).max().unwrap_or(0);
+            return Err(DescriptorError::OutOfDescriptors { requested: count, largest_free });
+        };
+
+        let (start, n) = self.free[i];
+        if n == count {
+            self.free.remove(i);
+        } else {
+            self.free[i] = (start + count, n - count);
+        }
+
+        Ok(self.range(start, count))
+    }
+
+    pub fn free(&mut self, range: DescriptorRange) -> Result<(), DescriptorError> {
+        let (start, count) = (range.start, range.count);
+        let invalid = DescriptorError::InvalidFree { start, count };
+
+        if count == 0 || start.checked_add(count).is_none_or(|end| end > self.persistent) {
+            return Err(invalid);
+        }
+
+        //空きと重なっていたら二重解放
+        let i = self.free.partition_point(|&(s, _)| s < start);
+        let overlaps_prev = i > 0 && self.free[i - 1].0 + self.free[i - 1].1 > start;
+        let overlaps_next = i < self.free.len() && start + count > self.free[i].0;
+        if overlaps_prev || overlaps_next {
+            return Err(invalid);
+        }
+
+        self.free.insert(i, (start, count));
+
+        //後ろ、前の順につなげる
+        if i + 1 < self.free.len() && start + count == self.free[i + 1].0 {
+            self.free[i].1 += self.free[i + 1].1;
+            self.free.remove(i + 1);
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == start {
+            self.free[i - 1].1 += self.free[i].1;
+            self.free.remove(i);
+        }
+
+        Ok(())
+    }
+
+    //フレームの最初に呼ぶ frame_indexのリングはそのフレームのGPUの処理が終わっていること
+    pub fn begin_frame(&mut self, frame_index: u32) {
+        self.frame_index = frame_index % self.frame_count.max(1);
+        self.transient_used = 0;
+    }
+
+    //そのフレームの間だけ使うディスクリプタ 解放はbegin_frameでまとめて
+    pub fn allocate_transient(&mut self, count: u32) -> Result<DescriptorRange, DescriptorError> {
+        let available = self.transient_per_frame - self.transient_used;
+        if count > available {
+            return Err(DescriptorError::TransientOverflow { requested: count, available });
+        }
+
+        let start = self.persistent + self.frame_index * self.transient_per_frame + self.transient_used;
+        self.transient_used += count;
+        Ok(self.range(start, count))
+    }
+
+    pub fn free_count(&self) -> u32 {
+        self.free.iter().map(|&(_, n)| n).sum()
+    }
+
+    fn range(&self, start: u32, count: u32) -> DescriptorRange {
+        let increment_size = self.heap.increment_size();
+        let offset = start as u64 * increment_size as u64;
+        DescriptorRange {
+            start,
+            count,
+            cpu: self.heap.cpu_start() + offset as usize,
+            gpu: if self.heap.gpu_start() == 0 { 0 } else { self.heap.gpu_start() + offset },
+            increment_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //16個のヒープのうち後ろの2フレーム x 4個をリングにする
+    fn allocator() -> DescriptorAllocator<VirtualHeap> {
+        let heap = VirtualHeap { cpu_start: 0x1000, gpu_start: 0x8000_0000, ..VirtualHeap::new(16, 32) };
+        DescriptorAllocator::new(heap, 2, 4).unwrap()
+    }
+
+    #[test]
+    fn persistent_ranges_come_from_the_front_of_the_heap() {
+        let mut descriptors = allocator();
+        assert_eq!(descriptors.free_count(), 8);
+
+        let a = descriptors.allocate().unwrap();
+        let b = descriptors.allocate_range(3).unwrap();
+        assert_eq!((a.start, a.count), (0, 1));
+        assert_eq!((b.start, b.count), (1, 3));
+        assert_eq!((b.cpu, b.gpu), (0x1000 + 32, 0x8000_0000 + 32));
+        assert_eq!(b.handle(2), (0x1000 + 3 * 32, 0x8000_0000 + 3 * 32));
+        assert_eq!(descriptors.free_count(), 4);
+    }
+
+    #[test]
+    fn freed_ranges_merge_and_are_reused() {
+        let mut descriptors = allocator();
+        let a = descriptors.allocate_range(2).unwrap();
+        let b = descriptors.allocate_range(2).unwrap();
+        let c = descriptors.allocate_range(4).unwrap();
+
+        descriptors.free(a).unwrap();
+        descriptors.free(c).unwrap();
+        //aとcの間が空くまでは4個続けて取れない
+        assert_eq!(descriptors.allocate_range(5), Err(DescriptorError::OutOfDescriptors { requested: 5, largest_free: 4 }));
+
+        descriptors.free(b).unwrap();
+        assert_eq!(descriptors.free_count(), 8);
+        assert_eq!(descriptors.allocate_range(8).unwrap().start, 0);
+    }
+
+    #[test]
+    fn double_and_foreign_frees_are_rejected() {
+        let mut descriptors = allocator();
+        let a = descriptors.allocate_range(2).unwrap();
+        descriptors.free(a).unwrap();
+        assert_eq!(descriptors.free(a), Err(DescriptorError::InvalidFree { start: 0, count: 2 }));
+
+        //リングの中は解放できない
+        descriptors.begin_frame(0);
+        let transient = descriptors.allocate_transient(1).unwrap();
+        assert_eq!(descriptors.free(transient), Err(DescriptorError::InvalidFree { start: 8, count: 1 }));
+    }
+
+    #[test]
+    fn exhaustion_reports_what_is_left() {
+        let mut descriptors = allocator();
+        descriptors.allocate_range(8).unwrap();
+        assert_eq!(descriptors.allocate(), Err(DescriptorError::OutOfDescriptors { requested: 1, largest_free: 0 }));
+
+        descriptors.begin_frame(0);
+        descriptors.allocate_transient(3).unwrap();
+        assert_eq!(descriptors.allocate_transient(2), Err(DescriptorError::TransientOverflow { requested: 2, available: 1 }));
+    }
+
+    #[test]
+    fn transient_rings_are_per_frame_and_recycled() {
+        let mut descriptors = allocator();
+
+        descriptors.begin_frame(0);
+        assert_eq!(descriptors.allocate_transient(2).unwrap().start, 8);
+        assert_eq!(descriptors.allocate_transient(2).unwrap().start, 10);
+
+        descriptors.begin_frame(1);
+        assert_eq!(descriptors.allocate_transient(4).unwrap().start, 12);
+
+        //スロット0に戻ったら最初から使い直す
+        descriptors.begin_frame(2);
+        assert_eq!(descriptors.allocate_transient(4).unwrap().start, 8);
+        //リングは長く使う領域を減らさない
+        assert_eq!(descriptors.free_count(), 8);
+    }
+
+    #[test]
+    fn layout_must_fit_the_heap() {
+        assert!(matches!(
+            DescriptorAllocator::new(VirtualHeap::new(8, 32), 3, 3),
+            Err(DescriptorError::InvalidLayout { capacity: 8, transient: 9 })
+        ));
+
+        //シェーダーから見えないヒープはgpuが0
+        let mut descriptors = DescriptorAllocator::new(VirtualHeap::new(4, 32), 1, 0).unwrap();
+        assert_eq!(descriptors.allocate().unwrap().gpu, 0);
+    }
+}
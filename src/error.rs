@@ -0,0 +1,173 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::buffer_allocator::BufferError;
+use crate::config::ConfigError;
+use crate::cpu::pipeline::TraceError;
+use crate::descriptor_allocator::DescriptorError;
+use crate::dxbc::DxbcError;
+use crate::pipeline_config::PipelineConfigError;
+use crate::render_graph::GraphError;
+use crate::root_signature::RootSignatureError;
+use crate::scene_file::SceneError;
+use crate::shader_library::LibraryValidationError;
+use crate::shader_manifest::ManifestError;
+use crate::shader_table::ShaderTableError;
+
+pub type Result<T> = std::result::Result<T, RwrError>;
+
+//公開APIが返すエラー
+//モジュールごとのエラーはそのまま包み、contextで何をしていたかを重ねる
+#[derive(Debug)]
+pub enum RwrError {
+    //create系を呼ぶ順番が足りない 中身は足りないもの
+    NotInitialized(&'static str),
+    RaytracingNotSupported,
+    ShaderLoad { path: String, message: String },
+    ShaderParse { path: String, source: DxbcError },
+    //dxcが起動できない、またはエラーを返した
+    ShaderCompile { path: String, message: String },
+    ShaderLibrary(LibraryValidationError),
+    Scene(SceneError),
+    Asset { path: String, source: io::Error },
+    Config(ConfigError),
+    Manifest(ManifestError),
+    PipelineConfig(PipelineConfigError),
+    RootSignature(RootSignatureError),
+    ShaderTable(ShaderTableError),
+    Graph(GraphError),
+    Trace(TraceError),
+    Buffer(BufferError),
+    Descriptor(DescriptorError),
+    //D3D12やWin32が返したHRESULT
+    Backend { code: i32, message: String },
+    Context { context: String, source: Box<RwrError> },
+}
+
+impl RwrError {
+    //contextを外した一番内側のエラー
+    pub fn root(&self) -> &RwrError {
+        match self {
+            RwrError::Context { source, .. } => source.root(),
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for RwrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RwrError::NotInitialized(what) => write!(f, "{} has not been initialized", what),
+            RwrError::RaytracingNotSupported => write!(f, "the device does not support DXR raytracing"),
+            RwrError::ShaderLoad { path, message } => write!(f, "failed to load shader {}: {}", path, message),
+            RwrError::ShaderParse { path, .. } => write!(f, "failed to parse shader {}", path),
+            RwrError::ShaderCompile { path, message } => write!(f, "failed to compile shader {}: {}", path, message),
+            RwrError::ShaderLibrary(_) => write!(f, "the shader library does not match the shaders"),
+            RwrError::Scene(_) => write!(f, "invalid scene"),
+            RwrError::Asset { path, .. } => write!(f, "failed to access {}", path),
+            RwrError::Config(_) => write!(f, "invalid command line"),
+            RwrError::Manifest(_) => write!(f, "shader manifest error"),
+            RwrError::PipelineConfig(_) => write!(f, "invalid pipeline config"),
+            RwrError::RootSignature(_) => write!(f, "invalid root signature"),
+            RwrError::ShaderTable(_) => write!(f, "invalid shader table"),
+            RwrError::Graph(_) => write!(f, "invalid frame graph"),
+            RwrError::Trace(_) => write!(f, "ray tracing failed"),
+            RwrError::Buffer(_) => write!(f, "buffer allocation failed"),
+            RwrError::Descriptor(_) => write!(f, "descriptor allocation failed"),
+            RwrError::Backend { code, message } => write!(f, "{} (HRESULT {:#010X})", message, code),
+            RwrError::Context { context, .. } => write!(f, "{}", context),
+        }
+    }
+}
+
+impl Error for RwrError {
+    //包んだエラーはDisplayに出さずsourceで返す mainが順につないで出す
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RwrError::ShaderParse { source, .. } => Some(source),
+            RwrError::ShaderLibrary(e) => Some(e),
+            RwrError::Scene(e) => Some(e),
+            RwrError::Asset { source, .. } => Some(source),
+            RwrError::Config(e) => Some(e),
+            RwrError::Manifest(e) => Some(e),
+            RwrError::PipelineConfig(e) => Some(e),
+            RwrError::RootSignature(e) => Some(e),
+            RwrError::ShaderTable(e) => Some(e),
+            RwrError::Graph(e) => Some(e),
+            RwrError::Trace(e) => Some(e),
+            RwrError::Buffer(e) => Some(e),
+            RwrError::Descriptor(e) => Some(e),
+            RwrError::Context { source, .. } => Some(source.as_ref()),
+            RwrError::NotInitialized(_) | RwrError::RaytracingNotSupported | RwrError::ShaderLoad { .. } | RwrError::ShaderCompile { .. } | RwrError::Backend { .. } => None,
+        }
+    }
+}
+
+//エラーに何をしていたかを重ねる
+pub trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+    fn with_context<S: Into<String>>(self, context: impl FnOnce() -> S) -> Result<T>;
+}
+
+impl<T, E: Into<RwrError>> Context<T> for std::result::Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| RwrError::Context { context: context.into(), source: Box::new(e.into()) })
+    }
+
+    fn with_context<S: Into<String>>(self, context: impl FnOnce() -> S) -> Result<T> {
+        self.map_err(|e| RwrError::Context { context: context().into(), source: Box::new(e.into()) })
+    }
+}
+
+macro_rules! from_error {
+    ($($error:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$error> for RwrError {
+                fn from(e: $error) -> Self {
+                    RwrError::$variant(e)
+                }
+            }
+        )*
+    };
+}
+
+from_error! {
+    LibraryValidationError => ShaderLibrary,
+    ConfigError => Config,
+    ManifestError => Manifest,
+    SceneError => Scene,
+    PipelineConfigError => PipelineConfig,
+    RootSignatureError => RootSignature,
+    ShaderTableError => ShaderTable,
+    GraphError => Graph,
+    TraceError => Trace,
+    BufferError => Buffer,
+    DescriptorError => Descriptor,
+}
+
+#[cfg(windows)]
+impl From<windows::core::Error> for RwrError {
+    fn from(e: windows::core::Error) -> Self {
+        RwrError::Backend { code: e.code().0, message: e.message().to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_errors_are_the_source() {
+        let e = Err::<(), _>(ConfigError::MissingValue("--scene")).context("failed to parse the arguments").unwrap_err();
+
+        let mut chain = vec![e.to_string()];
+        let mut source = e.source();
+        while let Some(e) = source {
+            chain.push(e.to_string());
+            source = e.source();
+        }
+        assert_eq!(chain, ["failed to parse the arguments", "invalid command line", "--scene needs a value"]);
+        assert!(matches!(e.root(), RwrError::Config(ConfigError::MissingValue("--scene"))));
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+
+//フレームごとのリソース(コマンドアロケーター、コマンドリスト、一時バッファ)をスロットに分けて順に使う
+//スロットを使い回すときだけ、そのスロットで前に積んだコマンドの完了を待つ
+
+pub trait Fence {
+    type Error;
+
+    //GPUが終えたところまでの値
+    fn completed_value(&self) -> u64;
+    //ここまでに積んだコマンドが終わったらvalueになるようにキューに積む
+    fn signal(&mut self, value: u64) -> Result<(), Self::Error>;
+    //completed_valueがvalue以上になるまでCPUを止める
+    fn wait(&mut self, value: u64) -> Result<(), Self::Error>;
+}
+
+//GPUの代わりに、signalした値をadvanceで1つずつ終わらせる
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimulatedFence {
+    completed: u64,
+    //signalしてまだ終わっていない値
+    pending: VecDeque<u64>,
+    //waitで止まった値
+    pub waits: Vec<u64>,
+}
+
+impl SimulatedFence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //GPUがsignalを1つ終える 終わったものがなければfalse
+    pub fn advance(&mut self) -> bool {
+        match self.pending.pop_front() {
+            Some(value) => {
+                self.completed = value;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Fence for SimulatedFence {
+    type Error = Infallible;
+
+    fn completed_value(&self) -> u64 {
+        self.completed
+    }
+
+    fn signal(&mut self, value: u64) -> Result<(), Infallible> {
+        self.pending.push_back(value);
+        Ok(())
+    }
+
+    fn wait(&mut self, value: u64) -> Result<(), Infallible> {
+        if self.completed >= value {
+            return Ok(());
+        }
+
+        self.waits.push(value);
+        while self.completed < value {
+            //signalしていない値を待つと永遠に終わらない
+            assert!(self.advance(), "waiting for fence value {} that was never signaled", value);
+        }
+        Ok(())
+    }
+}
+
+struct FrameSlot<T> {
+    resources: T,
+    //このスロットで最後に積んだコマンドのフェンスの値 0なら未使用
+    fence_value: u64,
+}
+
+pub struct FrameRing<T> {
+    slots: Vec<FrameSlot<T>>,
+    current: usize,
+    last_signaled: u64,
+}
+
+impl<T> FrameRing<T> {
+    pub fn new(resources: Vec<T>) -> Self {
+        assert!(!resources.is_empty(), "a frame ring needs at least one slot");
+        FrameRing {
+            slots: resources.into_iter().map(|resources| FrameSlot { resources, fence_value: 0 }).collect(),
+            current: 0,
+            last_signaled: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn current_index(&self) -> usize {
+        self.current
+    }
+
+    pub fn current(&self) -> &T {
+        &self.slots[self.current].resources
+    }
+
+    pub fn current_mut(&mut self) -> &mut T {
+        &mut self.slots[self.current].resources
+    }
+
+    //今のスロットを使い始める 前にこのスロットで積んだコマンドが終わっていなければ待つ
+    pub fn begin_frame<F: Fence>(&mut self, fence: &mut F) -> Result<usize, F::Error> {
+        let value = self.slots[self.current].fence_value;
+        if fence.completed_value() < value {
+            fence.wait(value)?;
+        }
+        Ok(self.current)
+    }
+
+    //今のスロットのコマンドをキューに積んだ後に呼ぶ 次のスロットに進む
+    pub fn end_frame<F: Fence>(&mut self, fence: &mut F) -> Result<u64, F::Error> {
+        let value = self.signal(fence)?;
+        self.slots[self.current].fence_value = value;
+        self.current = (self.current + 1) % self.slots.len();
+        Ok(value)
+    }
+
+    //積んだコマンドが全部終わるまで待つ 初期化のビルドや破棄の前に使う
+    pub fn flush<F: Fence>(&mut self, fence: &mut F) -> Result<(), F::Error> {
+        let value = self.signal(fence)?;
+        fence.wait(value)
+    }
+
+    //signalに失敗した値は使わない
+    fn signal<F: Fence>(&mut self, fence: &mut F) -> Result<u64, F::Error> {
+        fence.signal(self.last_signaled + 1)?;
+        self.last_signaled += 1;
+        Ok(self.last_signaled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_slots_do_not_wait() {
+        let mut fence = SimulatedFence::new();
+        let mut frames = FrameRing::new(vec!['a', 'b', 'c']);
+
+        for slot in 0..3 {
+            assert_eq!(frames.begin_frame(&mut fence).unwrap(), slot);
+            assert_eq!(frames.end_frame(&mut fence).unwrap(), slot as u64 + 1);
+        }
+
+        //GPUがまだ何も終えていなくても、使っていないスロットなら止まらない
+        assert_eq!(fence.completed_value(), 0);
+        assert!(fence.waits.is_empty());
+    }
+
+    #[test]
+    fn reusing_a_slot_waits_for_its_last_signal() {
+        let mut fence = SimulatedFence::new();
+        let mut frames = FrameRing::new(vec![0u32; 2]);
+
+        for _ in 0..2 {
+            frames.begin_frame(&mut fence).unwrap();
+            frames.end_frame(&mut fence).unwrap();
+        }
+
+        //スロット0に戻るとフレーム1の値1を待つ
+        assert_eq!(frames.begin_frame(&mut fence).unwrap(), 0);
+        assert_eq!(fence.waits, vec![1]);
+        assert_eq!(fence.completed_value(), 1);
+        *frames.current_mut() += 1;
+        frames.end_frame(&mut fence).unwrap();
+
+        //値2はもう終わっていれば待たない
+        fence.advance();
+        assert_eq!(frames.begin_frame(&mut fence).unwrap(), 1);
+        assert_eq!(fence.waits, vec![1]);
+        frames.end_frame(&mut fence).unwrap();
+
+        frames.begin_frame(&mut fence).unwrap();
+        assert_eq!(fence.waits, vec![1, 3]);
+        assert_eq!(*frames.current(), 1);
+    }
+
+    #[test]
+    fn flush_waits_for_the_last_signal() {
+        let mut fence = SimulatedFence::new();
+        let mut frames = FrameRing::new(vec![(); 3]);
+
+        frames.begin_frame(&mut fence).unwrap();
+        frames.end_frame(&mut fence).unwrap();
+        frames.begin_frame(&mut fence).unwrap();
+        frames.end_frame(&mut fence).unwrap();
+
+        frames.flush(&mut fence).unwrap();
+        assert_eq!(fence.waits, vec![3]);
+        assert_eq!(fence.completed_value(), 3);
+        //flushはスロットを進めない
+        assert_eq!(frames.current_index(), 2);
+        assert_eq!(frames.end_frame(&mut fence).unwrap(), 4);
+    }
+}
@@ -0,0 +1,538 @@
+//RustとHLSLで共有する構造体のレイアウト
+//hlsl_struct!で定義した型はHLSLの宣言を出力でき、パッキング規則とのずれやシェーダー側の宣言とのずれはconstの評価でビルドエラーにする
+
+//DXGI_FORMAT 頂点バッファなどで要素の形式として使えるもの
+pub const DXGI_FORMAT_UNKNOWN: u32 = 0;
+pub const DXGI_FORMAT_R32G32B32A32_FLOAT: u32 = 2;
+pub const DXGI_FORMAT_R32G32B32A32_UINT: u32 = 3;
+pub const DXGI_FORMAT_R32G32B32A32_SINT: u32 = 4;
+pub const DXGI_FORMAT_R32G32B32_FLOAT: u32 = 6;
+pub const DXGI_FORMAT_R32G32B32_UINT: u32 = 7;
+pub const DXGI_FORMAT_R32G32B32_SINT: u32 = 8;
+pub const DXGI_FORMAT_R32G32_FLOAT: u32 = 16;
+pub const DXGI_FORMAT_R32G32_UINT: u32 = 17;
+pub const DXGI_FORMAT_R32G32_SINT: u32 = 18;
+pub const DXGI_FORMAT_R32_FLOAT: u32 = 41;
+pub const DXGI_FORMAT_R32_UINT: u32 = 42;
+pub const DXGI_FORMAT_R32_SINT: u32 = 43;
+
+//cbufferの1レジスタ
+pub const REGISTER_SIZE: usize = 16;
+
+//HLSLのboolは4バイト
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bool(pub u32);
+
+impl From<bool> for Bool {
+    fn from(b: bool) -> Self {
+        Bool(b as u32)
+    }
+}
+
+//HLSLのスカラーとベクトルに対応するRustの型
+pub trait HlslType {
+    const HLSL_NAME: &'static str;
+    const SIZE: usize;
+    const DXGI_FORMAT: u32;
+    //hlsl_struct!で定義した構造体 cbufferではレジスタの先頭から置き、次のメンバーも次のレジスタから始まる
+    const AGGREGATE: bool = false;
+    //cbufferの中に置いたときのパッキングでも同じオフセットになる
+    const CONSTANT_BUFFER_LAYOUT: bool = true;
+}
+
+macro_rules! hlsl_type {
+    ($($ty:ty => $name:literal, $format:expr;)*) => {
+        $(
+            impl HlslType for $ty {
+                const HLSL_NAME: &'static str = $name;
+                const SIZE: usize = std::mem::size_of::<$ty>();
+                const DXGI_FORMAT: u32 = $format;
+            }
+        )*
+    };
+}
+
+hlsl_type! {
+    f32 => "float", DXGI_FORMAT_R32_FLOAT;
+    [f32; 2] => "float2", DXGI_FORMAT_R32G32_FLOAT;
+    [f32; 3] => "float3", DXGI_FORMAT_R32G32B32_FLOAT;
+    [f32; 4] => "float4", DXGI_FORMAT_R32G32B32A32_FLOAT;
+    u32 => "uint", DXGI_FORMAT_R32_UINT;
+    [u32; 2] => "uint2", DXGI_FORMAT_R32G32_UINT;
+    [u32; 3] => "uint3", DXGI_FORMAT_R32G32B32_UINT;
+    [u32; 4] => "uint4", DXGI_FORMAT_R32G32B32A32_UINT;
+    i32 => "int", DXGI_FORMAT_R32_SINT;
+    [i32; 2] => "int2", DXGI_FORMAT_R32G32_SINT;
+    [i32; 3] => "int3", DXGI_FORMAT_R32G32B32_SINT;
+    [i32; 4] => "int4", DXGI_FORMAT_R32G32B32A32_SINT;
+    Bool => "bool", DXGI_FORMAT_UNKNOWN;
+    crate::math::Vec3 => "float3", DXGI_FORMAT_R32G32B32_FLOAT;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Packing {
+    //StructuredBuffer、ペイロード、アトリビュート、頂点 4バイト単位で詰める
+    Packed,
+    //cbufferとルート定数 ベクトルは16バイトのレジスタをまたげない
+    ConstantBuffer,
+}
+
+impl Packing {
+    pub const fn keyword(self) -> &'static str {
+        match self {
+            Packing::Packed => "struct",
+            Packing::ConstantBuffer => "cbuffer",
+        }
+    }
+
+    //endまで埋まっているときにsizeバイトのメンバーを置くオフセット
+    pub const fn place(self, end: usize, size: usize) -> usize {
+        match self {
+            Packing::ConstantBuffer if end / REGISTER_SIZE != (end + size - 1) / REGISTER_SIZE => end.div_ceil(REGISTER_SIZE) * REGISTER_SIZE,
+            _ => end,
+        }
+    }
+
+    //構造体と配列を置くオフセット cbufferでは次のレジスタの先頭
+    pub const fn place_aggregate(self, end: usize) -> usize {
+        match self {
+            Packing::ConstantBuffer => end.div_ceil(REGISTER_SIZE) * REGISTER_SIZE,
+            Packing::Packed => end,
+        }
+    }
+
+    //配列の要素の間隔 cbufferでは要素ごとにレジスタを使う
+    pub const fn array_stride(self, element_size: usize) -> usize {
+        match self {
+            Packing::ConstantBuffer => element_size.div_ceil(REGISTER_SIZE) * REGISTER_SIZE,
+            Packing::Packed => element_size,
+        }
+    }
+
+    //最後の要素の後ろは詰めないので、後ろのメンバーがそのレジスタの残りに入れる
+    pub const fn array_size(self, element_size: usize, count: usize) -> usize {
+        if count == 0 {
+            return 0;
+        }
+        self.array_stride(element_size) * (count - 1) + element_size
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HlslField {
+    //HLSLでのメンバー名
+    pub name: &'static str,
+    pub hlsl_type: &'static str,
+    //Rustでのオフセット
+    pub offset: usize,
+    pub size: usize,
+    pub dxgi_format: u32,
+    pub aggregate: bool,
+    pub constant_buffer_layout: bool,
+}
+
+pub trait HlslStruct {
+    const HLSL_NAME: &'static str;
+    const PACKING: Packing;
+    const FIELDS: &'static [HlslField];
+    const SIZE: usize;
+
+    //シェーダーに貼る宣言 cbufferのregisterは付けない
+    fn hlsl_declaration() -> String {
+        let mut decl = format!("{} {} {{\n", Self::PACKING.keyword(), Self::HLSL_NAME);
+        for f in Self::FIELDS {
+            decl += &format!("    {} {};\n", f.hlsl_type, f.name);
+        }
+        decl + "};\n"
+    }
+}
+
+//HLSLのパッキング規則で並べたときのオフセットとRustのオフセットが全部同じならパスする
+pub const fn check_layout<T: HlslStruct>() {
+    if let Err(message) = layout_matches(T::PACKING, T::FIELDS, T::SIZE) {
+        panic!("{}", message);
+    }
+}
+
+//packingで並べたときにfieldsのオフセットとsizeが合うか
+pub const fn layout_matches(packing: Packing, fields: &[HlslField], size: usize) -> Result<(), &'static str> {
+    let mut end = 0;
+    //構造体の後ろのメンバーは次のレジスタから
+    let mut after_aggregate = false;
+    let mut i = 0;
+    while i < fields.len() {
+        let f = &fields[i];
+        if !f.size.is_multiple_of(4) {
+            return Err("HLSL members are made of 4-byte scalars");
+        }
+        if matches!(packing, Packing::ConstantBuffer) && !f.constant_buffer_layout {
+            return Err("nested struct does not follow the cbuffer layout");
+        }
+
+        let offset = if f.aggregate || after_aggregate { packing.place_aggregate(end) } else { packing.place(end, f.size) };
+        if f.offset != offset {
+            return Err(match packing {
+                Packing::Packed => "Rust field offset differs from the packed HLSL layout",
+                Packing::ConstantBuffer => "Rust field offset differs from the cbuffer layout (vectors cannot straddle 16-byte registers)",
+            });
+        }
+
+        end = offset + f.size;
+        after_aggregate = f.aggregate;
+        i += 1;
+    }
+
+    if size != end {
+        return Err("Rust struct size differs from the HLSL layout");
+    }
+    Ok(())
+}
+
+//sourceの中にT::hlsl_declaration()と同じメンバーの宣言があればパスする
+//空白とコメントは無視し、cbufferの`: register(b0)`のような名前と`{`の間も読み飛ばす
+pub const fn check_declared<T: HlslStruct>(source: &[u8]) {
+    let keyword = T::PACKING.keyword().as_bytes();
+    let name = T::HLSL_NAME.as_bytes();
+
+    //キーワードと名前が続くところを探す
+    let mut i = next_token(source, 0);
+    let mut found = false;
+    while i < source.len() {
+        let end = token_end(source, i);
+        if token_eq(source, i, end, keyword) {
+            let n = next_token(source, end);
+            let n_end = token_end(source, n);
+            if token_eq(source, n, n_end, name) {
+                i = n_end;
+                found = true;
+                break;
+            }
+        }
+        i = next_token(source, end);
+    }
+
+    if !found {
+        panic!("HLSL declaration not found in the shader source");
+    }
+
+    while i < source.len() && source[i] != b'{' {
+        i = next_token(source, token_end(source, i));
+    }
+    i = next_token(source, i + 1);
+
+    let mut f = 0;
+    while i < source.len() && source[i] != b'}' {
+        if f == T::FIELDS.len() {
+            panic!("HLSL declaration has more members than the Rust struct");
+        }
+
+        let ty_end = token_end(source, i);
+        if !token_eq(source, i, ty_end, T::FIELDS[f].hlsl_type.as_bytes()) {
+            panic!("HLSL member type differs from the Rust field type");
+        }
+
+        let n = next_token(source, ty_end);
+        let n_end = token_end(source, n);
+        if !token_eq(source, n, n_end, T::FIELDS[f].name.as_bytes()) {
+            panic!("HLSL member name differs from the Rust field name");
+        }
+
+        let semicolon = next_token(source, n_end);
+        if semicolon >= source.len() || source[semicolon] != b';' {
+            panic!("HLSL member is not a plain `type name;` declaration");
+        }
+
+        i = next_token(source, semicolon + 1);
+        f += 1;
+    }
+
+    if f != T::FIELDS.len() {
+        panic!("HLSL declaration has fewer members than the Rust struct");
+    }
+}
+
+const fn is_ident(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+//空白とコメントを飛ばして次のトークンの先頭
+const fn next_token(source: &[u8], mut i: usize) -> usize {
+    while i < source.len() {
+        if source[i].is_ascii_whitespace() {
+            i += 1;
+        } else if i + 1 < source.len() && source[i] == b'/' && source[i + 1] == b'/' {
+            while i < source.len() && source[i] != b'\n' {
+                i += 1;
+            }
+        } else if i + 1 < source.len() && source[i] == b'/' && source[i + 1] == b'*' {
+            i += 2;
+            while i + 1 < source.len() && !(source[i] == b'*' && source[i + 1] == b'/') {
+                i += 1;
+            }
+            i += 2;
+        } else {
+            break;
+        }
+    }
+    i
+}
+
+//識別子はまとめて1トークン、それ以外は1バイトで1トークン
+const fn token_end(source: &[u8], mut i: usize) -> usize {
+    if i >= source.len() {
+        return i;
+    }
+    if !is_ident(source[i]) {
+        return i + 1;
+    }
+    while i < source.len() && is_ident(source[i]) {
+        i += 1;
+    }
+    i
+}
+
+const fn token_eq(source: &[u8], start: usize, end: usize, token: &[u8]) -> bool {
+    if end - start != token.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < token.len() {
+        if source[start + i] != token[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+//#[repr(C)]の構造体を定義してHlslStructを実装し、レイアウトをビルド時に確かめる
+//hlsl_struct! { pub struct HlslPayload as "Payload" { pub color: [f32; 3], } }
+//cbufferならstructの代わりにcbufferと書く メンバー名がRustと違うときは`field: u32 as "gName"`
+//structで定義したものは他のhlsl_struct!のメンバーにできる
+//
+/// レイアウトがHLSLと合わないとビルドできない
+///
+/// ```compile_fail
+/// rwr::hlsl_struct! {
+///     //float3はレジスタをまたげないのでbは16から
+///     cbuffer Straddle as "Straddle" { a: [f32; 2], b: [f32; 3] }
+/// }
+/// ```
+///
+/// ```compile_fail
+/// rwr::hlsl_struct! { struct Inner as "Inner" { a: [f32; 3] } }
+/// rwr::hlsl_struct! {
+///     //構造体の後ろのメンバーは次のレジスタから
+///     cbuffer Outer as "Outer" { inner: Inner, b: f32 }
+/// }
+/// ```
+///
+/// ```compile_fail
+/// rwr::hlsl_struct! { cbuffer Frame as "Frame" { count: u32 } }
+/// const _: () = rwr::hlsl_layout::check_declared::<Frame>(b"cbuffer Frame { float count; };");
+/// ```
+///
+/// ```
+/// rwr::hlsl_struct! { struct Inner as "Inner" { a: [f32; 3], w: f32 } }
+/// rwr::hlsl_struct! {
+///     cbuffer Outer as "Outer" { inner: Inner, b: f32 }
+/// }
+/// const _: () = rwr::hlsl_layout::check_declared::<Outer>(b"cbuffer Outer : register(b0) { Inner inner; float b; };");
+/// ```
+#[macro_export]
+macro_rules! hlsl_struct {
+    ($(#[$attr:meta])* $vis:vis struct $name:ident as $hlsl_name:literal { $($body:tt)* }) => {
+        $crate::hlsl_struct!(@define $crate::hlsl_layout::Packing::Packed, $(#[$attr])* $vis $name $hlsl_name { $($body)* });
+
+        //他のhlsl_struct!のメンバーにできる
+        impl $crate::hlsl_layout::HlslType for $name {
+            const HLSL_NAME: &'static str = $hlsl_name;
+            const SIZE: usize = std::mem::size_of::<$name>();
+            const DXGI_FORMAT: u32 = $crate::hlsl_layout::DXGI_FORMAT_UNKNOWN;
+            const AGGREGATE: bool = true;
+            const CONSTANT_BUFFER_LAYOUT: bool = $crate::hlsl_layout::layout_matches(
+                $crate::hlsl_layout::Packing::ConstantBuffer,
+                <$name as $crate::hlsl_layout::HlslStruct>::FIELDS,
+                <$name as $crate::hlsl_layout::HlslStruct>::SIZE,
+            )
+            .is_ok();
+        }
+    };
+    ($(#[$attr:meta])* $vis:vis cbuffer $name:ident as $hlsl_name:literal { $($body:tt)* }) => {
+        $crate::hlsl_struct!(@define $crate::hlsl_layout::Packing::ConstantBuffer, $(#[$attr])* $vis $name $hlsl_name { $($body)* });
+    };
+    (@define $packing:expr, $(#[$attr:meta])* $vis:vis $name:ident $hlsl_name:literal {
+        $($(#[$field_attr:meta])* $field_vis:vis $field:ident : $ty:ty $(as $field_hlsl_name:literal)?),* $(,)?
+    }) => {
+        #[repr(C)]
+        $(#[$attr])*
+        $vis struct $name {
+            $($(#[$field_attr])* $field_vis $field: $ty,)*
+        }
+
+        impl $crate::hlsl_layout::HlslStruct for $name {
+            const HLSL_NAME: &'static str = $hlsl_name;
+            const PACKING: $crate::hlsl_layout::Packing = $packing;
+            const FIELDS: &'static [$crate::hlsl_layout::HlslField] = &[$(
+                $crate::hlsl_layout::HlslField {
+                    name: [$($field_hlsl_name,)? stringify!($field)][0],
+                    hlsl_type: <$ty as $crate::hlsl_layout::HlslType>::HLSL_NAME,
+                    offset: std::mem::offset_of!($name, $field),
+                    size: <$ty as $crate::hlsl_layout::HlslType>::SIZE,
+                    dxgi_format: <$ty as $crate::hlsl_layout::HlslType>::DXGI_FORMAT,
+                    aggregate: <$ty as $crate::hlsl_layout::HlslType>::AGGREGATE,
+                    constant_buffer_layout: <$ty as $crate::hlsl_layout::HlslType>::CONSTANT_BUFFER_LAYOUT,
+                },
+            )*];
+            const SIZE: usize = std::mem::size_of::<$name>();
+        }
+
+        const _: () = $crate::hlsl_layout::check_layout::<$name>();
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    hlsl_struct! {
+        #[derive(Clone, Copy, Default)]
+        struct Light as "Light" {
+            direction: [f32; 3],
+            intensity: f32,
+        }
+    }
+
+    //cbufferに入れるとbがレジスタをまたぐ
+    hlsl_struct! {
+        #[derive(Clone, Copy, Default)]
+        struct Packed as "Packed" {
+            a: [f32; 2],
+            b: [f32; 3],
+        }
+    }
+
+    hlsl_struct! {
+        #[derive(Clone, Copy, Default)]
+        cbuffer Scene as "Scene" {
+            count: u32 as "gCount",
+            pad: [u32; 3] as "gPad",
+            light: Light as "gLight",
+            exposure: f32 as "gExposure",
+        }
+    }
+
+    fn field(offset: usize, size: usize) -> HlslField {
+        HlslField { name: "x", hlsl_type: "float", offset, size, dxgi_format: DXGI_FORMAT_UNKNOWN, aggregate: false, constant_buffer_layout: true }
+    }
+
+    fn aggregate(offset: usize, size: usize, constant_buffer_layout: bool) -> HlslField {
+        HlslField { aggregate: true, constant_buffer_layout, ..field(offset, size) }
+    }
+
+    #[test]
+    fn cbuffer_vectors_do_not_cross_a_register() {
+        let cb = Packing::ConstantBuffer;
+        assert_eq!(cb.place(0, 16), 0);
+        assert_eq!(cb.place(4, 12), 4);
+        assert_eq!(cb.place(8, 8), 8);
+        assert_eq!(cb.place(8, 12), 16);
+        assert_eq!(cb.place(12, 8), 16);
+        assert_eq!(cb.place(12, 4), 12);
+        assert_eq!(cb.place(4, 16), 16);
+        assert_eq!(cb.place(28, 8), 32);
+
+        //詰める方はどこにでも置ける
+        assert_eq!(Packing::Packed.place(8, 12), 8);
+        assert_eq!(Packing::Packed.place(4, 16), 4);
+    }
+
+    #[test]
+    fn cbuffer_array_elements_start_a_register() {
+        let cb = Packing::ConstantBuffer;
+        assert_eq!(cb.place_aggregate(4), 16);
+        assert_eq!(cb.place_aggregate(32), 32);
+        assert_eq!(cb.array_stride(4), 16);
+        assert_eq!(cb.array_stride(16), 16);
+        assert_eq!(cb.array_stride(20), 32);
+        //float a[3]は32 + 4バイト 最後の要素の後ろには次のメンバーが入る
+        assert_eq!(cb.array_size(4, 3), 36);
+        assert_eq!(cb.array_size(12, 2), 28);
+        assert_eq!(cb.array_size(16, 0), 0);
+
+        assert_eq!(Packing::Packed.place_aggregate(4), 4);
+        assert_eq!(Packing::Packed.array_size(4, 3), 12);
+        assert_eq!(Packing::Packed.array_size(12, 2), 24);
+    }
+
+    #[test]
+    fn nested_structs_start_and_end_on_a_register() {
+        let cb = Packing::ConstantBuffer;
+        assert_eq!(std::mem::offset_of!(Scene, light), 16);
+        assert_eq!(std::mem::offset_of!(Scene, exposure), 32);
+        const { assert!(<Light as HlslType>::AGGREGATE && <Light as HlslType>::CONSTANT_BUFFER_LAYOUT) };
+        const { assert!(!<Packed as HlslType>::CONSTANT_BUFFER_LAYOUT) };
+
+        assert_eq!(layout_matches(cb, &[field(0, 4), aggregate(16, 16, true), field(32, 4)], 36), Ok(()));
+        //構造体はレジスタの先頭から
+        assert!(layout_matches(cb, &[field(0, 4), aggregate(4, 16, true)], 20).is_err());
+        //構造体の後ろは次のレジスタから 残りが空いていても入れない
+        assert!(layout_matches(cb, &[aggregate(0, 12, true), field(12, 4)], 16).is_err());
+        assert_eq!(layout_matches(cb, &[aggregate(0, 12, true), field(16, 4)], 20), Ok(()));
+        //中身がcbufferのパッキングに合わない構造体は入れられない
+        assert_eq!(layout_matches(cb, &[aggregate(0, 20, false)], 20), Err("nested struct does not follow the cbuffer layout"));
+
+        //詰める方ではそのまま続ける
+        assert_eq!(layout_matches(Packing::Packed, &[field(0, 4), aggregate(4, 20, false), field(24, 4)], 28), Ok(()));
+    }
+
+    #[test]
+    fn declarations_list_the_members() {
+        assert_eq!(Light::hlsl_declaration(), "struct Light {\n    float3 direction;\n    float intensity;\n};\n");
+        assert_eq!(
+            Scene::hlsl_declaration(),
+            "cbuffer Scene {\n    uint gCount;\n    uint3 gPad;\n    Light gLight;\n    float gExposure;\n};\n"
+        );
+    }
+
+    //check_declaredのパニックのメッセージ
+    fn declared<T: HlslStruct>(source: &str) -> Result<(), String> {
+        std::panic::catch_unwind(|| check_declared::<T>(source.as_bytes())).map_err(|e| match e.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(e) => *e.downcast::<String>().unwrap(),
+        })
+    }
+
+    #[test]
+    fn check_declared_skips_comments_and_registers() {
+        let source = "
+            struct NotLight { float3 direction; };
+            /* cbuffer Scene { float wrong; } */
+            cbuffer Scene : register(b1) //コメント
+            {
+                uint gCount; uint3 gPad;
+                Light /*光源*/ gLight;
+                float   gExposure ;
+            };";
+        assert_eq!(declared::<Scene>(source), Ok(()));
+        assert_eq!(declared::<Light>("struct Light{float3 direction;float intensity;}"), Ok(()));
+    }
+
+    #[test]
+    fn check_declared_reports_the_difference() {
+        let cases = [
+            ("struct Lights { float3 direction; float intensity; };", "HLSL declaration not found in the shader source"),
+            ("cbuffer Light { float3 direction; float intensity; };", "HLSL declaration not found in the shader source"),
+            ("struct Light { float4 direction; float intensity; };", "HLSL member type differs from the Rust field type"),
+            ("struct Light { float3 dir; float intensity; };", "HLSL member name differs from the Rust field name"),
+            ("struct Light { float3 direction : POSITION; float intensity; };", "HLSL member is not a plain `type name;` declaration"),
+            ("struct Light { float3 direction; };", "HLSL declaration has fewer members than the Rust struct"),
+            ("struct Light { float3 direction; float intensity; float range; };", "HLSL declaration has more members than the Rust struct"),
+        ];
+
+        for (source, message) in cases {
+            assert_eq!(declared::<Light>(source), Err(message.to_string()), "{}", source);
+        }
+    }
+}
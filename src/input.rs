@@ -0,0 +1,112 @@
+//ウィンドウのメッセージをプラットフォームに依らない形にしたもの
+//Win32ではwndprocがWM_*からこれに変換する
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    W,
+    A,
+    S,
+    D,
+    Q,
+    E,
+    Space,
+    Shift,
+    Left,
+    Right,
+    Up,
+    Down,
+    Escape,
+    //上以外はプラットフォームのキーコードのまま
+    Other(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    KeyDown(Key),
+    KeyUp(Key),
+    //クライアント領域の左上からのピクセル位置
+    MouseMove { x: f32, y: f32 },
+    MouseDown(MouseButton),
+    MouseUp(MouseButton),
+    //奥に回すと正 1ノッチで1.0
+    Wheel(f32),
+    Resize { width: u32, height: u32 },
+    //離したイベントが来なくなるので押しているものを全部離す
+    FocusLost,
+}
+
+//押されているキーとボタン、マウスの移動量を溜める
+#[derive(Clone, Debug, Default)]
+pub struct InputState {
+    keys: Vec<Key>,
+    buttons: Vec<MouseButton>,
+    mouse: Option<(f32, f32)>,
+    //前回take_mouse_deltaしてからの移動量
+    mouse_delta: (f32, f32),
+    wheel: f32,
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle(&mut self, event: &InputEvent) {
+        match *event {
+            InputEvent::KeyDown(key) => {
+                if !self.keys.contains(&key) {
+                    self.keys.push(key);
+                }
+            }
+            InputEvent::KeyUp(key) => self.keys.retain(|&k| k != key),
+            InputEvent::MouseMove { x, y } => {
+                if let Some((last_x, last_y)) = self.mouse {
+                    self.mouse_delta.0 += x - last_x;
+                    self.mouse_delta.1 += y - last_y;
+                }
+                self.mouse = Some((x, y));
+            }
+            InputEvent::MouseDown(button) => {
+                if !self.buttons.contains(&button) {
+                    self.buttons.push(button);
+                }
+            }
+            InputEvent::MouseUp(button) => self.buttons.retain(|&b| b != button),
+            InputEvent::Wheel(delta) => self.wheel += delta,
+            InputEvent::Resize { .. } => {}
+            InputEvent::FocusLost => {
+                self.keys.clear();
+                self.buttons.clear();
+                self.mouse = None;
+            }
+        }
+    }
+
+    pub fn is_key_down(&self, key: Key) -> bool {
+        self.keys.contains(&key)
+    }
+
+    pub fn is_button_down(&self, button: MouseButton) -> bool {
+        self.buttons.contains(&button)
+    }
+
+    pub fn mouse_position(&self) -> Option<(f32, f32)> {
+        self.mouse
+    }
+
+    //溜まった移動量を返して0に戻す
+    pub fn take_mouse_delta(&mut self) -> (f32, f32) {
+        std::mem::take(&mut self.mouse_delta)
+    }
+
+    pub fn take_wheel(&mut self) -> f32 {
+        std::mem::take(&mut self.wheel)
+    }
+}
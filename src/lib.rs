@@ -1,3 +1,25 @@
-pub(crate) mod vertex;
+pub mod as_planner;
+pub mod buffer_allocator;
+pub mod camera;
+pub mod config;
+pub mod cpu;
+pub mod descriptor_allocator;
+pub mod dxbc;
+pub mod error;
+pub mod frame_ring;
+pub mod hlsl_layout;
+pub mod input;
+pub mod math;
+pub mod md5;
+pub mod pipeline_config;
+pub mod render_graph;
+pub mod resource_state;
+pub mod root_signature;
+pub mod scene_file;
+pub mod shader_library;
+pub mod shader_manifest;
+pub mod shader_table;
+pub mod vertex;
 
-pub mod wnd;
\ No newline at end of file
+#[cfg(windows)]
+pub mod wnd;
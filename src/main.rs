@@ -1,6 +1,184 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+
+use rwr::config::{Command, Config};
+use rwr::cpu;
+use rwr::cpu::shaders;
+use rwr::dxbc::Container;
+use rwr::error::{Context, Result, RwrError};
+use rwr::render_graph::FrameGraph;
+use rwr::shader_manifest::{digest_hex, ShaderManifest};
+#[cfg(windows)]
 use rwr::wnd;
-use windows::core::*;
 
-fn main() -> Result<()> {
-    wnd::run_with_raytracing()
+const HEADLESS_SIZE: (u32, u32) = (640, 480);
+
+fn main() {
+    if let Err(e) = try_main() {
+        //contextから原因まで順に出す
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(e) = source {
+            message = format!("{}: {}", message, e);
+            source = e.source();
+        }
+        eprintln!("rwr: {}", message);
+        std::process::exit(1);
+    }
+}
+
+fn try_main() -> Result<()> {
+    let config = Config::from_args(std::env::args().skip(1))?;
+
+    match config.command {
+        Command::Info => return info(&config),
+        Command::Graph => return graph(&config),
+        Command::Compile => return compile(&config),
+        Command::Render => {}
+    }
+
+    //マニフェストがなければ何もしない
+    if Path::new(&config.manifest).exists() {
+        match ShaderManifest::load(&config.manifest) {
+            Ok(manifest) => manifest.check().iter().for_each(|w| eprintln!("rwr: warning: {}", w)),
+            Err(e) => eprintln!("rwr: warning: {}", e),
+        }
+    }
+
+    if config.headless {
+        headless(&config)
+    } else {
+        run(&config)
+    }
+}
+
+fn headless(config: &Config) -> Result<()> {
+    let scene = config.scene().context("failed to load the scene")?;
+    //パノラマは縦横比が決まっているので高さに合わせて幅を変える
+    let (width, height) = match scene.projection(1.0)?.fixed_aspect() {
+        Some(aspect) => ((HEADLESS_SIZE.1 as f32 * aspect).round() as u32, HEADLESS_SIZE.1),
+        None => HEADLESS_SIZE,
+    };
+    let pipeline_config = shaders::pipeline_config(config.max_recursion_depth)?;
+    let mask = config.alpha_mask.then(cpu::texture::sample_alpha_mask);
+    let camera = scene.camera(width as f32 / height as f32)?;
+    let pixels = cpu::render_headless(width, height, &camera, mask, &pipeline_config).context("headless render failed")?;
+
+    let asset = |source| RwrError::Asset { path: config.output.clone(), source };
+    let mut out = BufWriter::new(File::create(&config.output).map_err(asset)?);
+    cpu::image::write_ppm(&mut out, width, height, &pixels).map_err(asset)?;
+
+    println!("wrote {}", config.output);
+    Ok(())
+}
+
+fn info(config: &Config) -> Result<()> {
+    let mut manifest = ShaderManifest::load(&config.manifest)?;
+
+    if config.update_manifest {
+        let entries: Vec<_> = manifest.entries.iter().map(|e| (e.source.clone(), e.output.clone())).collect();
+        for (source, output) in entries {
+            manifest.record(source, output)?;
+        }
+        manifest.save(&config.manifest)?;
+        println!("updated {}", config.manifest);
+    }
+
+    for entry in &manifest.entries {
+        let output = manifest.resolve(&entry.output);
+        println!("{} (from {})", output.display(), manifest.resolve(&entry.source).display());
+        println!("  manifest: source {} shader {}", digest_hex(&entry.source_hash), digest_hex(&entry.shader_hash));
+
+        let Ok(bytes) = std::fs::read(&output) else {
+            continue;
+        };
+        let Ok(container) = Container::parse(&bytes) else {
+            continue;
+        };
+
+        if let Ok(hash) = container.hash() {
+            println!("  hash: {}", hash);
+        }
+        if let Ok(program) = container.program() {
+            println!("  {} {}.{}, DXIL {}.{}", program.kind, program.shader_model.0, program.shader_model.1, program.dxil_version.0, program.dxil_version.1);
+        }
+        if let Ok(runtime_data) = container.runtime_data() {
+            for r in &runtime_data.resources {
+                println!("  resource {} {}{} space{}", r.name, r.class.register_prefix(), r.lower_bound, r.space);
+            }
+            for f in &runtime_data.functions {
+                println!("  export {} ({}) payload {} attributes {}", f.name, f.kind, f.payload_size, f.attribute_size);
+            }
+        }
+    }
+
+    let warnings = manifest.check();
+    warnings.iter().for_each(|w| println!("warning: {}", w));
+    if warnings.is_empty() {
+        println!("all shaders are up to date");
+    }
+
+    Ok(())
+}
+
+//DXRのライブラリとしてコンパイルし、シーンのエクスポートが揃っているか確かめてからマニフェストに書く
+fn compile(config: &Config) -> Result<()> {
+    let mut manifest = ShaderManifest::load(&config.manifest)?;
+    let scene = config.scene().context("failed to load the scene")?;
+    let library = shaders::ray_shader_library(&scene.exports, shaders::default_materials().names());
+    let pipeline_config = shaders::pipeline_config(config.max_recursion_depth)?;
+
+    let entries: Vec<_> = manifest.entries.iter().map(|e| (e.source.clone(), e.output.clone())).collect();
+    for (source, output) in entries {
+        let (source_path, output_path) = (manifest.resolve(&source), manifest.resolve(&output));
+        let path = source_path.display().to_string();
+
+        let result = process::Command::new(&config.dxc)
+            .args(["-T", "lib_6_4", "-Fo"])
+            .arg(&output_path)
+            .arg(&source_path)
+            .output()
+            .map_err(|e| RwrError::ShaderCompile { path: path.clone(), message: format!("failed to run {}: {}", config.dxc, e) })?;
+        if !result.status.success() {
+            return Err(RwrError::ShaderCompile { path, message: String::from_utf8_lossy(&result.stderr).trim().to_string() });
+        }
+
+        let output_name = output_path.display().to_string();
+        let bytes = std::fs::read(&output_path).map_err(|source| RwrError::Asset { path: output_name.clone(), source })?;
+        let runtime_data = Container::parse(&bytes)
+            .and_then(|c| c.runtime_data())
+            .map_err(|source| RwrError::ShaderParse { path: output_name.clone(), source })?;
+        library.validate(&runtime_data, &pipeline_config).with_context(|| format!("{} does not match the shader library", output_name))?;
+
+        manifest.record(source, output)?;
+        println!("compiled {}", output_name);
+    }
+
+    manifest.save(&config.manifest)?;
+    println!("updated {}", config.manifest);
+    Ok(())
+}
+
+fn graph(config: &Config) -> Result<()> {
+    let (width, height) = HEADLESS_SIZE;
+    let mask = config.alpha_mask.then(cpu::texture::sample_alpha_mask);
+    let alpha_mask_size = mask.map_or(0, |m| std::mem::size_of_val(m.texels.as_slice()) as u64);
+
+    let graph = FrameGraph::new(width, height, alpha_mask_size).graph.compile().context("failed to compile the frame graph")?;
+    print!("{}", graph);
+    Ok(())
+}
+
+#[cfg(windows)]
+fn run(config: &Config) -> Result<()> {
+    wnd::run_with_raytracing(config)
+}
+
+//DXRが使えない環境ではヘッドレスで描く
+#[cfg(not(windows))]
+fn run(config: &Config) -> Result<()> {
+    eprintln!("rwr: the DXR renderer requires Windows, rendering headless instead");
+    headless(config)
 }
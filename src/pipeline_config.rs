@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fmt;
+
+//D3D12_RAYTRACING_SHADER_CONFIGとD3D12_RAYTRACING_PIPELINE_CONFIGの値を型から決める
+
+//D3D12_RAYTRACING_MAX_ATTRIBUTE_SIZE_IN_BYTES
+pub const MAX_ATTRIBUTE_SIZE: u32 = 32;
+//D3D12_RAYTRACING_MAX_DECLARABLE_TRACE_RECURSION_DEPTH
+pub const MAX_DECLARABLE_TRACE_RECURSION_DEPTH: u32 = 31;
+//raygen -> closest-hit -> 影のレイ
+pub const DEFAULT_MAX_RECURSION_DEPTH: u32 = 2;
+//raygenのTraceRay
+pub const MIN_RECURSION_DEPTH: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PipelineConfigError {
+    AttributeTooLarge(u32),
+    //ペイロードとアトリビュートは4バイトのスカラーの集まり
+    UnalignedSize(u32),
+    RecursionTooDeep(u32),
+    //シェーダーがTraceRayを呼ぶ段まで届かない
+    RecursionTooShallow { depth: u32, required: u32 },
+}
+
+impl fmt::Display for PipelineConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineConfigError::AttributeTooLarge(size) => {
+                write!(f, "attribute size {} exceeds {} bytes", size, MAX_ATTRIBUTE_SIZE)
+            }
+            PipelineConfigError::UnalignedSize(size) => write!(f, "payload/attribute size {} is not a multiple of 4", size),
+            PipelineConfigError::RecursionTooDeep(depth) => {
+                write!(f, "recursion depth {} exceeds {}", depth, MAX_DECLARABLE_TRACE_RECURSION_DEPTH)
+            }
+            PipelineConfigError::RecursionTooShallow { depth, required } => {
+                write!(f, "recursion depth {} is below the {} needed by the shaders", depth, required)
+            }
+        }
+    }
+}
+
+impl Error for PipelineConfigError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PipelineConfig {
+    pub max_payload_size: u32,
+    pub max_attribute_size: u32,
+    //raygenが0段目 TraceRayを呼ぶたびに1段深くなる
+    pub max_recursion_depth: u32,
+    //シェーダーがTraceRayを呼ぶ一番深い段 raygenからなら1
+    pub required_recursion_depth: u32,
+}
+
+impl PipelineConfig {
+    pub fn new(max_recursion_depth: u32) -> Self {
+        PipelineConfig { max_payload_size: 0, max_attribute_size: 0, max_recursion_depth, required_recursion_depth: MIN_RECURSION_DEPTH }
+    }
+
+    //シェーダーで使うペイロードの型(HLSLと同じレイアウトのもの)を全部登録する
+    pub fn payload<T>(mut self) -> Self {
+        self.max_payload_size = self.max_payload_size.max(std::mem::size_of::<T>() as u32);
+        self
+    }
+
+    pub fn attribute<T>(mut self) -> Self {
+        self.max_attribute_size = self.max_attribute_size.max(std::mem::size_of::<T>() as u32);
+        self
+    }
+
+    //depth段目のシェーダーがTraceRayを呼ぶ closest-hitからの影のレイなら2
+    pub fn trace_depth(mut self, depth: u32) -> Self {
+        self.required_recursion_depth = self.required_recursion_depth.max(depth);
+        self
+    }
+
+    pub fn validate(self) -> Result<Self, PipelineConfigError> {
+        if self.max_attribute_size > MAX_ATTRIBUTE_SIZE {
+            return Err(PipelineConfigError::AttributeTooLarge(self.max_attribute_size));
+        }
+
+        for size in [self.max_payload_size, self.max_attribute_size] {
+            if !size.is_multiple_of(4) {
+                return Err(PipelineConfigError::UnalignedSize(size));
+            }
+        }
+
+        if self.max_recursion_depth > MAX_DECLARABLE_TRACE_RECURSION_DEPTH {
+            return Err(PipelineConfigError::RecursionTooDeep(self.max_recursion_depth));
+        }
+
+        if self.max_recursion_depth < self.required_recursion_depth {
+            return Err(PipelineConfigError::RecursionTooShallow { depth: self.max_recursion_depth, required: self.required_recursion_depth });
+        }
+
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribute_size_is_limited() {
+        assert_eq!(PipelineConfig::new(1).attribute::<[f32; 8]>().validate().map(|c| c.max_attribute_size), Ok(32));
+        assert_eq!(PipelineConfig::new(1).attribute::<[f32; 9]>().validate(), Err(PipelineConfigError::AttributeTooLarge(36)));
+        //ペイロードには上限がない
+        assert!(PipelineConfig::new(1).payload::<[f32; 64]>().validate().is_ok());
+    }
+
+    #[test]
+    fn sizes_are_multiples_of_4() {
+        assert_eq!(PipelineConfig::new(1).payload::<[u8; 6]>().validate(), Err(PipelineConfigError::UnalignedSize(6)));
+        assert_eq!(PipelineConfig::new(1).attribute::<[u16; 3]>().validate(), Err(PipelineConfigError::UnalignedSize(6)));
+        //一番大きいものだけを見る
+        assert_eq!(PipelineConfig::new(1).payload::<[u8; 6]>().payload::<[f32; 4]>().validate().map(|c| c.max_payload_size), Ok(16));
+    }
+
+    #[test]
+    fn depth_must_reach_the_deepest_trace() {
+        assert_eq!(PipelineConfig::new(0).validate(), Err(PipelineConfigError::RecursionTooShallow { depth: 0, required: 1 }));
+        assert!(PipelineConfig::new(1).validate().is_ok());
+
+        //closest-hitから影のレイを飛ばす
+        let shadow = |depth| PipelineConfig::new(depth).trace_depth(2).validate();
+        assert_eq!(shadow(1), Err(PipelineConfigError::RecursionTooShallow { depth: 1, required: 2 }));
+        assert!(shadow(2).is_ok());
+        assert_eq!(PipelineConfig::new(1).trace_depth(2).trace_depth(1).required_recursion_depth, 2);
+    }
+
+    #[test]
+    fn depth_is_limited_to_31() {
+        assert!(PipelineConfig::new(MAX_DECLARABLE_TRACE_RECURSION_DEPTH).validate().is_ok());
+        assert_eq!(PipelineConfig::new(32).validate(), Err(PipelineConfigError::RecursionTooDeep(32)));
+    }
+}
@@ -0,0 +1,641 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::resource_state::{ Access, Barrier, ResourceId, ResourceStateTracker, ResourceStates };
+
+//1フレームのパスと、パスが読み書きするリソースを宣言してから実行順とバリアを決める
+//出力に届かないパスは捨て、寿命が重ならない一時リソースは同じメモリに置く
+//実行はGraphBackendに任せるのでDXRとCPUで同じグラフを使う
+
+pub const DXGI_FORMAT_R8G8B8A8_UNORM: u32 = 28;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Texture { width: u32, height: u32, format: u32 },
+    Buffer { size: u64 },
+    AccelerationStructure,
+}
+
+impl ResourceKind {
+    //一時リソースを置くのに必要な大きさ
+    pub fn size(&self) -> u64 {
+        match *self {
+            ResourceKind::Texture { width, height, format } => width as u64 * height as u64 * bytes_per_pixel(format),
+            ResourceKind::Buffer { size } => size,
+            ResourceKind::AccelerationStructure => 0,
+        }
+    }
+}
+
+fn bytes_per_pixel(format: u32) -> u64 {
+    use crate::hlsl_layout::*;
+    match format {
+        DXGI_FORMAT_R32G32B32A32_FLOAT | DXGI_FORMAT_R32G32B32A32_UINT | DXGI_FORMAT_R32G32B32A32_SINT => 16,
+        DXGI_FORMAT_R32G32B32_FLOAT | DXGI_FORMAT_R32G32B32_UINT | DXGI_FORMAT_R32G32B32_SINT => 12,
+        DXGI_FORMAT_R32G32_FLOAT | DXGI_FORMAT_R32G32_UINT | DXGI_FORMAT_R32G32_SINT => 8,
+        _ => 4,
+    }
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceKind::Texture { width, height, format } => write!(f, "texture {}x{} format {}", width, height, format),
+            ResourceKind::Buffer { size } => write!(f, "buffer {} bytes", size),
+            ResourceKind::AccelerationStructure => write!(f, "acceleration structure"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResourceDesc {
+    pub name: String,
+    pub kind: ResourceKind,
+    //外から渡すリソースの状態 グラフの最後でこの状態に戻す Noneならグラフの中だけで使う一時リソース
+    pub imported: Option<ResourceStates>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Usage {
+    ShaderRead,
+    UnorderedAccess,
+    AccelerationStructure,
+    CopySource,
+    CopyDest,
+    Present,
+}
+
+impl Usage {
+    pub fn state(self) -> ResourceStates {
+        match self {
+            Usage::ShaderRead => ResourceStates::NON_PIXEL_SHADER_RESOURCE,
+            Usage::UnorderedAccess => ResourceStates::UNORDERED_ACCESS,
+            Usage::AccelerationStructure => ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE,
+            Usage::CopySource => ResourceStates::COPY_SOURCE,
+            Usage::CopyDest => ResourceStates::COPY_DEST,
+            Usage::Present => ResourceStates::PRESENT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PassKind {
+    RayTrace,
+    //reads[0]のサンプルをwrites[0]の平均に足す
+    Accumulate,
+    //reads[0]を表示できる範囲にしてwrites[0]に書く
+    PostProcess,
+    Copy,
+    //スワップチェーンに出す 結果を使うパスがなくても残す
+    Present,
+}
+
+impl fmt::Display for PassKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassKind::RayTrace => write!(f, "ray trace"),
+            PassKind::Accumulate => write!(f, "accumulate"),
+            PassKind::PostProcess => write!(f, "post-process"),
+            PassKind::Copy => write!(f, "copy"),
+            PassKind::Present => write!(f, "present"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pass {
+    pub name: String,
+    pub kind: PassKind,
+    pub reads: Vec<(ResourceId, Usage)>,
+    pub writes: Vec<(ResourceId, Usage)>,
+}
+
+impl Pass {
+    pub fn new(name: impl Into<String>, kind: PassKind) -> Self {
+        Pass { name: name.into(), kind, reads: vec![], writes: vec![] }
+    }
+
+    pub fn read(mut self, resource: ResourceId, usage: Usage) -> Self {
+        self.reads.push((resource, usage));
+        self
+    }
+
+    pub fn write(mut self, resource: ResourceId, usage: Usage) -> Self {
+        self.writes.push((resource, usage));
+        self
+    }
+
+    fn uses(&self) -> impl Iterator<Item = (ResourceId, Usage, Access)> + '_ {
+        let reads = self.reads.iter().map(|&(r, u)| (r, u, Access::Read));
+        let writes = self.writes.iter().map(|&(r, u)| (r, u, Access::Write));
+        reads.chain(writes)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GraphError {
+    UnknownResource { pass: String, resource: ResourceId },
+    //一時リソースを前のパスで書く前に読んだ
+    ReadBeforeWrite { pass: String, resource: String },
+    //1つのパスで同じリソースを違う状態で使った
+    ConflictingUsage { pass: String, resource: String },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphError::UnknownResource { pass, resource } => write!(f, "pass {} uses unknown resource {}", pass, resource.0),
+            GraphError::ReadBeforeWrite { pass, resource } => write!(f, "pass {} reads {} before any pass writes it", pass, resource),
+            GraphError::ConflictingUsage { pass, resource } => write!(f, "pass {} uses {} in conflicting states", pass, resource),
+        }
+    }
+}
+
+impl Error for GraphError {}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderGraph {
+    resources: Vec<ResourceDesc>,
+    passes: Vec<Pass>,
+}
+
+impl RenderGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //stateは渡したときの状態
+    pub fn import(&mut self, name: impl Into<String>, kind: ResourceKind, state: ResourceStates) -> ResourceId {
+        self.resources.push(ResourceDesc { name: name.into(), kind, imported: Some(state) });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    pub fn create(&mut self, name: impl Into<String>, kind: ResourceKind) -> ResourceId {
+        self.resources.push(ResourceDesc { name: name.into(), kind, imported: None });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    //宣言した順に実行する
+    pub fn pass(&mut self, pass: Pass) {
+        self.passes.push(pass);
+    }
+
+    pub fn compile(&self) -> Result<CompiledGraph, GraphError> {
+        self.validate()?;
+
+        let kept = self.live_passes();
+        let culled = self.passes.iter().enumerate().filter(|(i, _)| !kept[*i]).map(|(_, p)| p.name.clone()).collect();
+        let order: Vec<usize> = (0..self.passes.len()).filter(|&i| kept[i]).collect();
+
+        let (placement, slots, aliasing) = self.place_transients(&order);
+
+        //一時リソースは最初に使う状態で置かれているものとする
+        let entry_states: Vec<ResourceStates> = self
+            .resources
+            .iter()
+            .enumerate()
+            .map(|(i, r)| r.imported.unwrap_or_else(|| self.first_use(&order, ResourceId(i)).map_or(ResourceStates::COMMON, |u| u.state())))
+            .collect();
+
+        let mut tracker = ResourceStateTracker::new();
+        for &state in &entry_states {
+            tracker.register(state, 1);
+        }
+
+        let mut passes = Vec::with_capacity(order.len());
+        for (n, &i) in order.iter().enumerate() {
+            let pass = &self.passes[i];
+            for (resource, usage, access) in pass.uses() {
+                tracker.require(resource, usage.state(), access);
+            }
+            passes.push(CompiledPass {
+                index: i,
+                pass: pass.clone(),
+                barriers: tracker.flush(),
+                aliasing: aliasing.iter().filter(|a| a.pass == n).copied().collect(),
+            });
+        }
+
+        //次のフレームも同じ順で実行できるように入ったときの状態に戻す
+        for (i, &state) in entry_states.iter().enumerate() {
+            if tracker.state(ResourceId(i)) != Some(state) {
+                tracker.require(ResourceId(i), state, Access::Read);
+            }
+        }
+
+        Ok(CompiledGraph { resources: self.resources.clone(), passes, final_barriers: tracker.flush(), culled, placement, slots })
+    }
+
+    fn validate(&self) -> Result<(), GraphError> {
+        let mut written = vec![false; self.resources.len()];
+
+        for pass in &self.passes {
+            for (resource, usage, _) in pass.uses() {
+                let Some(desc) = self.resources.get(resource.0) else {
+                    return Err(GraphError::UnknownResource { pass: pass.name.clone(), resource });
+                };
+
+                if pass.uses().any(|(r, u, _)| r == resource && u != usage) {
+                    return Err(GraphError::ConflictingUsage { pass: pass.name.clone(), resource: desc.name.clone() });
+                }
+
+                let read = pass.reads.iter().any(|&(r, _)| r == resource);
+                if read && desc.imported.is_none() && !written[resource.0] {
+                    return Err(GraphError::ReadBeforeWrite { pass: pass.name.clone(), resource: desc.name.clone() });
+                }
+            }
+
+            for &(resource, _) in &pass.writes {
+                written[resource.0] = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    //後ろから見て、外に出すリソースを書くパスと、残したパスが読むものを書くパスを残す
+    fn live_passes(&self) -> Vec<bool> {
+        let mut needed: Vec<bool> = self.resources.iter().map(|r| r.imported.is_some()).collect();
+        let mut kept = vec![false; self.passes.len()];
+
+        for (i, pass) in self.passes.iter().enumerate().rev() {
+            let live = pass.kind == PassKind::Present || pass.writes.iter().any(|&(r, _)| needed[r.0]);
+            if live {
+                kept[i] = true;
+                for &(r, _) in &pass.reads {
+                    needed[r.0] = true;
+                }
+            }
+        }
+
+        kept
+    }
+
+    fn first_use(&self, order: &[usize], resource: ResourceId) -> Option<Usage> {
+        order.iter().find_map(|&i| self.passes[i].uses().find(|&(r, _, _)| r == resource).map(|(_, u, _)| u))
+    }
+
+    //一時リソースを使い始めた順に、寿命が終わったスロットへ詰める
+    fn place_transients(&self, order: &[usize]) -> (Vec<Option<usize>>, Vec<u64>, Vec<Aliasing>) {
+        let mut lifetimes: Vec<(usize, usize, ResourceId)> = vec![];
+        for (i, r) in self.resources.iter().enumerate() {
+            if r.imported.is_some() {
+                continue;
+            }
+            let id = ResourceId(i);
+            let mut used = order.iter().enumerate().filter(|(_, &p)| self.passes[p].uses().any(|(u, _, _)| u == id)).map(|(n, _)| n);
+            if let Some(first) = used.next() {
+                lifetimes.push((first, used.next_back().unwrap_or(first), id));
+            }
+        }
+        lifetimes.sort_by_key(|&(first, last, id)| (first, last, id.0));
+
+        let mut placement = vec![None; self.resources.len()];
+        //(大きさ, 最後に使うパス, 今置いているリソース)
+        let mut slots: Vec<(u64, usize, ResourceId)> = vec![];
+        let mut aliasing = vec![];
+
+        for (first, last, id) in lifetimes {
+            let size = self.resources[id.0].kind.size();
+            match slots.iter().position(|&(_, end, _)| end < first) {
+                Some(s) => {
+                    aliasing.push(Aliasing { pass: first, slot: s, before: Some(slots[s].2), after: id });
+                    slots[s] = (slots[s].0.max(size), last, id);
+                    placement[id.0] = Some(s);
+                }
+                None => {
+                    aliasing.push(Aliasing { pass: first, slot: slots.len(), before: None, after: id });
+                    placement[id.0] = Some(slots.len());
+                    slots.push((size, last, id));
+                }
+            }
+        }
+
+        (placement, slots.into_iter().map(|(size, _, _)| size).collect(), aliasing)
+    }
+}
+
+//passはコンパイル後の順番 beforeがNoneならスロットを初めて使う
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Aliasing {
+    pub pass: usize,
+    pub slot: usize,
+    pub before: Option<ResourceId>,
+    pub after: ResourceId,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompiledPass {
+    //RenderGraphに宣言した順番
+    pub index: usize,
+    pub pass: Pass,
+    //パスの前に出すバリア
+    pub barriers: Vec<Barrier>,
+    pub aliasing: Vec<Aliasing>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompiledGraph {
+    pub resources: Vec<ResourceDesc>,
+    pub passes: Vec<CompiledPass>,
+    //最後のパスの後に出すバリア
+    pub final_barriers: Vec<Barrier>,
+    pub culled: Vec<String>,
+    //一時リソースを置くスロット 使われない一時リソースはNone
+    pub placement: Vec<Option<usize>>,
+    pub slots: Vec<u64>,
+}
+
+//グラフを実行する側 バリアが要らないバックエンドはbarriersを実装しなくていい
+pub trait GraphBackend {
+    type Error;
+
+    fn barriers(&mut self, _graph: &CompiledGraph, _barriers: &[Barrier], _aliasing: &[Aliasing]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn execute(&mut self, graph: &CompiledGraph, pass: &CompiledPass) -> Result<(), Self::Error>;
+}
+
+impl CompiledGraph {
+    pub fn execute<B: GraphBackend>(&self, backend: &mut B) -> Result<(), B::Error> {
+        for pass in &self.passes {
+            backend.barriers(self, &pass.barriers, &pass.aliasing)?;
+            backend.execute(self, pass)?;
+        }
+        backend.barriers(self, &self.final_barriers, &[])
+    }
+
+    pub fn name(&self, resource: ResourceId) -> &str {
+        &self.resources[resource.0].name
+    }
+}
+
+//graphコマンドで出す実行順
+impl fmt::Display for CompiledGraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "resources:")?;
+        for (i, r) in self.resources.iter().enumerate() {
+            match (r.imported, self.placement[i]) {
+                (Some(state), _) => writeln!(f, "  {} ({}) imported in {}", r.name, r.kind, state)?,
+                (None, Some(slot)) => writeln!(f, "  {} ({}) transient in slot {}", r.name, r.kind, slot)?,
+                (None, None) => writeln!(f, "  {} ({}) unused", r.name, r.kind)?,
+            }
+        }
+
+        writeln!(f, "slots:")?;
+        for (i, size) in self.slots.iter().enumerate() {
+            let names: Vec<&str> = (0..self.resources.len()).filter(|&r| self.placement[r] == Some(i)).map(|r| self.resources[r].name.as_str()).collect();
+            writeln!(f, "  {}: {} bytes, {}", i, size, names.join(", "))?;
+        }
+
+        writeln!(f, "passes:")?;
+        for (n, p) in self.passes.iter().enumerate() {
+            writeln!(f, "  {}: {} ({})", n, p.pass.name, p.pass.kind)?;
+            for a in &p.aliasing {
+                if let Some(before) = a.before {
+                    writeln!(f, "    alias slot {} {} -> {}", a.slot, self.name(before), self.name(a.after))?;
+                }
+            }
+            self.fmt_barriers(f, &p.barriers)?;
+            for &(r, u) in &p.pass.reads {
+                writeln!(f, "    read {} as {:?}", self.name(r), u)?;
+            }
+            for &(r, u) in &p.pass.writes {
+                writeln!(f, "    write {} as {:?}", self.name(r), u)?;
+            }
+        }
+
+        if !self.final_barriers.is_empty() {
+            writeln!(f, "end:")?;
+            self.fmt_barriers(f, &self.final_barriers)?;
+        }
+
+        if self.culled.is_empty() {
+            writeln!(f, "culled: none")
+        } else {
+            writeln!(f, "culled: {}", self.culled.join(", "))
+        }
+    }
+}
+
+impl CompiledGraph {
+    fn fmt_barriers(&self, f: &mut fmt::Formatter, barriers: &[Barrier]) -> fmt::Result {
+        for b in barriers {
+            match *b {
+                Barrier::Transition { resource, before, after, .. } => writeln!(f, "    barrier {} {} -> {}", self.name(resource), before, after)?,
+                Barrier::Uav { resource } => writeln!(f, "    barrier {} uav", self.name(resource))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+//どちらのバックエンドも使う1フレーム
+//レイトレの結果をフレームをまたいで平均し、表示できる範囲にしてバックバッファにコピーして出す
+//post_outputはtrace_outputと寿命が重ならないので同じスロットに置かれる DXRの方はどちらもu0に書く
+#[derive(Clone, Debug)]
+pub struct FrameGraph {
+    pub graph: RenderGraph,
+    pub tlas: ResourceId,
+    pub alpha_mask: ResourceId,
+    //フレームをまたいで残す平均
+    pub accumulation: ResourceId,
+    pub back_buffer: ResourceId,
+    pub trace_output: ResourceId,
+    pub post_output: ResourceId,
+}
+
+impl FrameGraph {
+    //alpha_mask_sizeはアルファマスクのStructuredBufferのバイト数
+    pub fn new(width: u32, height: u32, alpha_mask_size: u64) -> Self {
+        let mut graph = RenderGraph::new();
+        let output = ResourceKind::Texture { width, height, format: DXGI_FORMAT_R8G8B8A8_UNORM };
+        let hdr = ResourceKind::Texture { width, height, format: crate::hlsl_layout::DXGI_FORMAT_R32G32B32A32_FLOAT };
+
+        let tlas = graph.import("tlas", ResourceKind::AccelerationStructure, ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE);
+        let alpha_mask = graph.import("alpha_mask", ResourceKind::Buffer { size: alpha_mask_size }, ResourceStates::GENERIC_READ);
+        let accumulation = graph.import("accumulation", hdr, ResourceStates::UNORDERED_ACCESS);
+        let back_buffer = graph.import("back_buffer", output, ResourceStates::PRESENT);
+        let trace_output = graph.create("trace_output", output);
+        let post_output = graph.create("post_output", output);
+
+        graph.pass(
+            Pass::new("trace", PassKind::RayTrace)
+                .read(tlas, Usage::AccelerationStructure)
+                .read(alpha_mask, Usage::ShaderRead)
+                .write(trace_output, Usage::UnorderedAccess),
+        );
+        //シェーダーはどちらもRWTexture2Dで読む
+        graph.pass(
+            Pass::new("accumulate", PassKind::Accumulate)
+                .read(trace_output, Usage::UnorderedAccess)
+                .read(accumulation, Usage::UnorderedAccess)
+                .write(accumulation, Usage::UnorderedAccess),
+        );
+        graph.pass(
+            Pass::new("post_process", PassKind::PostProcess)
+                .read(accumulation, Usage::UnorderedAccess)
+                .write(post_output, Usage::UnorderedAccess),
+        );
+        graph.pass(Pass::new("copy", PassKind::Copy).read(post_output, Usage::CopySource).write(back_buffer, Usage::CopyDest));
+        graph.pass(Pass::new("present", PassKind::Present).read(back_buffer, Usage::Present));
+
+        FrameGraph { graph, tlas, alpha_mask, accumulation, back_buffer, trace_output, post_output }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource_state::ALL_SUBRESOURCES;
+
+    const TEXTURE: ResourceKind = ResourceKind::Texture { width: 4, height: 4, format: DXGI_FORMAT_R8G8B8A8_UNORM };
+
+    fn transition(resource: ResourceId, before: ResourceStates, after: ResourceStates) -> Barrier {
+        Barrier::Transition { resource, subresource: ALL_SUBRESOURCES, before, after }
+    }
+
+    //traceがaを書き、unusedがbを書くが誰も読まない
+    fn graph_with_unused_pass() -> (RenderGraph, [ResourceId; 3]) {
+        let mut graph = RenderGraph::new();
+        let out = graph.import("out", TEXTURE, ResourceStates::COPY_DEST);
+        let a = graph.create("a", TEXTURE);
+        let b = graph.create("b", TEXTURE);
+        graph.pass(Pass::new("trace", PassKind::RayTrace).write(a, Usage::UnorderedAccess));
+        graph.pass(Pass::new("unused", PassKind::PostProcess).read(a, Usage::UnorderedAccess).write(b, Usage::UnorderedAccess));
+        graph.pass(Pass::new("copy", PassKind::Copy).read(a, Usage::CopySource).write(out, Usage::CopyDest));
+        (graph, [out, a, b])
+    }
+
+    #[test]
+    fn passes_without_used_outputs_are_culled() {
+        let (graph, [_, a, b]) = graph_with_unused_pass();
+        let compiled = graph.compile().unwrap();
+
+        assert_eq!(compiled.culled, vec!["unused".to_string()]);
+        let names: Vec<&str> = compiled.passes.iter().map(|p| p.pass.name.as_str()).collect();
+        assert_eq!(names, ["trace", "copy"]);
+        assert_eq!(compiled.passes[1].index, 2);
+        assert_eq!(compiled.placement[a.0], Some(0));
+        assert_eq!(compiled.placement[b.0], None);
+
+        //Presentは読むパスがなくても残る
+        let mut graph = RenderGraph::new();
+        let back_buffer = graph.import("back_buffer", TEXTURE, ResourceStates::PRESENT);
+        graph.pass(Pass::new("present", PassKind::Present).read(back_buffer, Usage::Present));
+        assert_eq!(graph.compile().unwrap().passes.len(), 1);
+    }
+
+    #[test]
+    fn transients_share_a_slot_only_when_lifetimes_do_not_overlap() {
+        let big = ResourceKind::Texture { width: 8, height: 8, format: DXGI_FORMAT_R8G8B8A8_UNORM };
+        let mut graph = RenderGraph::new();
+        let out = graph.import("out", TEXTURE, ResourceStates::COPY_DEST);
+        let t1 = graph.create("t1", TEXTURE);
+        let t2 = graph.create("t2", TEXTURE);
+        let t3 = graph.create("t3", big);
+        graph.pass(Pass::new("p1", PassKind::RayTrace).write(t1, Usage::UnorderedAccess));
+        graph.pass(Pass::new("p2", PassKind::PostProcess).read(t1, Usage::UnorderedAccess).write(t2, Usage::UnorderedAccess));
+        graph.pass(Pass::new("p3", PassKind::PostProcess).read(t2, Usage::UnorderedAccess).write(t3, Usage::UnorderedAccess));
+        graph.pass(Pass::new("p4", PassKind::Copy).read(t3, Usage::CopySource).write(out, Usage::CopyDest));
+        let compiled = graph.compile().unwrap();
+
+        //t1はp2で終わるのでp3からのt3が同じスロットに入る t2はどちらとも重なる
+        assert_eq!(compiled.placement, [None, Some(0), Some(1), Some(0)]);
+        assert_eq!(compiled.slots, [big.size(), TEXTURE.size()]);
+        assert_eq!(compiled.passes[2].aliasing, [Aliasing { pass: 2, slot: 0, before: Some(t1), after: t3 }]);
+        assert_eq!(compiled.passes[0].aliasing, [Aliasing { pass: 0, slot: 0, before: None, after: t1 }]);
+
+        //p3を除くとt1とt2はp2で重なる
+        let mut graph = RenderGraph::new();
+        let out = graph.import("out", TEXTURE, ResourceStates::COPY_DEST);
+        let t1 = graph.create("t1", TEXTURE);
+        let t2 = graph.create("t2", TEXTURE);
+        graph.pass(Pass::new("p1", PassKind::RayTrace).write(t1, Usage::UnorderedAccess));
+        graph.pass(Pass::new("p2", PassKind::PostProcess).read(t1, Usage::UnorderedAccess).write(t2, Usage::UnorderedAccess));
+        graph.pass(Pass::new("p3", PassKind::Copy).read(t2, Usage::CopySource).write(out, Usage::CopyDest));
+        let compiled = graph.compile().unwrap();
+
+        assert_eq!(compiled.placement, [None, Some(0), Some(1)]);
+        assert!(compiled.passes.iter().flat_map(|p| &p.aliasing).all(|a| a.before.is_none()));
+    }
+
+    #[test]
+    fn frame_graph_barriers_are_ordered_by_pass() {
+        let frame = FrameGraph::new(4, 4, 16);
+        let compiled = frame.graph.compile().unwrap();
+        let uav = ResourceStates::UNORDERED_ACCESS;
+
+        let names: Vec<&str> = compiled.passes.iter().map(|p| p.pass.name.as_str()).collect();
+        assert_eq!(names, ["trace", "accumulate", "post_process", "copy", "present"]);
+        assert!(compiled.culled.is_empty());
+
+        //post_outputはtrace_outputのスロットを使い回す DXRの方はこれを前提にしている
+        assert_eq!(compiled.placement[frame.post_output.0], compiled.placement[frame.trace_output.0]);
+        assert_eq!(compiled.slots.len(), 1);
+
+        let barriers: Vec<&[Barrier]> = compiled.passes.iter().map(|p| p.barriers.as_slice()).collect();
+        assert_eq!(barriers[0], []);
+        assert_eq!(barriers[1], [Barrier::Uav { resource: frame.trace_output }, Barrier::Uav { resource: frame.accumulation }]);
+        assert_eq!(barriers[2], [Barrier::Uav { resource: frame.accumulation }]);
+        assert_eq!(
+            barriers[3],
+            [
+                transition(frame.post_output, uav, ResourceStates::COPY_SOURCE),
+                transition(frame.back_buffer, ResourceStates::PRESENT, ResourceStates::COPY_DEST),
+            ]
+        );
+        assert_eq!(barriers[4], [transition(frame.back_buffer, ResourceStates::COPY_DEST, ResourceStates::PRESENT)]);
+        //次のフレームのためにpost_outputを最初に使う状態へ戻す
+        assert_eq!(compiled.final_barriers, [transition(frame.post_output, ResourceStates::COPY_SOURCE, uav)]);
+    }
+
+    #[test]
+    fn invalid_graphs_are_rejected() {
+        let mut graph = RenderGraph::new();
+        graph.create("t", TEXTURE);
+        graph.pass(Pass::new("p", PassKind::Copy).read(ResourceId(7), Usage::CopySource));
+        let error = graph.compile().unwrap_err();
+        assert_eq!(error, GraphError::UnknownResource { pass: "p".to_string(), resource: ResourceId(7) });
+        assert_eq!(error.to_string(), "pass p uses unknown resource 7");
+
+        let mut graph = RenderGraph::new();
+        let t = graph.create("t", TEXTURE);
+        graph.pass(Pass::new("p", PassKind::Copy).read(t, Usage::CopySource));
+        let error = graph.compile().unwrap_err();
+        assert_eq!(error, GraphError::ReadBeforeWrite { pass: "p".to_string(), resource: "t".to_string() });
+        assert_eq!(error.to_string(), "pass p reads t before any pass writes it");
+
+        let mut graph = RenderGraph::new();
+        let out = graph.import("out", TEXTURE, ResourceStates::COPY_DEST);
+        graph.pass(Pass::new("p", PassKind::Copy).read(out, Usage::CopySource).write(out, Usage::CopyDest));
+        let error = graph.compile().unwrap_err();
+        assert_eq!(error, GraphError::ConflictingUsage { pass: "p".to_string(), resource: "out".to_string() });
+        assert_eq!(error.to_string(), "pass p uses out in conflicting states");
+    }
+
+    #[test]
+    fn display_lists_the_schedule() {
+        let (graph, _) = graph_with_unused_pass();
+        let expected = "\
+resources:
+  out (texture 4x4 format 28) imported in COPY_DEST
+  a (texture 4x4 format 28) transient in slot 0
+  b (texture 4x4 format 28) unused
+slots:
+  0: 64 bytes, a
+passes:
+  0: trace (ray trace)
+    write a as UnorderedAccess
+  1: copy (copy)
+    barrier a UNORDERED_ACCESS -> COPY_SOURCE
+    read a as CopySource
+    write out as CopyDest
+end:
+    barrier a COPY_SOURCE -> UNORDERED_ACCESS
+culled: unused
+";
+        assert_eq!(graph.compile().unwrap().to_string(), expected);
+    }
+}
@@ -0,0 +1,392 @@
+use std::fmt;
+use std::ops::BitOr;
+
+//リソースの今の状態を覚えておき、使い方を宣言されたら必要なバリアだけを積む
+//ResourceIdと状態の値だけを扱うので、D3D12_RESOURCE_BARRIERへの変換は呼び出し側で行う
+
+//D3D12_RESOURCE_STATES
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ResourceStates(pub u32);
+
+impl ResourceStates {
+    pub const COMMON: ResourceStates = ResourceStates(0);
+    pub const PRESENT: ResourceStates = ResourceStates(0);
+    pub const VERTEX_AND_CONSTANT_BUFFER: ResourceStates = ResourceStates(0x1);
+    pub const INDEX_BUFFER: ResourceStates = ResourceStates(0x2);
+    pub const RENDER_TARGET: ResourceStates = ResourceStates(0x4);
+    pub const UNORDERED_ACCESS: ResourceStates = ResourceStates(0x8);
+    pub const DEPTH_WRITE: ResourceStates = ResourceStates(0x10);
+    pub const DEPTH_READ: ResourceStates = ResourceStates(0x20);
+    pub const NON_PIXEL_SHADER_RESOURCE: ResourceStates = ResourceStates(0x40);
+    pub const PIXEL_SHADER_RESOURCE: ResourceStates = ResourceStates(0x80);
+    pub const STREAM_OUT: ResourceStates = ResourceStates(0x100);
+    pub const INDIRECT_ARGUMENT: ResourceStates = ResourceStates(0x200);
+    pub const COPY_DEST: ResourceStates = ResourceStates(0x400);
+    pub const COPY_SOURCE: ResourceStates = ResourceStates(0x800);
+    pub const RESOLVE_DEST: ResourceStates = ResourceStates(0x1000);
+    pub const RESOLVE_SOURCE: ResourceStates = ResourceStates(0x2000);
+    pub const RAYTRACING_ACCELERATION_STRUCTURE: ResourceStates = ResourceStates(0x400000);
+    pub const GENERIC_READ: ResourceStates = ResourceStates(0xAC3);
+
+    //読み取りだけの状態は同時に持てる
+    const READ_ONLY: u32 = 0x1 | 0x2 | 0x20 | 0x40 | 0x80 | 0x200 | 0x800 | 0x2000;
+
+    pub fn contains(self, states: ResourceStates) -> bool {
+        self.0 & states.0 == states.0
+    }
+
+    pub fn is_read_only(self) -> bool {
+        self.0 != 0 && self.0 & !Self::READ_ONLY == 0
+    }
+
+    //状態を変えずにUAVバリアで順序をつける状態
+    fn is_unordered(self) -> bool {
+        self == Self::UNORDERED_ACCESS || self == Self::RAYTRACING_ACCELERATION_STRUCTURE
+    }
+}
+
+impl fmt::Display for ResourceStates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [(u32, &str); 15] = [
+            (0x1, "VERTEX_AND_CONSTANT_BUFFER"),
+            (0x2, "INDEX_BUFFER"),
+            (0x4, "RENDER_TARGET"),
+            (0x8, "UNORDERED_ACCESS"),
+            (0x10, "DEPTH_WRITE"),
+            (0x20, "DEPTH_READ"),
+            (0x40, "NON_PIXEL_SHADER_RESOURCE"),
+            (0x80, "PIXEL_SHADER_RESOURCE"),
+            (0x100, "STREAM_OUT"),
+            (0x200, "INDIRECT_ARGUMENT"),
+            (0x400, "COPY_DEST"),
+            (0x800, "COPY_SOURCE"),
+            (0x1000, "RESOLVE_DEST"),
+            (0x2000, "RESOLVE_SOURCE"),
+            (0x400000, "RAYTRACING_ACCELERATION_STRUCTURE"),
+        ];
+
+        if self.0 == 0 {
+            return write!(f, "COMMON");
+        }
+        if *self == Self::GENERIC_READ {
+            return write!(f, "GENERIC_READ");
+        }
+
+        let names: Vec<&str> = NAMES.iter().filter(|&&(bit, _)| self.0 & bit != 0).map(|&(_, name)| name).collect();
+        let rest = NAMES.iter().fold(self.0, |rest, &(bit, _)| rest & !bit);
+        write!(f, "{}", names.join("|"))?;
+        if rest != 0 {
+            write!(f, "{}{:#x}", if names.is_empty() { "" } else { "|" }, rest)?;
+        }
+        Ok(())
+    }
+}
+
+impl BitOr for ResourceStates {
+    type Output = ResourceStates;
+
+    fn bitor(self, rhs: ResourceStates) -> ResourceStates {
+        ResourceStates(self.0 | rhs.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ResourceId(pub usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+//D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES
+pub const ALL_SUBRESOURCES: u32 = 0xFFFF_FFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Barrier {
+    //subresourceがALL_SUBRESOURCESなら全部
+    Transition { resource: ResourceId, subresource: u32, before: ResourceStates, after: ResourceStates },
+    Uav { resource: ResourceId },
+}
+
+#[derive(Clone, Debug)]
+struct TrackedResource {
+    //サブリソースごとの状態 全部同じなら1つだけ
+    states: Vec<ResourceStates>,
+    subresource_count: u32,
+    //最後のバリアの後にUAV/ASとして書いたか、読み書きしたか
+    written: bool,
+    accessed: bool,
+}
+
+impl TrackedResource {
+    fn state(&self, subresource: u32) -> ResourceStates {
+        if self.states.len() == 1 {
+            self.states[0]
+        } else {
+            self.states[subresource as usize]
+        }
+    }
+
+    fn is_uniform(&self) -> bool {
+        self.states.len() == 1
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ResourceStateTracker {
+    resources: Vec<TrackedResource>,
+    pending: Vec<Barrier>,
+}
+
+impl ResourceStateTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //リソースを作ったときのInitialState
+    pub fn register(&mut self, initial: ResourceStates, subresource_count: u32) -> ResourceId {
+        self.resources.push(TrackedResource {
+            states: vec![initial],
+            subresource_count: subresource_count.max(1),
+            written: false,
+            accessed: false,
+        });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    pub fn state(&self, id: ResourceId) -> Option<ResourceStates> {
+        let r = &self.resources[id.0];
+        r.is_uniform().then(|| r.states[0])
+    }
+
+    pub fn subresource_state(&self, id: ResourceId, subresource: u32) -> ResourceStates {
+        self.resources[id.0].state(subresource)
+    }
+
+    //リソース全体をstateで使う
+    pub fn require(&mut self, id: ResourceId, state: ResourceStates, access: Access) {
+        let r = &self.resources[id.0];
+        if r.is_uniform() {
+            self.require_uniform(id, state, access);
+            return;
+        }
+
+        //サブリソースごとに違う状態から1つの状態にそろえる すでに使える読み取り状態はそのまま
+        let mut states = r.states.clone();
+        for (sub, s) in states.iter_mut().enumerate() {
+            if !satisfies(*s, state) {
+                self.push_transition(id, sub as u32, *s, state);
+                *s = state;
+            }
+        }
+
+        let r = &mut self.resources[id.0];
+        r.states = if states.iter().all(|&s| s == states[0]) { vec![states[0]] } else { states };
+        r.written = access == Access::Write;
+        r.accessed = true;
+    }
+
+    pub fn require_subresource(&mut self, id: ResourceId, subresource: u32, state: ResourceStates, access: Access) {
+        let r = &self.resources[id.0];
+        if r.subresource_count == 1 || subresource == ALL_SUBRESOURCES {
+            self.require(id, state, access);
+            return;
+        }
+
+        let before = r.state(subresource);
+        if satisfies(before, state) {
+            self.uav_if_needed(id, before, access);
+            return;
+        }
+
+        let r = &mut self.resources[id.0];
+        if r.is_uniform() {
+            r.states = vec![before; r.subresource_count as usize];
+        }
+        r.states[subresource as usize] = state;
+        if r.states.iter().all(|&s| s == state) {
+            r.states = vec![state];
+        }
+        r.written = access == Access::Write;
+        r.accessed = true;
+
+        self.push_transition(id, subresource, before, state);
+    }
+
+    //積んだバリアを取り出す ResourceBarrierにまとめて渡す
+    pub fn flush(&mut self) -> Vec<Barrier> {
+        std::mem::take(&mut self.pending)
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn require_uniform(&mut self, id: ResourceId, state: ResourceStates, access: Access) {
+        let before = self.resources[id.0].states[0];
+
+        if satisfies(before, state) {
+            self.uav_if_needed(id, before, access);
+            return;
+        }
+
+        //別の読み取り状態を足すだけなら1回のバリアでまとめて持たせる
+        let after = if before.is_read_only() && state.is_read_only() && access == Access::Read { before | state } else { state };
+
+        self.push_transition(id, ALL_SUBRESOURCES, before, after);
+
+        let r = &mut self.resources[id.0];
+        r.states[0] = after;
+        r.written = access == Access::Write;
+        r.accessed = true;
+    }
+
+    //UAVとASは状態が変わらないので、読み書きの間にUAVバリアを挟む
+    fn uav_if_needed(&mut self, id: ResourceId, state: ResourceStates, access: Access) {
+        let r = &mut self.resources[id.0];
+        let needed = state.is_unordered() && (r.written || (access == Access::Write && r.accessed));
+
+        if needed {
+            r.written = false;
+            if !self.pending.contains(&Barrier::Uav { resource: id }) {
+                self.pending.push(Barrier::Uav { resource: id });
+            }
+        }
+
+        let r = &mut self.resources[id.0];
+        r.written |= access == Access::Write;
+        r.accessed = true;
+    }
+
+    //まだ出していない同じサブリソースの遷移があればつなげる
+    fn push_transition(&mut self, resource: ResourceId, subresource: u32, before: ResourceStates, after: ResourceStates) {
+        let pending = self.pending.iter().position(|b| {
+            matches!(b, Barrier::Transition { resource: r, subresource: s, after: a, .. } if *r == resource && *s == subresource && *a == before)
+        });
+
+        match pending {
+            Some(i) => {
+                let Barrier::Transition { before: first, .. } = self.pending[i] else { unreachable!() };
+                if first == after {
+                    self.pending.remove(i);
+                } else {
+                    self.pending[i] = Barrier::Transition { resource, subresource, before: first, after };
+                }
+            }
+            None => self.pending.push(Barrier::Transition { resource, subresource, before, after }),
+        }
+    }
+}
+
+//今の状態のままでstateとして使えるか
+fn satisfies(current: ResourceStates, state: ResourceStates) -> bool {
+    if state == ResourceStates::COMMON {
+        current == ResourceStates::COMMON
+    } else if state.is_read_only() {
+        current.contains(state)
+    } else {
+        current == state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transition(resource: ResourceId, before: ResourceStates, after: ResourceStates) -> Barrier {
+        Barrier::Transition { resource, subresource: ALL_SUBRESOURCES, before, after }
+    }
+
+    #[test]
+    fn read_states_are_merged_into_one_transition() {
+        let mut tracker = ResourceStateTracker::new();
+        let texture = tracker.register(ResourceStates::COPY_DEST, 1);
+
+        tracker.require(texture, ResourceStates::PIXEL_SHADER_RESOURCE, Access::Read);
+        tracker.require(texture, ResourceStates::NON_PIXEL_SHADER_RESOURCE, Access::Read);
+
+        //まだ出していない遷移の後ろに足されて1つになる
+        let both = ResourceStates::PIXEL_SHADER_RESOURCE | ResourceStates::NON_PIXEL_SHADER_RESOURCE;
+        assert_eq!(tracker.flush(), vec![transition(texture, ResourceStates::COPY_DEST, both)]);
+        assert_eq!(tracker.state(texture), Some(both));
+
+        //どちらの読み取りにもそのまま使える
+        tracker.require(texture, ResourceStates::PIXEL_SHADER_RESOURCE, Access::Read);
+        assert!(!tracker.has_pending());
+    }
+
+    #[test]
+    fn writes_to_unordered_resources_get_uav_barriers() {
+        let mut tracker = ResourceStateTracker::new();
+        let output = tracker.register(ResourceStates::UNORDERED_ACCESS, 1);
+        let blas = tracker.register(ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE, 1);
+
+        //最初の書き込みの前には何もいらない
+        tracker.require(output, ResourceStates::UNORDERED_ACCESS, Access::Write);
+        assert!(tracker.flush().is_empty());
+
+        tracker.require(output, ResourceStates::UNORDERED_ACCESS, Access::Write);
+        assert_eq!(tracker.flush(), vec![Barrier::Uav { resource: output }]);
+
+        //書いた後に読むときも、読んだ後に書くときも順序をつける
+        tracker.require(blas, ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE, Access::Write);
+        tracker.require(blas, ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE, Access::Read);
+        assert_eq!(tracker.flush(), vec![Barrier::Uav { resource: blas }]);
+        tracker.require(blas, ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE, Access::Write);
+        assert_eq!(tracker.flush(), vec![Barrier::Uav { resource: blas }]);
+
+        //読み続けるだけなら最初の1回で足りる
+        tracker.require(blas, ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE, Access::Read);
+        tracker.require(blas, ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE, Access::Read);
+        tracker.require(blas, ResourceStates::RAYTRACING_ACCELERATION_STRUCTURE, Access::Read);
+        assert_eq!(tracker.flush(), vec![Barrier::Uav { resource: blas }]);
+    }
+
+    #[test]
+    fn redundant_transitions_are_skipped() {
+        let mut tracker = ResourceStateTracker::new();
+        let target = tracker.register(ResourceStates::PRESENT, 1);
+
+        tracker.require(target, ResourceStates::PRESENT, Access::Read);
+        assert!(!tracker.has_pending());
+
+        tracker.require(target, ResourceStates::COPY_DEST, Access::Write);
+        tracker.require(target, ResourceStates::COPY_DEST, Access::Write);
+        assert_eq!(tracker.flush(), vec![transition(target, ResourceStates::PRESENT, ResourceStates::COPY_DEST)]);
+
+        //出す前に元に戻したら消える
+        tracker.require(target, ResourceStates::RENDER_TARGET, Access::Write);
+        tracker.require(target, ResourceStates::COPY_DEST, Access::Write);
+        assert!(tracker.flush().is_empty());
+
+        //途中の状態は飛ばしてつなげる
+        tracker.require(target, ResourceStates::RENDER_TARGET, Access::Write);
+        tracker.require(target, ResourceStates::PRESENT, Access::Read);
+        assert_eq!(tracker.flush(), vec![transition(target, ResourceStates::COPY_DEST, ResourceStates::PRESENT)]);
+    }
+
+    #[test]
+    fn subresources_are_tracked_separately_until_they_agree() {
+        let mut tracker = ResourceStateTracker::new();
+        let texture = tracker.register(ResourceStates::COMMON, 3);
+
+        tracker.require_subresource(texture, 1, ResourceStates::COPY_DEST, Access::Write);
+        assert_eq!(tracker.state(texture), None);
+        assert_eq!(tracker.subresource_state(texture, 1), ResourceStates::COPY_DEST);
+        assert_eq!(tracker.subresource_state(texture, 0), ResourceStates::COMMON);
+
+        tracker.require(texture, ResourceStates::COPY_DEST, Access::Write);
+        let barriers = tracker.flush();
+        assert_eq!(barriers.len(), 3);
+        assert!(barriers.iter().all(|b| matches!(b, Barrier::Transition { subresource, .. } if *subresource != ALL_SUBRESOURCES)));
+        assert_eq!(tracker.state(texture), Some(ResourceStates::COPY_DEST));
+    }
+
+    #[test]
+    fn states_display_like_d3d12() {
+        assert_eq!(ResourceStates::COMMON.to_string(), "COMMON");
+        assert_eq!(ResourceStates::GENERIC_READ.to_string(), "GENERIC_READ");
+        assert_eq!((ResourceStates::COPY_SOURCE | ResourceStates(0x8000)).to_string(), "COPY_SOURCE|0x8000");
+    }
+}
@@ -0,0 +1,615 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::dxbc::{read_u32, Container, DxbcError, Part, FOURCC_RTS0};
+
+//ルートシグニチャを宣言的に書いてRTS0パートを1つ持つDXBCコンテナにする
+//RTS0の中身はDirectXShaderCompilerのDxilRootSignatureのレイアウトに合わせているのでWindows以外でも作れるし読める
+//D3D12SerializeRootSignatureの出力とバイト単位で比べたことはないので、CreateRootSignatureに渡せることだけ当てにする
+
+//D3D12_ROOT_SIGNATURE_FLAG_LOCAL_ROOT_SIGNATURE
+pub const ROOT_SIGNATURE_FLAG_LOCAL: u32 = 0x80;
+
+//D3D12_DESCRIPTOR_RANGE_FLAGS (1.1のみ)
+pub const DESCRIPTOR_RANGE_FLAG_DESCRIPTORS_VOLATILE: u32 = 0x1;
+pub const DESCRIPTOR_RANGE_FLAG_DATA_VOLATILE: u32 = 0x2;
+pub const DESCRIPTOR_RANGE_FLAG_DATA_STATIC_WHILE_SET_AT_EXECUTE: u32 = 0x4;
+pub const DESCRIPTOR_RANGE_FLAG_DATA_STATIC: u32 = 0x8;
+
+//D3D12_ROOT_DESCRIPTOR_FLAGS (1.1のみ)
+pub const ROOT_DESCRIPTOR_FLAG_DATA_VOLATILE: u32 = 0x2;
+pub const ROOT_DESCRIPTOR_FLAG_DATA_STATIC_WHILE_SET_AT_EXECUTE: u32 = 0x4;
+pub const ROOT_DESCRIPTOR_FLAG_DATA_STATIC: u32 = 0x8;
+
+//D3D12_DESCRIPTOR_RANGE_OFFSET_APPEND
+pub const DESCRIPTOR_RANGE_OFFSET_APPEND: u32 = 0xFFFF_FFFF;
+//NumDescriptorsに入れると上限なし
+pub const UNBOUNDED_DESCRIPTOR_COUNT: u32 = 0xFFFF_FFFF;
+
+const HEADER_SIZE: usize = 24;
+const PARAMETER_SIZE: usize = 12;
+const STATIC_SAMPLER_SIZE: usize = 52;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RootSignatureError {
+    Container(DxbcError),
+    UnsupportedVersion(u32),
+    InvalidValue { field: &'static str, value: u32 },
+    //1.0には範囲とルートディスクリプタのフラグがない
+    FlagsRequireVersion11 { parameter: usize },
+    //サンプラーとCBV/SRV/UAVは同じテーブルに入れられない
+    MixedSamplerTable { parameter: usize },
+}
+
+impl fmt::Display for RootSignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RootSignatureError::Container(e) => write!(f, "{}", e),
+            RootSignatureError::UnsupportedVersion(version) => write!(f, "unsupported root signature version {}", version),
+            RootSignatureError::InvalidValue { field, value } => write!(f, "invalid {} {}", field, value),
+            RootSignatureError::FlagsRequireVersion11 { parameter } => {
+                write!(f, "root parameter {} uses flags, which need root signature version 1.1", parameter)
+            }
+            RootSignatureError::MixedSamplerTable { parameter } => {
+                write!(f, "descriptor table {} mixes samplers with CBV/SRV/UAV ranges", parameter)
+            }
+        }
+    }
+}
+
+impl Error for RootSignatureError {}
+
+impl From<DxbcError> for RootSignatureError {
+    fn from(e: DxbcError) -> Self {
+        RootSignatureError::Container(e)
+    }
+}
+
+//D3D_ROOT_SIGNATURE_VERSION
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootSignatureVersion {
+    V1_0 = 1,
+    V1_1 = 2,
+}
+
+//D3D12_SHADER_VISIBILITY
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShaderVisibility {
+    #[default]
+    All = 0,
+    Vertex = 1,
+    Hull = 2,
+    Domain = 3,
+    Geometry = 4,
+    Pixel = 5,
+    Amplification = 6,
+    Mesh = 7,
+}
+
+impl ShaderVisibility {
+    fn from_u32(value: u32) -> Result<Self, RootSignatureError> {
+        Ok(match value {
+            0 => ShaderVisibility::All,
+            1 => ShaderVisibility::Vertex,
+            2 => ShaderVisibility::Hull,
+            3 => ShaderVisibility::Domain,
+            4 => ShaderVisibility::Geometry,
+            5 => ShaderVisibility::Pixel,
+            6 => ShaderVisibility::Amplification,
+            7 => ShaderVisibility::Mesh,
+            value => return Err(RootSignatureError::InvalidValue { field: "shader visibility", value }),
+        })
+    }
+}
+
+//D3D12_DESCRIPTOR_RANGE_TYPE
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorRangeType {
+    Srv = 0,
+    Uav = 1,
+    Cbv = 2,
+    Sampler = 3,
+}
+
+impl DescriptorRangeType {
+    fn from_u32(value: u32) -> Result<Self, RootSignatureError> {
+        Ok(match value {
+            0 => DescriptorRangeType::Srv,
+            1 => DescriptorRangeType::Uav,
+            2 => DescriptorRangeType::Cbv,
+            3 => DescriptorRangeType::Sampler,
+            value => return Err(RootSignatureError::InvalidValue { field: "descriptor range type", value }),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DescriptorRange {
+    pub range_type: DescriptorRangeType,
+    pub count: u32,
+    pub base_register: u32,
+    pub space: u32,
+    pub flags: u32,
+    //テーブルの先頭からのディスクリプタ数 APPENDなら前の範囲の続き
+    pub offset: u32,
+}
+
+impl DescriptorRange {
+    pub fn new(range_type: DescriptorRangeType, base_register: u32, count: u32) -> Self {
+        DescriptorRange { range_type, count, base_register, space: 0, flags: 0, offset: DESCRIPTOR_RANGE_OFFSET_APPEND }
+    }
+
+    pub fn space(mut self, space: u32) -> Self {
+        self.space = space;
+        self
+    }
+
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+}
+
+//D3D12_ROOT_PARAMETER_TYPEのCBV/SRV/UAV
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootDescriptorType {
+    Cbv = 2,
+    Srv = 3,
+    Uav = 4,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RootParameterKind {
+    DescriptorTable(Vec<DescriptorRange>),
+    Constants { register: u32, space: u32, count: u32 },
+    Descriptor { descriptor_type: RootDescriptorType, register: u32, space: u32, flags: u32 },
+}
+
+impl RootParameterKind {
+    //D3D12_ROOT_PARAMETER_TYPE
+    fn type_value(&self) -> u32 {
+        match self {
+            RootParameterKind::DescriptorTable(_) => 0,
+            RootParameterKind::Constants { .. } => 1,
+            RootParameterKind::Descriptor { descriptor_type, .. } => *descriptor_type as u32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootParameter {
+    pub kind: RootParameterKind,
+    pub visibility: ShaderVisibility,
+}
+
+impl RootParameter {
+    pub fn table(ranges: &[DescriptorRange]) -> Self {
+        Self::with_kind(RootParameterKind::DescriptorTable(ranges.to_vec()))
+    }
+
+    //countは32bit値の数
+    pub fn constants(register: u32, space: u32, count: u32) -> Self {
+        Self::with_kind(RootParameterKind::Constants { register, space, count })
+    }
+
+    pub fn descriptor(descriptor_type: RootDescriptorType, register: u32, space: u32) -> Self {
+        Self::with_kind(RootParameterKind::Descriptor { descriptor_type, register, space, flags: 0 })
+    }
+
+    pub fn visibility(mut self, visibility: ShaderVisibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    fn with_kind(kind: RootParameterKind) -> Self {
+        RootParameter { kind, visibility: ShaderVisibility::All }
+    }
+}
+
+//D3D12_STATIC_SAMPLER_DESC 列挙型はD3D12の値のまま
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StaticSampler {
+    pub filter: u32,
+    pub address_u: u32,
+    pub address_v: u32,
+    pub address_w: u32,
+    pub mip_lod_bias: f32,
+    pub max_anisotropy: u32,
+    pub comparison_func: u32,
+    pub border_color: u32,
+    pub min_lod: f32,
+    pub max_lod: f32,
+    pub register: u32,
+    pub space: u32,
+    pub visibility: ShaderVisibility,
+}
+
+impl StaticSampler {
+    //CD3DX12_STATIC_SAMPLER_DESCと同じ既定値(異方性フィルタ、WRAP)
+    pub fn new(register: u32) -> Self {
+        StaticSampler {
+            filter: 0x55,
+            address_u: 1,
+            address_v: 1,
+            address_w: 1,
+            mip_lod_bias: 0.0,
+            max_anisotropy: 16,
+            comparison_func: 4,
+            border_color: 2,
+            min_lod: 0.0,
+            max_lod: f32::MAX,
+            register,
+            space: 0,
+            visibility: ShaderVisibility::All,
+        }
+    }
+
+    fn values(&self) -> [u32; 13] {
+        [
+            self.filter,
+            self.address_u,
+            self.address_v,
+            self.address_w,
+            self.mip_lod_bias.to_bits(),
+            self.max_anisotropy,
+            self.comparison_func,
+            self.border_color,
+            self.min_lod.to_bits(),
+            self.max_lod.to_bits(),
+            self.register,
+            self.space,
+            self.visibility as u32,
+        ]
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RootSignatureDesc {
+    pub flags: u32,
+    //並び順がルートパラメーターのインデックス
+    pub parameters: Vec<RootParameter>,
+    pub static_samplers: Vec<StaticSampler>,
+}
+
+impl RootSignatureDesc {
+    pub fn global() -> Self {
+        Self::default()
+    }
+
+    //シェーダーテーブルのレコードに引数を置くローカルルートシグニチャ
+    pub fn local() -> Self {
+        RootSignatureDesc { flags: ROOT_SIGNATURE_FLAG_LOCAL, ..Self::default() }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.flags & ROOT_SIGNATURE_FLAG_LOCAL != 0
+    }
+
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    pub fn parameter(mut self, parameter: RootParameter) -> Self {
+        self.parameters.push(parameter);
+        self
+    }
+
+    pub fn table(self, ranges: &[DescriptorRange]) -> Self {
+        self.parameter(RootParameter::table(ranges))
+    }
+
+    pub fn constants(self, register: u32, space: u32, count: u32) -> Self {
+        self.parameter(RootParameter::constants(register, space, count))
+    }
+
+    pub fn cbv(self, register: u32, space: u32) -> Self {
+        self.parameter(RootParameter::descriptor(RootDescriptorType::Cbv, register, space))
+    }
+
+    pub fn srv(self, register: u32, space: u32) -> Self {
+        self.parameter(RootParameter::descriptor(RootDescriptorType::Srv, register, space))
+    }
+
+    pub fn uav(self, register: u32, space: u32) -> Self {
+        self.parameter(RootParameter::descriptor(RootDescriptorType::Uav, register, space))
+    }
+
+    pub fn static_sampler(mut self, sampler: StaticSampler) -> Self {
+        self.static_samplers.push(sampler);
+        self
+    }
+
+    pub fn validate(&self, version: RootSignatureVersion) -> Result<(), RootSignatureError> {
+        for (i, p) in self.parameters.iter().enumerate() {
+            let uses_flags = match &p.kind {
+                RootParameterKind::DescriptorTable(ranges) => {
+                    let samplers = ranges.iter().filter(|r| r.range_type == DescriptorRangeType::Sampler).count();
+                    if samplers != 0 && samplers != ranges.len() {
+                        return Err(RootSignatureError::MixedSamplerTable { parameter: i });
+                    }
+                    ranges.iter().any(|r| r.flags != 0)
+                }
+                RootParameterKind::Constants { .. } => false,
+                RootParameterKind::Descriptor { flags, .. } => *flags != 0,
+            };
+
+            if uses_flags && version == RootSignatureVersion::V1_0 {
+                return Err(RootSignatureError::FlagsRequireVersion11 { parameter: i });
+            }
+        }
+
+        Ok(())
+    }
+
+    //D3D12SerializeRootSignatureの出力と同じくRTS0パートをDXBCコンテナに入れる
+    pub fn serialize(&self, version: RootSignatureVersion) -> Result<Vec<u8>, RootSignatureError> {
+        let part = self.serialize_part(version)?;
+        Ok(Container::write(&[Part { fourcc: FOURCC_RTS0, data: &part }]))
+    }
+
+    //RTS0パートの中身
+    pub fn serialize_part(&self, version: RootSignatureVersion) -> Result<Vec<u8>, RootSignatureError> {
+        self.validate(version)?;
+        let v11 = version == RootSignatureVersion::V1_1;
+
+        //ヘッダとルートパラメーターの配列の後に各パラメーターの中身、最後に静的サンプラー
+        let mut out = vec![0u8; HEADER_SIZE + PARAMETER_SIZE * self.parameters.len()];
+
+        for (i, p) in self.parameters.iter().enumerate() {
+            let payload_offset = out.len() as u32;
+
+            match &p.kind {
+                RootParameterKind::DescriptorTable(ranges) => {
+                    push_u32(&mut out, ranges.len() as u32);
+                    push_u32(&mut out, payload_offset + 8);
+                    for r in ranges {
+                        push_u32(&mut out, r.range_type as u32);
+                        push_u32(&mut out, r.count);
+                        push_u32(&mut out, r.base_register);
+                        push_u32(&mut out, r.space);
+                        if v11 {
+                            push_u32(&mut out, r.flags);
+                        }
+                        push_u32(&mut out, r.offset);
+                    }
+                }
+                RootParameterKind::Constants { register, space, count } => {
+                    [*register, *space, *count].iter().for_each(|&v| push_u32(&mut out, v));
+                }
+                RootParameterKind::Descriptor { register, space, flags, .. } => {
+                    push_u32(&mut out, *register);
+                    push_u32(&mut out, *space);
+                    if v11 {
+                        push_u32(&mut out, *flags);
+                    }
+                }
+            }
+
+            let offset = HEADER_SIZE + PARAMETER_SIZE * i;
+            set_u32(&mut out, offset, p.kind.type_value());
+            set_u32(&mut out, offset + 4, p.visibility as u32);
+            set_u32(&mut out, offset + 8, payload_offset);
+        }
+
+        let static_samplers_offset = out.len() as u32;
+        for s in &self.static_samplers {
+            s.values().iter().for_each(|&v| push_u32(&mut out, v));
+        }
+
+        let header = [
+            version as u32,
+            self.parameters.len() as u32,
+            HEADER_SIZE as u32,
+            self.static_samplers.len() as u32,
+            static_samplers_offset,
+            self.flags,
+        ];
+        for (i, v) in header.into_iter().enumerate() {
+            set_u32(&mut out, i * 4, v);
+        }
+
+        Ok(out)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<(RootSignatureDesc, RootSignatureVersion), RootSignatureError> {
+        let container = Container::parse(bytes)?;
+        let part = container.part(FOURCC_RTS0).ok_or(DxbcError::MissingPart(FOURCC_RTS0))?;
+        Self::parse_part(part.data)
+    }
+
+    pub fn parse_part(data: &[u8]) -> Result<(RootSignatureDesc, RootSignatureVersion), RootSignatureError> {
+        let version = match read_u32(data, 0)? {
+            1 => RootSignatureVersion::V1_0,
+            2 => RootSignatureVersion::V1_1,
+            v => return Err(RootSignatureError::UnsupportedVersion(v)),
+        };
+        let v11 = version == RootSignatureVersion::V1_1;
+
+        let parameter_count = read_u32(data, 4)? as usize;
+        let parameters_offset = read_u32(data, 8)? as usize;
+        let static_sampler_count = read_u32(data, 12)? as usize;
+        let static_samplers_offset = read_u32(data, 16)? as usize;
+        let flags = read_u32(data, 20)?;
+
+        let mut parameters = Vec::with_capacity(parameter_count);
+        for i in 0..parameter_count {
+            let offset = parameters_offset + PARAMETER_SIZE * i;
+            let parameter_type = read_u32(data, offset)?;
+            let visibility = ShaderVisibility::from_u32(read_u32(data, offset + 4)?)?;
+            let payload = read_u32(data, offset + 8)? as usize;
+
+            let kind = match parameter_type {
+                0 => {
+                    let range_count = read_u32(data, payload)? as usize;
+                    let ranges_offset = read_u32(data, payload + 4)? as usize;
+                    let range_size = if v11 { 24 } else { 20 };
+
+                    let mut ranges = Vec::with_capacity(range_count);
+                    for r in 0..range_count {
+                        let at = |field: usize| read_u32(data, ranges_offset + range_size * r + field * 4);
+                        ranges.push(DescriptorRange {
+                            range_type: DescriptorRangeType::from_u32(at(0)?)?,
+                            count: at(1)?,
+                            base_register: at(2)?,
+                            space: at(3)?,
+                            flags: if v11 { at(4)? } else { 0 },
+                            offset: if v11 { at(5)? } else { at(4)? },
+                        });
+                    }
+                    RootParameterKind::DescriptorTable(ranges)
+                }
+                1 => RootParameterKind::Constants {
+                    register: read_u32(data, payload)?,
+                    space: read_u32(data, payload + 4)?,
+                    count: read_u32(data, payload + 8)?,
+                },
+                2..=4 => RootParameterKind::Descriptor {
+                    descriptor_type: match parameter_type {
+                        2 => RootDescriptorType::Cbv,
+                        3 => RootDescriptorType::Srv,
+                        _ => RootDescriptorType::Uav,
+                    },
+                    register: read_u32(data, payload)?,
+                    space: read_u32(data, payload + 4)?,
+                    flags: if v11 { read_u32(data, payload + 8)? } else { 0 },
+                },
+                value => return Err(RootSignatureError::InvalidValue { field: "root parameter type", value }),
+            };
+
+            parameters.push(RootParameter { kind, visibility });
+        }
+
+        let mut static_samplers = Vec::with_capacity(static_sampler_count);
+        for i in 0..static_sampler_count {
+            let at = |field: usize| read_u32(data, static_samplers_offset + STATIC_SAMPLER_SIZE * i + field * 4);
+            static_samplers.push(StaticSampler {
+                filter: at(0)?,
+                address_u: at(1)?,
+                address_v: at(2)?,
+                address_w: at(3)?,
+                mip_lod_bias: f32::from_bits(at(4)?),
+                max_anisotropy: at(5)?,
+                comparison_func: at(6)?,
+                border_color: at(7)?,
+                min_lod: f32::from_bits(at(8)?),
+                max_lod: f32::from_bits(at(9)?),
+                register: at(10)?,
+                space: at(11)?,
+                visibility: ShaderVisibility::from_u32(at(12)?)?,
+            });
+        }
+
+        Ok((RootSignatureDesc { flags, parameters, static_samplers }, version))
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(out: &mut [u8], offset: usize, value: u32) {
+    out[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler() -> StaticSampler {
+        StaticSampler { filter: 0x15, address_u: 3, mip_lod_bias: -0.5, max_lod: 8.0, space: 2, visibility: ShaderVisibility::Pixel, ..StaticSampler::new(1) }
+    }
+
+    fn round_trip(desc: &RootSignatureDesc, version: RootSignatureVersion) {
+        let bytes = desc.serialize(version).unwrap();
+        assert_eq!(RootSignatureDesc::deserialize(&bytes).unwrap(), (desc.clone(), version));
+    }
+
+    #[test]
+    fn descriptor_tables_round_trip() {
+        let desc = RootSignatureDesc::global()
+            .table(&[DescriptorRange::new(DescriptorRangeType::Srv, 0, 1), DescriptorRange::new(DescriptorRangeType::Uav, 0, 2).space(1).offset(4)])
+            .table(&[DescriptorRange::new(DescriptorRangeType::Cbv, 3, UNBOUNDED_DESCRIPTOR_COUNT)])
+            .parameter(RootParameter::table(&[DescriptorRange::new(DescriptorRangeType::Sampler, 0, 4)]).visibility(ShaderVisibility::Pixel));
+
+        round_trip(&desc, RootSignatureVersion::V1_0);
+        round_trip(&desc, RootSignatureVersion::V1_1);
+
+        //範囲のフラグは1.1だけ
+        let flagged = RootSignatureDesc::global().table(&[DescriptorRange::new(DescriptorRangeType::Srv, 0, 1).flags(DESCRIPTOR_RANGE_FLAG_DATA_STATIC)]);
+        round_trip(&flagged, RootSignatureVersion::V1_1);
+    }
+
+    #[test]
+    fn root_constants_and_descriptors_round_trip() {
+        let desc = RootSignatureDesc::local()
+            .constants(0, 0, 3)
+            .constants(1, 2, 16)
+            .cbv(2, 0)
+            .srv(4, 1)
+            .parameter(RootParameter::descriptor(RootDescriptorType::Uav, 5, 0).visibility(ShaderVisibility::Mesh));
+
+        round_trip(&desc, RootSignatureVersion::V1_0);
+        round_trip(&desc, RootSignatureVersion::V1_1);
+    }
+
+    #[test]
+    fn static_samplers_round_trip() {
+        let desc = RootSignatureDesc::global().flags(0x1).static_sampler(StaticSampler::new(0)).static_sampler(sampler());
+
+        round_trip(&desc, RootSignatureVersion::V1_0);
+        round_trip(&desc, RootSignatureVersion::V1_1);
+    }
+
+    #[test]
+    fn ray_shader_root_signature_round_trips() {
+        round_trip(&crate::cpu::shaders::ray_shader_root_signature(), RootSignatureVersion::V1_1);
+    }
+
+    #[test]
+    fn part_layout_follows_dxil_root_signature() {
+        let desc = RootSignatureDesc::global()
+            .table(&[DescriptorRange::new(DescriptorRangeType::Uav, 0, 1)])
+            .constants(0, 0, 4)
+            .static_sampler(StaticSampler::new(0));
+        let part = desc.serialize_part(RootSignatureVersion::V1_0).unwrap();
+        let words: Vec<u32> = part.chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+
+        #[rustfmt::skip]
+        let expected = [
+            //ヘッダ バージョン, パラメーター数, パラメーターの位置, サンプラー数, サンプラーの位置, フラグ
+            1, 2, 24, 1, 88, 0,
+            //パラメーター 種類, 可視性, 中身の位置
+            0, 0, 48,
+            1, 0, 76,
+            //テーブル 範囲の数と位置、範囲(種類, 数, レジスタ, スペース, オフセット)
+            1, 56, 1, 1, 0, 0, DESCRIPTOR_RANGE_OFFSET_APPEND,
+            //ルート定数 レジスタ, スペース, 数
+            0, 0, 4,
+        ];
+        assert_eq!(words[..expected.len()], expected);
+        assert_eq!(words[expected.len()..], StaticSampler::new(0).values());
+    }
+
+    #[test]
+    fn invalid_signatures_are_rejected() {
+        let mixed = RootSignatureDesc::global().table(&[DescriptorRange::new(DescriptorRangeType::Srv, 0, 1), DescriptorRange::new(DescriptorRangeType::Sampler, 0, 1)]);
+        assert_eq!(mixed.serialize(RootSignatureVersion::V1_1), Err(RootSignatureError::MixedSamplerTable { parameter: 0 }));
+
+        let flagged = RootSignatureDesc::global().cbv(0, 0).parameter(RootParameter {
+            kind: RootParameterKind::Descriptor { descriptor_type: RootDescriptorType::Cbv, register: 1, space: 0, flags: ROOT_DESCRIPTOR_FLAG_DATA_STATIC },
+            visibility: ShaderVisibility::All,
+        });
+        assert_eq!(flagged.serialize(RootSignatureVersion::V1_0), Err(RootSignatureError::FlagsRequireVersion11 { parameter: 1 }));
+
+        let mut part = RootSignatureDesc::global().constants(0, 0, 1).serialize_part(RootSignatureVersion::V1_1).unwrap();
+        assert!(matches!(RootSignatureDesc::parse_part(&part[..part.len() - 4]), Err(RootSignatureError::Container(DxbcError::UnexpectedEof { .. }))));
+        part[0] = 3;
+        assert_eq!(RootSignatureDesc::parse_part(&part), Err(RootSignatureError::UnsupportedVersion(3)));
+    }
+}
@@ -0,0 +1,347 @@
+use std::error::Error;
+use std::f32::consts::{PI, TAU};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::camera::{Camera, CameraModel, CameraPose, ControllerKind, Projection};
+use crate::cpu::shaders::{RayShaderExports, RayType};
+use crate::math::Vec3;
+
+//シーンファイル 今はカメラとシェーダーのエクスポート名だけ書ける
+//1行に1つ「キー 値...」 #から後はコメント 角度は度
+//  camera equirectangular
+//  controller fly
+//  position 0 0 1
+//  target 0 0 0
+//  up 0 1 0
+//  fov 90
+//  aperture 0.05
+//  focus-distance 1
+//  blades 6
+//  blade-rotation 0
+//  extent 1 1
+//  ray-gen MainRayGen
+//  miss shadow ShadowMiss
+//  hit-group radiance DefaultHitGroup
+//  closest-hit MainClosestHit
+//  any-hit shadow ShadowAnyHit
+//書かなかったものはCameraModel、ControllerKind、RayShaderExportsの既定値 カメラに関係ない値、向きが決まらない姿勢、範囲の外の値はエラー
+//miss、hit-group、any-hitはレイの種類(radiance|shadow)ごと
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io { path: PathBuf, error: io::Error },
+    Parse { line: usize, message: String },
+    //選んだカメラにない値が書いてある
+    NotApplicable { key: &'static str, camera: CameraModel },
+    //fovは度
+    InvalidFov { fov: f32, camera: CameraModel },
+    //向きが決まらない姿勢や範囲の外の値
+    InvalidValue { key: &'static str, reason: &'static str },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            SceneError::Parse { line, message } => write!(f, "scene line {}: {}", line, message),
+            SceneError::NotApplicable { key, camera } => write!(f, "{} does not apply to the {} camera", key, camera.name()),
+            SceneError::InvalidFov { fov, camera } => {
+                let max = if *camera == CameraModel::Fisheye { 360 } else { 180 };
+                write!(f, "fov {} is out of range for the {} camera (0 < fov < {})", fov, camera.name(), max)
+            }
+            SceneError::InvalidValue { key, reason } => write!(f, "{} {}", key, reason),
+        }
+    }
+}
+
+impl Error for SceneError {}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SceneFile {
+    pub camera: Option<CameraModel>,
+    pub controller: Option<ControllerKind>,
+    pub position: Option<Vec3>,
+    pub target: Option<Vec3>,
+    pub up: Option<Vec3>,
+    //ここから下はラジアン
+    pub fov: Option<f32>,
+    pub aperture: Option<f32>,
+    pub focus_distance: Option<f32>,
+    pub blades: Option<u32>,
+    pub blade_rotation: Option<f32>,
+    pub extent: Option<[f32; 2]>,
+    pub exports: RayShaderExports,
+}
+
+impl SceneFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<SceneFile, SceneError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| SceneError::Io { path: path.to_path_buf(), error })?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<SceneFile, SceneError> {
+        let mut scene = SceneFile::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parse_error = |message: String| SceneError::Parse { line: i + 1, message };
+
+            let mut fields = line.split_whitespace();
+            let key = fields.next().unwrap_or("");
+            let values: Vec<&str> = fields.collect();
+            let numbers = |count: usize| -> Result<Vec<f32>, SceneError> {
+                let numbers: Option<Vec<f32>> = values.iter().map(|v| v.parse().ok()).collect();
+                match numbers {
+                    Some(numbers) if numbers.len() == count => Ok(numbers),
+                    _ => Err(parse_error(format!("{} expects {} number(s)", key, count))),
+                }
+            };
+            let vector = || numbers(3).map(|n| Vec3::new(n[0], n[1], n[2]));
+            let number = || numbers(1).map(|n| n[0]);
+            let export = || match values[..] {
+                [name] => Ok(name.to_string()),
+                _ => Err(parse_error(format!("{} expects an export name", key))),
+            };
+            //レイの種類ごとの名前
+            let per_ray_type = || match values[..] {
+                [ray_type, name] => match RayType::from_name(ray_type) {
+                    Some(r) => Ok((r as usize, name.to_string())),
+                    None => Err(parse_error(format!("unknown ray type \"{}\"", ray_type))),
+                },
+                _ => Err(parse_error(format!("{} expects a ray type and an export name", key))),
+            };
+
+            match key {
+                "camera" => {
+                    let [name] = values[..] else {
+                        return Err(parse_error("camera expects a model name".to_string()));
+                    };
+                    let model = CameraModel::from_name(name).ok_or_else(|| parse_error(format!("unknown camera \"{}\"", name)))?;
+                    scene.camera = Some(model);
+                }
+                "controller" => {
+                    let [name] = values[..] else {
+                        return Err(parse_error("controller expects a controller name".to_string()));
+                    };
+                    let kind = ControllerKind::from_name(name).ok_or_else(|| parse_error(format!("unknown controller \"{}\"", name)))?;
+                    scene.controller = Some(kind);
+                }
+                "position" => scene.position = Some(vector()?),
+                "target" => scene.target = Some(vector()?),
+                "up" => scene.up = Some(vector()?),
+                "fov" => scene.fov = Some(number()?.to_radians()),
+                "aperture" => scene.aperture = Some(number()?),
+                "focus-distance" => scene.focus_distance = Some(number()?),
+                "blades" => {
+                    let blades = values.first().and_then(|v| v.parse().ok()).filter(|_| values.len() == 1);
+                    scene.blades = Some(blades.ok_or_else(|| parse_error("blades expects an integer".to_string()))?);
+                }
+                "blade-rotation" => scene.blade_rotation = Some(number()?.to_radians()),
+                "extent" => {
+                    let n = numbers(2)?;
+                    scene.extent = Some([n[0], n[1]]);
+                }
+                "ray-gen" => scene.exports.ray_gen = export()?,
+                "closest-hit" => scene.exports.closest_hit = export()?,
+                "miss" => {
+                    let (r, name) = per_ray_type()?;
+                    scene.exports.miss[r] = name;
+                }
+                "hit-group" => {
+                    let (r, name) = per_ray_type()?;
+                    scene.exports.hit_groups[r] = name;
+                }
+                "any-hit" => {
+                    let (r, name) = per_ray_type()?;
+                    scene.exports.any_hit[r] = name;
+                }
+                _ => return Err(parse_error(format!("unknown key \"{}\"", key))),
+            }
+        }
+
+        scene.camera(1.0)?;
+        Ok(scene)
+    }
+
+    //aspectは出力の縦横比 パノラマでは使わない
+    pub fn camera(&self, aspect: f32) -> Result<Camera, SceneError> {
+        let default = CameraPose::default();
+        let position = self.position.unwrap_or(default.position);
+        let target = self.target.unwrap_or(position + default.forward);
+        let up = self.up.unwrap_or(default.up);
+
+        //どちらかが0か平行だと右と上が決まらない
+        let invalid = |key, reason| SceneError::InvalidValue { key, reason };
+        let forward = target - position;
+        if !positive(forward.length()) {
+            return Err(invalid("target", "must differ from position"));
+        }
+        if !positive(up.length()) || forward.normalize().cross(up.normalize()).length() < 1e-6 {
+            return Err(invalid("up", "must not be zero or parallel to the view direction"));
+        }
+
+        Ok(Camera::new(position, target, up, self.projection(aspect)?))
+    }
+
+    pub fn projection(&self, aspect: f32) -> Result<Projection, SceneError> {
+        let model = self.camera.unwrap_or_default();
+        let unused = |key| SceneError::NotApplicable { key, camera: model };
+
+        let mut projection = model.projection(aspect);
+        if let Some(fov) = self.fov {
+            //透視投影は180度で無限に広がる 魚眼は360度で真後ろまで
+            let max = match projection {
+                Projection::Perspective { .. } | Projection::ThinLens { .. } => PI,
+                Projection::Fisheye { .. } => TAU,
+                _ => return Err(unused("fov")),
+            };
+            if !(fov > 0.0 && fov < max) {
+                return Err(SceneError::InvalidFov { fov: fov.to_degrees(), camera: model });
+            }
+            projection = projection.with_fov(fov);
+        }
+
+        match &mut projection {
+            Projection::ThinLens { aperture, focus_distance, blades, blade_rotation, .. } => {
+                *aperture = self.aperture.unwrap_or(*aperture);
+                *focus_distance = self.focus_distance.unwrap_or(*focus_distance);
+                *blades = self.blades.unwrap_or(*blades);
+                *blade_rotation = self.blade_rotation.unwrap_or(*blade_rotation);
+
+                if aperture.is_nan() || *aperture < 0.0 {
+                    return Err(SceneError::InvalidValue { key: "aperture", reason: "must not be negative" });
+                }
+                if !positive(*focus_distance) {
+                    return Err(SceneError::InvalidValue { key: "focus-distance", reason: "must be positive" });
+                }
+                //3枚未満は円になるので円にしたいときは0と書く
+                if (1..=2).contains(blades) {
+                    return Err(SceneError::InvalidValue { key: "blades", reason: "must be 0 for a round aperture or at least 3" });
+                }
+            }
+            _ => {
+                for (key, set) in [
+                    ("aperture", self.aperture.is_some()),
+                    ("focus-distance", self.focus_distance.is_some()),
+                    ("blades", self.blades.is_some()),
+                    ("blade-rotation", self.blade_rotation.is_some()),
+                ] {
+                    if set {
+                        return Err(unused(key));
+                    }
+                }
+            }
+        }
+
+        match (&mut projection, self.extent) {
+            (Projection::Orthographic { .. }, Some(e)) if !positive(e[0]) || !positive(e[1]) => {
+                return Err(SceneError::InvalidValue { key: "extent", reason: "must be positive" });
+            }
+            (Projection::Orthographic { extent }, Some(e)) => *extent = e,
+            (_, Some(_)) => return Err(unused("extent")),
+            _ => {}
+        }
+
+        Ok(projection)
+    }
+}
+
+//NaNも弾く
+fn positive(v: f32) -> bool {
+    v > 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::shaders::ray_shader_library;
+
+    #[test]
+    fn exports_default_to_ray_shader_hlsl() {
+        let scene = SceneFile::parse("camera perspective\n").unwrap();
+        let library = ray_shader_library(&scene.exports, &["BarycentricMaterial"]);
+
+        assert_eq!(library.ray_gen, "MainRayGen");
+        assert_eq!(library.miss, vec!["MainMiss", "ShadowMiss"]);
+        assert_eq!(library.hit_group_names(), vec!["DefaultHitGroup", "ShadowHitGroup"]);
+        assert_eq!(library.hit_groups[0].closest_hit.as_deref(), Some("MainClosestHit"));
+        assert_eq!(library.hit_groups[0].any_hit.as_deref(), Some("MainAnyHit"));
+        assert_eq!(library.hit_groups[1].any_hit.as_deref(), Some("ShadowAnyHit"));
+    }
+
+    #[test]
+    fn exports_are_overridden_per_ray_type() {
+        let text = "ray-gen RayGen2\nmiss shadow Occluded\nhit-group radiance Opaque # comment\nclosest-hit Shade\nany-hit shadow Cutout\n";
+        let library = ray_shader_library(&SceneFile::parse(text).unwrap().exports, &[]);
+
+        assert_eq!(library.ray_gen, "RayGen2");
+        assert_eq!(library.miss, vec!["MainMiss", "Occluded"]);
+        assert_eq!(library.hit_group_names(), vec!["Opaque", "ShadowHitGroup"]);
+        assert_eq!(library.hit_groups[0].closest_hit.as_deref(), Some("Shade"));
+        assert_eq!(library.hit_groups[1].any_hit.as_deref(), Some("Cutout"));
+    }
+
+    #[test]
+    fn malformed_exports_report_the_line() {
+        let error = |text| match SceneFile::parse(text) {
+            Err(SceneError::Parse { line, message }) => (line, message),
+            other => panic!("{:?}", other),
+        };
+
+        assert_eq!(error("\nmiss primary MainMiss"), (2, "unknown ray type \"primary\"".to_string()));
+        assert_eq!(error("hit-group DefaultHitGroup"), (1, "hit-group expects a ray type and an export name".to_string()));
+        assert_eq!(error("ray-gen"), (1, "ray-gen expects an export name".to_string()));
+    }
+
+    #[test]
+    fn controller_is_selected_by_name() {
+        assert_eq!(SceneFile::parse("").unwrap().controller, None);
+        assert_eq!(SceneFile::parse("controller turntable").unwrap().controller, Some(ControllerKind::Turntable));
+        assert!(matches!(SceneFile::parse("controller trackball"), Err(SceneError::Parse { line: 1, .. })));
+    }
+
+    #[test]
+    fn fov_is_checked_for_each_camera() {
+        let fov = |text| SceneFile::parse(text).map(|scene| scene.fov.map(f32::to_degrees));
+        assert!(matches!(fov("camera perspective\nfov 0"), Err(SceneError::InvalidFov { camera: CameraModel::Perspective, .. })));
+        assert!(matches!(fov("camera thin-lens\nfov 180"), Err(SceneError::InvalidFov { camera: CameraModel::ThinLens, .. })));
+        assert!(matches!(fov("camera fisheye\nfov 360"), Err(SceneError::InvalidFov { .. })));
+        assert!(matches!(fov("camera equirectangular\nfov 90"), Err(SceneError::NotApplicable { key: "fov", .. })));
+        assert!((fov("camera perspective\nfov 179").unwrap().unwrap() - 179.0).abs() < 1e-3);
+        assert!((fov("camera fisheye\nfov 200").unwrap().unwrap() - 200.0).abs() < 1e-3);
+
+        //コマンドラインのfovも同じところで弾く
+        let args = |args: &[&str]| crate::config::Config::from_args(args.iter().map(|a| a.to_string())).unwrap();
+        assert!(matches!(args(&["--camera", "perspective", "--fov", "180"]).scene(), Err(SceneError::InvalidFov { .. })));
+        assert!(matches!(args(&["--camera", "perspective", "--fov", "-10"]).scene(), Err(SceneError::InvalidFov { .. })));
+        assert!(args(&["--camera", "perspective", "--fov", "60"]).scene().is_ok());
+    }
+
+    #[test]
+    fn degenerate_cameras_are_rejected() {
+        let invalid = |text| match SceneFile::parse(text) {
+            Err(SceneError::InvalidValue { key, .. }) => key,
+            other => panic!("{}: {:?}", text, other),
+        };
+
+        assert_eq!(invalid("position 1 2 3\ntarget 1 2 3"), "target");
+        //既定では-Zを見るので+Zのupは平行
+        assert_eq!(invalid("up 0 0 1"), "up");
+        assert_eq!(invalid("up 0 0 0"), "up");
+        assert_eq!(invalid("camera thin-lens\naperture -0.1"), "aperture");
+        assert_eq!(invalid("camera thin-lens\nfocus-distance 0"), "focus-distance");
+        assert_eq!(invalid("camera thin-lens\nblades 2"), "blades");
+        assert_eq!(invalid("camera orthographic\nextent 1 0"), "extent");
+
+        assert!(SceneFile::parse("camera thin-lens\naperture 0\nblades 0\nup 0 0 1\ntarget 1 0 1").is_ok());
+        assert!(SceneFile::parse("camera orthographic\nextent 2 1").is_ok());
+    }
+}
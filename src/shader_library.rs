@@ -0,0 +1,356 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::dxbc::{RuntimeData, ShaderKind};
+use crate::pipeline_config::PipelineConfig;
+
+//ステートオブジェクトに入れるエクスポートとヒットグループ
+//CreateStateObjectに渡す前にライブラリ(RDAT)のエクスポートと突き合わせる
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HitGroupDesc {
+    pub name: String,
+    pub closest_hit: Option<String>,
+    pub any_hit: Option<String>,
+    //SomeならD3D12_HIT_GROUP_TYPE_PROCEDURAL_PRIMITIVE
+    pub intersection: Option<String>,
+}
+
+impl HitGroupDesc {
+    pub fn new(name: &str) -> Self {
+        HitGroupDesc { name: name.to_string(), closest_hit: None, any_hit: None, intersection: None }
+    }
+
+    pub fn closest_hit(mut self, name: &str) -> Self {
+        self.closest_hit = Some(name.to_string());
+        self
+    }
+
+    pub fn any_hit(mut self, name: &str) -> Self {
+        self.any_hit = Some(name.to_string());
+        self
+    }
+
+    pub fn intersection(mut self, name: &str) -> Self {
+        self.intersection = Some(name.to_string());
+        self
+    }
+
+    pub fn imports(&self) -> impl Iterator<Item = (&str, ShaderKind)> {
+        [
+            (&self.closest_hit, ShaderKind::ClosestHit),
+            (&self.any_hit, ShaderKind::AnyHit),
+            (&self.intersection, ShaderKind::Intersection),
+        ]
+        .into_iter()
+        .filter_map(|(name, kind)| name.as_deref().map(|n| (n, kind)))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderLibraryDesc {
+    pub ray_gen: String,
+    //並び順がMissShaderIndex
+    pub miss: Vec<String>,
+    //並び順がRayContributionToHitGroupIndex
+    pub hit_groups: Vec<HitGroupDesc>,
+    //並び順がCallShader()のインデックス
+    pub callables: Vec<String>,
+    //トレース以外のパスで使うraygen DispatchRaysごとに1つ選ぶ
+    pub pass_ray_gens: Vec<String>,
+}
+
+impl ShaderLibraryDesc {
+    //ライブラリからエクスポートする関数と期待するシェーダーの種類
+    //ヒットグループから参照されるものも含み、重複は除く
+    pub fn exports(&self) -> Vec<(&str, ShaderKind)> {
+        let mut unique: Vec<(&str, ShaderKind)> = vec![];
+        for e in self.uses() {
+            if !unique.iter().any(|u| u.0 == e.0) {
+                unique.push(e);
+            }
+        }
+        unique
+    }
+
+    fn uses(&self) -> Vec<(&str, ShaderKind)> {
+        let mut uses: Vec<(&str, ShaderKind)> = vec![(self.ray_gen.as_str(), ShaderKind::RayGeneration)];
+        uses.extend(self.miss.iter().map(|m| (m.as_str(), ShaderKind::Miss)));
+        uses.extend(self.hit_groups.iter().flat_map(HitGroupDesc::imports));
+        uses.extend(self.callables.iter().map(|c| (c.as_str(), ShaderKind::Callable)));
+        uses.extend(self.pass_ray_gens.iter().map(|r| (r.as_str(), ShaderKind::RayGeneration)));
+        uses
+    }
+
+    pub fn hit_group_names(&self) -> Vec<&str> {
+        self.hit_groups.iter().map(|h| h.name.as_str()).collect()
+    }
+
+    //見つかった問題を全部まとめて返す
+    pub fn validate(&self, library: &RuntimeData, config: &PipelineConfig) -> Result<(), LibraryValidationError> {
+        let mut problems = vec![];
+
+        let mut expected: Vec<(&str, ShaderKind)> = vec![];
+        for (name, kind) in self.uses() {
+            //同じ関数を別の種類として使っている
+            if let Some(&(_, first)) = expected.iter().find(|e| e.0 == name) {
+                if first != kind {
+                    problems.push(ExportProblem::ConflictingUse { name: name.to_string(), first, second: kind });
+                }
+                continue;
+            }
+            expected.push((name, kind));
+
+            let Some(function) = library.function(name) else {
+                problems.push(ExportProblem::Missing { name: name.to_string(), expected: kind });
+                continue;
+            };
+
+            if function.kind != kind {
+                problems.push(ExportProblem::WrongKind { name: name.to_string(), expected: kind, actual: function.kind });
+                continue;
+            }
+
+            //callableのpayload_sizeは引数のサイズなのでMaxPayloadSizeInBytesとは関係ない
+            if matches!(kind, ShaderKind::Miss | ShaderKind::ClosestHit | ShaderKind::AnyHit) && function.payload_size > config.max_payload_size {
+                problems.push(ExportProblem::PayloadTooLarge { name: name.to_string(), size: function.payload_size, max: config.max_payload_size });
+            }
+
+            if function.attribute_size > config.max_attribute_size {
+                problems.push(ExportProblem::AttributeTooLarge {
+                    name: name.to_string(),
+                    size: function.attribute_size,
+                    max: config.max_attribute_size,
+                });
+            }
+        }
+
+        for (i, hit_group) in self.hit_groups.iter().enumerate() {
+            let name = hit_group.name.as_str();
+
+            if library.function(name).is_some() || self.hit_groups[..i].iter().any(|h| h.name == name) {
+                problems.push(ExportProblem::DuplicateName(name.to_string()));
+            }
+
+            if hit_group.closest_hit.is_none() && hit_group.any_hit.is_none() && hit_group.intersection.is_none() {
+                problems.push(ExportProblem::EmptyHitGroup(name.to_string()));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(LibraryValidationError { problems })
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExportProblem {
+    Missing { name: String, expected: ShaderKind },
+    WrongKind { name: String, expected: ShaderKind, actual: ShaderKind },
+    ConflictingUse { name: String, first: ShaderKind, second: ShaderKind },
+    PayloadTooLarge { name: String, size: u32, max: u32 },
+    AttributeTooLarge { name: String, size: u32, max: u32 },
+    //ヒットグループ名がエクスポートや他のヒットグループと被っている
+    DuplicateName(String),
+    EmptyHitGroup(String),
+}
+
+impl fmt::Display for ExportProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportProblem::Missing { name, expected } => write!(f, "{} ({}) is not exported by the library", name, expected),
+            ExportProblem::WrongKind { name, expected, actual } => write!(f, "{} is a {} shader, expected {}", name, actual, expected),
+            ExportProblem::ConflictingUse { name, first, second } => write!(f, "{} is used both as {} and {}", name, first, second),
+            ExportProblem::PayloadTooLarge { name, size, max } => {
+                write!(f, "{} uses a {} byte payload, but MaxPayloadSizeInBytes is {}", name, size, max)
+            }
+            ExportProblem::AttributeTooLarge { name, size, max } => {
+                write!(f, "{} uses {} bytes of attributes, but MaxAttributeSizeInBytes is {}", name, size, max)
+            }
+            ExportProblem::DuplicateName(name) => write!(f, "hit group name {} is already used", name),
+            ExportProblem::EmptyHitGroup(name) => write!(f, "hit group {} imports no shaders", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LibraryValidationError {
+    pub problems: Vec<ExportProblem>,
+}
+
+impl fmt::Display for LibraryValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "shader library does not match the pipeline:")?;
+        self.problems.iter().try_for_each(|p| write!(f, "\n  {}", p))
+    }
+}
+
+impl Error for LibraryValidationError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dxbc::FunctionInfo;
+
+    fn function(name: &str, kind: ShaderKind, payload_size: u32, attribute_size: u32) -> FunctionInfo {
+        FunctionInfo {
+            name: name.to_string(),
+            mangled_name: format!("\u{1}?{}@@YAXXZ", name),
+            kind,
+            resources: vec![],
+            function_dependencies: vec![],
+            payload_size,
+            attribute_size,
+            feature_flags: 0,
+            shader_stage_flag: 0,
+            min_shader_target: 0,
+        }
+    }
+
+    fn library() -> RuntimeData {
+        RuntimeData {
+            resources: vec![],
+            functions: vec![
+                function("RayGen", ShaderKind::RayGeneration, 0, 0),
+                function("Miss", ShaderKind::Miss, 16, 0),
+                function("ClosestHit", ShaderKind::ClosestHit, 16, 8),
+                function("AnyHit", ShaderKind::AnyHit, 16, 8),
+                function("ShadowMiss", ShaderKind::Miss, 4, 0),
+                function("Post", ShaderKind::RayGeneration, 0, 0),
+            ],
+        }
+    }
+
+    fn desc() -> ShaderLibraryDesc {
+        ShaderLibraryDesc {
+            ray_gen: "RayGen".to_string(),
+            miss: vec!["Miss".to_string(), "ShadowMiss".to_string()],
+            hit_groups: vec![HitGroupDesc::new("HitGroup").closest_hit("ClosestHit").any_hit("AnyHit"), HitGroupDesc::new("ShadowHitGroup").any_hit("AnyHit")],
+            callables: vec![],
+            pass_ray_gens: vec!["Post".to_string()],
+        }
+    }
+
+    fn config() -> PipelineConfig {
+        PipelineConfig::new(2).payload::<[f32; 4]>().attribute::<[f32; 2]>()
+    }
+
+    fn problems(desc: &ShaderLibraryDesc, library: &RuntimeData) -> Vec<ExportProblem> {
+        desc.validate(library, &config()).map(|_| vec![]).unwrap_or_else(|e| e.problems)
+    }
+
+    #[test]
+    fn matching_library_is_accepted() {
+        let desc = desc();
+        assert_eq!(desc.validate(&library(), &config()), Ok(()));
+        //AnyHitは2つのヒットグループで使っているが1回だけ
+        let exports = desc.exports();
+        assert_eq!(exports.iter().filter(|e| e.0 == "AnyHit").count(), 1);
+        assert_eq!(exports.len(), 6);
+    }
+
+    #[test]
+    fn missing_exports_are_reported() {
+        let mut library = library();
+        library.functions.retain(|f| f.name != "ShadowMiss" && f.name != "Post");
+
+        assert_eq!(
+            problems(&desc(), &library),
+            vec![
+                ExportProblem::Missing { name: "ShadowMiss".to_string(), expected: ShaderKind::Miss },
+                ExportProblem::Missing { name: "Post".to_string(), expected: ShaderKind::RayGeneration },
+            ]
+        );
+    }
+
+    #[test]
+    fn hit_group_imports_must_exist() {
+        let mut desc = desc();
+        desc.hit_groups[0] = HitGroupDesc::new("HitGroup").closest_hit("MissingClosestHit").any_hit("MissingAnyHit");
+
+        assert_eq!(
+            problems(&desc, &library()),
+            vec![
+                ExportProblem::Missing { name: "MissingClosestHit".to_string(), expected: ShaderKind::ClosestHit },
+                ExportProblem::Missing { name: "MissingAnyHit".to_string(), expected: ShaderKind::AnyHit },
+            ]
+        );
+    }
+
+    #[test]
+    fn hit_group_names_must_be_unique() {
+        let mut desc = desc();
+        //エクスポートと同じ名前、他のヒットグループと同じ名前
+        desc.hit_groups.push(HitGroupDesc::new("ClosestHit").closest_hit("ClosestHit"));
+        desc.hit_groups.push(HitGroupDesc::new("HitGroup").closest_hit("ClosestHit"));
+        desc.hit_groups.push(HitGroupDesc::new("EmptyHitGroup"));
+
+        assert_eq!(
+            problems(&desc, &library()),
+            vec![
+                ExportProblem::DuplicateName("ClosestHit".to_string()),
+                ExportProblem::DuplicateName("HitGroup".to_string()),
+                ExportProblem::EmptyHitGroup("EmptyHitGroup".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn shaders_must_have_the_kind_of_their_slot() {
+        let mut desc = desc();
+        desc.miss[1] = "ClosestHit".to_string();
+        desc.callables.push("AnyHit".to_string());
+        desc.hit_groups[1] = HitGroupDesc::new("ShadowHitGroup").closest_hit("Miss");
+
+        assert_eq!(
+            problems(&desc, &library()),
+            vec![
+                ExportProblem::WrongKind { name: "ClosestHit".to_string(), expected: ShaderKind::Miss, actual: ShaderKind::ClosestHit },
+                ExportProblem::ConflictingUse { name: "ClosestHit".to_string(), first: ShaderKind::Miss, second: ShaderKind::ClosestHit },
+                ExportProblem::ConflictingUse { name: "Miss".to_string(), first: ShaderKind::Miss, second: ShaderKind::ClosestHit },
+                ExportProblem::ConflictingUse { name: "AnyHit".to_string(), first: ShaderKind::AnyHit, second: ShaderKind::Callable },
+            ]
+        );
+    }
+
+    #[test]
+    fn sizes_are_checked_against_the_config() {
+        let mut library = library();
+        library.functions[1].payload_size = 20;
+        library.functions[2].attribute_size = 12;
+        //raygenのペイロードは数えない
+        library.functions[0].payload_size = 64;
+
+        assert_eq!(
+            problems(&desc(), &library),
+            vec![
+                ExportProblem::PayloadTooLarge { name: "Miss".to_string(), size: 20, max: 16 },
+                ExportProblem::AttributeTooLarge { name: "ClosestHit".to_string(), size: 12, max: 8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn message_lists_each_problem() {
+        let mut desc = desc();
+        desc.ray_gen = "MissingRayGen".to_string();
+        desc.miss[0] = "AnyHit".to_string();
+        desc.hit_groups.push(HitGroupDesc::new("HitGroup"));
+
+        let error = desc.validate(&library(), &config()).unwrap_err();
+        //AnyHitは2つのヒットグループで使っているのでその分だけ出る
+        assert_eq!(error.problems.len(), 6);
+        assert_eq!(
+            error.to_string(),
+            "shader library does not match the pipeline:\n  \
+             MissingRayGen (raygeneration) is not exported by the library\n  \
+             AnyHit is a anyhit shader, expected miss\n  \
+             AnyHit is used both as miss and anyhit\n  \
+             AnyHit is used both as miss and anyhit\n  \
+             hit group name HitGroup is already used\n  \
+             hit group HitGroup imports no shaders"
+        );
+    }
+}
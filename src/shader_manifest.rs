@@ -0,0 +1,352 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::dxbc::{Container, DxbcError};
+use crate::md5::{self, Digest};
+
+//コンパイル済みシェーダー(.cso)とそのソースの対応
+//1行に1つ「ソース ソースのMD5 出力 シェーダーハッシュ」 パスはマニフェストのあるディレクトリから
+//ハッシュはコンパイルした時点のもので、ずれていたら.csoが古いか壊れている
+
+pub const DEFAULT_MANIFEST: &str = "shaders/manifest.txt";
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io { path: PathBuf, error: io::Error },
+    Parse { line: usize, message: &'static str },
+    Container { path: PathBuf, error: DxbcError },
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ManifestError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ManifestError::Parse { line, message } => write!(f, "manifest line {}: {}", line, message),
+            ManifestError::Container { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl Error for ManifestError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub source: PathBuf,
+    pub source_hash: Digest,
+    pub output: PathBuf,
+    //出力のHASHパート
+    pub shader_hash: Digest,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShaderWarning {
+    OutputMissing { output: PathBuf, error: String },
+    //HASHパートが中身と合わない、コンテナとして読めないなど
+    Corrupt { output: PathBuf, error: DxbcError },
+    //マニフェストを書いた後で出力が差し替えられた
+    OutputChanged { output: PathBuf },
+    SourceMissing { source: PathBuf },
+    //マニフェストを書いた後でソースが変わった
+    SourceChanged { source: PathBuf, output: PathBuf },
+    //ソースの方が新しい
+    OlderThanSource { source: PathBuf, output: PathBuf },
+}
+
+impl fmt::Display for ShaderWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderWarning::OutputMissing { output, error } => write!(f, "{}: {}", output.display(), error),
+            ShaderWarning::Corrupt { output, error } => write!(f, "{} is corrupt: {}", output.display(), error),
+            ShaderWarning::OutputChanged { output } => write!(f, "{} does not match the shader manifest", output.display()),
+            ShaderWarning::SourceMissing { source } => write!(f, "{} does not exist", source.display()),
+            ShaderWarning::SourceChanged { source, output } => {
+                write!(f, "{} has changed since {} was compiled", source.display(), output.display())
+            }
+            ShaderWarning::OlderThanSource { source, output } => {
+                write!(f, "{} is older than {}", output.display(), source.display())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderManifest {
+    //エントリのパスの基準
+    pub base: PathBuf,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl ShaderManifest {
+    pub fn load(path: impl AsRef<Path>) -> Result<ShaderManifest, ManifestError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| ManifestError::Io { path: path.to_path_buf(), error })?;
+        Self::parse(&text, path.parent().unwrap_or(Path::new("")))
+    }
+
+    pub fn parse(text: &str, base: impl AsRef<Path>) -> Result<ShaderManifest, ManifestError> {
+        let mut entries = vec![];
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parse_error = |message| ManifestError::Parse { line: i + 1, message };
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [source, source_hash, output, shader_hash] = fields[..] else {
+                return Err(parse_error("expected <source> <source md5> <output> <shader hash>"));
+            };
+
+            entries.push(ManifestEntry {
+                source: PathBuf::from(source),
+                source_hash: parse_digest(source_hash).ok_or_else(|| parse_error("invalid source hash"))?,
+                output: PathBuf::from(output),
+                shader_hash: parse_digest(shader_hash).ok_or_else(|| parse_error("invalid shader hash"))?,
+            });
+        }
+
+        Ok(ShaderManifest { base: base.as_ref().to_path_buf(), entries })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ManifestError> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string()).map_err(|error| ManifestError::Io { path: path.to_path_buf(), error })
+    }
+
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        self.base.join(path)
+    }
+
+    //今のソースと出力のハッシュでエントリを書き直す(なければ足す)
+    pub fn record(&mut self, source: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<(), ManifestError> {
+        let (source, output) = (source.as_ref(), output.as_ref());
+
+        let source_path = self.resolve(source);
+        let source_bytes = fs::read(&source_path).map_err(|error| ManifestError::Io { path: source_path, error })?;
+
+        let output_path = self.resolve(output);
+        let output_bytes = fs::read(&output_path).map_err(|error| ManifestError::Io { path: output_path.clone(), error })?;
+        let shader_hash = Container::parse(&output_bytes)
+            .and_then(|c| c.verify_hash())
+            .map_err(|error| ManifestError::Container { path: output_path, error })?;
+
+        let entry = ManifestEntry {
+            source: source.to_path_buf(),
+            source_hash: md5::digest(&source_bytes),
+            output: output.to_path_buf(),
+            shader_hash: shader_hash.digest,
+        };
+
+        match self.entries.iter_mut().find(|e| e.output == entry.output) {
+            Some(e) => *e = entry,
+            None => self.entries.push(entry),
+        }
+
+        Ok(())
+    }
+
+    pub fn check(&self) -> Vec<ShaderWarning> {
+        self.entries.iter().flat_map(|e| self.check_entry(e)).collect()
+    }
+
+    pub fn check_entry(&self, entry: &ManifestEntry) -> Vec<ShaderWarning> {
+        let mut warnings = vec![];
+        let source = self.resolve(&entry.source);
+        let output = self.resolve(&entry.output);
+
+        match fs::read(&output) {
+            Err(e) => warnings.push(ShaderWarning::OutputMissing { output: output.clone(), error: e.to_string() }),
+            Ok(bytes) => match Container::parse(&bytes).and_then(|c| c.verify_hash()) {
+                Err(error) => warnings.push(ShaderWarning::Corrupt { output: output.clone(), error }),
+                Ok(hash) if hash.digest != entry.shader_hash => warnings.push(ShaderWarning::OutputChanged { output: output.clone() }),
+                Ok(_) => {}
+            },
+        }
+
+        match fs::read(&source) {
+            Err(_) => warnings.push(ShaderWarning::SourceMissing { source }),
+            Ok(bytes) if md5::digest(&bytes) != entry.source_hash => warnings.push(ShaderWarning::SourceChanged { source, output }),
+            //中身が同じなら更新日時だけ見る
+            Ok(_) => {
+                let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+                if let (Some(s), Some(o)) = (modified(&source), modified(&output)) {
+                    if s > o {
+                        warnings.push(ShaderWarning::OlderThanSource { source, output });
+                    }
+                }
+            }
+        }
+
+        warnings
+    }
+}
+
+impl fmt::Display for ShaderManifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "#source source-md5 output shader-hash")?;
+        for e in &self.entries {
+            writeln!(
+                f,
+                "{} {} {} {}",
+                e.source.display(),
+                digest_hex(&e.source_hash),
+                e.output.display(),
+                digest_hex(&e.shader_hash)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn digest_hex(digest: &Digest) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_digest(hex: &str) -> Option<Digest> {
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut digest = [0u8; 16];
+    for (i, d) in digest.iter_mut().enumerate() {
+        *d = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dxbc::{Part, FOURCC_DXIL};
+
+    const RAY_SHADER: &[u8] = include_bytes!("../ray_shader.cso");
+    const SOURCE: &str = "[shader(\"raygeneration\")] void MainRayGen() {}\n";
+
+    //テストごとの一時ディレクトリ
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rwr-manifest-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("output")).unwrap();
+        dir
+    }
+
+    //ソースと出力を書いて記録したマニフェスト
+    fn recorded(name: &str) -> ShaderManifest {
+        let dir = temp_dir(name);
+        fs::write(dir.join("shader.hlsl"), SOURCE).unwrap();
+        fs::write(dir.join("output/shader.cso"), RAY_SHADER).unwrap();
+
+        let mut manifest = ShaderManifest { base: dir, entries: vec![] };
+        manifest.record("shader.hlsl", "output/shader.cso").unwrap();
+        manifest
+    }
+
+    #[test]
+    fn parse_reads_what_display_writes() {
+        let manifest = recorded("parse");
+        let entry = &manifest.entries[0];
+        assert_eq!(entry.source_hash, md5::digest(SOURCE.as_bytes()));
+        assert_eq!(entry.shader_hash, Container::parse(RAY_SHADER).unwrap().hash().unwrap().digest);
+
+        let text = manifest.to_string();
+        assert!(text.starts_with("#source "));
+        assert_eq!(ShaderManifest::parse(&text, &manifest.base).unwrap(), manifest);
+
+        //保存して読み直す
+        let path = manifest.base.join("manifest.txt");
+        manifest.save(&path).unwrap();
+        assert_eq!(ShaderManifest::load(&path).unwrap(), manifest);
+        fs::remove_dir_all(&manifest.base).unwrap();
+    }
+
+    #[test]
+    fn parse_reports_the_line() {
+        let hash = "0123456789abcdef0123456789abcdef";
+        let text = format!("# comment\n\na.hlsl {hash} a.cso {hash}\nb.hlsl {hash} b.cso\n");
+        assert!(matches!(ShaderManifest::parse(&text, ""), Err(ManifestError::Parse { line: 4, .. })));
+
+        let text = format!("a.hlsl {hash} a.cso xyz\n");
+        assert!(matches!(ShaderManifest::parse(&text, ""), Err(ManifestError::Parse { line: 1, message: "invalid shader hash" })));
+        let text = format!("a.hlsl {} a.cso {hash}\n", &hash[1..]);
+        assert!(matches!(ShaderManifest::parse(&text, ""), Err(ManifestError::Parse { line: 1, message: "invalid source hash" })));
+    }
+
+    #[test]
+    fn record_replaces_the_entry_for_the_same_output() {
+        let mut manifest = recorded("record");
+        fs::write(manifest.base.join("shader.hlsl"), "//changed\n").unwrap();
+        manifest.record("shader.hlsl", "output/shader.cso").unwrap();
+
+        assert_eq!(manifest.entries.len(), 1);
+        assert_eq!(manifest.entries[0].source_hash, md5::digest(b"//changed\n"));
+
+        //出力がなければ記録しない
+        assert!(matches!(manifest.record("shader.hlsl", "output/missing.cso"), Err(ManifestError::Io { .. })));
+        fs::remove_dir_all(&manifest.base).unwrap();
+    }
+
+    #[test]
+    fn check_passes_when_nothing_changed() {
+        let manifest = recorded("match");
+        assert_eq!(manifest.check(), vec![]);
+        fs::remove_dir_all(&manifest.base).unwrap();
+    }
+
+    #[test]
+    fn check_reports_a_changed_source() {
+        let manifest = recorded("source");
+        let source = manifest.base.join("shader.hlsl");
+        fs::write(&source, "//changed\n").unwrap();
+
+        let output = manifest.base.join("output/shader.cso");
+        assert_eq!(manifest.check(), vec![ShaderWarning::SourceChanged { source: source.clone(), output }]);
+
+        fs::remove_file(&source).unwrap();
+        assert_eq!(manifest.check(), vec![ShaderWarning::SourceMissing { source }]);
+        fs::remove_dir_all(&manifest.base).unwrap();
+    }
+
+    #[test]
+    fn check_reports_a_missing_output() {
+        let manifest = recorded("missing");
+        let output = manifest.base.join("output/shader.cso");
+        fs::remove_file(&output).unwrap();
+
+        let warnings = manifest.check();
+        assert!(matches!(&warnings[..], [ShaderWarning::OutputMissing { output: o, .. }] if *o == output), "{:?}", warnings);
+        fs::remove_dir_all(&manifest.base).unwrap();
+    }
+
+    #[test]
+    fn check_reports_corrupt_and_replaced_outputs() {
+        let manifest = recorded("corrupt");
+        let output = manifest.base.join("output/shader.cso");
+
+        //途中で切れている
+        fs::write(&output, &RAY_SHADER[..RAY_SHADER.len() / 2]).unwrap();
+        let warnings = manifest.check();
+        assert!(matches!(&warnings[..], [ShaderWarning::Corrupt { error: DxbcError::SizeMismatch { .. }, .. }]), "{:?}", warnings);
+
+        //DXILのビットコードを書き換えてダイジェストだけ付け直した
+        let container = Container::parse(RAY_SHADER).unwrap();
+        let mut dxil = container.part(FOURCC_DXIL).unwrap().data.to_vec();
+        let last = dxil.len() - 1;
+        dxil[last] ^= 1;
+        let parts: Vec<Part> = container.parts.iter().map(|p| if p.fourcc == FOURCC_DXIL { Part { fourcc: p.fourcc, data: &dxil } } else { *p }).collect();
+        fs::write(&output, Container::write(&parts)).unwrap();
+        let warnings = manifest.check();
+        assert!(matches!(&warnings[..], [ShaderWarning::Corrupt { error: DxbcError::HashMismatch { .. }, .. }]), "{:?}", warnings);
+
+        //壊れてはいないが記録した後で別のものに差し替えられた
+        let mut manifest = manifest;
+        fs::write(&output, RAY_SHADER).unwrap();
+        manifest.entries[0].shader_hash[0] ^= 1;
+        assert_eq!(manifest.check(), vec![ShaderWarning::OutputChanged { output }]);
+        fs::remove_dir_all(&manifest.base).unwrap();
+    }
+}
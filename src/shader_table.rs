@@ -0,0 +1,356 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::as_planner::align;
+
+//シェーダーテーブルのバイト列とD3D12_DISPATCH_RAYS_DESCに入れる範囲を作る
+//シェーダー識別子の取得は呼び出し側に任せるのでWindows以外でも動く
+
+//D3D12_SHADER_IDENTIFIER_SIZE_IN_BYTES
+pub const SHADER_IDENTIFIER_SIZE: u64 = 32;
+//D3D12_RAYTRACING_SHADER_RECORD_BYTE_ALIGNMENT
+pub const SHADER_RECORD_ALIGNMENT: u64 = 32;
+//D3D12_RAYTRACING_SHADER_TABLE_BYTE_ALIGNMENT
+pub const SHADER_TABLE_ALIGNMENT: u64 = 64;
+//D3D12_RAYTRACING_MAX_SHADER_RECORD_STRIDE
+pub const MAX_SHADER_RECORD_STRIDE: u64 = 4096;
+
+pub type ShaderIdentifier = [u8; SHADER_IDENTIFIER_SIZE as usize];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableKind {
+    RayGen,
+    Miss,
+    HitGroup,
+    Callable,
+}
+
+impl fmt::Display for TableKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TableKind::RayGen => "ray generation",
+            TableKind::Miss => "miss",
+            TableKind::HitGroup => "hit group",
+            TableKind::Callable => "callable",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+//ローカルルートシグニチャの引数
+//ルート定数は4バイト、ディスクリプタ(GPUアドレス/ディスクリプタテーブル)は8バイト境界に置く
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LocalRootArguments {
+    bytes: Vec<u8>,
+}
+
+impl LocalRootArguments {
+    pub fn new() -> Self {
+        LocalRootArguments { bytes: vec![] }
+    }
+
+    pub fn constant(mut self, value: u32) -> Self {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn constants(self, values: &[u32]) -> Self {
+        values.iter().fold(self, |args, &v| args.constant(v))
+    }
+
+    //CBV/SRV/UAVのルートディスクリプタ
+    pub fn gpu_address(mut self, address: u64) -> Self {
+        self.bytes.resize(align(self.bytes.len() as u64, 8) as usize, 0);
+        self.bytes.extend_from_slice(&address.to_le_bytes());
+        self
+    }
+
+    //D3D12_GPU_DESCRIPTOR_HANDLE
+    pub fn descriptor_table(self, handle: u64) -> Self {
+        self.gpu_address(handle)
+    }
+
+    //レイアウトを自分で組んだバイト列をそのまま足す
+    pub fn raw(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderRecord {
+    //エクスポート名(ヒットグループ名)
+    pub name: String,
+    pub arguments: Vec<u8>,
+}
+
+//テーブル先頭からの位置 sizeが0のテーブルは使わない
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableRange {
+    pub offset: u64,
+    pub size: u64,
+    pub stride: u64,
+}
+
+impl TableRange {
+    pub fn record_count(&self) -> u64 {
+        self.size.checked_div(self.stride).unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ShaderTable {
+    pub data: Vec<u8>,
+    pub ray_gen: TableRange,
+    pub miss: TableRange,
+    pub hit_group: TableRange,
+    pub callable: TableRange,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShaderTableError {
+    //DispatchRaysに渡せるraygenは1つだけ
+    RayGenCount(usize),
+    UnknownExport(String),
+    //ルート引数は4バイト単位
+    UnalignedArguments { name: String, size: u64 },
+    RecordTooLarge { kind: TableKind, stride: u64 },
+}
+
+impl fmt::Display for ShaderTableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderTableError::RayGenCount(n) => write!(f, "exactly one ray generation record is required, got {}", n),
+            ShaderTableError::UnknownExport(name) => write!(f, "no shader identifier for export \"{}\"", name),
+            ShaderTableError::UnalignedArguments { name, size } => {
+                write!(f, "local root arguments of \"{}\" are {} bytes, not a multiple of 4", name, size)
+            }
+            ShaderTableError::RecordTooLarge { kind, stride } => {
+                write!(f, "{} record stride {} exceeds {} bytes", kind, stride, MAX_SHADER_RECORD_STRIDE)
+            }
+        }
+    }
+}
+
+impl Error for ShaderTableError {}
+
+#[derive(Clone, Debug, Default)]
+pub struct ShaderTableBuilder {
+    ray_gen: Vec<ShaderRecord>,
+    miss: Vec<ShaderRecord>,
+    hit_groups: Vec<ShaderRecord>,
+    callables: Vec<ShaderRecord>,
+}
+
+impl ShaderTableBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ray_gen(mut self, name: &str, arguments: Option<LocalRootArguments>) -> Self {
+        self.ray_gen.push(record(name, arguments));
+        self
+    }
+
+    //追加した順番がMissShaderIndex
+    pub fn miss(mut self, name: &str, arguments: Option<LocalRootArguments>) -> Self {
+        self.miss.push(record(name, arguments));
+        self
+    }
+
+    //追加した順番がヒットグループのインデックス
+    pub fn hit_group(mut self, name: &str, arguments: Option<LocalRootArguments>) -> Self {
+        self.hit_groups.push(record(name, arguments));
+        self
+    }
+
+    //ジオメトリごとにレイの種類の数だけヒットグループを並べる
+    //TraceRayのRayContributionToHitGroupIndexをレイの種類、MultiplierForGeometryContributionToHitGroupIndexをhit_groups.len()にすれば引ける
+    pub fn hit_groups_per_geometry(self, geometry_count: usize, hit_groups: &[&str]) -> Self {
+        (0..geometry_count).fold(self, |builder, _| hit_groups.iter().fold(builder, |b, name| b.hit_group(name, None)))
+    }
+
+    pub fn callable(mut self, name: &str, arguments: Option<LocalRootArguments>) -> Self {
+        self.callables.push(record(name, arguments));
+        self
+    }
+
+    //identifierはエクスポート名からシェーダー識別子を引く(ID3D12StateObjectProperties::GetShaderIdentifier)
+    pub fn build(&self, mut identifier: impl FnMut(&str) -> Option<ShaderIdentifier>) -> Result<ShaderTable, ShaderTableError> {
+        if self.ray_gen.len() != 1 {
+            return Err(ShaderTableError::RayGenCount(self.ray_gen.len()));
+        }
+
+        let mut table = ShaderTable::default();
+
+        let tables = [
+            (TableKind::RayGen, &self.ray_gen),
+            (TableKind::Miss, &self.miss),
+            (TableKind::HitGroup, &self.hit_groups),
+            (TableKind::Callable, &self.callables),
+        ];
+
+        for (kind, records) in tables {
+            let offset = align(table.data.len() as u64, SHADER_TABLE_ALIGNMENT);
+            let range = layout(kind, records, offset)?;
+
+            table.data.resize(offset as usize, 0);
+            for r in records.iter() {
+                let id = identifier(&r.name).ok_or_else(|| ShaderTableError::UnknownExport(r.name.clone()))?;
+
+                let start = table.data.len();
+                table.data.extend_from_slice(&id);
+                table.data.extend_from_slice(&r.arguments);
+                table.data.resize(start + range.stride as usize, 0);
+            }
+
+            match kind {
+                TableKind::RayGen => table.ray_gen = range,
+                TableKind::Miss => table.miss = range,
+                TableKind::HitGroup => table.hit_group = range,
+                TableKind::Callable => table.callable = range,
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+//DXRがヒットグループのレコードを選ぶ式
+pub fn hit_group_index(ray_contribution: u32, geometry_multiplier: u32, geometry_index: u32, instance_contribution: u32) -> u32 {
+    ray_contribution + geometry_multiplier * geometry_index + instance_contribution
+}
+
+fn record(name: &str, arguments: Option<LocalRootArguments>) -> ShaderRecord {
+    ShaderRecord { name: name.to_string(), arguments: arguments.map_or(vec![], |a| a.bytes) }
+}
+
+//テーブル内のレコードは同じストライドなので一番大きいレコードに合わせる
+fn layout(kind: TableKind, records: &[ShaderRecord], offset: u64) -> Result<TableRange, ShaderTableError> {
+    let mut stride = 0;
+
+    for r in records {
+        let size = r.arguments.len() as u64;
+        if !size.is_multiple_of(4) {
+            return Err(ShaderTableError::UnalignedArguments { name: r.name.clone(), size });
+        }
+
+        stride = stride.max(align(SHADER_IDENTIFIER_SIZE + size, SHADER_RECORD_ALIGNMENT));
+    }
+
+    if stride > MAX_SHADER_RECORD_STRIDE {
+        return Err(ShaderTableError::RecordTooLarge { kind, stride });
+    }
+
+    Ok(TableRange { offset, size: stride * records.len() as u64, stride })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORTS: [&str; 5] = ["RayGen", "Miss", "ShadowMiss", "HitGroup", "Material"];
+
+    //エクスポートの番号で埋めた識別子
+    fn identifier(name: &str) -> Option<ShaderIdentifier> {
+        EXPORTS.iter().position(|e| *e == name).map(|i| [i as u8 + 1; SHADER_IDENTIFIER_SIZE as usize])
+    }
+
+    #[test]
+    fn record_stride_is_rounded_to_the_record_alignment() {
+        let table = ShaderTableBuilder::new()
+            .ray_gen("RayGen", None)
+            .miss("Miss", None)
+            //32 + 4バイトは64に切り上げ
+            .miss("ShadowMiss", Some(LocalRootArguments::new().constant(1)))
+            .hit_group("HitGroup", Some(LocalRootArguments::new().constants(&[1, 2, 3, 4, 5, 6, 7, 8])))
+            .build(identifier)
+            .unwrap();
+
+        assert_eq!(table.ray_gen.stride, 32);
+        assert_eq!(table.miss.stride, 64);
+        assert_eq!(table.hit_group.stride, 64);
+        assert_eq!(table.miss.record_count(), 2);
+    }
+
+    #[test]
+    fn tables_start_on_the_table_alignment() {
+        let table = ShaderTableBuilder::new()
+            .ray_gen("RayGen", None)
+            .miss("Miss", None)
+            .hit_groups_per_geometry(3, &["HitGroup"])
+            .callable("Material", None)
+            .build(identifier)
+            .unwrap();
+
+        for range in [table.ray_gen, table.miss, table.hit_group, table.callable] {
+            assert_eq!(range.offset % SHADER_TABLE_ALIGNMENT, 0);
+        }
+
+        //32バイトのraygenの後は64まで空ける
+        assert_eq!(table.miss.offset, 64);
+        assert_eq!(table.hit_group.offset, 128);
+        //ヒットグループ3つで96バイト、次は256から
+        assert_eq!(table.callable.offset, 256);
+        assert_eq!(table.data.len(), 256 + 32);
+    }
+
+    #[test]
+    fn records_hold_the_identifier_then_the_arguments() {
+        let arguments = LocalRootArguments::new().constant(0xAABBCCDD).descriptor_table(0x1122_3344_5566_7788);
+        let table = ShaderTableBuilder::new().ray_gen("RayGen", None).hit_group("HitGroup", Some(arguments)).build(identifier).unwrap();
+
+        let offset = table.hit_group.offset as usize;
+        let record = &table.data[offset..offset + table.hit_group.stride as usize];
+
+        assert_eq!(record[..32], [4; 32]);
+        assert_eq!(record[32..36], 0xAABBCCDDu32.to_le_bytes());
+        //ディスクリプタは8バイト境界
+        assert_eq!(record[36..40], [0; 4]);
+        assert_eq!(record[40..48], 0x1122_3344_5566_7788u64.to_le_bytes());
+        assert!(record[48..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn ranges_describe_dispatch_rays() {
+        let table = ShaderTableBuilder::new()
+            .ray_gen("RayGen", None)
+            .miss("Miss", None)
+            .miss("ShadowMiss", None)
+            .hit_groups_per_geometry(2, &["HitGroup", "HitGroup"])
+            .build(identifier)
+            .unwrap();
+
+        //D3D12_DISPATCH_RAYS_DESCのRayGenerationShaderRecordはStartAddressとSizeInBytesだけ
+        assert_eq!(table.ray_gen, TableRange { offset: 0, size: 32, stride: 32 });
+        assert_eq!(table.miss, TableRange { offset: 64, size: 64, stride: 32 });
+        assert_eq!(table.hit_group, TableRange { offset: 128, size: 128, stride: 32 });
+        //callableがなければSizeInBytesは0
+        assert_eq!(table.callable.size, 0);
+        assert_eq!(table.callable.record_count(), 0);
+
+        for range in [table.ray_gen, table.miss, table.hit_group] {
+            assert!(range.offset + range.size <= table.data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn invalid_records_are_rejected() {
+        let build = |builder: ShaderTableBuilder| builder.build(identifier).unwrap_err();
+
+        assert_eq!(build(ShaderTableBuilder::new()), ShaderTableError::RayGenCount(0));
+        assert_eq!(build(ShaderTableBuilder::new().ray_gen("Unknown", None)), ShaderTableError::UnknownExport("Unknown".to_string()));
+        assert_eq!(
+            build(ShaderTableBuilder::new().ray_gen("RayGen", None).miss("Miss", Some(LocalRootArguments::new().raw(&[1, 2])))),
+            ShaderTableError::UnalignedArguments { name: "Miss".to_string(), size: 2 }
+        );
+        assert_eq!(
+            build(ShaderTableBuilder::new().ray_gen("RayGen", None).callable("Material", Some(LocalRootArguments::new().raw(&[0; 4096])))),
+            ShaderTableError::RecordTooLarge { kind: TableKind::Callable, stride: 4096 + 32 }
+        );
+    }
+}
@@ -1,10 +1,25 @@
-#[repr(C)]
-pub struct Vertex {
-    pub position: [f32; 3],
+use crate::hlsl_layout::{HlslStruct, DXGI_FORMAT_R32G32B32_FLOAT};
+use crate::hlsl_struct;
+
+hlsl_struct! {
+    pub struct Vertex as "Vertex" {
+        pub position: [f32; 3],
+    }
 }
 
+//BLASのVertexFormatはDXGI_FORMAT_R32G32B32_FLOAT、VertexBuffer.StrideInBytesはsize_of::<Vertex>()
+const _: () = assert!(Vertex::FIELDS[0].offset == 0 && Vertex::FIELDS[0].dxgi_format == DXGI_FORMAT_R32G32B32_FLOAT);
+
 impl Vertex {
     pub fn new(p_x: f32, p_y: f32, p_z: f32) -> Self {
         Vertex { position: [p_x, p_y, p_z] }
     }
-}
\ No newline at end of file
+}
+//init_dxrとヘッドレス描画で使う三角形
+pub fn sample_triangle() -> [Vertex; 3] {
+    [
+        Vertex::new(-0.5, -0.5, 0.0),
+        Vertex::new(0.5, -0.5, 0.0),
+        Vertex::new(0.0, 0.75, 0.0),
+    ]
+}
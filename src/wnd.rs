@@ -1,4 +1,7 @@
+mod barrier;
+mod buffer;
 mod dx12_rt;
+mod frame;
 pub(crate) mod descriptor;
 
 use dx12_rt::*;
@@ -10,33 +13,38 @@ use windows::{
     Win32::System::WindowsProgramming::*, Win32::UI::WindowsAndMessaging::*,
 };
 
-use crate::vertex::Vertex;
+use crate::config::Config;
+use crate::cpu::{ shaders, texture };
+use crate::error::{ Context, Result, RwrError };
+use crate::input::{ InputEvent, Key, MouseButton };
+use crate::vertex;
 
 const TITLE: &str = "rwr";
 const CLASSNAME: &str = "rwr";
 const CLASSNAMEWITHNULL: &[u8] = b"rwr\0";
 const SIZE: (u32, u32) = (640, 480);
+//WM_MOUSEWHEELの1ノッチ
+const WHEEL_NOTCH: f32 = 120.0;
 
 //hwndとかライフタイム的にstructに持ってないとダメ？
-pub fn run_with_raytracing() -> Result<()> {
+pub fn run_with_raytracing(config: &Config) -> Result<()> {
 
-    let mut wnd = Wnd::new();
+    let mut wnd = Wnd::new()?;
 
     //DXR
-    wnd.check_raytracing_support().unwrap_or_else(|e| panic!("{}", e));
-    wnd.init_dxr().unwrap_or_else(|e| panic!("{}", e));
+    wnd.check_raytracing_support()?;
+    wnd.init_dxr(config).context("failed to initialize DXR")?;
 
     if cfg!(debug_assertions) {
         println!("initialized");
     }
 
-    message_main_loop(&mut wnd);
-
-    Ok(())
+    message_main_loop(&mut wnd)
 }
 
     
-fn message_main_loop(wnd: &mut Wnd) {
+fn message_main_loop(wnd: &mut Wnd) -> Result<()> {
+    let mut last = std::time::Instant::now();
     loop {
         let mut message = MSG::default();
 
@@ -47,31 +55,38 @@ fn message_main_loop(wnd: &mut Wnd) {
             }
 
             if message.message == WM_QUIT {
-                break;
+                return Ok(());
             }
         }
-        wnd.dx.render();
+
+        //カメラは前のフレームからの経過時間で進める
+        let now = std::time::Instant::now();
+        wnd.dx.update(now.duration_since(last).as_secs_f32());
+        last = now;
+
+        wnd.dx.render().context("failed to render a frame")?;
     }
 }
 
 struct Wnd {
     hwnd: HWND,
-    dx: Dx12Rt,
+    //wndprocがポインタを持つので動かないようにBoxに入れる
+    dx: Box<Dx12Rt>,
 }
 
 impl Wnd {
-    pub fn new() -> Self {
-        let (hwnd, dx) = Self::init_wnd().expect("Failed init_wnd");
+    pub fn new() -> Result<Self> {
+        let (hwnd, dx) = Self::init_wnd().context("failed to create the window")?;
 
         let mut wnd = Self { hwnd, dx };
 
-        wnd.init_d3d().expect("Failed init d3d");
+        wnd.init_d3d().context("failed to initialize D3D12")?;
 
         if cfg!(debug_assertions) {
             println!("create wnd");
         }
 
-        wnd
+        Ok(wnd)
     }
 
     fn init_wnd() -> Result<(HWND, Dx12Rt)> {
@@ -87,7 +102,7 @@ impl Wnd {
             ..Default::default()
         };
         
-        let mut dx = Dx12Rt::new(SIZE.0, SIZE.1, 2);
+        let mut dx = Box::new(Dx12Rt::new(SIZE.0, SIZE.1, 2)?);
     
         let atom = unsafe { RegisterClassExA(&wc) };
         debug_assert_ne!(atom, 0);
@@ -113,7 +128,7 @@ impl Wnd {
                 None,
                 None,
                 instance,
-                &mut dx as *mut _ as _,
+                &mut *dx as *mut Dx12Rt as _,
             )
         };
 
@@ -130,9 +145,10 @@ impl Wnd {
             let mut debug: Option<ID3D12Debug> = None;
             unsafe {
                 if D3D12GetDebugInterface(&mut debug).is_ok() {
-                    println!("enable debug");
-                    let debug = debug.as_ref().unwrap();
-                    debug.EnableDebugLayer();
+                    if let Some(debug) = debug {
+                        println!("enable debug");
+                        debug.EnableDebugLayer();
+                    }
                 }
             }
         }
@@ -140,45 +156,59 @@ impl Wnd {
         self.dx.create_device()?;
         self.dx.create_factory()?;
         self.dx.create_command_queue()?;
-        self.dx.create_command_allocator()?;
-        self.dx.create_command_list()?;
+        self.dx.create_frame_resources()?;
         self.dx.create_swap_chain(&self.hwnd)?;
         self.dx.create_fence()?;
     
         Ok(())
     }
 
-    pub fn check_raytracing_support(&self) -> std::result::Result<(), &'static str> {
+    pub fn check_raytracing_support(&self) -> Result<()> {
 
-        let ops = self.dx.chack_dxr_support().expect("Failed to check dxr support");
+        let ops = self.dx.chack_dxr_support().context("failed to check DXR support")?;
         if ops.RaytracingTier == D3D12_RAYTRACING_TIER_NOT_SUPPORTED {
-            Err("Not supported raytracing")
+            Err(RwrError::RaytracingNotSupported)
         } else {
             Ok(())
         }
     }
 
-    pub fn init_dxr(&mut self) -> Result<()> {
+    //config.alpha_maskなら三角形をアルファマスクで切り抜く
+    pub fn init_dxr(&mut self, config: &Config) -> Result<()> {
+
+        let tri = vertex::sample_triangle();
+        let pipeline_config = shaders::pipeline_config(config.max_recursion_depth)?;
 
-        let tri = [
-            Vertex::new(-0.5, -0.5, 0.0),
-            Vertex::new(0.5, -0.5, 0.0),
-            Vertex::new(0.0, 0.75, 0.0),
-        ];
+        //パノラマはウィンドウの大きさに引き伸ばして表示する
+        let scene = config.scene().context("failed to load the scene")?;
+        self.dx.set_camera(&scene.camera(SIZE.0 as f32 / SIZE.1 as f32)?, scene.controller.unwrap_or_default());
+        self.dx.set_exports(&scene.exports);
 
         self.dx.create_vertex_buffer(tri)?;
-        self.dx.build_blas()?;
-        self.dx.build_tlas()?;
+        self.dx.create_alpha_mask(&texture::sample_alpha_mask(), config.alpha_mask)?;
+        self.dx.build_acceleration_structures().context("failed to build the acceleration structures")?;
         self.dx.create_global_root_signature()?;
-        self.dx.create_state_object()?;
+        self.dx.validate_library(&pipeline_config)?;
+        self.dx.create_state_object(&pipeline_config).context("failed to create the state object")?;
         self.dx.create_result_resource()?;
-        self.dx.create_shader_table()?;
+        self.dx.compile_frame_graph()?;
+        self.dx.create_shader_table().context("failed to create the shader table")?;
+
+        if cfg!(debug_assertions) {
+            self.dx.print_buffer_stats();
+        }
 
         Ok(())
     }
     
     //Win32Api
-    fn sample_wndproc(sample: &mut Dx12Rt, message: u32, _: WPARAM) -> bool {
+    fn sample_wndproc(sample: &mut Dx12Rt, message: u32, wparam: WPARAM, lparam: LPARAM) -> bool {
+        //入力はカメラに渡してDefWindowProcにも流す
+        if let Some(event) = Self::input_event(message, wparam, lparam) {
+            sample.handle_input(&event);
+            return false;
+        }
+
         match message {
             WM_PAINT => {
                 //sample.update();
@@ -191,6 +221,51 @@ impl Wnd {
             }
         }
     }
+
+    //Win32のメッセージをInputEventにする
+    fn input_event(message: u32, wparam: WPARAM, lparam: LPARAM) -> Option<InputEvent> {
+        //下位と上位のワード 座標とホイールは符号付き
+        let low = (lparam.0 & 0xFFFF) as u16;
+        let high = ((lparam.0 >> 16) & 0xFFFF) as u16;
+
+        let event = match message {
+            WM_KEYDOWN => InputEvent::KeyDown(Self::key(wparam.0 as u32)),
+            WM_KEYUP => InputEvent::KeyUp(Self::key(wparam.0 as u32)),
+            WM_MOUSEMOVE => InputEvent::MouseMove { x: low as i16 as f32, y: high as i16 as f32 },
+            WM_LBUTTONDOWN => InputEvent::MouseDown(MouseButton::Left),
+            WM_LBUTTONUP => InputEvent::MouseUp(MouseButton::Left),
+            WM_RBUTTONDOWN => InputEvent::MouseDown(MouseButton::Right),
+            WM_RBUTTONUP => InputEvent::MouseUp(MouseButton::Right),
+            WM_MBUTTONDOWN => InputEvent::MouseDown(MouseButton::Middle),
+            WM_MBUTTONUP => InputEvent::MouseUp(MouseButton::Middle),
+            WM_MOUSEWHEEL => InputEvent::Wheel(((wparam.0 >> 16) & 0xFFFF) as u16 as i16 as f32 / WHEEL_NOTCH),
+            WM_SIZE => InputEvent::Resize { width: low as u32, height: high as u32 },
+            WM_KILLFOCUS => InputEvent::FocusLost,
+            _ => return None,
+        };
+
+        Some(event)
+    }
+
+    //仮想キーコード 英字は大文字のASCIIと同じ
+    fn key(vk: u32) -> Key {
+        match vk {
+            0x57 => Key::W,
+            0x41 => Key::A,
+            0x53 => Key::S,
+            0x44 => Key::D,
+            0x51 => Key::Q,
+            0x45 => Key::E,
+            0x20 => Key::Space,
+            0x10 => Key::Shift,
+            0x25 => Key::Left,
+            0x27 => Key::Right,
+            0x26 => Key::Up,
+            0x28 => Key::Down,
+            0x1B => Key::Escape,
+            vk => Key::Other(vk),
+        }
+    }
     
     extern "system" fn wndproc(
         window: HWND,
@@ -214,7 +289,7 @@ impl Wnd {
                 let user_data = unsafe { GetWindowLong(window, GWLP_USERDATA) };
                 let sample = std::ptr::NonNull::<Dx12Rt>::new(user_data as _);
                 let handled = sample.map_or(false, |mut s| {
-                    Self::sample_wndproc(unsafe { s.as_mut() }, message, wparam)
+                    Self::sample_wndproc(unsafe { s.as_mut() }, message, wparam, lparam)
                 });
     
                 if handled {
@@ -0,0 +1,83 @@
+use windows::Win32::Graphics::Direct3D12::*;
+
+use crate::resource_state::{ Access, Barrier, ResourceId, ResourceStateTracker, ResourceStates };
+
+//ResourceStateTrackerにID3D12Resourceを対応付け、積んだバリアをコマンドリストに出す
+#[derive(Default)]
+pub struct TrackedResources {
+    tracker: ResourceStateTracker,
+    resources: Vec<ID3D12Resource>,
+}
+
+impl TrackedResources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //initialはCreateCommittedResourceに渡したInitialState
+    pub fn register(&mut self, resource: &ID3D12Resource, initial: D3D12_RESOURCE_STATES, subresource_count: u32) -> ResourceId {
+        self.resources.push(resource.clone());
+        self.tracker.register(ResourceStates(initial.0 as u32), subresource_count)
+    }
+
+    pub fn require(&mut self, id: ResourceId, state: D3D12_RESOURCE_STATES, access: Access) {
+        self.tracker.require(id, ResourceStates(state.0 as u32), access);
+    }
+
+    pub fn require_subresource(&mut self, id: ResourceId, subresource: u32, state: D3D12_RESOURCE_STATES, access: Access) {
+        self.tracker.require_subresource(id, subresource, ResourceStates(state.0 as u32), access);
+    }
+
+    //積んだバリアを1回のResourceBarrierで出す
+    pub fn flush(&mut self, command_list: &ID3D12GraphicsCommandList4) {
+        let resources = &self.resources;
+        resource_barriers(command_list, &self.tracker.flush(), |id| &resources[id.0]);
+    }
+}
+
+//ResourceIdからリソースを引いてバリアを出す 空なら何もしない
+pub fn resource_barriers<'a>(command_list: &ID3D12GraphicsCommandList4, barriers: &[Barrier], resource: impl Fn(ResourceId) -> &'a ID3D12Resource) {
+    let barriers: Vec<D3D12_RESOURCE_BARRIER> = barriers.iter().map(|&b| to_d3d12(b, &resource)).collect();
+    if barriers.is_empty() {
+        return;
+    }
+
+    unsafe { command_list.ResourceBarrier(barriers.len() as u32, barriers.as_ptr()) };
+
+    //ManuallyDropに入れたリソースの参照を返す
+    for b in barriers {
+        unsafe {
+            if b.Type == D3D12_RESOURCE_BARRIER_TYPE_TRANSITION {
+                std::mem::ManuallyDrop::into_inner(b.Anonymous.Transition);
+            } else {
+                std::mem::ManuallyDrop::into_inner(b.Anonymous.UAV);
+            }
+        }
+    }
+}
+
+fn to_d3d12<'a>(barrier: Barrier, resource: &impl Fn(ResourceId) -> &'a ID3D12Resource) -> D3D12_RESOURCE_BARRIER {
+    match barrier {
+        Barrier::Transition { resource: id, subresource, before, after } => D3D12_RESOURCE_BARRIER {
+            Type: D3D12_RESOURCE_BARRIER_TYPE_TRANSITION,
+            Flags: D3D12_RESOURCE_BARRIER_FLAG_NONE,
+            Anonymous: D3D12_RESOURCE_BARRIER_0 {
+                Transition: std::mem::ManuallyDrop::new(D3D12_RESOURCE_TRANSITION_BARRIER {
+                    pResource: Some(resource(id).clone()),
+                    StateBefore: D3D12_RESOURCE_STATES(before.0 as _),
+                    StateAfter: D3D12_RESOURCE_STATES(after.0 as _),
+                    Subresource: subresource,
+                }),
+            },
+        },
+        Barrier::Uav { resource: id } => D3D12_RESOURCE_BARRIER {
+            Type: D3D12_RESOURCE_BARRIER_TYPE_UAV,
+            Flags: D3D12_RESOURCE_BARRIER_FLAG_NONE,
+            Anonymous: D3D12_RESOURCE_BARRIER_0 {
+                UAV: std::mem::ManuallyDrop::new(D3D12_RESOURCE_UAV_BARRIER {
+                    pResource: Some(resource(id).clone()),
+                }),
+            },
+        },
+    }
+}
@@ -0,0 +1,130 @@
+use windows::{
+    core::*, Win32::Graphics::Direct3D12::*, Win32::Graphics::Dxgi::Common::*,
+};
+
+use crate::buffer_allocator::{ BufferAllocation, BufferError, BufferHeapBackend, BufferStats, BuddyAllocator, HeapKind, LinearAllocator };
+use crate::resource_state::ResourceStates;
+
+//長く使うアップロード(頂点、シェーダーテーブル)
+const UPLOAD_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+//フレームごとのアップロード(インスタンス)
+const FRAME_UPLOAD_BLOCK_SIZE: u64 = 1024 * 1024;
+const ACCELERATION_STRUCTURE_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+const SCRATCH_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+
+//ブロックを1本のコミットリソースとして作る
+pub struct D3d12BufferHeap {
+    device: ID3D12Device5,
+    kind: HeapKind,
+    state: D3D12_RESOURCE_STATES,
+    flags: D3D12_RESOURCE_FLAGS,
+}
+
+impl D3d12BufferHeap {
+    //stateはDefaultのときだけ使う アップロードとリードバックは決まった状態で作る
+    pub fn new(device: &ID3D12Device5, kind: HeapKind, state: D3D12_RESOURCE_STATES, flags: D3D12_RESOURCE_FLAGS) -> Self {
+        let state = kind.required_state().map_or(state, |s: ResourceStates| D3D12_RESOURCE_STATES(s.0 as _));
+        D3d12BufferHeap { device: device.clone(), kind, state, flags }
+    }
+}
+
+impl BufferHeapBackend for D3d12BufferHeap {
+    type Block = ID3D12Resource;
+
+    fn create_block(&mut self, size: u64) -> core::result::Result<ID3D12Resource, BufferError> {
+        let prop = D3D12_HEAP_PROPERTIES {
+            Type: match self.kind {
+                HeapKind::Upload => D3D12_HEAP_TYPE_UPLOAD,
+                HeapKind::Default => D3D12_HEAP_TYPE_DEFAULT,
+                HeapKind::Readback => D3D12_HEAP_TYPE_READBACK,
+            },
+            CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_UNKNOWN,
+            MemoryPoolPreference: D3D12_MEMORY_POOL_UNKNOWN,
+            CreationNodeMask: 1,
+            VisibleNodeMask: 1,
+        };
+
+        let desc = D3D12_RESOURCE_DESC {
+            Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
+            Alignment: 0,
+            Width: size,
+            Height: 1,
+            DepthOrArraySize: 1,
+            MipLevels: 1,
+            Format: DXGI_FORMAT_UNKNOWN,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
+            Flags: self.flags,
+        };
+
+        let mut block: Option<ID3D12Resource> = None;
+        unsafe {
+            self.device.CreateCommittedResource(
+                &prop,
+                D3D12_HEAP_FLAG_NONE,
+                &desc,
+                self.state,
+                std::ptr::null(),
+                &mut block,
+            )
+        }
+        .map_err(|e| BufferError::BlockCreation { size, message: e.message().to_string() })?;
+
+        block.ok_or_else(|| BufferError::BlockCreation { size, message: "no resource was returned".to_string() })
+    }
+
+    fn gpu_address(&self, block: &ID3D12Resource) -> u64 {
+        unsafe { block.GetGPUVirtualAddress() }
+    }
+}
+
+//Dx12Rtのバッファはここから切り出す
+pub struct BufferPools {
+    pub upload: BuddyAllocator<D3d12BufferHeap>,
+    pub frame_upload: LinearAllocator<D3d12BufferHeap>,
+    pub acceleration_structure: BuddyAllocator<D3d12BufferHeap>,
+    pub scratch: BuddyAllocator<D3d12BufferHeap>,
+}
+
+impl BufferPools {
+    pub fn new(device: &ID3D12Device5, frame_count: u32) -> Self {
+        let upload = || D3d12BufferHeap::new(device, HeapKind::Upload, D3D12_RESOURCE_STATE_GENERIC_READ, D3D12_RESOURCE_FLAG_NONE);
+        let uav = |state| D3d12BufferHeap::new(device, HeapKind::Default, state, D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS);
+
+        BufferPools {
+            upload: BuddyAllocator::new(upload(), UPLOAD_BLOCK_SIZE),
+            frame_upload: LinearAllocator::new(upload(), FRAME_UPLOAD_BLOCK_SIZE, frame_count),
+            acceleration_structure: BuddyAllocator::new(uav(D3D12_RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE), ACCELERATION_STRUCTURE_BLOCK_SIZE),
+            scratch: BuddyAllocator::new(uav(D3D12_RESOURCE_STATE_UNORDERED_ACCESS), SCRATCH_BLOCK_SIZE),
+        }
+    }
+
+    pub fn stats(&self) -> [(&'static str, BufferStats); 4] {
+        [
+            ("upload", self.upload.stats()),
+            ("frame upload", self.frame_upload.stats()),
+            ("acceleration structure", self.acceleration_structure.stats()),
+            ("scratch", self.scratch.stats()),
+        ]
+    }
+}
+
+//アップロードヒープのブロックのoffsetからdataを書き込む
+pub fn write_buffer<T>(block: &ID3D12Resource, allocation: &BufferAllocation, data: &[T]) -> Result<()> {
+    let size = std::mem::size_of_val(data);
+    assert!(size as u64 <= allocation.size, "{} bytes do not fit in an allocation of {}", size, allocation.size);
+
+    unsafe {
+        //CPUから読まないので読み取り範囲は空
+        let read_range = D3D12_RANGE { Begin: 0, End: 0 };
+        let mut mapped = std::ptr::null_mut();
+        block.Map(0, &read_range, &mut mapped)?;
+        std::ptr::copy_nonoverlapping(data.as_ptr() as *const u8, (mapped as *mut u8).add(allocation.offset as usize), size);
+        block.Unmap(0, std::ptr::null());
+    }
+
+    Ok(())
+}
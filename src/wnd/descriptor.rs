@@ -5,44 +5,103 @@ use windows::{
     Win32::System::WindowsProgramming::*, Win32::UI::WindowsAndMessaging::*,
 };
 
+use crate::descriptor_allocator::{ DescriptorAllocator, DescriptorError, DescriptorHeapBackend, DescriptorRange };
+
 pub struct Descriptor {
     pub offset: usize,
     pub h_cpu: D3D12_CPU_DESCRIPTOR_HANDLE,
     pub h_gpu: D3D12_GPU_DESCRIPTOR_HANDLE,
     pub heap_type: D3D12_DESCRIPTOR_HEAP_TYPE,
+    //解放とディスクリプタテーブルの中身に使う
+    pub range: DescriptorRange,
 }
 
 impl Descriptor {
-    pub fn new(offset: usize, h_cpu: D3D12_CPU_DESCRIPTOR_HANDLE, h_gpu: D3D12_GPU_DESCRIPTOR_HANDLE, heap_type: D3D12_DESCRIPTOR_HEAP_TYPE) -> Self {
-        Descriptor { offset, h_cpu, h_gpu, heap_type }
-    } 
+    fn from_range(range: DescriptorRange, heap_start: usize, heap_type: D3D12_DESCRIPTOR_HEAP_TYPE) -> Self {
+        Descriptor {
+            offset: range.cpu - heap_start,
+            h_cpu: D3D12_CPU_DESCRIPTOR_HANDLE { ptr: range.cpu },
+            h_gpu: D3D12_GPU_DESCRIPTOR_HANDLE { ptr: range.gpu },
+            heap_type,
+            range,
+        }
+    }
+
+    //テーブルのi番目
+    pub fn handle(&self, i: u32) -> (D3D12_CPU_DESCRIPTOR_HANDLE, D3D12_GPU_DESCRIPTOR_HANDLE) {
+        let (cpu, gpu) = self.range.handle(i);
+        (D3D12_CPU_DESCRIPTOR_HANDLE { ptr: cpu }, D3D12_GPU_DESCRIPTOR_HANDLE { ptr: gpu })
+    }
 }
 
-#[derive(Clone)]
-pub struct DescriptorHeapManager {
+pub struct D3d12DescriptorHeap {
     pub heap: ID3D12DescriptorHeap,
     heap_desc: D3D12_DESCRIPTOR_HEAP_DESC,
     inc_size: u32,
-    allocate_index: u32,
 }
 
-impl DescriptorHeapManager {
-    pub fn new(heap: ID3D12DescriptorHeap, heap_desc: D3D12_DESCRIPTOR_HEAP_DESC, inc_size: u32) -> Self {
-        DescriptorHeapManager { heap, heap_desc, inc_size, allocate_index: 0 }
+impl DescriptorHeapBackend for D3d12DescriptorHeap {
+    fn capacity(&self) -> u32 {
+        self.heap_desc.NumDescriptors
     }
 
-    pub fn allocate(&self) -> core::result::Result<Descriptor, &'static str> {
-        let mut h_cpu = unsafe { self.heap.GetCPUDescriptorHandleForHeapStart() };
-        let mut h_gpu = unsafe { self.heap.GetGPUDescriptorHandleForHeapStart() };
+    fn increment_size(&self) -> u32 {
+        self.inc_size
+    }
 
-        if self.allocate_index < self.heap_desc.NumDescriptors {
-            let offset = self.inc_size as usize * self.allocate_index as usize;
-            h_cpu.ptr += offset;
-            h_gpu.ptr += offset as u64;
+    fn cpu_start(&self) -> usize {
+        unsafe { self.heap.GetCPUDescriptorHandleForHeapStart() }.ptr
+    }
 
-            Ok(Descriptor::new(offset, h_cpu, h_gpu, self.heap_desc.Type))
+    fn gpu_start(&self) -> u64 {
+        //シェーダーから見えないヒープのGetGPUDescriptorHandleForHeapStartは使えない
+        if self.heap_desc.Flags == D3D12_DESCRIPTOR_HEAP_FLAG_SHADER_VISIBLE {
+            unsafe { self.heap.GetGPUDescriptorHandleForHeapStart() }.ptr
         } else {
-            Err("Failed to allocate descriptor")
+            0
         }
     }
-}
\ No newline at end of file
+}
+
+pub struct DescriptorHeapManager {
+    allocator: DescriptorAllocator<D3d12DescriptorHeap>,
+}
+
+impl DescriptorHeapManager {
+    //transient_per_frameはフレームごとのリングの大きさ 残りがallocateで使える
+    pub fn new(heap: ID3D12DescriptorHeap, heap_desc: D3D12_DESCRIPTOR_HEAP_DESC, inc_size: u32, frame_count: u32, transient_per_frame: u32) -> core::result::Result<Self, DescriptorError> {
+        let allocator = DescriptorAllocator::new(D3d12DescriptorHeap { heap, heap_desc, inc_size }, frame_count, transient_per_frame)?;
+        Ok(DescriptorHeapManager { allocator })
+    }
+
+    pub fn heap(&self) -> &ID3D12DescriptorHeap {
+        &self.allocator.heap().heap
+    }
+
+    pub fn allocate(&mut self) -> core::result::Result<Descriptor, DescriptorError> {
+        self.allocate_table(1)
+    }
+
+    pub fn allocate_table(&mut self, count: u32) -> core::result::Result<Descriptor, DescriptorError> {
+        let range = self.allocator.allocate_range(count)?;
+        Ok(self.descriptor(range))
+    }
+
+    pub fn free(&mut self, descriptor: Descriptor) -> core::result::Result<(), DescriptorError> {
+        self.allocator.free(descriptor.range)
+    }
+
+    pub fn begin_frame(&mut self, frame_index: u32) {
+        self.allocator.begin_frame(frame_index);
+    }
+
+    pub fn allocate_transient(&mut self, count: u32) -> core::result::Result<Descriptor, DescriptorError> {
+        let range = self.allocator.allocate_transient(count)?;
+        Ok(self.descriptor(range))
+    }
+
+    fn descriptor(&self, range: DescriptorRange) -> Descriptor {
+        let heap = self.allocator.heap();
+        Descriptor::from_range(range, heap.cpu_start(), heap.heap_desc.Type)
+    }
+}
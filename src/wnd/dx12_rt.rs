@@ -1,5 +1,8 @@
 use std::borrow::Cow;
+use super::barrier::{ resource_barriers, TrackedResources };
+use super::buffer::{ write_buffer, BufferPools };
 use super::descriptor::{ Descriptor, DescriptorHeapManager };
+use super::frame::{ Dx12Fence, FrameResources };
 
 use windows::{
     core::*, Win32::Foundation::*, Win32::Graphics::Direct3D::Fxc::*, Win32::Graphics::Direct3D::*,
@@ -8,8 +11,33 @@ use windows::{
     Win32::System::WindowsProgramming::*, Win32::UI::WindowsAndMessaging::*,
 };
 
+use crate::as_planner::{ AsPlanner, AsType, PrebuildInfo, AS_BYTE_ALIGNMENT };
+use crate::buffer_allocator::BufferAllocation;
+use crate::camera::{ Camera, CameraController, CameraPose, ControllerKind, HlslCamera, OrbitController, Projection };
+use crate::cpu::shaders::{
+    default_materials, ray_shader_library, ray_shader_root_signature, HlslAlphaTest, HlslFrame, RayShaderExports, ALPHA_CUTOFF,
+};
+use crate::cpu::texture::AlphaTexture;
+use crate::dxbc::Container;
+use crate::error::{ Context, Result, RwrError };
+use crate::frame_ring::FrameRing;
+use crate::hlsl_layout::HlslStruct;
+use crate::input::InputEvent;
+use crate::math::Vec3;
+use crate::pipeline_config::PipelineConfig;
+use crate::render_graph::{ Aliasing, CompiledGraph, CompiledPass, FrameGraph, GraphBackend, PassKind };
+use crate::resource_state::{ Access, Barrier, ResourceId };
+use crate::root_signature::RootSignatureVersion;
+use crate::shader_library::ShaderLibraryDesc;
+use crate::shader_table::{ ShaderIdentifier, ShaderTable, ShaderTableBuilder, TableRange, SHADER_IDENTIFIER_SIZE };
 use crate::vertex::Vertex;
 
+//[TODO]: argsで受け取れるように
+const RAY_SHADER_PATH: &str = "E:\\Projects\\rwr\\ray_shader.cso";
+
+//CBV/SRV/UAVヒープのうちフレームごとのリングに使う数
+const TRANSIENT_DESCRIPTORS_PER_FRAME: u32 = 256;
+
 #[repr(C)]
 pub struct Dx12Rt {
     width: u32,
@@ -21,17 +49,21 @@ pub struct Dx12Rt {
     swap_chain: Option<IDXGISwapChain3>,
     render_targets: Vec<ID3D12Resource>,
     render_target_view_descriptor: Option<ID3D12DescriptorHeap>,
-    command_allocator: Option<Vec<ID3D12CommandAllocator>>,
-    command_list: Option<Vec<ID3D12GraphicsCommandList4>>,
+    //フレームごとのコマンドアロケーターとコマンドリスト 使い回すときだけ待つ
+    frames: Option<FrameRing<FrameResources>>,
+    //今のバックバッファ
     frame_index: u32,
 
-    vb: Option<ID3D12Resource>,
+    //バッファはbuffersから切り出す
+    buffers: Option<BufferPools>,
+    vb: Option<BufferAllocation>,
     vbv: Option<D3D12_VERTEX_BUFFER_VIEW>,
     vertices_count: u32,
-    blas_scratch: Option<ID3D12Resource>,
-    blas: Option<ID3D12Resource>,
-    tlas_scratch: Option<ID3D12Resource>,
-    tlas: Option<ID3D12Resource>,
+    //BLASとTLASを詰めたプール
+    acceleration_structures: Vec<BufferAllocation>,
+    //TLASが入っているプール
+    tlas: Option<BufferAllocation>,
+    tlas_id: Option<ResourceId>,
     global_root_signature: Option<ID3D12RootSignature>,
     state_object: Option<ID3D12StateObject>,
 
@@ -42,21 +74,40 @@ pub struct Dx12Rt {
     result_buffer: Option<ID3D12Resource>,
     result_resource_descriptor: Option<Descriptor>,
 
-    shader_table: Option<ID3D12Resource>,
+    //フレームをまたいだ平均 カメラが動いたらsample_countを0に戻して捨てる
+    accumulation: Option<ID3D12Resource>,
+    accumulation_descriptor: Option<Descriptor>,
+    sample_count: u32,
+    accumulated_pose: Option<CameraPose>,
+    exposure: f32,
+
+    //any-hitで使うアルファマスク
+    alpha_test: bool,
+    alpha_mask: Option<ID3D12Resource>,
+    alpha_mask_descriptor: Option<Descriptor>,
+    alpha_mask_size: (u32, u32),
+
+    shader_table: Option<BufferAllocation>,
 
     dispatch_ray_desc: D3D12_DISPATCH_RAYS_DESC,
+    //library.pass_ray_gensの順 raygenのレコードだけが違う
+    pass_shader_tables: Vec<BufferAllocation>,
+    pass_dispatch_ray_descs: Vec<D3D12_DISPATCH_RAYS_DESC>,
+
+    //バリアを出すリソースの状態
+    resources: TrackedResources,
+    //1フレームのパスとコンパイルした実行順
+    frame_graph: Option<(FrameGraph, CompiledGraph)>,
 
     //Fence
-    fence: Option<ID3D12Fence>,
-    fence_value: u64,
-    fence_event: HANDLE,
+    fence: Option<Dx12Fence>,
+
+    //wndprocからの入力で動かす
+    camera: Box<dyn CameraController>,
+    projection: Projection,
 
-    //shader symbols
-    ray_gen_symbol: Vec<u16>,
-    miss_symbol: Vec<u16>,
-    closest_hit_symbol: Vec<u16>,
-    //hit group
-    default_hit_group_symbol: Vec<u16>,
+    //ステートオブジェクトに入れるエクスポートとヒットグループ
+    library: ShaderLibraryDesc,
 
     //shader
     ray_shader_blob: ID3DBlob,
@@ -64,12 +115,11 @@ pub struct Dx12Rt {
 }
 
 impl Dx12Rt {
-    pub fn new(width: u32, height: u32, frame_count: u32) -> Self {
+    pub fn new(width: u32, height: u32, frame_count: u32) -> Result<Self> {
 
-        //[TODO]: argsで受け取れるように
-        let ray_shader_blob = Self::load_shader("E:\\Projects\\rwr\\ray_shader.cso").expect("Failed to load ray shader");
+        let ray_shader_blob = Self::load_shader(RAY_SHADER_PATH)?;
 
-        Dx12Rt { 
+        Ok(Dx12Rt { 
             width, 
             height, 
             frame_count, 
@@ -79,34 +129,44 @@ impl Dx12Rt {
             swap_chain: None, 
             render_targets: vec![],
             render_target_view_descriptor: None,
-            command_allocator: None, 
-            command_list: None,
+            frames: None,
             frame_index: 0,
+            buffers: None,
             vb: None, 
             vbv: None,
             vertices_count: 0,
-            blas_scratch: None,
-            blas: None,
-            tlas_scratch: None,
+            acceleration_structures: vec![],
             tlas: None,
+            tlas_id: None,
             global_root_signature: None,
             state_object: None,
             cbv_srv_uav_descriptor_heap: None,
             tlas_descriptor: None,
             result_buffer: None,
             result_resource_descriptor: None,
+            accumulation: None,
+            accumulation_descriptor: None,
+            sample_count: 0,
+            accumulated_pose: None,
+            exposure: 1.0,
+            alpha_test: false,
+            alpha_mask: None,
+            alpha_mask_descriptor: None,
+            alpha_mask_size: (0, 0),
             shader_table: None,
             dispatch_ray_desc: D3D12_DISPATCH_RAYS_DESC::default(),
+            pass_shader_tables: vec![],
+            pass_dispatch_ray_descs: vec![],
+            resources: TrackedResources::new(),
+            frame_graph: None,
             fence: None,
-            fence_value: 1,
-            fence_event: unsafe { CreateEventA(std::ptr::null(), false, false, None) },
-            ray_gen_symbol: "MainRayGen\0".encode_utf16().collect(),
-            miss_symbol: "MainMiss\0".encode_utf16().collect(),
-            closest_hit_symbol: "MainClosestHit\0".encode_utf16().collect(),
-            default_hit_group_symbol: "DefaultHitGroup\0".encode_utf16().collect(),
+            //原点の周りを回る 初めはprimary_raysと同じz=1から-Zを見る
+            camera: Box::new(OrbitController::new(Vec3::ZERO, 1.0)),
+            projection: Camera::default().projection,
+            library: ray_shader_library(&RayShaderExports::default(), default_materials().names()),
             ray_shader_blob,
             check: false,
-        }
+        })
     }
 
     //create系はcreate_swapchainがhwndを必要とするので統一性を持たせるためにnew()で呼ばないようにしている
@@ -119,6 +179,7 @@ impl Dx12Rt {
             D3D_FEATURE_LEVEL_12_0, 
             &mut device) }?;
 
+        self.buffers = device.as_ref().map(|device| BufferPools::new(device, self.frame_count));
         self.device = device;
 
         Ok(())
@@ -139,7 +200,7 @@ impl Dx12Rt {
 
     pub fn create_command_queue(&mut self) -> Result<()> {
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
 
         self.command_queue = Some(unsafe {
             device.CreateCommandQueue(&D3D12_COMMAND_QUEUE_DESC {
@@ -153,9 +214,9 @@ impl Dx12Rt {
 
     pub fn create_swap_chain(&mut self, hwnd: &HWND) -> Result<()> {
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
-        let factory = self.dxgi_factory.as_ref().expect("You have to initialize a factory");
-        let command_queue = self.command_queue.as_ref().expect("You have to initialize a command queue");
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
+        let factory = self.dxgi_factory.as_ref().ok_or(RwrError::NotInitialized("factory"))?;
+        let command_queue = self.command_queue.as_ref().ok_or(RwrError::NotInitialized("command queue"))?;
 
         let swap_chain_desc = DXGI_SWAP_CHAIN_DESC {
             BufferDesc: DXGI_MODE_DESC { 
@@ -179,7 +240,7 @@ impl Dx12Rt {
             factory.CreateSwapChain(command_queue, &swap_chain_desc)?
         }.cast()?);
 
-        let swap_chain = self.swap_chain.as_ref().unwrap();
+        let swap_chain = self.swap_chain.as_ref().ok_or(RwrError::NotInitialized("swap chain"))?;
 
         let heap_desc = D3D12_DESCRIPTOR_HEAP_DESC {
             Type: D3D12_DESCRIPTOR_HEAP_TYPE_RTV,
@@ -194,7 +255,7 @@ impl Dx12Rt {
         )}?;
 
         unsafe {
-            rtv_heap.SetName("RTV_HEAP").expect("Failed to set name");
+            rtv_heap.SetName("RTV_HEAP")?;
         }
 
         let mut handle = unsafe { rtv_heap.GetCPUDescriptorHandleForHeapStart() };
@@ -225,63 +286,33 @@ impl Dx12Rt {
         Ok(())
     }
     
-    pub fn create_command_allocator(&mut self) -> Result<()> {
+    pub fn create_frame_resources(&mut self) -> Result<()> {
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
 
-        let mut alc: Vec<ID3D12CommandAllocator> = vec![];
+        let mut frames = vec![];
         for _ in 0..self.frame_count {
-            alc.push(
-                unsafe {
-                    device.CreateCommandAllocator(D3D12_COMMAND_LIST_TYPE_DIRECT)
-                }?
-            );
-        }
-
-        self.command_allocator = Some(alc);
-
-        Ok(())
-    }
-
-    pub fn create_command_list(&mut self) -> Result<()> {
-
-        let device = self.device.as_ref().expect("You have to initialize a device");
-        let command_allocators = self.command_allocator.as_ref().expect("You have to initialize a command allocator");
-
-        let mut cmd_lists: Vec<ID3D12GraphicsCommandList4> = vec![];
-        //コマンドリストはRTVごとに作らなくて良いので後で修正
-        for alc in command_allocators {
-            cmd_lists.push(
-                unsafe {
-                    device.CreateCommandList(
-                        0, 
-                        D3D12_COMMAND_LIST_TYPE_DIRECT, 
-                        alc, 
-                        //psoは後で設定
-                        None,
-                    )?
-                }
-            );
+            frames.push(FrameResources::new(device)?);
         }
 
-        self.command_list = Some(cmd_lists);
+        self.frames = Some(FrameRing::new(frames));
 
         Ok(())
     }
 
     pub fn create_fence(&mut self) -> Result<()> {
         
-        let device = self.device.as_ref().expect("You have to initialize a device");
-
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
+        let queue = self.command_queue.as_ref().ok_or(RwrError::NotInitialized("command queue"))?;
 
-        self.fence = unsafe { Some(device.CreateFence(0, D3D12_FENCE_FLAG_NONE)?) };
+        self.fence = Some(Dx12Fence::new(device, queue)?);
         
         Ok(())
     }
 
     pub fn chack_dxr_support(&self) -> Result<D3D12_FEATURE_DATA_D3D12_OPTIONS5> {
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
         
         let mut ops = D3D12_FEATURE_DATA_D3D12_OPTIONS5::default();
         unsafe {
@@ -297,7 +328,29 @@ impl Dx12Rt {
 
     pub fn create_vertex_buffer<const SIZE: usize>(&mut self, vertices: [Vertex; SIZE]) -> Result<()> {
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
+        let buffers = self.buffers.as_mut().ok_or(RwrError::NotInitialized("device"))?;
+
+        let vb = buffers.upload.allocate(std::mem::size_of_val(&vertices) as u64, std::mem::align_of::<Vertex>() as u64)?;
+        write_buffer(buffers.upload.block(vb.block), &vb, &vertices)?;
+
+        self.vbv = Some(
+            D3D12_VERTEX_BUFFER_VIEW {
+                BufferLocation: vb.gpu_address,
+                StrideInBytes: std::mem::size_of::<Vertex>() as u32,
+                SizeInBytes: std::mem::size_of_val(&vertices) as u32,
+            }
+        );
+        self.vb = Some(vb);
+
+        self.vertices_count = SIZE as u32;
+
+        Ok(())
+    }
+
+    //build_acceleration_structuresより前に呼ぶ alpha_testならジオメトリを不透明にしない
+    pub fn create_alpha_mask(&mut self, mask: &AlphaTexture, alpha_test: bool) -> Result<()> {
+
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
 
         let prop = D3D12_HEAP_PROPERTIES {
             Type: D3D12_HEAP_TYPE_UPLOAD,
@@ -310,7 +363,7 @@ impl Dx12Rt {
         let desc = D3D12_RESOURCE_DESC {
             Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
             Alignment: 0,
-            Width: std::mem::size_of_val(&vertices) as u64,
+            Width: std::mem::size_of_val(mask.texels.as_slice()) as u64,
             Height: 1,
             DepthOrArraySize: 1,
             MipLevels: 1,
@@ -330,402 +383,192 @@ impl Dx12Rt {
                 &desc, 
                 D3D12_RESOURCE_STATE_GENERIC_READ, 
                 std::ptr::null(), 
-                &mut self.vb,
+                &mut self.alpha_mask,
             )?;
         };
 
-        let vertex_buffer = self.vb.as_ref().expect("Failed to create committed resource of vertex buffer");
+        let alpha_mask = self.alpha_mask.as_ref().ok_or(RwrError::NotInitialized("alpha mask"))?;
 
         unsafe {
             let mut data = std::ptr::null_mut();
             
-            vertex_buffer.Map(0, std::ptr::null(), &mut data)?;
+            alpha_mask.Map(0, std::ptr::null(), &mut data)?;
             std::ptr::copy_nonoverlapping(
-                vertices.as_ptr(), 
-                data as *mut Vertex, 
-                std::mem::size_of_val(&vertices)
+                mask.texels.as_ptr(), 
+                data as *mut f32, 
+                mask.texels.len()
             );
-            vertex_buffer.Unmap(0, std::ptr::null());
+            alpha_mask.Unmap(0, std::ptr::null());
         };
 
-        self.vbv = Some(
-            D3D12_VERTEX_BUFFER_VIEW {
-                BufferLocation: unsafe { vertex_buffer.GetGPUVirtualAddress() },
-                StrideInBytes: std::mem::size_of::<Vertex>() as u32,
-                SizeInBytes: std::mem::size_of_val(&vertices) as u32,
-            }
-        );
-
-        self.vertices_count = SIZE as u32;
+        self.alpha_mask_size = (mask.width, mask.height);
+        self.alpha_test = alpha_test;
 
         Ok(())
     }
 
-    pub fn build_blas(&mut self) -> Result<()> {
+    //BLASとTLASの必要量をまとめて1つのプランで配置し、batchの順にビルドする
+    //BLASはジオメトリごとに1つ作り、TLASにはそれぞれ単位行列のインスタンスとして入れる
+    pub fn build_acceleration_structures(&mut self) -> Result<()> {
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
-        let vertex_buffer = self.vb.as_ref().expect("You have to initialize a vertex buffer");
-        let command_list = &self.command_list.as_ref().expect("You have to initialize a command list")[self.frame_index as usize];
-        let queue = self.command_queue.as_ref().expect("You have to initialize a command queue");
-        let command_allocator = &self.command_allocator.as_ref().expect("You have to initialize a command allocator")[self.frame_index as usize];
-        let fence = self.fence.as_ref().expect("You have to initialize a fence");
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
+        let vertex_buffer = self.vb.as_ref().ok_or(RwrError::NotInitialized("vertex buffer"))?;
+        let buffers = self.buffers.as_mut().ok_or(RwrError::NotInitialized("device"))?;
+        let frames = self.frames.as_mut().ok_or(RwrError::NotInitialized("frame resources"))?;
+        let queue = self.command_queue.as_ref().ok_or(RwrError::NotInitialized("command queue"))?;
+        let fence = self.fence.as_mut().ok_or(RwrError::NotInitialized("fence"))?;
 
-        //まずBLASに必要なメモリ量を求める
-        let mut geom_desc = unsafe { D3D12_RAYTRACING_GEOMETRY_DESC {
+        //初期化のビルドは今のスロットのコマンドリストを借りて、終わるまで待つ
+        let FrameResources { command_list, .. } = frames.current().clone();
+        let command_list = &command_list;
+        frames.current().reset()?;
+
+        //OPAQUEを外すとany-hitが呼ばれる
+        let geometry_flags = if self.alpha_test { D3D12_RAYTRACING_GEOMETRY_FLAG_NONE } else { D3D12_RAYTRACING_GEOMETRY_FLAG_OPAQUE };
+
+        let mut geom_descs = vec![unsafe { D3D12_RAYTRACING_GEOMETRY_DESC {
             Type: D3D12_RAYTRACING_GEOMETRY_TYPE_TRIANGLES,
-            Flags: D3D12_RAYTRACING_GEOMETRY_FLAG_OPAQUE,
+            Flags: geometry_flags,
             Anonymous: D3D12_RAYTRACING_GEOMETRY_DESC_0 {
                 //今回は三角形なのでこの構造体を指定
                 Triangles: D3D12_RAYTRACING_GEOMETRY_TRIANGLES_DESC {
                     VertexBuffer: D3D12_GPU_VIRTUAL_ADDRESS_AND_STRIDE {
-                        StartAddress: vertex_buffer.GetGPUVirtualAddress(),
+                        StartAddress: vertex_buffer.gpu_address,
                         StrideInBytes: std::mem::size_of::<Vertex>() as u64,
                     },
+                    //vertex.rsで確かめている
                     VertexFormat: DXGI_FORMAT_R32G32B32_FLOAT,
                     VertexCount: self.vertices_count,
                     ..Default::default()
                 },
             },
-        }};
+        }}];
 
-        let mut build_as_desc = D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_DESC {
-            //このINPUTSはTLASとBLASのどちらにも使われる
-            Inputs: D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS {
+        let blas_inputs: Vec<D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS> = geom_descs
+            .iter_mut()
+            .map(|geom_desc| D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS {
                 Type: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_TYPE_BOTTOM_LEVEL,
                 DescsLayout: D3D12_ELEMENTS_LAYOUT_ARRAY,
                 Flags: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BUILD_FLAG_NONE,
                 NumDescs: 1,
                 Anonymous: D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS_0 {
-                    pGeometryDescs: &mut geom_desc
+                    pGeometryDescs: geom_desc
                 }
-            },
+            })
+            .collect();
+
+        //インスタンスのアドレスはBLASを配置してから書く 必要量はインスタンス数だけで決まる
+        let mut tlas_inputs = D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS {
+            Type: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_TYPE_TOP_LEVEL,
+            DescsLayout: D3D12_ELEMENTS_LAYOUT_ARRAY,
+            Flags: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BUILD_FLAG_NONE,
+            NumDescs: blas_inputs.len() as u32,
             ..Default::default()
         };
 
-        let inputs = &build_as_desc.Inputs;
-
-        let mut blas_pre_build = D3D12_RAYTRACING_ACCELERATION_STRUCTURE_PREBUILD_INFO::default();
-
-        unsafe { 
-            //必要なメモリ量を求める
-            device.GetRaytracingAccelerationStructurePrebuildInfo(
-                inputs, 
-                &mut blas_pre_build
-            ) 
-        };
-
-        //必要なメモリ量を求めたのでBLASのバッファとスクラッチバッファ(UAVアクセス)のバッファ確保
-
-        //スクラッチリソース
-
-        let prop = D3D12_HEAP_PROPERTIES {
-            Type: D3D12_HEAP_TYPE_DEFAULT,
-            CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_UNKNOWN,
-            MemoryPoolPreference: D3D12_MEMORY_POOL_UNKNOWN,
-            CreationNodeMask: 1,
-            VisibleNodeMask: 1,
-        };
-
-        let scratch_desc = D3D12_RESOURCE_DESC {
-            Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
-            Alignment: 0,
-            Width: blas_pre_build.ScratchDataSizeInBytes,
-            Height: 1,
-            DepthOrArraySize: 1,
-            MipLevels: 1,
-            Format: DXGI_FORMAT_UNKNOWN,
-            SampleDesc: DXGI_SAMPLE_DESC {
-                Count: 1,
-                Quality: 0,
-            },
-            Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
-            Flags: D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS,
-        };
-
-        unsafe {
-            device.CreateCommittedResource(
-                &prop, 
-                D3D12_HEAP_FLAG_NONE, 
-                &scratch_desc, 
-                D3D12_RESOURCE_STATE_UNORDERED_ACCESS, 
-                std::ptr::null(), 
-                &mut self.blas_scratch,
-            )?;
+        //必要なメモリ量を求めて、結果とスクラッチの配置を全部まとめて決める
+        let mut planner = AsPlanner::new();
+        for (as_type, inputs) in blas_inputs.iter().map(|i| (AsType::BottomLevel, i)).chain([(AsType::TopLevel, &tlas_inputs)]) {
+            let mut pre_build = D3D12_RAYTRACING_ACCELERATION_STRUCTURE_PREBUILD_INFO::default();
+            unsafe { device.GetRaytracingAccelerationStructurePrebuildInfo(inputs, &mut pre_build) };
+            planner.add(as_type, PrebuildInfo::from(pre_build));
         }
+        let plan = planner.plan();
+        let tlas_index = blas_inputs.len();
 
-        let blas_buffer_desc = D3D12_RESOURCE_DESC {
-            Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
-            Alignment: 0,
-            Width: blas_pre_build.ResultDataMaxSizeInBytes,
-            Height: 1,
-            DepthOrArraySize: 1,
-            MipLevels: 1,
-            Format: DXGI_FORMAT_UNKNOWN,
-            SampleDesc: DXGI_SAMPLE_DESC {
-                Count: 1,
-                Quality: 0,
-            },
-            Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
-            Flags: D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS,
-        };
-
-        unsafe {
-            device.CreateCommittedResource(
-                &prop, 
-                D3D12_HEAP_FLAG_NONE, 
-                &blas_buffer_desc, 
-                D3D12_RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE, 
-                std::ptr::null(), 
-                &mut self.blas,
-            )?;
+        //結果はプールごとに1つ確保する 同じブロックから切り出したものは1つのリソースとして状態を追う
+        let mut pools = vec![];
+        for pool in &plan.result_pools {
+            pools.push(buffers.acceleration_structure.allocate(pool.size, AS_BYTE_ALIGNMENT)?);
         }
-
-        let blas_scratch = self.blas_scratch.as_ref().expect("Failed to create blas scratch");
-        let blas = self.blas.as_ref().expect("Failed to create blas");
-
-        //アクセラレーションストラクチャー構築
-        build_as_desc.ScratchAccelerationStructureData = unsafe { blas_scratch.GetGPUVirtualAddress() };
-        build_as_desc.DestAccelerationStructureData = unsafe { blas.GetGPUVirtualAddress() };
-
-        //コマンドリストに積んで実行
-        //もしかしてCopyしてるから反映されない？
-
-        //BLASを実際にビルド
-        //疑問: TLASと一緒にコマンドリストに登録sh知恵ビルドではなく一回リセットを挟んでからでも良いのか？
-
-        unsafe {
-            command_list.BuildRaytracingAccelerationStructure(
-                &build_as_desc, 
-                0, 
-                std::ptr::null(),
-            );
+        let mut pool_ids: Vec<(usize, ResourceId)> = vec![];
+        for pool in &pools {
+            if !pool_ids.iter().any(|&(block, _)| block == pool.block) {
+                let id = self.resources.register(buffers.acceleration_structure.block(pool.block), D3D12_RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE, 1);
+                pool_ids.push((pool.block, id));
+            }
         }
 
-        //リソースバリアの設定
-        let uav_barrier = D3D12_RESOURCE_BARRIER {
-            Type: D3D12_RESOURCE_BARRIER_TYPE_UAV,
-            Anonymous: D3D12_RESOURCE_BARRIER_0 {
-                UAV: std::mem::ManuallyDrop::new(D3D12_RESOURCE_UAV_BARRIER {
-                    pResource: Some(blas.clone()),
-                }),
-            },
-            ..Default::default()
-        };
+        //スクラッチ(UAVアクセス)はbatchの間で使い回し、ビルドが終わったら返す
+        let scratch = buffers.scratch.allocate(plan.peak_scratch_size, AS_BYTE_ALIGNMENT)?;
+        let scratch_id = self.resources.register(buffers.scratch.block(scratch.block), D3D12_RESOURCE_STATE_UNORDERED_ACCESS, 1);
 
-        unsafe {
-            command_list.ResourceBarrier(1, &uav_barrier);
-            command_list.Close()?;
-            queue.ExecuteCommandLists(1, &Some(command_list.cast()?));
+        let address = |i: usize| {
+            let result = plan.allocations[i].result;
+            pools[result.pool].gpu_address + result.offset
         };
-        
-        //リソースバリア
-        self.fence_value = Self::wait_for_gpu(queue, fence, self.fence_value, &self.fence_event)?;
-        
-        //command_listがレコード状態でResetをかけるとエラーとなるので使った後に必ずResetをかけることで二重Resetを防ぐ
-        unsafe { command_allocator.Reset()?; }
-        unsafe { command_list.Reset(command_allocator, None)? };
-
-        Ok(())
-    }
-
-    pub fn build_tlas(&mut self) -> Result<()> {
-        
-        let device = self.device.as_ref().expect("You have to initialize a device");
-        let vertex_buffer = self.vb.as_ref().expect("You have to initialize a vertex buffer");
-        let command_list = &self.command_list.as_ref().expect("You have to initialize a command list")[self.frame_index as usize];
-        let command_allocator = &self.command_allocator.as_ref().expect("You have to initialize a command allocator")[self.frame_index as usize];
-        let queue = self.command_queue.as_ref().expect("You have to initialize a command queue");
-        let fence = self.fence.as_ref().expect("You have to initialize a fence");
-
-        let blas = self.blas.as_ref().expect("You have to build a blas");
-
-        //ここからTLAS
-    
-        //instance_descの生成
 
         //https://docs.microsoft.com/en-us/windows/win32/api/d3d12/ns-d3d12-d3d12_raytracing_instance_desc
         /*
         _bitfield1と_bitfield2は上位24bitと下位8bitでそれぞれ分かれている？
         */
-        let instance_desc = D3D12_RAYTRACING_INSTANCE_DESC {
-            //単位行列
-            Transform: [1.0, 0.0, 0.0, 0.0,
-                        0.0, 1.0, 0.0, 0.0,
-                        0.0, 0.0, 1.0, 0.0],
-            _bitfield1: 0x0000_00FF, //0x0000_0000 + 0xFF
-            _bitfield2: D3D12_RAYTRACING_INSTANCE_FLAG_NONE, //0x0000_0000 + D3D12_RAYTRACING_INSTANCE_FLAG_NONE.0
-            AccelerationStructure: unsafe { blas.GetGPUVirtualAddress() }
-        };
-
-        let prop = D3D12_HEAP_PROPERTIES {
-            Type: D3D12_HEAP_TYPE_UPLOAD,
-            CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_UNKNOWN,
-            MemoryPoolPreference: D3D12_MEMORY_POOL_UNKNOWN,
-            CreationNodeMask: 1,
-            VisibleNodeMask: 1,
-        };
-
-        let desc = D3D12_RESOURCE_DESC {
-            Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
-            Alignment: 0,
-            Width: std::mem::size_of_val(&instance_desc) as u64,
-            Height: 1,
-            DepthOrArraySize: 1,
-            MipLevels: 1,
-            Format: DXGI_FORMAT_UNKNOWN,
-            SampleDesc: DXGI_SAMPLE_DESC {
-                Count: 1,
-                Quality: 0,
-            },
-            Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
-            //ここのFlagで悩んだ
-            Flags: D3D12_RESOURCE_FLAG_NONE,
-        };
-
-        let mut instance_desc_buffer: Option<ID3D12Resource> = None;
-        unsafe {
-            device.CreateCommittedResource(
-                &prop, 
-                D3D12_HEAP_FLAG_NONE, 
-                &desc, 
-                D3D12_RESOURCE_STATE_GENERIC_READ, 
-                std::ptr::null(), 
-                &mut instance_desc_buffer,
-            )?;
-        }
-
-        unsafe {
-            let mut data = std::ptr::null_mut();
-            
-            vertex_buffer.Map(0, std::ptr::null(), &mut data)?;
-            std::ptr::copy_nonoverlapping(
-                &instance_desc, 
-                data as *mut _ as _, 
-                1,
-            );
-            vertex_buffer.Unmap(0, std::ptr::null());
-        };
-        
-
-        let mut build_as_desc = D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_DESC {
-            Inputs: D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_INPUTS {
-                Type: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_TYPE_TOP_LEVEL,
-                DescsLayout: D3D12_ELEMENTS_LAYOUT_ARRAY,
-                Flags: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_BUILD_FLAG_NONE,
-                NumDescs: 1,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        let inputs = &build_as_desc.Inputs;
-
-        let mut tlas_pre_build = D3D12_RAYTRACING_ACCELERATION_STRUCTURE_PREBUILD_INFO::default();
-
-        unsafe {
-            device.GetRaytracingAccelerationStructurePrebuildInfo(
-                inputs, 
-                &mut tlas_pre_build,
-            );
-        }
-
-        //tlas scratch
-
-        let prop = D3D12_HEAP_PROPERTIES {
-            Type: D3D12_HEAP_TYPE_DEFAULT,
-            CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_UNKNOWN,
-            MemoryPoolPreference: D3D12_MEMORY_POOL_UNKNOWN,
-            CreationNodeMask: 1,
-            VisibleNodeMask: 1,
-        };
-
-        let scratch_desc = D3D12_RESOURCE_DESC {
-            Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
-            Alignment: 0,
-            Width: tlas_pre_build.ScratchDataSizeInBytes,
-            Height: 1,
-            DepthOrArraySize: 1,
-            MipLevels: 1,
-            Format: DXGI_FORMAT_UNKNOWN,
-            SampleDesc: DXGI_SAMPLE_DESC {
-                Count: 1,
-                Quality: 0,
-            },
-            Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
-            Flags: D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS,
-        };
-
-        unsafe {
-            device.CreateCommittedResource(
-                &prop, 
-                D3D12_HEAP_FLAG_NONE, 
-                &scratch_desc,
-                D3D12_RESOURCE_STATE_UNORDERED_ACCESS, 
-                std::ptr::null(), 
-                &mut self.tlas_scratch,
-            )?;
-        }
-
-        let tlas_buffer_desc = D3D12_RESOURCE_DESC {
-            Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
-            Alignment: 0,
-            Width: tlas_pre_build.ResultDataMaxSizeInBytes,
-            Height: 1,
-            DepthOrArraySize: 1,
-            MipLevels: 1,
-            Format: DXGI_FORMAT_UNKNOWN,
-            SampleDesc: DXGI_SAMPLE_DESC {
-                Count: 1,
-                Quality: 0,
-            },
-            Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
-            Flags: D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS,
-        };
-
-        unsafe {
-            device.CreateCommittedResource(
-                &prop, 
-                D3D12_HEAP_FLAG_NONE, 
-                &tlas_buffer_desc, 
-                D3D12_RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE, 
-                std::ptr::null(), 
-                &mut self.tlas,
-            )?;
-        }
+        let instance_descs: Vec<D3D12_RAYTRACING_INSTANCE_DESC> = (0..tlas_index)
+            .map(|i| D3D12_RAYTRACING_INSTANCE_DESC {
+                //単位行列
+                Transform: [1.0, 0.0, 0.0, 0.0,
+                            0.0, 1.0, 0.0, 0.0,
+                            0.0, 0.0, 1.0, 0.0],
+                _bitfield1: 0x0000_00FF, //0x0000_0000 + 0xFF
+                _bitfield2: D3D12_RAYTRACING_INSTANCE_FLAG_NONE, //0x0000_0000 + D3D12_RAYTRACING_INSTANCE_FLAG_NONE.0
+                AccelerationStructure: address(i),
+            })
+            .collect();
+
+        //インスタンスはフレームごとのアップロードに置く
+        let instance_desc_buffer = buffers
+            .frame_upload
+            .allocate(std::mem::size_of_val(instance_descs.as_slice()) as u64, D3D12_RAYTRACING_INSTANCE_DESCS_BYTE_ALIGNMENT as u64)
+            ?;
+        write_buffer(buffers.frame_upload.block(instance_desc_buffer.block), &instance_desc_buffer, &instance_descs)?;
+        tlas_inputs.Anonymous.InstanceDescs = instance_desc_buffer.gpu_address;
+
+        let inputs: Vec<_> = blas_inputs.into_iter().chain([tlas_inputs]).collect();
+
+        //同じbatchのビルドは並んで走る batchの間は結果とスクラッチへのUAVバリアで前の書き込みを待つ
+        //TLASはBLASより後のbatchなので、BLASを読む前にもバリアが入る
+        for batch in 0..plan.batch_count {
+            for &(_, id) in &pool_ids {
+                self.resources.require(id, D3D12_RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE, Access::Write);
+            }
+            self.resources.require(scratch_id, D3D12_RESOURCE_STATE_UNORDERED_ACCESS, Access::Write);
+            self.resources.flush(command_list);
 
-        let instance_desc_buffer = instance_desc_buffer.expect("Failed to create instance desc buffer");
-        let tlas_scratch = self.tlas_scratch.as_ref().expect("Failed to create tlas scratch");
-        let tlas = self.tlas.as_ref().expect("Failed to create tlas");
+            for (i, (alloc, inputs)) in plan.allocations.iter().zip(&inputs).enumerate() {
+                if alloc.batch != batch {
+                    continue;
+                }
 
-        build_as_desc.Inputs.Anonymous.InstanceDescs = unsafe { instance_desc_buffer.GetGPUVirtualAddress() };
-        build_as_desc.ScratchAccelerationStructureData = unsafe { tlas_scratch.GetGPUVirtualAddress() };
-        build_as_desc.DestAccelerationStructureData = unsafe { tlas.GetGPUVirtualAddress() };
+                let build_as_desc = D3D12_BUILD_RAYTRACING_ACCELERATION_STRUCTURE_DESC {
+                    DestAccelerationStructureData: address(i),
+                    Inputs: inputs.clone(),
+                    ScratchAccelerationStructureData: scratch.gpu_address + alloc.scratch_offset,
+                    ..Default::default()
+                };
 
-        unsafe {
-            command_list.BuildRaytracingAccelerationStructure(
-                &build_as_desc, 
-                0, 
-                std::ptr::null(),
-            );
+                unsafe {
+                    command_list.BuildRaytracingAccelerationStructure(
+                        &build_as_desc,
+                        0,
+                        std::ptr::null(),
+                    );
+                }
+            }
         }
 
-        let uav_barrier = D3D12_RESOURCE_BARRIER {
-            Type: D3D12_RESOURCE_BARRIER_TYPE_UAV,
-            Anonymous: D3D12_RESOURCE_BARRIER_0 {
-                UAV: std::mem::ManuallyDrop::new(D3D12_RESOURCE_UAV_BARRIER {
-                    pResource: Some(tlas.clone()),
-                }),
-            },
-            ..Default::default()
-        };
-
+        //TLASを読むときにUAVバリアが入る
         unsafe {
-            command_list.ResourceBarrier(1, &uav_barrier);
             command_list.Close()?;
             queue.ExecuteCommandLists(1, &Some(command_list.cast()?));
         };
-        
-        self.fence_value = Self::wait_for_gpu(queue, fence, self.fence_value, &self.fence_event)?;
+
+        frames.flush(fence)?;
+        buffers.scratch.free(scratch)?;
+
+        let tlas_address = address(tlas_index);
+        let tlas = pools[plan.allocations[tlas_index].result.pool];
+        self.tlas_id = pool_ids.iter().find(|&&(block, _)| block == tlas.block).map(|&(_, id)| id);
+        self.tlas = Some(tlas);
+        self.acceleration_structures = pools;
 
         //D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAVの確保
         let heap_desc = D3D12_DESCRIPTOR_HEAP_DESC {
@@ -740,21 +583,27 @@ impl Dx12Rt {
         self.cbv_srv_uav_descriptor_heap = Some(unsafe {
             let desc_heap: ID3D12DescriptorHeap = device.CreateDescriptorHeap(&heap_desc)?;
             
-            desc_heap.SetName("cbv_srv_uav_descriptor_heap").expect("Failed to set name");
-
-            DescriptorHeapManager::new(desc_heap, heap_desc, device.GetDescriptorHandleIncrementSize(D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV))
+            desc_heap.SetName("cbv_srv_uav_descriptor_heap")?;
+
+            DescriptorHeapManager::new(
+                desc_heap,
+                heap_desc,
+                device.GetDescriptorHandleIncrementSize(D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV),
+                self.frame_count,
+                TRANSIENT_DESCRIPTORS_PER_FRAME,
+            )?
         });
 
-        let heap_desc = self.cbv_srv_uav_descriptor_heap.as_ref().unwrap();
+        let heap_desc = self.cbv_srv_uav_descriptor_heap.as_mut().ok_or(RwrError::NotInitialized("descriptor heap"))?;
 
-        let tlas_descriptor = heap_desc.allocate().unwrap();
+        let tlas_descriptor = heap_desc.allocate()?;
 
         let srv_desc = D3D12_SHADER_RESOURCE_VIEW_DESC {
             ViewDimension: D3D12_SRV_DIMENSION_RAYTRACING_ACCELERATION_STRUCTURE,
             Shader4ComponentMapping: D3D12_DEFAULT_SHADER_4_COMPONENT_MAPPING,
             Anonymous: D3D12_SHADER_RESOURCE_VIEW_DESC_0 {
                 RaytracingAccelerationStructure: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_SRV {
-                    Location: unsafe { tlas.GetGPUVirtualAddress() },
+                    Location: tlas_address,
                 },
             },
             ..Default::default()
@@ -771,79 +620,21 @@ impl Dx12Rt {
 
         self.tlas_descriptor = Some(tlas_descriptor);
 
-        unsafe { command_list.Reset(command_allocator, None)? };
-
         Ok(())
     }
 
     pub fn create_global_root_signature(&mut self) -> Result<()> {
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
 
-        //TLASをt0レジスタに割り当てる用の設定
-        let mut desc_range_tlas = D3D12_DESCRIPTOR_RANGE {
-            BaseShaderRegister: 0, //t0レジスタ
-            NumDescriptors: 1,
-            RangeType: D3D12_DESCRIPTOR_RANGE_TYPE_SRV,
-            ..Default::default()
-        };
+        //レジスタの割り当てはshaders::ray_shader_root_signature
+        let blob = ray_shader_root_signature().serialize(RootSignatureVersion::V1_0)?;
 
-        //UAVの出力バッファをu0レジスタに割り当てる用の設定
-        let mut desc_range_output = D3D12_DESCRIPTOR_RANGE {
-            BaseShaderRegister: 0, //u0レジスタ
-            NumDescriptors: 1,
-            RangeType: D3D12_DESCRIPTOR_RANGE_TYPE_UAV,
-            ..Default::default()
-        };
-
-        let mut root_params = [
-            D3D12_ROOT_PARAMETER {
-                ParameterType: D3D12_ROOT_PARAMETER_TYPE_DESCRIPTOR_TABLE,
-                Anonymous: D3D12_ROOT_PARAMETER_0 {
-                    DescriptorTable: D3D12_ROOT_DESCRIPTOR_TABLE {
-                        NumDescriptorRanges: 1,
-                        pDescriptorRanges: &mut desc_range_tlas,
-                    }
-                },
-                ..Default::default()
-            }, 
-            D3D12_ROOT_PARAMETER {
-                ParameterType: D3D12_ROOT_PARAMETER_TYPE_DESCRIPTOR_TABLE,
-                Anonymous: D3D12_ROOT_PARAMETER_0 {
-                    DescriptorTable: D3D12_ROOT_DESCRIPTOR_TABLE {
-                        NumDescriptorRanges: 1,
-                        pDescriptorRanges: &mut desc_range_output,
-                    }
-                },
-                ..Default::default()
-            },
-        ];
-
-
-        let root_sig_desc = D3D12_ROOT_SIGNATURE_DESC {
-            NumParameters: root_params.len() as u32,
-            pParameters: &mut root_params as *mut _ as _,
-            ..Default::default()
-        };
-        
         unsafe {
-            let mut blob: Option<ID3DBlob> = Some(D3DCreateBlob(2048)?);
-            let mut err_blob: Option<ID3DBlob> = Some(D3DCreateBlob(2048)?);
-
-            D3D12SerializeRootSignature(
-                &root_sig_desc, 
-                D3D_ROOT_SIGNATURE_VERSION_1, 
-                &mut blob, 
-                &mut err_blob
-            )?;
-
-            let blob: ID3DBlob = blob.unwrap();
-            //let err_blob = err_blob.unwrap();
-
             let root_sig: ID3D12RootSignature = device.CreateRootSignature(
                 0,
-                blob.GetBufferPointer(),
-                blob.GetBufferSize()
+                blob.as_ptr() as _,
+                blob.len()
             )?;
     
             root_sig.SetName("global_root_signature")?;
@@ -854,9 +645,9 @@ impl Dx12Rt {
         Ok(())
     }
 
-    pub fn create_state_object(&mut self) -> Result<()> {
+    pub fn create_state_object(&mut self, config: &PipelineConfig) -> Result<()> {
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
 
         //global root sigantureの生成とメソッドを分けるべきか？
 
@@ -864,30 +655,42 @@ impl Dx12Rt {
             pGlobalRootSignature: self.global_root_signature.clone(),
         };
 
-        let mut exports = [
-            D3D12_EXPORT_DESC {
-                Name: PWSTR(self.ray_gen_symbol.as_mut_ptr()),
-                Flags: D3D12_EXPORT_FLAG_NONE,
-                ..Default::default()
-            },
-            D3D12_EXPORT_DESC {
-                Name: PWSTR(self.miss_symbol.as_mut_ptr()),
-                Flags: D3D12_EXPORT_FLAG_NONE,
-                ..Default::default()
-            },
-            D3D12_EXPORT_DESC {
-                Name: PWSTR(self.closest_hit_symbol.as_mut_ptr()),
+        //PWSTRが指す先なのでCreateStateObjectまで残しておく
+        let mut export_symbols: Vec<Vec<u16>> = self.library.exports().iter().map(|(name, _)| wide(name)).collect();
+
+        let mut exports: Vec<D3D12_EXPORT_DESC> = export_symbols
+            .iter_mut()
+            .map(|symbol| D3D12_EXPORT_DESC {
+                Name: PWSTR(symbol.as_mut_ptr()),
                 Flags: D3D12_EXPORT_FLAG_NONE,
                 ..Default::default()
-            },
-        ];
-        
-        let mut hit_group_desc = D3D12_HIT_GROUP_DESC {
-            Type: D3D12_HIT_GROUP_TYPE_TRIANGLES,
-            ClosestHitShaderImport: PWSTR(self.closest_hit_symbol.as_mut_ptr()),
-            HitGroupExport: PWSTR(self.default_hit_group_symbol.as_mut_ptr()),
-            ..Default::default()
-        };
+            })
+            .collect();
+
+        //[export, closest-hit, any-hit, intersection] インポートしないものは空
+        let mut hit_group_symbols: Vec<[Vec<u16>; 4]> = self
+            .library
+            .hit_groups
+            .iter()
+            .map(|h| {
+                let import = |name: &Option<String>| name.as_deref().map_or(vec![], wide);
+                [wide(&h.name), import(&h.closest_hit), import(&h.any_hit), import(&h.intersection)]
+            })
+            .collect();
+
+        let mut hit_group_descs: Vec<D3D12_HIT_GROUP_DESC> = hit_group_symbols
+            .iter_mut()
+            .map(|[export, closest_hit, any_hit, intersection]| {
+                let import = |symbol: &mut Vec<u16>| if symbol.is_empty() { PWSTR::default() } else { PWSTR(symbol.as_mut_ptr()) };
+                D3D12_HIT_GROUP_DESC {
+                    HitGroupExport: PWSTR(export.as_mut_ptr()),
+                    Type: if intersection.is_empty() { D3D12_HIT_GROUP_TYPE_TRIANGLES } else { D3D12_HIT_GROUP_TYPE_PROCEDURAL_PRIMITIVE },
+                    ClosestHitShaderImport: import(closest_hit),
+                    AnyHitShaderImport: import(any_hit),
+                    IntersectionShaderImport: import(intersection),
+                }
+            })
+            .collect();
 
         let mut dxil_lib_desc = D3D12_DXIL_LIBRARY_DESC {
             DXILLibrary: D3D12_SHADER_BYTECODE {
@@ -899,13 +702,14 @@ impl Dx12Rt {
             pExports: exports.as_mut_ptr(),
         };
 
+        //サイズはシェーダーで宣言した型から決まる(shaders::pipeline_config)
         let mut shader_config = D3D12_RAYTRACING_SHADER_CONFIG {
-            MaxPayloadSizeInBytes: std::mem::size_of::<[f32; 3]>() as u32,
-            MaxAttributeSizeInBytes: std::mem::size_of::<[f32; 2]>() as u32,
+            MaxPayloadSizeInBytes: config.max_payload_size,
+            MaxAttributeSizeInBytes: config.max_attribute_size,
         };
 
         let mut pipeline_config = D3D12_RAYTRACING_PIPELINE_CONFIG {
-            MaxTraceRecursionDepth: 1,
+            MaxTraceRecursionDepth: config.max_recursion_depth,
         };
 
         let mut sub_objs = vec![
@@ -926,13 +730,14 @@ impl Dx12Rt {
                 Type: D3D12_STATE_SUBOBJECT_TYPE_DXIL_LIBRARY,
                 pDesc: &mut dxil_lib_desc as *mut _ as _,
             },
-            D3D12_STATE_SUBOBJECT {
-                Type: D3D12_STATE_SUBOBJECT_TYPE_HIT_GROUP,
-                pDesc: &mut hit_group_desc as *mut _ as _,
-            },
 
         ];
 
+        sub_objs.extend(hit_group_descs.iter_mut().map(|desc| D3D12_STATE_SUBOBJECT {
+            Type: D3D12_STATE_SUBOBJECT_TYPE_HIT_GROUP,
+            pDesc: desc as *mut _ as _,
+        }));
+
         let state_obj_desc = D3D12_STATE_OBJECT_DESC {
             Type: D3D12_STATE_OBJECT_TYPE_RAYTRACING_PIPELINE,
             NumSubobjects: sub_objs.len() as u32,
@@ -950,7 +755,7 @@ impl Dx12Rt {
 
     pub fn create_result_resource(&mut self) -> Result<()> {
         
-        let device = self.device.as_ref().expect("You have to initialize a device");
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
 
         let output_desc = D3D12_RESOURCE_DESC {
             Dimension: D3D12_RESOURCE_DIMENSION_TEXTURE2D,
@@ -981,22 +786,22 @@ impl Dx12Rt {
                 &prop,
                 D3D12_HEAP_FLAG_NONE,
                 &output_desc,
-                D3D12_RESOURCE_STATE_COPY_SOURCE,
+                D3D12_RESOURCE_STATE_UNORDERED_ACCESS,
                 std::ptr::null(),
                 &mut self.result_buffer,
             )?;
         };
 
-        let output_buffer = self.result_buffer.as_ref().unwrap().clone();
+        let output_buffer = self.result_buffer.as_ref().ok_or(RwrError::NotInitialized("result buffer"))?.clone();
 
         let uav_desc = D3D12_UNORDERED_ACCESS_VIEW_DESC {
             ViewDimension: D3D12_UAV_DIMENSION_TEXTURE2D,
             ..Default::default()
         };
 
-        let heap_desc = self.cbv_srv_uav_descriptor_heap.as_ref().unwrap();
+        let heap_desc = self.cbv_srv_uav_descriptor_heap.as_mut().ok_or(RwrError::NotInitialized("descriptor heap"))?;
 
-        let result_resource_descriptor = heap_desc.allocate().unwrap();
+        let result_resource_descriptor = heap_desc.allocate()?;
 
         unsafe {
             device.CreateUnorderedAccessView(
@@ -1009,205 +814,261 @@ impl Dx12Rt {
 
         self.result_resource_descriptor = Some(result_resource_descriptor);
 
-        Ok(())
-    }
-
-    pub fn create_shader_table(&mut self) -> Result<()> {
-        
-        let device = self.device.as_ref().expect("You have to initialize a device");
-        let state_object = self.state_object.as_ref().expect("You have to initialize a device");
-
-        //レコードはシェーダーテーブルのそれぞれの要素のこと
-        let record_size = D3D12_SHADER_IDENTIFIER_SIZE_IN_BYTES;
-        let record_size = (record_size + D3D12_RAYTRACING_SHADER_RECORD_BYTE_ALIGNMENT - 1) & !(D3D12_RAYTRACING_SHADER_RECORD_BYTE_ALIGNMENT - 1);
-
-        let ray_gen_size = 1 * record_size; //ray gen shaderは一つ
-        let miss_size = 1 * record_size; //miss shaderは一つ
-        let hit_group_size = 1 * record_size; //hit groupは一つ
-
-        //アライメント調整
-        let table_align = D3D12_RAYTRACING_SHADER_TABLE_BYTE_ALIGNMENT;
-        let ray_gen_region = ((ray_gen_size + table_align - 1) & !(table_align - 1)) as usize;
-        let miss_region = ((miss_size + table_align - 1) & !(table_align - 1)) as usize;
-        let hit_group_region = ((hit_group_size + table_align - 1) & !(table_align - 1)) as usize;
-
-        //シェーダーテーブル生成
-        let table_size = ray_gen_region + miss_region + hit_group_region;
-        
-        let prop = D3D12_HEAP_PROPERTIES {
-            Type: D3D12_HEAP_TYPE_UPLOAD,
-            CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_UNKNOWN,
-            MemoryPoolPreference: D3D12_MEMORY_POOL_UNKNOWN,
-            CreationNodeMask: 1,
-            VisibleNodeMask: 1,
-        };
-
-        let desc = D3D12_RESOURCE_DESC {
-            Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
-            Alignment: 0,
-            Width: table_size as u64,
-            Height: 1,
-            DepthOrArraySize: 1,
-            MipLevels: 1,
-            Format: DXGI_FORMAT_UNKNOWN,
-            SampleDesc: DXGI_SAMPLE_DESC {
-                Count: 1,
-                Quality: 0,
-            },
-            Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
-            //ここのFlagで悩んだ
-            Flags: D3D12_RESOURCE_FLAG_NONE,
-        };
-
+        //平均は8ビットに丸めずに持つ
+        let accumulation_desc = D3D12_RESOURCE_DESC { Format: DXGI_FORMAT_R32G32B32A32_FLOAT, ..output_desc };
         unsafe {
             device.CreateCommittedResource(
-                &prop, 
-                D3D12_HEAP_FLAG_NONE, 
-                &desc, 
-                D3D12_RESOURCE_STATE_GENERIC_READ, 
-                std::ptr::null(), 
-                &mut self.shader_table,
+                &prop,
+                D3D12_HEAP_FLAG_NONE,
+                &accumulation_desc,
+                D3D12_RESOURCE_STATE_UNORDERED_ACCESS,
+                std::ptr::null(),
+                &mut self.accumulation,
             )?;
-        }
+        };
 
-        let rtso_props: ID3D12StateObjectProperties = state_object.cast()?;
-        
+        let accumulation = self.accumulation.as_ref().ok_or(RwrError::NotInitialized("accumulation"))?.clone();
+        let accumulation_descriptor = heap_desc.allocate()?;
         unsafe {
-            let shader_table = self.shader_table.as_ref().unwrap();
+            device.CreateUnorderedAccessView(
+                accumulation,
+                None,
+                &uav_desc,
+                accumulation_descriptor.h_cpu
+            )
+        }
+        self.accumulation_descriptor = Some(accumulation_descriptor);
 
-            let mut data = std::ptr::null_mut();
-            shader_table.Map(0, std::ptr::null(), &mut data)?;
+        //アルファマスクはStructuredBuffer<float>として見せる
+        let alpha_mask = self.alpha_mask.as_ref().ok_or(RwrError::NotInitialized("alpha mask"))?;
+        let alpha_mask_descriptor = heap_desc.allocate()?;
 
-            //Raygenシェーダー
-            let ray_gen_shader_p = data;
-            let id = rtso_props.GetShaderIdentifier(PWSTR(self.ray_gen_symbol.as_mut_ptr()));
+        let srv_desc = D3D12_SHADER_RESOURCE_VIEW_DESC {
+            Format: DXGI_FORMAT_UNKNOWN,
+            ViewDimension: D3D12_SRV_DIMENSION_BUFFER,
+            Shader4ComponentMapping: D3D12_DEFAULT_SHADER_4_COMPONENT_MAPPING,
+            Anonymous: D3D12_SHADER_RESOURCE_VIEW_DESC_0 {
+                Buffer: D3D12_BUFFER_SRV {
+                    FirstElement: 0,
+                    NumElements: self.alpha_mask_size.0 * self.alpha_mask_size.1,
+                    StructureByteStride: std::mem::size_of::<f32>() as u32,
+                    Flags: D3D12_BUFFER_SRV_FLAG_NONE,
+                },
+            },
+        };
 
-            std::ptr::copy_nonoverlapping(
-                id, 
-                ray_gen_shader_p, 
-                D3D12_SHADER_IDENTIFIER_SIZE_IN_BYTES as usize,
+        unsafe {
+            device.CreateShaderResourceView(
+                alpha_mask, 
+                &srv_desc, 
+                alpha_mask_descriptor.h_cpu,
             );
+        }
 
-            //Raygenシェーダーにローカルルートシグニチャが存在するならこのあとに書く
-            //let ray_gen_shader_p = ray_gen_shader_p.add(D3D12_SHADER_IDENTIFIER_SIZE_IN_BYTES as usize);
+        self.alpha_mask_descriptor = Some(alpha_mask_descriptor);
 
-            let miss_shader_p = data.add(ray_gen_region);
-            let id = rtso_props.GetShaderIdentifier(PWSTR(self.miss_symbol.as_mut_ptr()));
+        Ok(())
+    }
 
-            std::ptr::copy_nonoverlapping(
-                id, 
-                miss_shader_p, 
-                D3D12_SHADER_IDENTIFIER_SIZE_IN_BYTES as usize,
-            );
+    //create_result_resourceの後に呼ぶ
+    pub fn compile_frame_graph(&mut self) -> Result<()> {
+        let alpha_mask = self.alpha_mask.as_ref().ok_or(RwrError::NotInitialized("alpha mask"))?;
+        let frame = FrameGraph::new(self.width, self.height, unsafe { alpha_mask.GetDesc() }.Width);
+        let schedule = frame.graph.compile().context("failed to compile the frame graph")?;
+        self.frame_graph = Some((frame, schedule));
+        Ok(())
+    }
 
-            //Missシェーダーにローカルルートシグニチャが存在するならこの後に書く
-            //let miss_shader_p = miss_shader_p.add((D3D12_SHADER_IDENTIFIER_SIZE_IN_BYTES as usize);
+    pub fn create_shader_table(&mut self) -> Result<()> {
+        
+        let device = self.device.as_ref().ok_or(RwrError::NotInitialized("device"))?;
+        let state_object = self.state_object.as_ref().ok_or(RwrError::NotInitialized("state object"))?;
 
-            let hit_group_p = data.add(ray_gen_region).add(miss_region);
-            let id = rtso_props.GetShaderIdentifier(PWSTR(self.default_hit_group_symbol.as_mut_ptr()));
+        let rtso_props: ID3D12StateObjectProperties = state_object.cast()?;
 
-            std::ptr::copy_nonoverlapping(
-                id, 
-                hit_group_p,
-                D3D12_SHADER_IDENTIFIER_SIZE_IN_BYTES as usize,
-            );
+        //レコードはシェーダーテーブルのそれぞれの要素のこと
+        //ローカルルートシグニチャの引数はここで各レコードに渡す
+        //missとヒットグループはRayTypeの順に並べる
+        let hit_groups = self.library.hit_group_names();
+        let builder = self
+            .library
+            .miss
+            .iter()
+            .fold(ShaderTableBuilder::new().ray_gen(&self.library.ray_gen, None), |b, name| b.miss(name, None))
+            .hit_groups_per_geometry(1, &hit_groups);
+
+        //InstanceID()番目のcallableがそのインスタンスのマテリアル
+        let builder = self.library.callables.iter().fold(builder, |b, name| b.callable(name, None));
+
+        let identifier = |name: &str| {
+            let mut symbol = wide(name);
+            let id = unsafe { rtso_props.GetShaderIdentifier(PWSTR(symbol.as_mut_ptr())) };
+            if id.is_null() {
+                return None;
+            }
 
-            //HitGroupにローカルルートシグニチャが存在するならこの後に書く
-            //let hit_group_p = hit_group_p.add(D3D12_SHADER_IDENTIFIER_SIZE_IN_BYTES as usize);
+            let mut identifier: ShaderIdentifier = [0; SHADER_IDENTIFIER_SIZE as usize];
+            unsafe { std::ptr::copy_nonoverlapping(id as *const u8, identifier.as_mut_ptr(), identifier.len()) };
+            Some(identifier)
+        };
 
-            shader_table.Unmap(0, std::ptr::null());
+        let table = builder.build(identifier)?;
+        //accumulateとpost_processはTraceRayしないのでraygenのレコードだけ
+        let pass_tables = self
+            .library
+            .pass_ray_gens
+            .iter()
+            .map(|name| ShaderTableBuilder::new().ray_gen(name, None).build(identifier))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
 
-            //RayGenerationShaderRecordの設定
-            let start_address = shader_table.GetGPUVirtualAddress();
-            self.dispatch_ray_desc.RayGenerationShaderRecord = D3D12_GPU_VIRTUAL_ADDRESS_RANGE {
-                StartAddress: start_address,
-                SizeInBytes: ray_gen_size as u64,
-            };
-            
-            let start_address = start_address + ray_gen_region as u64;
-            self.dispatch_ray_desc.MissShaderTable = D3D12_GPU_VIRTUAL_ADDRESS_RANGE_AND_STRIDE {
-                StartAddress: start_address,
-                SizeInBytes: miss_size as u64,
-                StrideInBytes: record_size as u64,
+        //シェーダーテーブル生成
+        let buffers = self.buffers.as_mut().ok_or(RwrError::NotInitialized("device"))?;
+        let mut upload = |table: &ShaderTable| -> Result<(BufferAllocation, D3D12_DISPATCH_RAYS_DESC)> {
+            let allocation = buffers
+                .upload
+                .allocate(table.data.len() as u64, D3D12_RAYTRACING_SHADER_TABLE_BYTE_ALIGNMENT as u64)
+                ?;
+            write_buffer(buffers.upload.block(allocation.block), &allocation, &table.data)?;
+
+            //sizeが0のテーブルはアドレスも0にしておく
+            let start_address = allocation.gpu_address;
+            let range = |r: &TableRange| D3D12_GPU_VIRTUAL_ADDRESS_RANGE_AND_STRIDE {
+                StartAddress: if r.size == 0 { 0 } else { start_address + r.offset },
+                SizeInBytes: r.size,
+                StrideInBytes: r.stride,
             };
 
-            let start_address = start_address + miss_region as u64;
-            self.dispatch_ray_desc.HitGroupTable = D3D12_GPU_VIRTUAL_ADDRESS_RANGE_AND_STRIDE {
-                StartAddress: start_address,
-                SizeInBytes: hit_group_size as u64,
-                StrideInBytes: record_size as u64,
+            let desc = D3D12_DISPATCH_RAYS_DESC {
+                RayGenerationShaderRecord: D3D12_GPU_VIRTUAL_ADDRESS_RANGE {
+                    StartAddress: start_address + table.ray_gen.offset,
+                    SizeInBytes: table.ray_gen.size,
+                },
+                MissShaderTable: range(&table.miss),
+                HitGroupTable: range(&table.hit_group),
+                CallableShaderTable: range(&table.callable),
+                Width: self.width,
+                Height: self.height,
+                Depth: 1,
             };
-            
-            //let start_address = start_address + hit_group_region as u64;
-
-            self.dispatch_ray_desc.Width = self.width;
-            self.dispatch_ray_desc.Height = self.height;
-            self.dispatch_ray_desc.Depth = 1;
+            Ok((allocation, desc))
         };
-        
-        Ok(())
-    }
 
-    fn wait_for_gpu(queue: &ID3D12CommandQueue, fence: &ID3D12Fence, fence_value: u64, fence_event: &HANDLE) -> Result<u64> {
+        let (shader_table, dispatch_ray_desc) = upload(&table)?;
+        self.shader_table = Some(shader_table);
+        self.dispatch_ray_desc = dispatch_ray_desc;
 
-        /*
-        Fenceに設定された初期値は0でqueueを通してシグナルを送るとそのqueueのコマンドがGPU上で実行が完了していたときに第２引数の値に更新する
-        Fenceには常に前に呼び出されたときの値が入っているため" + 1"して更新してあげると良い感じに待てる
-        fenceの中に入ってる値の初期値は0
-        */
+        let passes = pass_tables.iter().map(&mut upload).collect::<Result<Vec<_>>>()?;
+        self.pass_shader_tables = passes.iter().map(|&(allocation, _)| allocation).collect();
+        self.pass_dispatch_ray_descs = passes.into_iter().map(|(_, desc)| desc).collect();
 
-        unsafe {
-            queue.Signal(fence, fence_value)
-        }
-        .ok()
-        //Optionをunwrapしなければいけない？
-        .unwrap();
+        Ok(())
+    }
 
-        if unsafe { fence.GetCompletedValue() } < fence_value {
-            unsafe {
-                fence.SetEventOnCompletion(fence_value, fence_event)
+    //バッファのプールごとの使用量
+    pub fn print_buffer_stats(&self) {
+        if let Some(buffers) = &self.buffers {
+            for (name, stats) in buffers.stats() {
+                println!("{} buffers: {}", name, stats);
             }
-            .ok()
-            .unwrap();
-
-            unsafe { WaitForSingleObject(fence_event, INFINITE) };
         }
+    }
+
+    //CreateStateObjectは何が足りないのか教えてくれないので先にRDATと突き合わせる
+    pub fn validate_library(&self, config: &PipelineConfig) -> Result<()> {
+        let bytes = unsafe { std::slice::from_raw_parts(self.ray_shader_blob.GetBufferPointer() as *const u8, self.ray_shader_blob.GetBufferSize()) };
+        let runtime_data = Container::parse(bytes)
+            .and_then(|c| c.runtime_data())
+            .map_err(|source| RwrError::ShaderParse { path: RAY_SHADER_PATH.to_string(), source })?;
 
-        Ok(fence_value + 1)
+        self.library.validate(&runtime_data, config).with_context(|| format!("{} does not match the shader library", RAY_SHADER_PATH))?;
+        Ok(())
     }
 
     fn load_shader<'a>(path: impl Into<Cow<'a, str>>) -> Result<ID3DBlob> {
         let path: &str = &path.into();
 
-        Ok(unsafe {
-            D3DReadFileToBlob(path)?
-        })
+        let blob = unsafe { D3DReadFileToBlob(path) }
+            .map_err(|e| RwrError::ShaderLoad { path: path.to_string(), message: e.message().to_string() })?;
+
+        //.csoとして読めないものはCreateStateObjectまで持ち込まない
+        let bytes = unsafe { std::slice::from_raw_parts(blob.GetBufferPointer() as *const u8, blob.GetBufferSize()) };
+        Container::parse(bytes)
+            .and_then(|c| c.runtime_data())
+            .map_err(|source| RwrError::ShaderParse { path: path.to_string(), source })?;
+
+        Ok(blob)
     }
 
-    pub fn update(&mut self) {
+    //シーンファイルのエクスポート名を使う create_state_objectより前に呼ぶ
+    pub fn set_exports(&mut self, exports: &RayShaderExports) {
+        self.library = ray_shader_library(exports, default_materials().names());
+    }
 
+    pub fn handle_input(&mut self, event: &InputEvent) {
+        self.camera.handle(event);
     }
 
-    pub fn render(&mut self) {
+    //シーンのカメラの位置からcontrollerで動かす
+    pub fn set_camera(&mut self, camera: &Camera, controller: ControllerKind) {
+        self.camera = controller.create(camera);
+        self.projection = camera.projection;
+        self.accumulated_pose = None;
+    }
+
+    //dtは前のフレームからの秒数
+    pub fn update(&mut self, dt: f32) {
+        self.camera.update(dt);
+    }
+
+    pub fn render(&mut self) -> Result<()> {
         if cfg!(debug_assertions) {
             println!("render");
         }
 
-        let device = self.device.as_ref().expect("You have to initialize a device");
-        let command_list = &self.command_list.as_ref().expect("You have to initialize a command list")[self.frame_index as usize];
-        let command_allocator = &self.command_allocator.as_ref().expect("You have to initialize a command allocator")[self.frame_index as usize];
-        let command_queue = self.command_queue.as_ref().expect("You have to initialize a command queue");
-        let global_root_signature = self.global_root_signature.as_ref().expect("You have ot initialize a global root signature");
-        let tlas_descriptor = self.tlas_descriptor.as_ref().expect("You have to initialize a tlas descriptor");
-        let result_resource_descriptor = self.result_resource_descriptor.as_ref().expect("You have to initialize a result resource discriptor");
-        let state_object = self.state_object.as_ref().expect("You have to initialize a state object");
-        let result_buffer = self.result_buffer.as_ref().expect("You have to initialize a result buffer");
+        //スロットを使い回すときだけ、前にそのスロットで積んだ描画が終わるまで待つ
+        let frames = self.frames.as_mut().ok_or(RwrError::NotInitialized("frame resources"))?;
+        let slot = frames.begin_frame(self.fence.as_mut().ok_or(RwrError::NotInitialized("fence"))?)? as u32;
+        frames.current().reset()?;
+        let FrameResources { command_list, .. } = frames.current().clone();
+        let command_list = &command_list;
+
+        //カメラが動いたら平均をやり直す
+        let pose = self.camera.pose();
+        if self.accumulated_pose != Some(pose) {
+            self.accumulated_pose = Some(pose);
+            self.sample_count = 0;
+        }
+        let frame_constants = HlslFrame { sample_count: self.sample_count, exposure: self.exposure };
+        self.sample_count = self.sample_count.saturating_add(1);
+
+        //ディスクリプタとアップロードのリングもスロットに合わせて使い直す
+        self.cbv_srv_uav_descriptor_heap.as_mut().ok_or(RwrError::NotInitialized("descriptor heap"))?.begin_frame(slot);
+        self.buffers.as_mut().ok_or(RwrError::NotInitialized("device"))?.frame_upload.begin_frame(slot);
+
+        let command_queue = self.command_queue.as_ref().ok_or(RwrError::NotInitialized("command queue"))?;
+        let global_root_signature = self.global_root_signature.as_ref().ok_or(RwrError::NotInitialized("global root signature"))?;
+        let tlas_descriptor = self.tlas_descriptor.as_ref().ok_or(RwrError::NotInitialized("tlas descriptor"))?;
+        let result_resource_descriptor = self.result_resource_descriptor.as_ref().ok_or(RwrError::NotInitialized("result resource descriptor"))?;
+        let alpha_mask_descriptor = self.alpha_mask_descriptor.as_ref().ok_or(RwrError::NotInitialized("alpha mask"))?;
+        let state_object = self.state_object.as_ref().ok_or(RwrError::NotInitialized("state object"))?;
+        let result_buffer = self.result_buffer.as_ref().ok_or(RwrError::NotInitialized("result buffer"))?;
         let render_target = &self.render_targets[self.frame_index as usize];
+        let tlas_id = self.tlas_id.ok_or(RwrError::NotInitialized("tlas"))?;
+        let buffers = self.buffers.as_ref().ok_or(RwrError::NotInitialized("device"))?;
+        let tlas = buffers.acceleration_structure.block(self.tlas.as_ref().ok_or(RwrError::NotInitialized("tlas"))?.block);
+        let alpha_mask = self.alpha_mask.as_ref().ok_or(RwrError::NotInitialized("alpha mask"))?;
+        let accumulation = self.accumulation.as_ref().ok_or(RwrError::NotInitialized("accumulation"))?;
+        let accumulation_descriptor = self.accumulation_descriptor.as_ref().ok_or(RwrError::NotInitialized("accumulation"))?;
+        //shaders::ray_shader_libraryのpass_ray_gensの順
+        let [accumulate, post_process] = &self.pass_dispatch_ray_descs[..] else {
+            return Err(RwrError::NotInitialized("shader table"));
+        };
+        let (frame, schedule) = self.frame_graph.as_ref().ok_or(RwrError::NotInitialized("frame graph"))?;
         
         unsafe {
             
             let descriptor_heaps: [Option<ID3D12DescriptorHeap>; 1] = [
-                Some(self.cbv_srv_uav_descriptor_heap.clone().unwrap().heap),
+                Some(self.cbv_srv_uav_descriptor_heap.as_ref().ok_or(RwrError::NotInitialized("descriptor heap"))?.heap().clone()),
             ];
 
             //ルートシグニチャとリソースをセット
@@ -1215,106 +1076,142 @@ impl Dx12Rt {
             command_list.SetDescriptorHeaps(descriptor_heaps.len() as u32, &descriptor_heaps as *const _);
             command_list.SetComputeRootDescriptorTable(0, tlas_descriptor.h_gpu);
             command_list.SetComputeRootDescriptorTable(1, result_resource_descriptor.h_gpu);
+            command_list.SetComputeRootDescriptorTable(2, alpha_mask_descriptor.h_gpu);
+
+            let alpha_test = HlslAlphaTest { width: self.alpha_mask_size.0, height: self.alpha_mask_size.1, cutoff: ALPHA_CUTOFF };
+            command_list.SetComputeRoot32BitConstants(3, (HlslAlphaTest::SIZE / 4) as u32, &alpha_test as *const _ as _, 0);
+
+            //コントローラーの今の姿勢でレイを飛ばす
+            let camera = Camera::from_pose(&pose, self.projection).constants();
+            command_list.SetComputeRoot32BitConstants(4, (HlslCamera::SIZE / 4) as u32, &camera as *const _ as _, 0);
+
+            command_list.SetComputeRootDescriptorTable(5, accumulation_descriptor.h_gpu);
+            command_list.SetComputeRoot32BitConstants(6, (HlslFrame::SIZE / 4) as u32, &frame_constants as *const _ as _, 0);
             
-            //バリア設定
-            let barrier_to_uav = D3D12_RESOURCE_BARRIER {
-                Type: D3D12_RESOURCE_BARRIER_TYPE_TRANSITION,
-                Flags: D3D12_RESOURCE_BARRIER_FLAG_NONE,
-                Anonymous: D3D12_RESOURCE_BARRIER_0 {
-                    Transition: std::mem::ManuallyDrop::new(D3D12_RESOURCE_TRANSITION_BARRIER {
-                        //Cloneでも大丈夫か
-                        pResource: self.result_buffer.clone(),
-                        StateBefore: D3D12_RESOURCE_STATE_COPY_SOURCE,
-                        StateAfter: D3D12_RESOURCE_STATE_UNORDERED_ACCESS,
-                        Subresource: D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES,
-                    }),
-                }
+            //TLASのビルドを待つ
+            self.resources.require(tlas_id, D3D12_RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE, Access::Read);
+            self.resources.flush(command_list);
+
+            //ResourceIdの順に実際のリソースを並べる
+            //一時リソースはtrace_outputとpost_outputの2つで、どちらもスロット0のresult_bufferに置かれる
+            let mut resources = vec![result_buffer; schedule.resources.len()];
+            resources[frame.tlas.0] = tlas;
+            resources[frame.alpha_mask.0] = alpha_mask;
+            resources[frame.accumulation.0] = accumulation;
+            resources[frame.back_buffer.0] = render_target;
+            resources[frame.trace_output.0] = result_buffer;
+            resources[frame.post_output.0] = result_buffer;
+
+            let mut backend = Dx12Frame {
+                command_list,
+                state_object,
+                dispatch_ray_desc: &self.dispatch_ray_desc,
+                accumulate,
+                post_process,
+                resources,
             };
+            let Ok(()) = schedule.execute(&mut backend);
 
-            command_list.ResourceBarrier(1, &barrier_to_uav);
-
-            //レイトレ
-            command_list.SetPipelineState1(state_object);
-            command_list.DispatchRays(&self.dispatch_ray_desc);
-
-
-            let barriers = [
-                D3D12_RESOURCE_BARRIER {
-                    Type: D3D12_RESOURCE_BARRIER_TYPE_TRANSITION,
-                    Flags: D3D12_RESOURCE_BARRIER_FLAG_NONE,
-                    Anonymous: D3D12_RESOURCE_BARRIER_0 {
-                        Transition: std::mem::ManuallyDrop::new(D3D12_RESOURCE_TRANSITION_BARRIER {
-                            //Cloneでも大丈夫か
-                            pResource: self.result_buffer.clone(),
-                            StateBefore: D3D12_RESOURCE_STATE_UNORDERED_ACCESS,
-                            StateAfter: D3D12_RESOURCE_STATE_COPY_SOURCE,
-                            Subresource: D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES,
-                        }),
-                    }
-                }, D3D12_RESOURCE_BARRIER {
-                    Type: D3D12_RESOURCE_BARRIER_TYPE_TRANSITION,
-                    Flags: D3D12_RESOURCE_BARRIER_FLAG_NONE,
-                    Anonymous: D3D12_RESOURCE_BARRIER_0 {
-                        Transition: std::mem::ManuallyDrop::new(D3D12_RESOURCE_TRANSITION_BARRIER {
-                            //Cloneでも大丈夫か
-                            pResource: Some(render_target.clone()),
-                            StateBefore: D3D12_RESOURCE_STATE_PRESENT,
-                            StateAfter: D3D12_RESOURCE_STATE_COPY_DEST,
-                            Subresource: D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES,
-                        }),
-                    }
-                },
-            ];
-            command_list.ResourceBarrier(barriers.len() as u32, &barriers as *const _);
-            command_list.CopyResource(render_target, result_buffer);
-
-            let barrier_to_present = D3D12_RESOURCE_BARRIER {
-                Type: D3D12_RESOURCE_BARRIER_TYPE_TRANSITION,
-                Flags: D3D12_RESOURCE_BARRIER_FLAG_NONE,
-                Anonymous: D3D12_RESOURCE_BARRIER_0 {
-                    Transition: std::mem::ManuallyDrop::new(D3D12_RESOURCE_TRANSITION_BARRIER {
-                        //Cloneでも大丈夫か
-                        pResource: Some(render_target.clone()),
-                        StateBefore: D3D12_RESOURCE_STATE_COPY_DEST,
-                        StateAfter: D3D12_RESOURCE_STATE_PRESENT,
-                        Subresource: D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES,
-                    }),
-                }
-            };
-            command_list.ResourceBarrier(1, &barrier_to_present);
-            command_list.Close().unwrap();
+            command_list.Close()?;
 
-            let command_list: ID3D12CommandList = command_list.cast().unwrap();
+            let command_list: ID3D12CommandList = command_list.cast()?;
             let command_list = Some(command_list);
 
             command_queue.ExecuteCommandLists(1, &command_list as *const _);
 
-            self.present(1);
-
+            self.present(1)
         }
     }
 
-    fn present(&mut self, interval: u32) {
+    fn present(&mut self, interval: u32) -> Result<()> {
 
-        let swap_chain = self.swap_chain.as_ref().unwrap();
-        let command_list = &self.command_list.as_ref().expect("You have to initialize a command list")[self.frame_index as usize];
-        let command_allocator = &self.command_allocator.as_ref().expect("You have to initialize a command allocator")[self.frame_index as usize];
-        let command_queue = self.command_queue.as_ref().unwrap();
-        let fence = self.fence.as_ref().unwrap();
+        let swap_chain = self.swap_chain.as_ref().ok_or(RwrError::NotInitialized("swap chain"))?;
+        let frames = self.frames.as_mut().ok_or(RwrError::NotInitialized("frame resources"))?;
+        let fence = self.fence.as_mut().ok_or(RwrError::NotInitialized("fence"))?;
 
         unsafe { 
-            swap_chain.Present(interval, 0).unwrap();
+            swap_chain.Present(interval, 0).ok()?;
 
-            command_queue.Signal(fence, self.fence_value).unwrap();
-
-            self.fence_value = Self::wait_for_gpu(command_queue, fence, self.fence_value, &self.fence_event).unwrap();
+            //GPUを待たずに次のスロットへ進む
+            frames.end_frame(fence)?;
 
             self.frame_index = swap_chain.GetCurrentBackBufferIndex();
+        }
 
+        Ok(())
+    }
+}
 
-            //command_listがレコード状態でResetをかけるとエラーとなるので使った後に必ずResetをかけることで二重Resetを防ぐ
-            command_allocator.Reset().unwrap(); 
-            command_list.Reset(command_allocator, None).unwrap();
+//まだGPUが使っているリソースを解放しないように、積んだコマンドが全部終わるまで待つ
+impl Drop for Dx12Rt {
+    fn drop(&mut self) {
+        if let (Some(frames), Some(fence)) = (self.frames.as_mut(), self.fence.as_mut()) {
+            //デバイスが失われていたら待つものもないので失敗は無視する
+            let _ = frames.flush(fence);
         }
     }
-}
\ No newline at end of file
+}
+
+//フレームグラフのパスをコマンドリストに積む
+//一時リソースはスロットごとのコミットリソースに置くので、エイリアシングのバリアの代わりに
+//前のリソースのアクセスが終わるのを待つUAVバリアを出す
+struct Dx12Frame<'a> {
+    command_list: &'a ID3D12GraphicsCommandList4,
+    state_object: &'a ID3D12StateObject,
+    dispatch_ray_desc: &'a D3D12_DISPATCH_RAYS_DESC,
+    accumulate: &'a D3D12_DISPATCH_RAYS_DESC,
+    post_process: &'a D3D12_DISPATCH_RAYS_DESC,
+    //ResourceIdの順
+    resources: Vec<&'a ID3D12Resource>,
+}
+
+impl GraphBackend for Dx12Frame<'_> {
+    type Error = std::convert::Infallible;
+
+    fn barriers(&mut self, _graph: &CompiledGraph, barriers: &[Barrier], aliasing: &[Aliasing]) -> std::result::Result<(), Self::Error> {
+        let reuse = aliasing.iter().filter(|a| a.before.is_some()).map(|a| Barrier::Uav { resource: a.after });
+        let barriers: Vec<Barrier> = reuse.chain(barriers.iter().copied()).collect();
+        resource_barriers(self.command_list, &barriers, |id| self.resources[id.0]);
+        Ok(())
+    }
+
+    fn execute(&mut self, _graph: &CompiledGraph, pass: &CompiledPass) -> std::result::Result<(), Self::Error> {
+        unsafe {
+            match pass.pass.kind {
+                //同じステートオブジェクトでraygenのレコードだけ替える
+                PassKind::RayTrace | PassKind::Accumulate | PassKind::PostProcess => {
+                    let desc = match pass.pass.kind {
+                        PassKind::Accumulate => self.accumulate,
+                        PassKind::PostProcess => self.post_process,
+                        _ => self.dispatch_ray_desc,
+                    };
+                    self.command_list.SetPipelineState1(self.state_object);
+                    self.command_list.DispatchRays(desc);
+                }
+                PassKind::Copy => {
+                    let (source, _) = pass.pass.reads[0];
+                    let (dest, _) = pass.pass.writes[0];
+                    self.command_list.CopyResource(self.resources[dest.0], self.resources[source.0]);
+                }
+                //Presentはコマンドリストを実行した後
+                PassKind::Present => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<D3D12_RAYTRACING_ACCELERATION_STRUCTURE_PREBUILD_INFO> for PrebuildInfo {
+    fn from(info: D3D12_RAYTRACING_ACCELERATION_STRUCTURE_PREBUILD_INFO) -> Self {
+        PrebuildInfo {
+            result_data_max_size: info.ResultDataMaxSizeInBytes,
+            scratch_data_size: info.ScratchDataSizeInBytes,
+            update_scratch_data_size: info.UpdateScratchDataSizeInBytes,
+        }
+    }
+}
+
+//ヌル終端のUTF-16
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
@@ -0,0 +1,81 @@
+use windows::{
+    core::*, Win32::Foundation::*, Win32::Graphics::Direct3D12::*, Win32::System::Threading::*,
+    Win32::System::WindowsProgramming::*,
+};
+
+use crate::frame_ring::Fence;
+
+//キューに積んだコマンドの完了をID3D12Fenceで待つ
+pub struct Dx12Fence {
+    queue: ID3D12CommandQueue,
+    fence: ID3D12Fence,
+    event: HANDLE,
+}
+
+impl Dx12Fence {
+    pub fn new(device: &ID3D12Device5, queue: &ID3D12CommandQueue) -> Result<Self> {
+        //fenceの中に入ってる値の初期値は0
+        let fence = unsafe { device.CreateFence(0, D3D12_FENCE_FLAG_NONE)? };
+        let event = unsafe { CreateEventA(std::ptr::null(), false, false, None) };
+        Ok(Dx12Fence { queue: queue.clone(), fence, event })
+    }
+}
+
+impl Fence for Dx12Fence {
+    type Error = Error;
+
+    fn completed_value(&self) -> u64 {
+        unsafe { self.fence.GetCompletedValue() }
+    }
+
+    //queueのコマンドがGPU上で実行し終わったときにvalueに更新される
+    fn signal(&mut self, value: u64) -> Result<()> {
+        unsafe { self.queue.Signal(&self.fence, value) }
+    }
+
+    fn wait(&mut self, value: u64) -> Result<()> {
+        if self.completed_value() >= value {
+            return Ok(());
+        }
+
+        unsafe {
+            self.fence.SetEventOnCompletion(value, self.event)?;
+            WaitForSingleObject(self.event, INFINITE);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Dx12Fence {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.event) };
+    }
+}
+
+//フレームのスロットごとに持つコマンドアロケーターとコマンドリスト
+#[derive(Clone)]
+pub struct FrameResources {
+    pub command_allocator: ID3D12CommandAllocator,
+    pub command_list: ID3D12GraphicsCommandList4,
+}
+
+impl FrameResources {
+    //作ったコマンドリストはレコード状態なので閉じておき、使う前に必ずresetする
+    pub fn new(device: &ID3D12Device5) -> Result<Self> {
+        unsafe {
+            let command_allocator: ID3D12CommandAllocator = device.CreateCommandAllocator(D3D12_COMMAND_LIST_TYPE_DIRECT)?;
+            //psoは後で設定
+            let command_list: ID3D12GraphicsCommandList4 = device.CreateCommandList(0, D3D12_COMMAND_LIST_TYPE_DIRECT, &command_allocator, None)?;
+            command_list.Close()?;
+            Ok(FrameResources { command_allocator, command_list })
+        }
+    }
+
+    //このスロットで前に積んだコマンドが終わってから呼ぶ
+    pub fn reset(&self) -> Result<()> {
+        unsafe {
+            self.command_allocator.Reset()?;
+            self.command_list.Reset(&self.command_allocator, None)
+        }
+    }
+}